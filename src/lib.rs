@@ -0,0 +1,490 @@
+//! Runs Pascal programs from Rust.
+//!
+//! A program is compiled once and can then be run any number of times, each
+//! time against an `Env` that holds the values of its global variables:
+//! they are read from the environment when the program starts and written
+//! back to it when it stops.
+//!
+//! ```
+//! use rusterp::{compile, Env, Value};
+//!
+//! let program = compile("PROGRAM area; VAR side : INTEGER; area : REAL; BEGIN area := side * side / 2 END.").unwrap();
+//! let mut env = Env::new();
+//! env.set("side", 3);
+//! program.run(&mut env).unwrap();
+//! assert_eq!(env.get("area"), Some(Value::Real(4.5)));
+//! ```
+//!
+//! Programs can call procedures and functions written in Rust, registered
+//! with a `Host` before the program is compiled:
+//!
+//! ```
+//! use rusterp::{compile_with_host, Env, Host, Type, Value};
+//!
+//! let mut host = Host::new();
+//! host.function("Sqrt", &[Type::Real], Type::Real, |args| Ok(Value::Real(args[0].as_real().sqrt())));
+//! let program = compile_with_host("PROGRAM p; VAR r : REAL; BEGIN r := Sqrt(16) + 1 END.", &host).unwrap();
+//! let mut env = Env::new();
+//! program.run(&mut env).unwrap();
+//! assert_eq!(env.get("r"), Some(Value::Real(5.0)));
+//! ```
+//!
+//! Programs nobody has checked can be run within `Limits` on the
+//! instructions they run, the memory their values take and the time they
+//! take. Their source may nest `Limits::depth` levels deep, their calls
+//! `Limits::calls` levels, and they may compile to `Limits::code`
+//! instructions. They read no files with `{$I}` and `USES` unless
+//! `CompileOptions` lets them.
+
+#![allow(clippy::upper_case_acronyms, clippy::module_inception)]
+
+#[doc(hidden)]
+pub mod utils;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use utils::ast::ident;
+use utils::bytecode::{self, Chunk, Symbol};
+use utils::limits;
+use utils::optimizer;
+use utils::parser::Parser;
+use utils::preprocessor::Preprocessor;
+use utils::source::SourceMap;
+use utils::units;
+use utils::vm::Vm;
+
+pub use utils::ast::decl::Type;
+pub use utils::err::diagnostic::{Diagnostic, Diagnostics};
+pub use utils::host::Host;
+pub use utils::interpreter::Value;
+pub use utils::limits::{Limit, Limits, DEFAULT_CALLS, DEFAULT_CODE, DEFAULT_DEPTH};
+pub use utils::source::FileAccess;
+
+/// Compiles the program in `source`. It may not include files or use
+/// units: `compile_with` can let it.
+///
+/// Errors may point into `source`, a file it includes or a unit, and keep
+/// those files to be rendered against.
+pub fn compile(source: &str) -> Result<Program, CompileError> {
+    compile_with_host(source, &Host::new())
+}
+
+/// Like `compile`, for a program that may call the procedures and
+/// functions of `host`. The arguments of every call to them are checked
+/// here, and the program keeps the host to call them when it runs.
+pub fn compile_with_host(source: &str, host: &Host) -> Result<Program, CompileError> {
+    compile_with(source, &CompileOptions { host: host.clone(), ..CompileOptions::default() })
+}
+
+/// How a program is compiled.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// The procedures and functions the program may call.
+    pub host: Host,
+    /// Only `depth` and `code` apply here: how deeply the program and its
+    /// units may nest, and how many instructions they may compile to.
+    pub limits: Limits,
+    /// How the files the program includes and the units it uses are read,
+    /// not at all by default.
+    pub files: FileAccess,
+}
+
+/// Like `compile`, as `options` say.
+pub fn compile_with(source: &str, options: &CompileOptions) -> Result<Program, CompileError> {
+    let sources = Arc::new(SourceMap::with_access(options.files.clone()));
+    let file = sources.add("<source>", source.as_bytes().to_vec());
+    let depth = options.limits.depth;
+    // Code nests no deeper than it is long, so a program that reads no
+    // other files needs no more stack than its length takes.
+    let levels = match options.files {
+        FileAccess::Forbidden => depth.min(source.len()),
+        _ => depth,
+    };
+    let compiled = limits::with_stack(levels, 0, || {
+        let parser = Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file))?;
+        let mut program = parser.with_max_depth(depth).parse()?;
+        let mut units = units::load(&program.uses, file, &sources, &[], depth)?;
+        optimizer::optimize(&mut program);
+        units.iter_mut().for_each(optimizer::optimize_unit);
+        bytecode::compile_with_limit(&program, &units, &options.host, options.limits.code)
+    });
+    match compiled {
+        Ok(chunk) => Ok(Program { chunk, sources, host: options.host.clone() }),
+        Err(diagnostics) => Err(CompileError { diagnostics, sources }),
+    }
+}
+
+/// Why a program did not compile, with the files it was compiled from.
+#[derive(Debug)]
+pub struct CompileError {
+    diagnostics: Diagnostics,
+    sources: Arc<SourceMap>,
+}
+
+impl CompileError {
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    /// Renders the errors as rusterp prints them, each with the line of
+    /// the file it is in.
+    pub fn render(&self) -> String {
+        self.sources.render(&self.diagnostics, false)
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.diagnostics.fmt(f)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<CompileError> for Diagnostics {
+    fn from(error: CompileError) -> Self {
+        error.diagnostics
+    }
+}
+
+/// A compiled program, ready to run.
+#[derive(Debug)]
+pub struct Program {
+    chunk: Chunk,
+    /// The files it was compiled from, for rendering its errors.
+    sources: Arc<SourceMap>,
+    host: Host,
+}
+
+impl Program {
+    /// Runs the program, starting from the values `env` holds for its
+    /// global variables; those it holds nothing for start out as zero. When
+    /// the program stops, at its end or at an error, `env` is given the
+    /// value of every global variable.
+    ///
+    /// An INTEGER in `env` can start a REAL variable, but a REAL cannot
+    /// start an INTEGER one: the program does not run then.
+    pub fn run(&self, env: &mut Env) -> Result<(), Diagnostic> {
+        self.run_with_limits(env, &Limits::default())
+    }
+
+    /// Like `run`, stopping the program with an error once it goes past
+    /// `limits`. The error's `limit` tells which one it hit.
+    pub fn run_with_limits(&self, env: &mut Env, limits: &Limits) -> Result<(), Diagnostic> {
+        let mut slots = self.chunk.slots.iter().map(|(_, ty)| Value::zero(*ty)).collect::<Vec<_>>();
+        for (name, symbol) in &self.chunk.globals {
+            let (Symbol::Variable { ty, slot }, Some(value)) = (symbol, env.get(name)) else {
+                continue;
+            };
+            slots[*slot as usize] = match (ty, value) {
+                (Type::Real, Value::Integer(value)) => Value::Real(value as f64),
+                (Type::Integer, Value::Real(value)) => {
+                    return Err(Diagnostic::new(format!(
+                        "`{}` is an INTEGER, but the environment holds the REAL {}.",
+                        name, value
+                    ))
+                    .with_help("a REAL can only be given to a REAL variable"))
+                }
+                (_, value) => value,
+            };
+        }
+        let mut vm = Vm::with_host(&self.chunk, slots, &self.host).with_limits(*limits);
+        let result = vm.run();
+        for (name, value) in vm.globals() {
+            if let Some(value) = value {
+                env.set(&name, value);
+            }
+        }
+        result
+    }
+
+    /// The program's global variables with their types, by name as
+    /// declared, sorted regardless of case.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Type)> {
+        self.chunk.globals.iter().filter_map(|(name, symbol)| match symbol {
+            Symbol::Variable { ty, .. } => Some((name.as_str(), *ty)),
+            Symbol::Procedure => None,
+        })
+    }
+
+    /// Renders errors from running the program as rusterp prints them,
+    /// each with the line of the file it is in.
+    pub fn render(&self, diagnostics: impl Into<Diagnostics>) -> String {
+        self.sources.render(&diagnostics.into(), false)
+    }
+}
+
+/// The values of global variables, by name. Names are looked up regardless
+/// of case, as in Pascal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Env {
+    /// Keyed by `ident::key`, with the name as last set.
+    values: BTreeMap<String, (String, Value)>,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.values.get(&ident::key(name)).map(|(_, value)| *value)
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) {
+        self.values.insert(ident::key(name), (name.to_string(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        self.values.remove(&ident::key(name)).map(|(_, value)| value)
+    }
+
+    /// Every variable with its value, sorted regardless of case.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.values.values().map(|(name, value)| (name.as_str(), *value))
+    }
+}
+
+#[test]
+fn runs_against_the_environment() {
+    let program = compile("PROGRAM p; VAR Count : INTEGER; total : REAL; BEGIN count := count + 1; total := total + count END.")
+        .unwrap();
+    assert_eq!(program.globals().collect::<Vec<_>>(), [("Count", Type::Integer), ("total", Type::Real)]);
+    let mut env = Env::new();
+    env.set("TOTAL", 10);
+    program.run(&mut env).unwrap();
+    program.run(&mut env).unwrap();
+    assert_eq!(env.iter().collect::<Vec<_>>(), [("Count", Value::Integer(2)), ("total", Value::Real(13.0))]);
+
+    env.set("count", 1.5);
+    let error = program.run(&mut env).unwrap_err();
+    assert_eq!(error.message(), "`Count` is an INTEGER, but the environment holds the REAL 1.5.");
+    assert_eq!(env.get("total"), Some(Value::Real(13.0)));
+}
+
+#[test]
+fn keeps_what_ran_before_an_error() {
+    let program = compile("PROGRAM p; VAR i, j : INTEGER;\nBEGIN\n  i := 7;\n  j := i DIV j;\n  i := 0\nEND.").unwrap();
+    let mut env = Env::new();
+    let error = program.run(&mut env).unwrap_err();
+    assert_eq!(env.get("i"), Some(Value::Integer(7)));
+    let rendered = program.render(error);
+    assert!(rendered.contains(" --> <source>:4:14\n"), "{}", rendered);
+
+    let errors = compile("PROGRAM p; BEGIN i := END.").unwrap_err();
+    assert!(errors.render().starts_with("error: Expected expression"));
+}
+
+#[test]
+fn renders_compile_errors_against_their_file() {
+    let dir = std::env::temp_dir().join(format!("rusterp-lib-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let include = dir.join("body.inc");
+    std::fs::write(&include, "i := 1;\nj := ;\n").unwrap();
+    let source = format!("PROGRAM p; VAR i, j : INTEGER;\nBEGIN\n{{$I {}}}\nEND.", include.display());
+    let options = CompileOptions { files: FileAccess::Filesystem, ..CompileOptions::default() };
+    let error = compile_with(&source, &options).unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let rendered = error.render();
+    assert!(rendered.contains(&format!(" --> {}:2:6\n", include.display())), "{}", rendered);
+    assert!(rendered.contains("2 | j := ;\n"), "{}", rendered);
+    assert_eq!(error.diagnostics().len(), 1);
+}
+
+#[test]
+fn programs_and_hosts_go_to_other_threads() {
+    fn shared<T: Send + Sync>(_: &T) {}
+
+    let mut host = Host::new();
+    host.function("Answer", &[], Type::Integer, |_| Ok(Value::Integer(42)));
+    let program = compile_with_host("PROGRAM p; VAR i : INTEGER; BEGIN i := Answer END.", &host).unwrap();
+    shared(&host);
+    shared(&program);
+    let env = std::thread::spawn(move || {
+        let mut env = Env::new();
+        program.run(&mut env).unwrap();
+        env
+    })
+    .join()
+    .unwrap();
+    assert_eq!(env.get("i"), Some(Value::Integer(42)));
+}
+
+#[test]
+fn calls_the_host() {
+    use std::sync::Mutex;
+
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let log = logged.clone();
+    let mut host = Host::new();
+    host.procedure("Log", &[Type::Integer, Type::Real], move |args| {
+        log.lock().unwrap().push(args.to_vec());
+        Ok(())
+    })
+    .function("Half", &[Type::Real], Type::Real, |args| Ok(Value::Real(args[0].as_real() / 2.0)))
+    .function("Answer", &[], Type::Integer, |_| Ok(Value::Integer(42)))
+    .function("Positive", &[Type::Integer], Type::Integer, |args| match args[0] {
+        Value::Integer(value) if value > 0 => Ok(Value::Integer(value)),
+        value => Err(format!("{} is not positive.", value)),
+    });
+
+    let source = "PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN i := answer + 1; r := Half(i); Log(i, i); Half(1) END.";
+    let mut env = Env::new();
+    compile_with_host(source, &host).unwrap().run(&mut env).unwrap();
+    assert_eq!(env.get("i"), Some(Value::Integer(43)));
+    assert_eq!(env.get("r"), Some(Value::Real(21.5)));
+    assert_eq!(*logged.lock().unwrap(), [vec![Value::Integer(43), Value::Real(43.0)]]);
+
+    // What the program declares hides the host.
+    let program = compile_with_host("PROGRAM p; VAR answer : INTEGER; BEGIN answer := Answer + 1 END.", &host).unwrap();
+    let mut env = Env::new();
+    program.run(&mut env).unwrap();
+    assert_eq!(env.get("answer"), Some(Value::Integer(1)));
+
+    let program = compile_with_host("PROGRAM p; VAR i : INTEGER;\nBEGIN\n  i := 1 + Positive(i - 5)\nEND.", &host).unwrap();
+    let rendered = program.render(program.run(&mut Env::new()).unwrap_err());
+    assert_eq!(
+        rendered,
+        "error: -5 is not positive.\n --> <source>:3:12\n  |\n3 |   i := 1 + Positive(i - 5)\n  |            \
+         ^^^^^^^^^^^^^^^ in this call to `Positive`\n"
+    );
+
+    host.function("Broken", &[], Type::Integer, |_| Ok(Value::Real(0.5)));
+    let error = compile_with_host("PROGRAM p; VAR i : INTEGER; BEGIN i := Broken END.", &host)
+        .unwrap()
+        .run(&mut Env::new())
+        .unwrap_err();
+    assert_eq!(error.message(), "`Broken` gave the REAL 0.5 instead of an INTEGER.");
+}
+
+#[test]
+fn stops_at_its_limits() {
+    use std::time::Duration;
+
+    let body = "i := i + 1; r := r + i / 2;\n".repeat(100);
+    let program = compile(&format!("PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN\n{}END.", body)).unwrap();
+    let run = |limits: Limits| {
+        let mut env = Env::new();
+        let result = program.run_with_limits(&mut env, &limits);
+        (result.err().and_then(|error| error.limit()), env.get("i"))
+    };
+    assert_eq!(run(Limits::default()), (None, Some(Value::Integer(100))));
+    let generous = Limits {
+        instructions: Some(10_000),
+        memory: Some(1 << 20),
+        time: Some(Duration::from_secs(60)),
+        ..Limits::default()
+    };
+    assert_eq!(run(generous), (None, Some(Value::Integer(100))));
+    // `i := i + 1` is four instructions.
+    let instructions = Limits { instructions: Some(4), ..Limits::default() };
+    assert_eq!(run(instructions), (Some(Limit::Instructions), Some(Value::Integer(1))));
+    // Two variables take 32 bytes; the first value pushed goes past that.
+    let memory = Limits { memory: Some(32), ..Limits::default() };
+    assert_eq!(run(memory), (Some(Limit::Memory), Some(Value::Integer(0))));
+    assert_eq!(run(Limits { time: Some(Duration::ZERO), ..Limits::default() }).0, Some(Limit::Time));
+
+    let source = "PROGRAM p; VAR i : INTEGER; PROCEDURE Deeper; BEGIN i := i + 1; Deeper() END; BEGIN Deeper() END.";
+    let program = compile(source).unwrap();
+    let run = |limits: Limits| {
+        let mut env = Env::new();
+        let error = program.run_with_limits(&mut env, &limits).unwrap_err();
+        (error.limit(), env.get("i"))
+    };
+    let calls = Some(Value::Integer(DEFAULT_CALLS as i64));
+    assert_eq!(run(Limits::default()), (Some(Limit::Calls), calls));
+    assert_eq!(run(Limits { calls: 3, ..Limits::default() }), (Some(Limit::Calls), Some(Value::Integer(3))));
+    assert_eq!(run(Limits { calls: 0, ..Limits::default() }), (Some(Limit::Calls), Some(Value::Integer(0))));
+
+    let mut host = Host::new();
+    host.procedure("Wait", &[Type::Integer], |args| {
+        std::thread::sleep(Duration::from_millis(args[0].as_real() as u64));
+        Ok(())
+    });
+    let program = compile_with_host("PROGRAM p; VAR i : INTEGER; BEGIN Wait(20); i := 1 END.", &host).unwrap();
+    let mut env = Env::new();
+    let limits = Limits { time: Some(Duration::from_millis(10)), ..Limits::default() };
+    let error = program.run_with_limits(&mut env, &limits).unwrap_err();
+    assert_eq!(error.limit(), Some(Limit::Time));
+    assert!(program.render(error).contains("^^^^^^^^ stopped here"));
+    assert_eq!(env.get("i"), Some(Value::Integer(0)));
+}
+
+#[test]
+fn runs_code_nested_to_its_limit() {
+    // Blocks and parentheses count towards the same depth; runs of
+    // operators do not.
+    let depth = (DEFAULT_DEPTH - 5) / 2;
+    let source = format!(
+        "PROGRAM p; VAR i : INTEGER; BEGIN {}i := {}1{} + i * 2{}{} END.",
+        "BEGIN ".repeat(depth),
+        "(".repeat(depth),
+        ")".repeat(depth),
+        " - i".repeat(DEFAULT_DEPTH * 100),
+        " END".repeat(depth)
+    );
+    let mut env = Env::new();
+    compile(&source).unwrap().run(&mut env).unwrap();
+    assert_eq!(env.get("i"), Some(Value::Integer(1)));
+
+    let source = format!("PROGRAM p; VAR i : INTEGER; BEGIN i := {}1 END.", "-".repeat(DEFAULT_DEPTH * 1000));
+    let errors = compile(&source).unwrap_err();
+    assert_eq!(errors.diagnostics().iter().next().unwrap().limit(), Some(Limit::Depth));
+    let options = CompileOptions { limits: Limits { depth: 3, ..Limits::default() }, ..CompileOptions::default() };
+    let errors = compile_with("PROGRAM p; VAR i : INTEGER; BEGIN i := ((1)) END.", &options).unwrap_err();
+    assert_eq!(errors.diagnostics().iter().next().unwrap().limit(), Some(Limit::Depth));
+}
+
+#[test]
+fn stops_compiling_past_the_code_limit() {
+    let source = "PROGRAM p; VAR i : INTEGER;
+        PROCEDURE Q; BEGIN i := i + 1; i := i + 2; i := i + 3 END;
+        BEGIN Q(); i := i * 2; i := i * 3 END.";
+    let options = |code| CompileOptions { limits: Limits { code, ..Limits::default() }, ..CompileOptions::default() };
+    let program = compile_with(source, &options(22)).unwrap();
+    let mut env = Env::new();
+    program.run(&mut env).unwrap();
+    assert_eq!(env.get("i"), Some(Value::Integer(36)));
+
+    let error = compile_with(source, &options(21)).unwrap_err();
+    assert_eq!(error.diagnostics().len(), 1);
+    let rendered = error.render();
+    assert!(rendered.starts_with("error: The program compiles to more than 21 instructions."), "{}", rendered);
+    assert!(rendered.contains("3 |         BEGIN Q(); i := i * 2; i := i * 3 END.\n"), "{}", rendered);
+    let error = compile_with(source, &options(5)).unwrap_err();
+    assert_eq!(error.diagnostics().iter().next().unwrap().limit(), Some(Limit::Code));
+    assert!(error.render().contains("2 |         PROCEDURE Q;"), "{}", error.render());
+}
+
+#[test]
+fn reads_files_as_allowed() {
+    let source = "PROGRAM p; USES Lib; VAR i : INTEGER;\nBEGIN\n{$I body.inc}\nEND.";
+    let options = |files| CompileOptions { files, ..CompileOptions::default() };
+    let rendered = compile(source).unwrap_err().render();
+    assert!(rendered.starts_with("error: Cannot include `body.inc`: this program may not read files."), "{}", rendered);
+    let source = "PROGRAM p; VAR i : INTEGER; BEGIN {$I /etc/passwd} END.";
+    let rendered = compile_with(source, &CompileOptions::default()).unwrap_err().render();
+    assert!(rendered.starts_with("error: Cannot include `/etc/passwd`: this program may not read files."), "{}", rendered);
+    let source = "PROGRAM p; USES Lib; VAR i : INTEGER;\nBEGIN\n{$I body.inc}\nEND.";
+    let rendered = compile_with(source, &options(FileAccess::Forbidden)).unwrap_err().render();
+    assert!(rendered.starts_with("error: Cannot include `body.inc`: this program may not read files."), "{}", rendered);
+    let source = "PROGRAM p; USES Lib; BEGIN END.";
+    let rendered = compile_with(source, &options(FileAccess::Forbidden)).unwrap_err().render();
+    assert!(rendered.starts_with("error: Cannot load unit `Lib`: this program may not read files."), "{}", rendered);
+
+    let files = FileAccess::Callback(Arc::new(|path| match path.to_str() {
+        Some("body.inc") => Ok(b"i := Answer + 1".to_vec()),
+        Some("Lib.pa") => {
+            Ok(b"UNIT Lib; INTERFACE VAR Answer : INTEGER; IMPLEMENTATION BEGIN Answer := 41 END.".to_vec())
+        }
+        _ => Err("not here".to_string()),
+    }));
+    let source = "PROGRAM p; USES Lib; VAR i : INTEGER;\nBEGIN\n{$I body.inc}\nEND.";
+    let program = compile_with(source, &options(files.clone())).unwrap();
+    let mut env = Env::new();
+    program.run(&mut env).unwrap();
+    assert_eq!(env.get("i"), Some(Value::Integer(42)));
+    let rendered = compile_with("PROGRAM p; BEGIN {$I other.inc} END.", &options(files)).unwrap_err().render();
+    assert!(rendered.starts_with("error: Cannot include `other.inc`: not here."), "{}", rendered);
+}
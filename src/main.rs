@@ -1,49 +1,455 @@
-mod utils;
-
-use utils::interpreter::Interpreter;
-use utils::lexer::Lexer;
+use rusterp::utils::ast::program::Program;
+use rusterp::utils::ast::unit::Unit;
+use rusterp::utils::backend;
+use rusterp::utils::bytecode::disasm::disassemble;
+use rusterp::utils::bytecode::image::{self, Image};
+use rusterp::utils::bytecode::{self, Chunk};
+use rusterp::utils::err::diagnostic::{Diagnostic, Diagnostics};
+use rusterp::utils::formatter::{self, FormatOptions, KeywordCase};
+use rusterp::utils::host::Host;
+use rusterp::utils::interpreter::Interpreter;
+use rusterp::utils::optimizer;
+use rusterp::utils::lexer::Lexer;
+use rusterp::utils::limits::{self, DEFAULT_CALLS, DEFAULT_DEPTH};
+use rusterp::utils::parser::Parser;
+use rusterp::utils::preprocessor::Preprocessor;
+use rusterp::utils::repl::Repl;
+use rusterp::utils::source::{FileId, SourceMap};
+use rusterp::utils::units;
+use rusterp::utils::vm::Vm;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use crate::utils::lexer::TokenType;
-
-
-
-fn main() {
-    let  mut args = std::env::args().skip(1);
-    let next = args.next();
-    let mut string = String::new();
-    let mut file = File::open("test.pa").unwrap();
-    file.read_to_string(&mut string).unwrap();
-    match next {
-        Some(s) => {
-            match s.as_str() {
-                "rewrite" => {
-                    println!("{}",Interpreter::new(string.as_bytes()).unwrap().spit().unwrap())
-                }
-                "ast" => {
-                    let mut lex = Lexer::new(string.as_bytes());
-                    let mut token = lex.get_next_token().unwrap();
-                    loop {
-                        println!("{:?}", token);
-                        if let TokenType::EOF = token.token_type() {
-                            break;
-                        }
-                        token = lex.get_next_token().unwrap();
-                    }
+use rusterp::utils::lexer::TokenType;
+
+const USAGE: &str = "\
+Usage: rusterp <command> <file>
+       rusterp run|check|scope [--unit-path <dir>]... <file>
+       rusterp run|scope [--engine vm|tree] <file>
+       rusterp compile [--unit-path <dir>]... <file> [-o <out.pbc>]
+       rusterp disasm [--unit-path <dir>]... <file>
+       rusterp emit-c [--unit-path <dir>]... <file> [-o <out.c>]
+       rusterp emit-rust [--unit-path <dir>]... <file> [-o <dir>]
+       rusterp emit-wasm [--unit-path <dir>]... <file> [-o <out.wasm>]
+       rusterp fmt [--indent <n>] [--keyword-case upper|lower] [--emit=source|optimized] <file>
+       rusterp repl
+
+Commands:
+    run        Run the program
+    tokens     Print the tokens of the program
+    ast        Print the syntax tree of the program
+    fmt        Print the program formatted
+    check      Report errors without running the program or unit
+    scope      Run the program and print its global scope
+    compile    Compile the program to a bytecode file, `<file>.pbc` by default
+    disasm     Print the bytecode of the program with its source lines
+    emit-c     Print the program as C99 source, to build without rusterp
+    emit-rust  Write the program as a Cargo crate, by default in a directory
+               named after `<file>`
+    emit-wasm  Compile the program to a WebAssembly module, `<file>.wasm` by
+               default
+    repl       Start an interactive session
+
+Units named after USES are looked for next to the file naming them, then
+in each `--unit-path` directory in turn.
+
+`run` and `scope` compile the program to bytecode for a virtual machine;
+`--engine tree` walks the syntax tree instead, with the same results.
+`run`, `scope`, `disasm`, `emit-c` and `emit-wasm` also take a bytecode
+file written by `compile`.
+Programs are simplified before they run; `fmt --emit=optimized` shows how.
+
+Pass `-` as the file to read the program from stdin.";
+
+/// A source file given on the command line, and the files it includes
+/// once it is parsed.
+struct Source {
+    sources: Arc<SourceMap>,
+    file: FileId,
+}
+
+impl Source {
+    fn read(path: &str) -> Result<Self, String> {
+        let mut bytes = Vec::new();
+        let name = if path == "-" {
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("cannot read stdin: {}", e))?;
+            "<stdin>".to_string()
+        } else {
+            File::open(path)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+            path.to_string()
+        };
+        let sources = Arc::new(SourceMap::new());
+        let file = sources.add(name, bytes);
+        Ok(Self { sources, file })
+    }
+
+    fn parser(&self) -> Result<Parser<'static>, Diagnostic> {
+        Parser::with_preprocessor(Preprocessor::with_sources(self.sources.clone(), self.file))
+    }
+
+    /// Parses the program and loads the units it uses, simplified for
+    /// running.
+    fn program(&self, unit_path: &[PathBuf]) -> Result<(Program, Vec<Unit>), Diagnostics> {
+        let mut program = self.parser()?.parse()?;
+        let mut units = units::load(&program.uses, self.file, &self.sources, unit_path, DEFAULT_DEPTH)?;
+        optimizer::optimize(&mut program);
+        units.iter_mut().for_each(optimizer::optimize_unit);
+        Ok((program, units))
+    }
+
+    /// Parses the unit and loads the units it uses.
+    fn unit(&self, unit_path: &[PathBuf]) -> Result<Unit, Diagnostics> {
+        let unit = self.parser()?.parse_unit()?;
+        let uses = [unit.interface.uses.as_slice(), &unit.implementation.uses].concat();
+        units::load(&uses, self.file, &self.sources, unit_path, DEFAULT_DEPTH)?;
+        Ok(unit)
+    }
+
+    /// Like `program`, also compiling the program and the units it uses,
+    /// which finds the errors parsing does not.
+    fn compiled(&self, unit_path: &[PathBuf]) -> Result<(Program, Vec<Unit>, Chunk), Diagnostics> {
+        let (program, units) = self.program(unit_path)?;
+        let chunk = bytecode::compile_with_host(&program, &units, &Host::new())?;
+        Ok((program, units, chunk))
+    }
+
+    /// Compiles the program and the units it uses.
+    fn compile(&self, unit_path: &[PathBuf]) -> Result<Image, Diagnostics> {
+        let (_, _, chunk) = self.compiled(unit_path)?;
+        Ok(Image {
+            chunk,
+            sources: self.sources.clone(),
+        })
+    }
+
+    /// Whether the file is a bytecode file rather than Pascal source.
+    fn is_image(&self) -> bool {
+        let file = self.sources.file(self.file);
+        file.name.ends_with(&format!(".{}", image::EXTENSION)) || file.bytes.starts_with(image::MAGIC)
+    }
+
+    /// Compiles the source, or reads the bytecode file.
+    fn image(&self, unit_path: &[PathBuf]) -> Result<Image, Diagnostics> {
+        if self.is_image() {
+            Ok(Image::from_bytes(&self.sources.file(self.file).bytes)?)
+        } else {
+            self.compile(unit_path)
+        }
+    }
+
+    /// Whether the file holds a unit rather than a program.
+    fn is_unit(&self) -> bool {
+        let file = self.sources.file(self.file);
+        let mut lexer = Lexer::new(&file.bytes);
+        loop {
+            match lexer.get_next_token().as_ref().map(|token| token.token_type()) {
+                Ok(TokenType::DIRECTIVE(_)) => {}
+                first => return matches!(first, Ok(TokenType::UNIT)),
+            }
+        }
+    }
+
+    /// Prints `diagnostics` to stderr, each against the file it is in.
+    fn report(&self, diagnostics: impl Into<Diagnostics>) -> ExitCode {
+        let color = std::io::stderr().is_terminal();
+        eprint!("{}", self.sources.render(&diagnostics.into(), color));
+        ExitCode::FAILURE
+    }
+}
+
+fn usage_error(message: &str) -> ExitCode {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    ExitCode::from(2)
+}
+
+#[derive(Default)]
+struct Options {
+    format: FormatOptions,
+    unit_path: Vec<PathBuf>,
+    /// Walk the tree rather than compile to bytecode.
+    tree: bool,
+    /// Where `compile` writes the bytecode, `emit-c` the C source, or
+    /// `emit-rust` the crate, or `emit-wasm` the module.
+    output: Option<PathBuf>,
+    /// Have `fmt` print the program as the optimizer leaves it.
+    optimized: bool,
+}
+
+/// Reads the options after the command from the front of `args`.
+fn options(args: &mut Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let command = args.first().cloned().unwrap_or_default();
+    if matches!(command.as_str(), "compile" | "emit-c" | "emit-rust" | "emit-wasm") {
+        if let Some(at) = args.iter().position(|arg| arg == "-o") {
+            if at + 1 == args.len() {
+                return Err("`-o` needs a file name".to_string());
+            }
+            options.output = Some(PathBuf::from(args.remove(at + 1)));
+            args.remove(at);
+        }
+    }
+    while args.len() > 2 && args[1].starts_with("--") {
+        let flag = args.remove(1);
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), value.to_string()),
+            None => (flag, args.remove(1)),
+        };
+        match flag.as_str() {
+            "--unit-path" if matches!(command.as_str(), "run" | "check" | "scope" | "compile" | "disasm" | "emit-c" | "emit-rust" | "emit-wasm") => {
+                options.unit_path.push(PathBuf::from(value))
+            }
+            "--engine" if matches!(command.as_str(), "run" | "scope") => {
+                options.tree = match value.as_str() {
+                    "vm" => false,
+                    "tree" => true,
+                    _ => return Err(format!("`{}` is not an engine, use `vm` or `tree`", value)),
                 }
-                "genscope" =>  {
-                    let interp = Interpreter::new(string.as_bytes()).unwrap();
-                    interp.interprete().unwrap();
-                    interp.print_global_scope();
-                },
-                _ => {
-                    println!("{}",s);
+            }
+            "--indent" if command == "fmt" => {
+                options.format.indent = value
+                    .parse()
+                    .map_err(|_| format!("`{}` is not a valid indent", value))?
+            }
+            "--emit" if command == "fmt" => {
+                options.optimized = match value.as_str() {
+                    "source" => false,
+                    "optimized" => true,
+                    _ => return Err(format!("`{}` cannot be emitted, use `source` or `optimized`", value)),
                 }
             }
+            "--keyword-case" if command == "fmt" => {
+                options.format.keyword_case = match value.as_str() {
+                    "upper" => KeywordCase::Upper,
+                    "lower" => KeywordCase::Lower,
+                    _ => return Err(format!("`{}` is not a keyword case, use `upper` or `lower`", value)),
+                }
+            }
+            _ => return Err(format!("unknown option `{}` for `{}`", flag, command)),
         }
-        None => {todo!()}
     }
+    Ok(options)
+}
+
+/// Runs `chunk`, compiled from `source`, and prints the global scope after
+/// it if `scope` is set.
+fn execute(source: &Source, chunk: &Chunk, scope: bool) -> ExitCode {
+    let mut vm = Vm::new(chunk);
+    if let Err(e) = vm.run() {
+        return source.report(e);
+    }
+    if scope {
+        vm.print_global_scope();
+    }
+    ExitCode::SUCCESS
 }
 
+/// Passes over the syntax tree recurse as deep as the code nests, and the
+/// tree walker as deep as calls nest, so the command runs on a thread with
+/// the stack that takes.
+fn main() -> ExitCode {
+    limits::with_stack(DEFAULT_DEPTH, DEFAULT_CALLS, command)
+}
+
+fn command() -> ExitCode {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let options = match options(&mut args) {
+        Ok(options) => options,
+        Err(e) => return usage_error(&e),
+    };
+    let (command, path) = match args.as_slice() {
+        [flag] if flag == "-h" || flag == "--help" || flag == "help" => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        [command] if command == "repl" => {
+            let mut repl = Repl::new(std::io::stderr().is_terminal());
+            return match repl.run(std::io::stdin().lock(), std::io::stdout(), std::io::stderr()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        [command, path] => (command.as_str(), path.as_str()),
+        [] => return usage_error("no command given"),
+        [_] => return usage_error("no file given"),
+        _ => return usage_error("too many arguments"),
+    };
+    if !matches!(command, "run" | "tokens" | "ast" | "fmt" | "check" | "scope" | "compile" | "disasm" | "emit-c" | "emit-rust" | "emit-wasm") {
+        return usage_error(&format!("unknown command `{}`", command));
+    }
+    let source = match Source::read(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let file = source.sources.file(source.file);
+    let bytes = file.bytes.as_slice();
 
+    match command {
+        "tokens" => {
+            let mut lex = Lexer::new(bytes);
+            loop {
+                let token = match lex.get_next_token() {
+                    Ok(token) => token,
+                    Err(e) => return source.report(e),
+                };
+                println!("{:?}", token);
+                if let TokenType::EOF = token.token_type() {
+                    break;
+                }
+            }
+        }
+        "ast" if source.is_unit() => match source.parser().map_err(Diagnostics::from).and_then(|mut p| p.parse_unit()) {
+            Ok(unit) => println!("{:#?}", unit),
+            Err(e) => return source.report(e),
+        },
+        "ast" => match source.parser().map_err(Diagnostics::from).and_then(|mut p| p.parse()) {
+            Ok(program) => println!("{:#?}", program),
+            Err(e) => return source.report(e),
+        },
+        "check" => {
+            let checked = if source.is_unit() {
+                source.unit(&options.unit_path).map(drop)
+            } else {
+                source.compiled(&options.unit_path).map(drop)
+            };
+            if let Err(e) = checked {
+                return source.report(e);
+            }
+        }
+        "fmt" if options.optimized => {
+            let printed = if source.is_unit() {
+                source.parser().map_err(Diagnostics::from).and_then(|mut p| p.parse_unit()).map(|mut unit| {
+                    optimizer::optimize_unit(&mut unit);
+                    formatter::print_unit(&unit, &options.format)
+                })
+            } else {
+                source.parser().map_err(Diagnostics::from).and_then(|mut p| p.parse()).map(|mut program| {
+                    optimizer::optimize(&mut program);
+                    formatter::print_program(&program, &options.format)
+                })
+            };
+            match printed {
+                Ok(printed) => print!("{}", printed),
+                Err(e) => return source.report(e),
+            }
+        }
+        "fmt" => {
+            match formatter::format(bytes, &options.format) {
+                Ok(formatted) => print!("{}", formatted),
+                Err(e) => return source.report(e),
+            }
+        }
+        "run" | "scope" if source.is_image() => {
+            if options.tree {
+                return usage_error("`--engine tree` needs the source, not a bytecode file");
+            }
+            match source.image(&options.unit_path) {
+                Ok(image) => {
+                    let compiled = Source { sources: image.sources.clone(), file: 0 };
+                    return execute(&compiled, &image.chunk, command == "scope");
+                }
+                Err(e) => return source.report(e),
+            }
+        }
+        "run" | "scope" => {
+            let (program, units, chunk) = match source.compiled(&options.unit_path) {
+                Ok(compiled) => compiled,
+                Err(e) => return source.report(e),
+            };
+            if options.tree {
+                let interp = Interpreter::with_units(program, units);
+                if let Err(e) = interp.interprete() {
+                    return source.report(e);
+                }
+                if command == "scope" {
+                    interp.print_global_scope();
+                }
+            } else {
+                return execute(&source, &chunk, command == "scope");
+            }
+        }
+        "compile" => {
+            let output = match (options.output, path) {
+                (Some(output), _) => output,
+                (None, "-") => return usage_error("give the bytecode file a name with `-o` when reading stdin"),
+                (None, path) => PathBuf::from(path).with_extension(image::EXTENSION),
+            };
+            let image = match source.compile(&options.unit_path) {
+                Ok(image) => image,
+                Err(e) => return source.report(e),
+            };
+            if let Err(e) = std::fs::write(&output, image.to_bytes()) {
+                eprintln!("error: cannot write `{}`: {}", output.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+        "disasm" => match source.image(&options.unit_path) {
+            Ok(image) => print!("{}", disassemble(&image.chunk, &image.sources)),
+            Err(e) => return source.report(e),
+        },
+        "emit-c" => {
+            let c = match source.image(&options.unit_path) {
+                Ok(image) => backend::c::emit(&image.chunk, &image.sources),
+                Err(e) => return source.report(e),
+            };
+            match options.output {
+                Some(output) => {
+                    if let Err(e) = std::fs::write(&output, c) {
+                        eprintln!("error: cannot write `{}`: {}", output.display(), e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                None => print!("{}", c),
+            }
+        }
+        "emit-wasm" => {
+            let output = match (options.output, path) {
+                (Some(output), _) => output,
+                (None, "-") => return usage_error("give the module a name with `-o` when reading stdin"),
+                (None, path) => PathBuf::from(path).with_extension(backend::wasm::EXTENSION),
+            };
+            let module = match source.image(&options.unit_path) {
+                Ok(image) => backend::wasm::emit(&image.chunk, &image.sources),
+                Err(e) => return source.report(e),
+            };
+            if let Err(e) = std::fs::write(&output, module) {
+                eprintln!("error: cannot write `{}`: {}", output.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+        "emit-rust" => {
+            let output = match (options.output, path) {
+                (Some(output), _) => output,
+                (None, "-") => return usage_error("give the crate a directory with `-o` when reading stdin"),
+                (None, path) => PathBuf::from(path).with_extension(""),
+            };
+            let (program, units, _) = match source.compiled(&options.unit_path) {
+                Ok(compiled) => compiled,
+                Err(e) => return source.report(e),
+            };
+            for (file, contents) in backend::rust::emit(&program, &units, &source.sources) {
+                let file = output.join(file);
+                let written = std::fs::create_dir_all(file.parent().unwrap()).and_then(|()| std::fs::write(&file, contents));
+                if let Err(e) = written {
+                    eprintln!("error: cannot write `{}`: {}", file.display(), e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+    ExitCode::SUCCESS
+}
@@ -1,86 +1,32 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use crate::utils::lexer::Span;
 
-use crate::utils::lexer::TokenType;
+use super::decl::Decl;
+use super::ident::key;
+use super::stmt::Compound;
 
-use super::ast::AST;
-use super::super::lexer::Token;
-
-#[derive(Debug,Clone)]
+/// Declarations followed by a compound statement, the body of a program or
+/// procedure.
+#[derive(Debug, Clone, Default)]
 pub struct Block {
-    pub declarations: RefCell<HashMap<String, Token>>,
-    statements: AST<Token>
+    pub declarations: Vec<Decl>,
+    pub body: Compound,
+    pub span: Span,
 }
 
 impl Block {
-    pub fn new(declarations: HashMap<String, Token>, statements: AST<Token>) -> Self{
-        Self {
-            declarations: RefCell::new(declarations),
-            statements
-        }
-    }
-
-
-    pub fn statements(&self) -> &AST<Token> {
-        return &self.statements;
-    }
-
-    pub fn set_proc(&self, proc: Token) -> Result<(), String> {
-        let name = match proc.token_type() {
-            TokenType::PROCEDURE(p) => { 
-                match p{
-                    Some(procedure) => {
-                        procedure.get_name()
-                    }
-                    None => unreachable!()
-                }
-            }
-            _ => unreachable!()
-        };
-        self.declarations.borrow_mut().insert(
-            name,
-            proc
-        );
-        
-        Ok(())
-    }
-
-    pub fn set_var(&self, token: &Token, val: f64) -> Result<(), String>{
-        let ident = match token.token_type() {
-            TokenType::IDENTIFIER(e) => e.clone(),
-            _ => unreachable!()
-        };
-        match self.declarations.borrow_mut().get_mut(&ident) {
-            None => return Err(format!("Variable {} not found.",ident)),
-            Some(e) => match (e.line_no(), e.column(), e.token_type_mut()) {
-                (_, _, TokenType::Real(num)) => *num = val as usize,
-                (_, _, TokenType::Integer(num)) => *num = val,
-                (l,c, e) => return Err(
-                        format!(
-                            "Expected Real or Integer found {:?}. Position line_no: {}, column: {}",e, l, c)
-                        ), 
-                        
-            }
-        }
-        Ok(())
-    }
-
-    pub fn contains(&self, var:&String) -> bool{
-        self.declarations.borrow().contains_key(var)
+    /// Whether a variable or procedure called `name`, in any case, is
+    /// declared here.
+    pub fn declares(&self, name: &str) -> bool {
+        let name = key(name);
+        self.declarations.iter().any(|decl| match decl {
+            Decl::Var(var) => var.names.iter().any(|ident| ident.key() == name),
+            Decl::Procedure(procedure) => procedure.name.key() == name,
+        })
     }
+}
 
-    pub fn get_num(&self, ident: &String) -> Option<f64> {
-        match self.declarations.borrow().get(ident){
-            None => None,
-            Some(token) => {
-                match token.token_type() {
-                    TokenType::Integer(i) => Some(i.clone()),
-                    TokenType::Real(r) => Some(r.clone() as f64),
-                    _ => None
-                }
-            }
-        }
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.declarations == other.declarations && self.body == other.body
     }
-    
-    
 }
@@ -0,0 +1,62 @@
+use crate::utils::lexer::Span;
+
+use super::ident::Ident;
+use super::proc::Procedure;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decl {
+    Var(VarDecl),
+    Procedure(Procedure),
+}
+
+impl Decl {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Var(var) => var.span,
+            Self::Procedure(procedure) => procedure.span,
+        }
+    }
+}
+
+/// Names declared together with one type, as in `a, b : INTEGER`. Used for
+/// both variables and procedure parameters.
+#[derive(Debug, Clone)]
+pub struct VarDecl {
+    pub names: Vec<Ident>,
+    pub ty: TypeSpec,
+    pub span: Span,
+}
+
+impl PartialEq for VarDecl {
+    fn eq(&self, other: &Self) -> bool {
+        self.names == other.names && self.ty == other.ty
+    }
+}
+
+/// A type named in a declaration.
+#[derive(Debug, Clone)]
+pub struct TypeSpec {
+    pub ty: Type,
+    pub span: Span,
+}
+
+impl PartialEq for TypeSpec {
+    fn eq(&self, other: &Self) -> bool {
+        self.ty == other.ty
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Integer,
+    Real,
+}
+
+impl Type {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Integer => "INTEGER",
+            Self::Real => "REAL",
+        }
+    }
+}
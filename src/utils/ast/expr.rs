@@ -0,0 +1,226 @@
+use std::fmt;
+
+use crate::utils::lexer::{Operators, Span};
+
+use super::ident::Ident;
+
+pub enum Expr {
+    /// A number as written, such as `$FF`, or as computed by a pass over
+    /// the tree, with no `text`.
+    Integer {
+        value: i64,
+        text: Option<String>,
+        span: Span,
+    },
+    Real {
+        value: f64,
+        text: Option<String>,
+        span: Span,
+    },
+    Variable(Ident),
+    /// A call to a function, with its arguments in parentheses. A function
+    /// called without arguments is read as a `Variable`.
+    Call {
+        name: Ident,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+        span: Span,
+    },
+    Binary {
+        op: Operators,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        span: Span,
+    },
+}
+
+/// An operator on the left spine of an expression, as `Expr::left_spine`
+/// gives it, with its right operand and the span of the whole operation.
+#[derive(Debug, Clone, Copy)]
+pub struct Operation<'a> {
+    pub op: &'a Operators,
+    pub right: &'a Expr,
+    pub span: Span,
+}
+
+/// A sign written in front of an operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Plus,
+    Minus,
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Plus => "+",
+            Self::Minus => "-",
+        }
+    }
+}
+
+impl Expr {
+    /// `sign` is the span of the sign itself.
+    pub fn unary(op: UnaryOp, sign: Span, operand: Expr) -> Self {
+        Self::Unary {
+            op,
+            span: sign.to(operand.span()),
+            operand: Box::new(operand),
+        }
+    }
+
+    pub fn binary(op: Operators, left: Expr, right: Expr) -> Self {
+        Self::Binary {
+            op,
+            span: left.span().to(right.span()),
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    /// Splits a run of binary operators, such as `a + b * c - d`, along its
+    /// left spine: the operand it starts with, `a`, and the operations
+    /// applied to it in turn, `+ b * c` then `- d`. Anything else starts a
+    /// run with no operations.
+    ///
+    /// A run is as long as the source makes it, and does not count as
+    /// nesting, so passes go along it this way instead of recursing into
+    /// left operands.
+    pub fn left_spine(&self) -> (&Expr, Vec<Operation<'_>>) {
+        let mut operations = Vec::new();
+        let mut expr = self;
+        while let Self::Binary { op, left, right, span } = expr {
+            operations.push(Operation { op, right, span: *span });
+            expr = left;
+        }
+        operations.reverse();
+        (expr, operations)
+    }
+
+    /// Like `left_spine`, taking the expression apart. `from_left_spine`
+    /// puts it back together.
+    pub fn into_left_spine(mut self) -> (Expr, Vec<(Operators, Expr, Span)>) {
+        let mut operations = Vec::new();
+        while let Self::Binary { op, left, right, span } = &mut self {
+            let operation = (op.clone(), std::mem::take(&mut **right), *span);
+            let left = std::mem::take(&mut **left);
+            operations.push(operation);
+            self = left;
+        }
+        operations.reverse();
+        (self, operations)
+    }
+
+    /// `first` with `operations` applied to it in turn.
+    pub fn from_left_spine(first: Expr, operations: impl IntoIterator<Item = (Operators, Expr, Span)>) -> Self {
+        operations.into_iter().fold(first, |left, (op, right, span)| Self::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            span,
+        })
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Variable(ident) => ident.span,
+            Self::Integer { span, .. }
+            | Self::Real { span, .. }
+            | Self::Call { span, .. }
+            | Self::Unary { span, .. }
+            | Self::Binary { span, .. } => *span,
+        }
+    }
+}
+
+/// A placeholder, left behind where an expression is taken out of the tree.
+impl Default for Expr {
+    fn default() -> Self {
+        Self::Integer { value: 0, text: None, span: Span::default() }
+    }
+}
+
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        let (first, operations) = self.left_spine();
+        let first = match first {
+            Self::Integer { value, text, span } => Self::Integer { value: *value, text: text.clone(), span: *span },
+            Self::Real { value, text, span } => Self::Real { value: *value, text: text.clone(), span: *span },
+            Self::Variable(ident) => Self::Variable(ident.clone()),
+            Self::Call { name, args, span } => Self::Call { name: name.clone(), args: args.clone(), span: *span },
+            Self::Unary { op, operand, span } => Self::Unary { op: *op, operand: operand.clone(), span: *span },
+            Self::Binary { .. } => unreachable!("a left spine starts with an operand"),
+        };
+        let operations = operations
+            .into_iter()
+            .map(|operation| (operation.op.clone(), operation.right.clone(), operation.span));
+        Self::from_left_spine(first, operations)
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        let ((a, x), (b, y)) = (self.left_spine(), other.left_spine());
+        let same = |x: &Operation, y: &Operation| x.op == y.op && x.right == y.right;
+        let first = match (a, b) {
+            (Self::Integer { value: a, .. }, Self::Integer { value: b, .. }) => a == b,
+            (Self::Real { value: a, .. }, Self::Real { value: b, .. }) => a == b,
+            (Self::Variable(a), Self::Variable(b)) => a == b,
+            (Self::Call { name: a, args: x, .. }, Self::Call { name: b, args: y, .. }) => a == b && x == y,
+            (
+                Self::Unary { op: a, operand: x, .. },
+                Self::Unary { op: b, operand: y, .. },
+            ) => a == b && x == y,
+            _ => false,
+        };
+        first && x.len() == y.len() && x.iter().zip(&y).all(|(x, y)| same(x, y))
+    }
+}
+
+/// Prints a run of binary operators as the operand it starts with and the
+/// operations applied to it, as `left_spine` gives them, so that a long run
+/// prints without recursing and indented no deeper than its first operand.
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer { value, text, span } => {
+                f.debug_struct("Integer").field("value", value).field("text", text).field("span", span).finish()
+            }
+            Self::Real { value, text, span } => {
+                f.debug_struct("Real").field("value", value).field("text", text).field("span", span).finish()
+            }
+            Self::Variable(ident) => f.debug_tuple("Variable").field(ident).finish(),
+            Self::Call { name, args, span } => {
+                f.debug_struct("Call").field("name", name).field("args", args).field("span", span).finish()
+            }
+            Self::Unary { op, operand, span } => {
+                f.debug_struct("Unary").field("op", op).field("operand", operand).field("span", span).finish()
+            }
+            Self::Binary { span, .. } => {
+                let (first, operations) = self.left_spine();
+                f.debug_struct("Binary")
+                    .field("first", first)
+                    .field("operations", &operations)
+                    .field("span", span)
+                    .finish()
+            }
+        }
+    }
+}
+
+/// Drops the left spine of a run of binary operators without recursing.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut below = match self {
+            Self::Binary { left, .. } => std::mem::take(&mut **left),
+            _ => return,
+        };
+        while let Self::Binary { left, .. } = &mut below {
+            below = std::mem::take(&mut **left);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use crate::utils::lexer::Span;
+
+/// A name as written in the source. Pascal names are case-insensitive, so
+/// two identifiers are equal when they differ only in case.
+#[derive(Debug, Clone, Default)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+impl Ident {
+    pub fn new(name: impl Into<String>, span: Span) -> Self {
+        Self {
+            name: name.into(),
+            span,
+        }
+    }
+
+    /// The name as it is looked up in a scope.
+    pub fn key(&self) -> String {
+        key(&self.name)
+    }
+}
+
+/// Folds `name` to the form scopes store it under.
+pub fn key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+impl PartialEq for Ident {
+    fn eq(&self, other: &Self) -> bool {
+        key(&self.name) == key(&other.name)
+    }
+}
@@ -1,4 +1,16 @@
-pub mod ast;
+//! The syntax tree the parser builds.
+//!
+//! Every node records the span of source it was parsed from. Nodes compare
+//! equal when they have the same shape and contents, wherever they are in
+//! the source, the same way tokens do.
+
 pub mod block;
+pub mod decl;
+pub mod expr;
+pub mod ident;
+pub mod proc;
 pub mod program;
-pub mod proc;
\ No newline at end of file
+pub mod stmt;
+pub mod unit;
+pub mod visit;
+pub mod visit_mut;
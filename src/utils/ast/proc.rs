@@ -1,41 +1,26 @@
-use crate::utils::{lexer::Token, err::functions::better_error};
-use super::{block::Block, ast::AST};
+use crate::utils::lexer::Span;
 
-#[derive(Debug, Clone)]
-pub struct ProcedureCall {
-    pub name: String,
-    pub params: Option<Vec<AST<Token>>>
-}
+use super::block::Block;
+use super::decl::VarDecl;
+use super::ident::Ident;
 
 #[derive(Debug, Clone)]
 pub struct Procedure {
-    name: String,
-    params: Option<Vec<(String, Token)>>,
-    block: Block
+    pub name: Ident,
+    pub params: Vec<VarDecl>,
+    pub block: Block,
+    pub span: Span,
 }
 
-impl Procedure{
-    pub fn new(name:String, params: Option<Vec<(String, Token)>>, block: Block) -> Result<Self, String> {
-        for n in &params {
-            for var in n {
-                if block.contains(&var.0){
-                    return Err(
-                        better_error(
-                            format!("Duplicate identifier `{}` found in Procedure {}.", &var.0, &name), 
-                            &var.1
-                        )
-                    )
-                }
-            }
-        }
-        Ok(Self {
-            name,
-            params,
-            block
-        })
+impl Procedure {
+    /// The parameter names in declaration order.
+    pub fn param_names(&self) -> impl Iterator<Item = &Ident> {
+        self.params.iter().flat_map(|param| &param.names)
     }
+}
 
-    pub fn get_name(&self) -> String{
-        self.name.clone()
+impl PartialEq for Procedure {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.params == other.params && self.block == other.block
     }
-}
\ No newline at end of file
+}
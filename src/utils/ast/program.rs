@@ -1,39 +1,19 @@
-use crate::utils::lexer::{Token, TokenType};
+use crate::utils::lexer::Span;
 
-use super::{block::Block, ast::AST, proc::Procedure};
+use super::block::Block;
+use super::ident::Ident;
 
+#[derive(Debug, Clone, Default)]
 pub struct Program {
-    name: String,
-    block: Block
+    pub name: Ident,
+    /// The units named after `USES`.
+    pub uses: Vec<Ident>,
+    pub block: Block,
+    pub span: Span,
 }
 
-impl Program {
-    pub fn new(name: String, block: Block ) -> Self{
-        Self {
-            name,
-            block
-        }
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.uses == other.uses && self.block == other.block
     }
-
-    pub fn scope(&self) {
-        println!("{:?}",&self.block.declarations.borrow())
-    }
-
-    pub fn set_proc(&self, proc: Token) -> Result<(), String> {
-        assert!(proc.token_type().is_procedure());
-        self.block.set_proc(proc)
-    }
-
-    pub fn set_var(&self, ident: &Token, val: f64) -> Result<(), String>{
-        self.block.set_var(ident, val)
-    }
-
-    pub fn get_num(&self, ident: &String) -> Option<f64> {
-        self.block.get_num(ident)
-    }
-
-    pub fn statements(&self) -> &AST<Token> {
-        return self.block.statements()
-    }
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,84 @@
+use crate::utils::lexer::Span;
+
+use super::expr::Expr;
+use super::ident::Ident;
+use super::proc::Procedure;
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Compound(Compound),
+    Assign {
+        target: Ident,
+        value: Expr,
+        checks: Checks,
+        span: Span,
+    },
+    Call {
+        name: Ident,
+        args: Vec<Expr>,
+        checks: Checks,
+        span: Span,
+    },
+    /// A procedure declared among the statements. It is declared when
+    /// execution reaches it.
+    Procedure(Procedure),
+    /// Nothing, as between two `;` or before an `END`. The span is empty and
+    /// sits at the token that follows.
+    Empty {
+        span: Span,
+    },
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Compound(compound) => compound.span,
+            Self::Procedure(procedure) => procedure.span,
+            Self::Assign { span, .. } | Self::Call { span, .. } | Self::Empty { span } => *span,
+        }
+    }
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Compound(a), Self::Compound(b)) => a == b,
+            (
+                Self::Assign { target: a, value: x, checks: c, .. },
+                Self::Assign { target: b, value: y, checks: d, .. },
+            ) => a == b && x == y && c == d,
+            (
+                Self::Call { name: a, args: x, checks: c, .. },
+                Self::Call { name: b, args: y, checks: d, .. },
+            ) => a == b && x == y && c == d,
+            (Self::Procedure(a), Self::Procedure(b)) => a == b,
+            (Self::Empty { .. }, Self::Empty { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The run-time checks in force where a statement is written, as switched
+/// by the `{$R}` and `{$Q}` directives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Checks {
+    /// A REAL assigned to an INTEGER variable must be a whole number that
+    /// fits. Without the check it is truncated toward zero.
+    pub range: bool,
+    /// INTEGER arithmetic must not overflow. Without the check it wraps
+    /// around.
+    pub overflow: bool,
+}
+
+/// Statements between `BEGIN` and `END`. The span covers both keywords.
+#[derive(Debug, Clone, Default)]
+pub struct Compound {
+    pub statements: Vec<Stmt>,
+    pub span: Span,
+}
+
+impl PartialEq for Compound {
+    fn eq(&self, other: &Self) -> bool {
+        self.statements == other.statements
+    }
+}
@@ -0,0 +1,58 @@
+use crate::utils::lexer::Span;
+
+use super::decl::{Decl, VarDecl};
+use super::ident::Ident;
+use super::stmt::Compound;
+
+/// `UNIT name; INTERFACE ... IMPLEMENTATION ... END.`: declarations for
+/// programs and other units to use.
+#[derive(Debug, Clone, Default)]
+pub struct Unit {
+    pub name: Ident,
+    pub interface: Interface,
+    pub implementation: Implementation,
+    /// Statements run before those of any program using the unit, written
+    /// as a `BEGIN ... END` in place of the final `END`.
+    pub initialization: Option<Compound>,
+    pub span: Span,
+}
+
+impl PartialEq for Unit {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.interface == other.interface
+            && self.implementation == other.implementation
+            && self.initialization == other.initialization
+    }
+}
+
+/// What a unit exports. Procedures are only declared here and implemented
+/// in the implementation part.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Interface {
+    pub uses: Vec<Ident>,
+    pub vars: Vec<VarDecl>,
+    pub procedures: Vec<Heading>,
+}
+
+/// What a unit keeps to itself, along with the bodies of the procedures
+/// its interface declares.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Implementation {
+    pub uses: Vec<Ident>,
+    pub declarations: Vec<Decl>,
+}
+
+/// `PROCEDURE name(params);` without a body.
+#[derive(Debug, Clone)]
+pub struct Heading {
+    pub name: Ident,
+    pub params: Vec<VarDecl>,
+    pub span: Span,
+}
+
+impl PartialEq for Heading {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.params == other.params
+    }
+}
@@ -0,0 +1,194 @@
+//! Read-only traversal of the syntax tree.
+//!
+//! Every `visit_*` method of `Visitor` defaults to the matching `walk_*`
+//! function, which visits the children of the node. A pass overrides the
+//! methods for the nodes it cares about and calls `walk_*` from them to
+//! keep going down.
+//!
+//! A run of binary operators such as `a + b + c` is walked along its left
+//! spine without recursing, since it can be as long as the source makes
+//! it: `visit_expr` is given the run itself, then its first operand and
+//! each right operand, but not the operators in between.
+
+use super::block::Block;
+use super::decl::{Decl, TypeSpec, VarDecl};
+use super::expr::Expr;
+use super::ident::Ident;
+use super::proc::Procedure;
+use super::program::Program;
+use super::stmt::{Compound, Stmt};
+use super::unit::Unit;
+
+pub trait Visitor {
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program)
+    }
+
+    fn visit_unit(&mut self, unit: &Unit) {
+        walk_unit(self, unit)
+    }
+
+    fn visit_block(&mut self, block: &Block) {
+        walk_block(self, block)
+    }
+
+    fn visit_decl(&mut self, decl: &Decl) {
+        walk_decl(self, decl)
+    }
+
+    fn visit_var_decl(&mut self, var: &VarDecl) {
+        walk_var_decl(self, var)
+    }
+
+    fn visit_procedure(&mut self, procedure: &Procedure) {
+        walk_procedure(self, procedure)
+    }
+
+    fn visit_compound(&mut self, compound: &Compound) {
+        walk_compound(self, compound)
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt)
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_ident(&mut self, _ident: &Ident) {}
+
+    fn visit_type(&mut self, _ty: &TypeSpec) {}
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    visitor.visit_ident(&program.name);
+    for name in &program.uses {
+        visitor.visit_ident(name);
+    }
+    visitor.visit_block(&program.block);
+}
+
+pub fn walk_unit<V: Visitor + ?Sized>(visitor: &mut V, unit: &Unit) {
+    visitor.visit_ident(&unit.name);
+    for name in &unit.interface.uses {
+        visitor.visit_ident(name);
+    }
+    for var in &unit.interface.vars {
+        visitor.visit_var_decl(var);
+    }
+    for heading in &unit.interface.procedures {
+        visitor.visit_ident(&heading.name);
+        for param in &heading.params {
+            visitor.visit_var_decl(param);
+        }
+    }
+    for name in &unit.implementation.uses {
+        visitor.visit_ident(name);
+    }
+    for decl in &unit.implementation.declarations {
+        visitor.visit_decl(decl);
+    }
+    if let Some(initialization) = &unit.initialization {
+        visitor.visit_compound(initialization);
+    }
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(visitor: &mut V, block: &Block) {
+    for decl in &block.declarations {
+        visitor.visit_decl(decl);
+    }
+    visitor.visit_compound(&block.body);
+}
+
+pub fn walk_decl<V: Visitor + ?Sized>(visitor: &mut V, decl: &Decl) {
+    match decl {
+        Decl::Var(var) => visitor.visit_var_decl(var),
+        Decl::Procedure(procedure) => visitor.visit_procedure(procedure),
+    }
+}
+
+pub fn walk_var_decl<V: Visitor + ?Sized>(visitor: &mut V, var: &VarDecl) {
+    for name in &var.names {
+        visitor.visit_ident(name);
+    }
+    visitor.visit_type(&var.ty);
+}
+
+pub fn walk_procedure<V: Visitor + ?Sized>(visitor: &mut V, procedure: &Procedure) {
+    visitor.visit_ident(&procedure.name);
+    for param in &procedure.params {
+        visitor.visit_var_decl(param);
+    }
+    visitor.visit_block(&procedure.block);
+}
+
+pub fn walk_compound<V: Visitor + ?Sized>(visitor: &mut V, compound: &Compound) {
+    for stmt in &compound.statements {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match stmt {
+        Stmt::Compound(compound) => visitor.visit_compound(compound),
+        Stmt::Assign { target, value, .. } => {
+            visitor.visit_ident(target);
+            visitor.visit_expr(value);
+        }
+        Stmt::Call { name, args, .. } => {
+            visitor.visit_ident(name);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Stmt::Procedure(procedure) => visitor.visit_procedure(procedure),
+        Stmt::Empty { .. } => {}
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Integer { .. } | Expr::Real { .. } => {}
+        Expr::Variable(ident) => visitor.visit_ident(ident),
+        Expr::Call { name, args, .. } => {
+            visitor.visit_ident(name);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expr::Unary { operand, .. } => visitor.visit_expr(operand),
+        Expr::Binary { .. } => {
+            let (first, operations) = expr.left_spine();
+            visitor.visit_expr(first);
+            for operation in operations {
+                visitor.visit_expr(operation.right);
+            }
+        }
+    }
+}
+
+#[test]
+fn visits_every_node_in_source_order() {
+    use crate::utils::parser::Parser;
+
+    /// Collects the variables read by expressions.
+    struct Reads(Vec<String>);
+
+    impl Visitor for Reads {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::Variable(ident) = expr {
+                self.0.push(ident.name.clone());
+            }
+            walk_expr(self, expr)
+        }
+    }
+
+    let source = "PROGRAM p; VAR a, b : INTEGER;
+        PROCEDURE q(x : INTEGER); BEGIN a := x END;
+        BEGIN a := b + -(a * b); BEGIN q(a DIV 2) END; a := a - b + a END.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let mut reads = Reads(Vec::new());
+    reads.visit_program(&program);
+    assert_eq!(reads.0, ["x", "b", "a", "b", "a", "a", "b", "a"]);
+}
@@ -0,0 +1,189 @@
+//! In-place rewriting of the syntax tree.
+//!
+//! The same shape as `visit`, with mutable references: a pass overrides the
+//! nodes it rewrites, for instance by replacing `*expr`, and calls `walk_*`
+//! to rewrite their children. Runs of binary operators are walked along
+//! their left spine, as in `visit`: a pass that rewrites the operators in
+//! between goes along the spine itself, with `Expr::into_left_spine`.
+
+use super::block::Block;
+use super::decl::{Decl, TypeSpec, VarDecl};
+use super::expr::Expr;
+use super::ident::Ident;
+use super::proc::Procedure;
+use super::program::Program;
+use super::stmt::{Compound, Stmt};
+use super::unit::Unit;
+
+pub trait MutVisitor {
+    fn visit_program(&mut self, program: &mut Program) {
+        walk_program(self, program)
+    }
+
+    fn visit_unit(&mut self, unit: &mut Unit) {
+        walk_unit(self, unit)
+    }
+
+    fn visit_block(&mut self, block: &mut Block) {
+        walk_block(self, block)
+    }
+
+    fn visit_decl(&mut self, decl: &mut Decl) {
+        walk_decl(self, decl)
+    }
+
+    fn visit_var_decl(&mut self, var: &mut VarDecl) {
+        walk_var_decl(self, var)
+    }
+
+    fn visit_procedure(&mut self, procedure: &mut Procedure) {
+        walk_procedure(self, procedure)
+    }
+
+    fn visit_compound(&mut self, compound: &mut Compound) {
+        walk_compound(self, compound)
+    }
+
+    fn visit_stmt(&mut self, stmt: &mut Stmt) {
+        walk_stmt(self, stmt)
+    }
+
+    fn visit_expr(&mut self, expr: &mut Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_ident(&mut self, _ident: &mut Ident) {}
+
+    fn visit_type(&mut self, _ty: &mut TypeSpec) {}
+}
+
+pub fn walk_program<V: MutVisitor + ?Sized>(visitor: &mut V, program: &mut Program) {
+    visitor.visit_ident(&mut program.name);
+    for name in &mut program.uses {
+        visitor.visit_ident(name);
+    }
+    visitor.visit_block(&mut program.block);
+}
+
+pub fn walk_unit<V: MutVisitor + ?Sized>(visitor: &mut V, unit: &mut Unit) {
+    visitor.visit_ident(&mut unit.name);
+    for name in &mut unit.interface.uses {
+        visitor.visit_ident(name);
+    }
+    for var in &mut unit.interface.vars {
+        visitor.visit_var_decl(var);
+    }
+    for heading in &mut unit.interface.procedures {
+        visitor.visit_ident(&mut heading.name);
+        for param in &mut heading.params {
+            visitor.visit_var_decl(param);
+        }
+    }
+    for name in &mut unit.implementation.uses {
+        visitor.visit_ident(name);
+    }
+    for decl in &mut unit.implementation.declarations {
+        visitor.visit_decl(decl);
+    }
+    if let Some(initialization) = &mut unit.initialization {
+        visitor.visit_compound(initialization);
+    }
+}
+
+pub fn walk_block<V: MutVisitor + ?Sized>(visitor: &mut V, block: &mut Block) {
+    for decl in &mut block.declarations {
+        visitor.visit_decl(decl);
+    }
+    visitor.visit_compound(&mut block.body);
+}
+
+pub fn walk_decl<V: MutVisitor + ?Sized>(visitor: &mut V, decl: &mut Decl) {
+    match decl {
+        Decl::Var(var) => visitor.visit_var_decl(var),
+        Decl::Procedure(procedure) => visitor.visit_procedure(procedure),
+    }
+}
+
+pub fn walk_var_decl<V: MutVisitor + ?Sized>(visitor: &mut V, var: &mut VarDecl) {
+    for name in &mut var.names {
+        visitor.visit_ident(name);
+    }
+    visitor.visit_type(&mut var.ty);
+}
+
+pub fn walk_procedure<V: MutVisitor + ?Sized>(visitor: &mut V, procedure: &mut Procedure) {
+    visitor.visit_ident(&mut procedure.name);
+    for param in &mut procedure.params {
+        visitor.visit_var_decl(param);
+    }
+    visitor.visit_block(&mut procedure.block);
+}
+
+pub fn walk_compound<V: MutVisitor + ?Sized>(visitor: &mut V, compound: &mut Compound) {
+    for stmt in &mut compound.statements {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<V: MutVisitor + ?Sized>(visitor: &mut V, stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Compound(compound) => visitor.visit_compound(compound),
+        Stmt::Assign { target, value, .. } => {
+            visitor.visit_ident(target);
+            visitor.visit_expr(value);
+        }
+        Stmt::Call { name, args, .. } => {
+            visitor.visit_ident(name);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Stmt::Procedure(procedure) => visitor.visit_procedure(procedure),
+        Stmt::Empty { .. } => {}
+    }
+}
+
+pub fn walk_expr<V: MutVisitor + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Integer { .. } | Expr::Real { .. } => {}
+        Expr::Variable(ident) => visitor.visit_ident(ident),
+        Expr::Call { name, args, .. } => {
+            visitor.visit_ident(name);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expr::Unary { operand, .. } => visitor.visit_expr(operand),
+        Expr::Binary { .. } => {
+            let (mut first, mut operations) = std::mem::take(expr).into_left_spine();
+            visitor.visit_expr(&mut first);
+            for (_, right, _) in &mut operations {
+                visitor.visit_expr(right);
+            }
+            *expr = Expr::from_left_spine(first, operations);
+        }
+    }
+}
+
+#[test]
+fn rewrites_nodes_in_place() {
+    use crate::utils::ast::expr::UnaryOp;
+    use crate::utils::parser::Parser;
+
+    /// Drops every unary `+`, the way a simplifying pass would.
+    struct DropPlus;
+
+    impl MutVisitor for DropPlus {
+        fn visit_expr(&mut self, expr: &mut Expr) {
+            walk_expr(self, expr);
+            if let Expr::Unary { op: UnaryOp::Plus, operand, .. } = expr {
+                *expr = std::mem::replace(&mut **operand, Expr::Integer { value: 0, text: None, span: Default::default() });
+            }
+        }
+    }
+
+    let parse = |source: &str| Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let mut program = parse("PROGRAM p; VAR a : INTEGER; BEGIN a := +(+a - +1); BEGIN a := -+a END END.");
+    DropPlus.visit_program(&mut program);
+    assert_eq!(program, parse("PROGRAM p; VAR a : INTEGER; BEGIN a := a - 1; BEGIN a := -a END END."));
+}
@@ -0,0 +1,705 @@
+//! C99 source for a compiled program, to build into a native binary with
+//! any C compiler:
+//!
+//! ```text
+//! rusterp emit-c prog.pa -o prog.c && cc -std=c99 -o prog prog.c
+//! ```
+//!
+//! The C is written from the bytecode rather than from the tree, since there
+//! every name is already resolved and every type known. The stack of the VM
+//! becomes nested C expressions; anything that may fail is taken out into a
+//! statement of its own so that it fails in the same order. Failures print
+//! the error rusterp would, as rusterp renders it, and exit with status 1.
+//!
+//! Each routine becomes a function taking a pointer to its frame, a struct,
+//! and pointers to the frames of the procedures it is declared in. Every
+//! call is recorded in a list kept on the C stack, so that a failure can
+//! print the notes on the calls it happened in. Calls nest at most
+//! `DEFAULT_CALLS` deep, as in the VM by default.
+//!
+//! The language has no arrays, records, VAR parameters or `write` yet, so
+//! there are none to translate. Without output statements the program
+//! prints its global scope once it has run, the way `rusterp scope` does.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::utils::ast::decl::Type;
+use crate::utils::bytecode::{Chunk, Op, Symbol};
+use crate::utils::err::diagnostic::Diagnostic;
+use crate::utils::host;
+use crate::utils::interpreter;
+use crate::utils::lexer::Span;
+use crate::utils::limits::{self, DEFAULT_CALLS};
+use crate::utils::source::SourceMap;
+#[cfg(test)]
+use crate::utils::parser::Parser;
+
+/// A function of the C runtime: its name, the functions it calls and its
+/// definition. Only those the program uses are written out, in this order.
+const RUNTIME: &[(&str, &[&str], &str)] = &[
+    ("rp_calls", &[], "\
+/* The procedure calls being run, the innermost first, each with the error
+   note on it. */
+struct rp_call {
+    int note;
+    const struct rp_call *caller;
+};
+static const struct rp_call *rp_calls = NULL;
+"),
+    ("rp_depth", &[], "\
+/* How many procedure calls are being run. With no IF, a procedure that calls
+   itself does so until there are too many, which compilers warn about. */
+#if defined(__GNUC__) && __GNUC__ >= 12 || defined(__clang__)
+#pragma GCC diagnostic ignored \"-Winfinite-recursion\"
+#endif
+static int rp_depth = 0;
+"),
+    ("rp_trace", &["rp_calls"], "\
+static void rp_trace(void) {
+    const struct rp_call *call;
+    for (call = rp_calls; call != NULL; call = call->caller) fputs(errors[call->note], stderr);
+}
+"),
+    ("rp_fail", &["rp_trace"], "\
+static void rp_fail(int error) {
+    fputs(errors[error], stderr);
+    rp_trace();
+    exit(EXIT_FAILURE);
+}
+"),
+    ("rp_wrap", &[], "\
+/* Two's complement wrapping, as INTEGER arithmetic does without {$Q+}. */
+static int64_t rp_wrap(uint64_t value) {
+    return value <= INT64_MAX ? (int64_t)value : -(int64_t)(UINT64_MAX - value) - 1;
+}
+"),
+    ("rp_neg", &["rp_wrap"], "\
+static int64_t rp_neg(int64_t a) {
+    return rp_wrap(0 - (uint64_t)a);
+}
+"),
+    ("rp_add", &["rp_wrap"], "\
+static int64_t rp_add(int64_t a, int64_t b) {
+    return rp_wrap((uint64_t)a + (uint64_t)b);
+}
+"),
+    ("rp_sub", &["rp_wrap"], "\
+static int64_t rp_sub(int64_t a, int64_t b) {
+    return rp_wrap((uint64_t)a - (uint64_t)b);
+}
+"),
+    ("rp_mul", &["rp_wrap"], "\
+static int64_t rp_mul(int64_t a, int64_t b) {
+    return rp_wrap((uint64_t)a * (uint64_t)b);
+}
+"),
+    ("rp_div", &[], "\
+static int64_t rp_div(int64_t a, int64_t b) {
+    return a == INT64_MIN && b == -1 ? INT64_MIN : a / b;
+}
+"),
+    ("rp_neg_checked", &["rp_fail"], "\
+static int64_t rp_neg_checked(int64_t a, int error) {
+    if (a == INT64_MIN) rp_fail(error);
+    return -a;
+}
+"),
+    ("rp_add_checked", &["rp_fail"], "\
+static int64_t rp_add_checked(int64_t a, int64_t b, int error) {
+    if (b > 0 ? a > INT64_MAX - b : a < INT64_MIN - b) rp_fail(error);
+    return a + b;
+}
+"),
+    ("rp_sub_checked", &["rp_fail"], "\
+static int64_t rp_sub_checked(int64_t a, int64_t b, int error) {
+    if (b < 0 ? a > INT64_MAX + b : a < INT64_MIN + b) rp_fail(error);
+    return a - b;
+}
+"),
+    ("rp_mul_checked", &["rp_fail", "rp_mul"], "\
+static int64_t rp_mul_checked(int64_t a, int64_t b, int error) {
+    int64_t product = rp_mul(a, b);
+    if ((a == -1 && b == INT64_MIN) || (b == -1 && a == INT64_MIN) || (a != 0 && a != -1 && product / a != b)) {
+        rp_fail(error);
+    }
+    return product;
+}
+"),
+    ("rp_div_checked", &["rp_fail"], "\
+static int64_t rp_div_checked(int64_t a, int64_t b, int error) {
+    if (a == INT64_MIN && b == -1) rp_fail(error);
+    return a / b;
+}
+"),
+    ("rp_truncate", &[], "\
+/* REAL to INTEGER toward zero, stopping at the ends of the range. */
+static int64_t rp_truncate(double value) {
+    if (isnan(value)) return 0;
+    if (value >= 9223372036854775808.0) return INT64_MAX;
+    if (value < -9223372036854775808.0) return INT64_MIN;
+    return (int64_t)value;
+}
+"),
+    ("rp_format_real", &[], "\
+/* Writes a REAL the way rusterp prints it: with the fewest digits that read
+   back as the same number, and no exponent. `out` must hold 400 bytes. */
+static void rp_format_real(double value, char *out) {
+    char text[32], digits[20];
+    const char *c;
+    int precision, exponent, count = 0, i;
+    if (isnan(value) || isinf(value)) {
+        strcpy(out, isnan(value) ? \"NaN\" : value < 0 ? \"-inf\" : \"inf\");
+        return;
+    }
+    for (precision = 1; precision < 17; precision++) {
+        snprintf(text, sizeof text, \"%.*e\", precision - 1, value);
+        if (strtod(text, NULL) == value) break;
+    }
+    snprintf(text, sizeof text, \"%.*e\", precision - 1, value);
+    c = text;
+    if (*c == '-') *out++ = *c++;
+    for (; *c != 'e'; c++) {
+        if (*c != '.') digits[count++] = *c;
+    }
+    exponent = atoi(c + 1);
+    while (count > 1 && digits[count - 1] == '0') count--;
+    if (exponent >= count - 1) {
+        memcpy(out, digits, count);
+        out += count;
+        for (i = count - 1; i < exponent; i++) *out++ = '0';
+    } else if (exponent >= 0) {
+        memcpy(out, digits, exponent + 1);
+        out += exponent + 1;
+        *out++ = '.';
+        memcpy(out, digits + exponent + 1, count - exponent - 1);
+        out += count - exponent - 1;
+    } else {
+        *out++ = '0';
+        *out++ = '.';
+        for (i = -1; i > exponent; i--) *out++ = '0';
+        memcpy(out, digits, count);
+        out += count;
+    }
+    *out = '\\0';
+}
+"),
+    ("rp_range_check", &["rp_format_real", "rp_trace"], "\
+/* The REAL as an INTEGER, if it is a whole number that fits. The error
+   message comes in two parts, to go either side of the value. */
+static int64_t rp_range_check(double value, int error) {
+    char text[400];
+    if (value > -9223372036854775808.0 && value < 9223372036854775808.0 && (double)(int64_t)value == value) {
+        return (int64_t)value;
+    }
+    rp_format_real(value, text);
+    fprintf(stderr, \"%s%s%s\", errors[error], text, errors[error + 1]);
+    rp_trace();
+    exit(EXIT_FAILURE);
+}
+"),
+    ("rp_print_integer", &[], "\
+static void rp_print_integer(const char *name, int64_t value) {
+    printf(\"%s : INTEGER = %\" PRId64 \"\\n\", name, value);
+}
+"),
+    ("rp_print_real", &["rp_format_real"], "\
+static void rp_print_real(const char *name, double value) {
+    char text[400];
+    rp_format_real(value, text);
+    printf(\"%s : REAL = %s\\n\", name, text);
+}
+"),
+];
+
+/// C99 source for `chunk`, which was compiled from `sources`.
+///
+/// # Panics
+///
+/// If `chunk` calls host functions.
+pub fn emit(chunk: &Chunk, sources: &SourceMap) -> String {
+    let mut emitter = Emitter {
+        chunk,
+        sources,
+        body: String::new(),
+        main: String::new(),
+        functions: vec![None; chunk.routines.len()],
+        called: Vec::new(),
+        routine: None,
+        frames: BTreeSet::new(),
+        pc: 0,
+        errors: Vec::new(),
+        stack: Vec::new(),
+        temps: 0,
+        used: Vec::new(),
+    };
+    emitter.code(chunk.top_level());
+    emitter.main = std::mem::take(&mut emitter.body);
+    while let Some(routine) = emitter.called.pop() {
+        emitter.routine = Some(routine);
+        emitter.code(chunk.routines[routine].code.clone());
+        emitter.functions[routine] = Some(emitter.function(routine));
+    }
+    emitter.finish()
+}
+
+struct Emitter<'a> {
+    chunk: &'a Chunk,
+    sources: &'a SourceMap,
+    /// The statements of the function being written.
+    body: String,
+    /// The statements of `main`.
+    main: String,
+    /// The function of each routine, once written. Only those called are.
+    functions: Vec<Option<String>>,
+    /// Routines called whose functions are still to be written.
+    called: Vec<usize>,
+    /// The routine being written, or `None` for `main`.
+    routine: Option<usize>,
+    /// The levels of the frames the routine being written uses, counting
+    /// from 1 for the outermost.
+    frames: BTreeSet<usize>,
+    /// The instruction being written.
+    pc: usize,
+    /// Rendered errors, which the code refers to by index.
+    errors: Vec<String>,
+    /// What the VM's stack would hold: C expressions free of side effects,
+    /// with their types.
+    stack: Vec<(String, Type)>,
+    /// Temporaries declared so far.
+    temps: usize,
+    /// Runtime functions the code calls.
+    used: Vec<&'static str>,
+}
+
+impl Emitter<'_> {
+    /// Writes `code`, that of the top level or of the routine being
+    /// written, into `body`.
+    fn code(&mut self, code: Range<usize>) {
+        let chunk = self.chunk;
+        let mut line = None;
+        for pc in code {
+            let (op, span) = (&chunk.code[pc], &chunk.spans[pc]);
+            self.pc = pc;
+            if line != Some((span.file, span.line_no)) {
+                line = Some((span.file, span.line_no));
+                let file = self.sources.file(span.file);
+                let text = file.text.lines().nth(span.line_no.saturating_sub(1)).unwrap_or("").trim();
+                let comment = format!("{}:{}   {}", file.name, span.line_no, text);
+                self.statement(format!("/* {} */", comment.replace("*/", "* /").replace("??", "? ?")));
+            }
+            let span = *span;
+            let name = |index: u32| chunk.names[index as usize].as_str();
+            let (value, ty) = match *op {
+                Op::Integer(value) => (integer(value), Type::Integer),
+                Op::Real(value) => (real(value), Type::Real),
+                Op::Load(slot) => (self.variable(slot), chunk.slots[slot as usize].1),
+                Op::Store(slot) => {
+                    let (value, _) = self.pop();
+                    self.statement(format!("{} = {};", self.variable(slot), value));
+                    continue;
+                }
+                Op::LoadLocal { up, local } => self.local(up, local),
+                Op::StoreLocal { up, local } => {
+                    let (value, _) = self.pop();
+                    let (local, _) = self.local(up, local);
+                    self.statement(format!("{} = {};", local, value));
+                    continue;
+                }
+                Op::Call { routine, .. } => {
+                    self.call_routine(routine as usize);
+                    continue;
+                }
+                // The function ends there.
+                Op::Return => continue,
+                Op::Pop => {
+                    let (value, _) = self.pop();
+                    self.discard(value);
+                    continue;
+                }
+                Op::ToReal => {
+                    let (value, _) = self.pop();
+                    match value.parse::<i64>() {
+                        Ok(literal) => (real(literal as f64), Type::Real),
+                        Err(_) => (format!("(double){}", value), Type::Real),
+                    }
+                }
+                Op::Truncate => {
+                    let (value, _) = self.pop();
+                    (self.call("rp_truncate", &[value]), Type::Integer)
+                }
+                Op::RangeCheck(target) => {
+                    let value = self.pop().0;
+                    let message = self.render(interpreter::out_of_range(f64::NAN, name(target), span));
+                    let (before, after) = message.split_once("NaN").expect("the message shows the value");
+                    let error = self.errors.len();
+                    self.errors.extend([before.to_string(), after.to_string()]);
+                    let checked = self.call("rp_range_check", &[value, error.to_string()]);
+                    (self.temp(checked, Type::Integer), Type::Integer)
+                }
+                Op::NonZero => {
+                    let (divisor, ty) = self.pop();
+                    let divisor = if divisor.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                        divisor
+                    } else {
+                        self.temp(divisor, ty)
+                    };
+                    let fail = self.fail(interpreter::division_by_zero(span));
+                    self.statement(format!("if ({} == 0) {};", divisor, fail));
+                    (divisor, ty)
+                }
+                Op::NegInteger { checked } => self.integer_op("rp_neg", 1, checked, span),
+                Op::AddInteger { checked } => self.integer_op("rp_add", 2, checked, span),
+                Op::SubInteger { checked } => self.integer_op("rp_sub", 2, checked, span),
+                Op::MulInteger { checked } => self.integer_op("rp_mul", 2, checked, span),
+                Op::DivInteger { checked } => self.integer_op("rp_div", 2, checked, span),
+                Op::NegReal => (format!("(-{})", self.pop().0), Type::Real),
+                Op::AddReal => self.real_op("+"),
+                Op::SubReal => self.real_op("-"),
+                Op::MulReal => self.real_op("*"),
+                Op::DivReal => self.real_op("/"),
+                // Nothing after an instruction that always fails is run.
+                Op::NotFound(target) => return self.fail_here(interpreter::not_found(name(target), span)),
+                Op::NotAVariable(target) => return self.fail_here(interpreter::not_a_variable(name(target), span)),
+                Op::NotAssignable(target) => return self.fail_here(interpreter::not_assignable(name(target), span)),
+                Op::NotFoundToCall(target) => {
+                    return self.fail_here(interpreter::not_found_to_call(name(target), span))
+                }
+                Op::NotAProcedure(target) => return self.fail_here(interpreter::not_a_procedure(name(target), span)),
+                Op::NoValue(target) => return self.fail_here(host::no_value(name(target), span)),
+                Op::RealDivOperand => return self.fail_here(interpreter::real_div_operand(span)),
+                Op::Host { .. } => unreachable!("host functions are only called from Rust"),
+            };
+            self.stack.push((value, ty));
+        }
+    }
+
+    /// The whole C file around the statements of `main`.
+    fn finish(mut self) -> String {
+        let mut out = String::new();
+        let file = self.sources.file(self.chunk.spans.first().map_or(0, |span| span.file));
+        writeln!(out, "/* Compiled by rusterp from {}. */", file.name.replace("*/", "* /")).unwrap();
+        for header in ["inttypes.h", "math.h", "stdio.h", "stdlib.h", "string.h"] {
+            writeln!(out, "#include <{}>", header).unwrap();
+        }
+
+        let mut globals = String::new();
+        for (name, symbol) in &self.chunk.globals {
+            match symbol {
+                Symbol::Variable { ty: Type::Integer, slot } => {
+                    let print = self.call("rp_print_integer", &[string(name), self.variable(*slot)]);
+                    writeln!(globals, "    {};", print).unwrap();
+                }
+                Symbol::Variable { ty: Type::Real, slot } => {
+                    let print = self.call("rp_print_real", &[string(name), self.variable(*slot)]);
+                    writeln!(globals, "    {};", print).unwrap();
+                }
+                Symbol::Procedure => writeln!(globals, "    puts({});", string(&format!("{} : PROCEDURE", name))).unwrap(),
+            }
+        }
+
+        // Without failures, nothing prints the errors.
+        if self.used.contains(&"rp_trace") {
+            out.push_str("\n/* The errors the program can stop with. */\nstatic const char *const errors[] = {\n");
+            for error in &self.errors {
+                writeln!(out, "    {},", string(error)).unwrap();
+            }
+            out.push_str("};\n");
+        }
+        for (name, _, definition) in RUNTIME {
+            if self.used.contains(name) {
+                out.push('\n');
+                out.push_str(definition);
+            }
+        }
+
+        out.push('\n');
+        for (slot, (_, ty)) in self.chunk.slots.iter().enumerate() {
+            match ty {
+                Type::Integer => writeln!(out, "static int64_t {} = 0;", self.variable(slot as u32)).unwrap(),
+                Type::Real => writeln!(out, "static double {} = 0.0;", self.variable(slot as u32)).unwrap(),
+            }
+        }
+        for (index, routine) in self.chunk.routines.iter().enumerate() {
+            let fields = routine.locals.iter().enumerate().map(|(local, (name, ty))| {
+                let ty = match ty {
+                    Type::Integer => "int64_t",
+                    Type::Real => "double",
+                };
+                format!("    {} {};\n", ty, field(local, name))
+            });
+            // C has no empty structs.
+            let fields = fields.collect::<String>();
+            let fields = if fields.is_empty() { "    char none;\n".to_string() } else { fields };
+            if self.functions[index].is_some() {
+                let name = &routine.name;
+                write!(out, "\n/* The frame of {}. */\nstruct frame{} {{\n{}}};\n", name, index, fields).unwrap();
+            }
+        }
+        if self.functions.iter().any(Option::is_some) {
+            out.push('\n');
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            if function.is_some() {
+                writeln!(out, "static void {}({});", self.function_name(index), self.params(index)).unwrap();
+            }
+        }
+        for function in self.functions.iter().flatten() {
+            out.push('\n');
+            out.push_str(function);
+        }
+        write!(out, "\nstatic void print_globals(void) {{\n{}}}\n", globals).unwrap();
+        write!(out, "\nint main(void) {{\n{}    print_globals();\n    return 0;\n}}\n", self.main).unwrap();
+        out
+    }
+
+    /// The routines whose frames `routine` sees, the outermost first and
+    /// itself last. The frame at level `n` is `f{n}`.
+    fn chain(&self, routine: usize) -> Vec<usize> {
+        let mut chain = vec![routine];
+        while let Some(parent) = self.chunk.routines[chain[chain.len() - 1]].parent {
+            chain.push(parent as usize);
+        }
+        chain.reverse();
+        chain
+    }
+
+    fn function_name(&self, routine: usize) -> String {
+        format!("p{}_{}", routine, self.chunk.routines[routine].name)
+    }
+
+    /// The parameters of the function of `routine`: its frames.
+    fn params(&self, routine: usize) -> String {
+        let chain = self.chain(routine);
+        let params = chain.iter().enumerate();
+        params.map(|(level, routine)| format!("struct frame{} *f{}", routine, level + 1)).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The function of `routine`, whose statements are in `body`.
+    fn function(&mut self, routine: usize) -> String {
+        let mut function = format!("static void {}({}) {{\n", self.function_name(routine), self.params(routine));
+        for level in 1..=self.chain(routine).len() {
+            if !self.frames.contains(&level) {
+                writeln!(function, "    (void)f{};", level).unwrap();
+            }
+        }
+        self.frames.clear();
+        function.push_str(&std::mem::take(&mut self.body));
+        function.push_str("}\n");
+        function
+    }
+
+    /// A local of the frame `up` frames out from that of the routine being
+    /// written, as `Op::LoadLocal` finds it, with its type.
+    fn local(&mut self, up: u32, local: u32) -> (String, Type) {
+        let chain = self.chain(self.routine.expect("locals are only found in routines"));
+        let level = chain.len() - up as usize;
+        self.frames.insert(level);
+        let (name, ty) = &self.chunk.routines[chain[level - 1]].locals[local as usize];
+        (format!("f{}->{}", level, field(local as usize, name)), *ty)
+    }
+
+    /// Calls `routine` with the arguments on the stack, in a new frame,
+    /// unless calls nest too deep already. The call is recorded for as long
+    /// as it runs.
+    fn call_routine(&mut self, routine: usize) {
+        if self.functions[routine].is_none() && !self.called.contains(&routine) {
+            self.called.push(routine);
+        }
+        let callee = &self.chunk.routines[routine];
+        let params = callee.params as usize;
+        let args = self.stack.split_off(self.stack.len() - params);
+        let mut fields = args.into_iter().map(|(arg, _)| arg).collect::<Vec<_>>();
+        fields.extend(callee.locals[params..].iter().map(|(_, ty)| match ty {
+            Type::Integer => "0".to_string(),
+            Type::Real => "0.0".to_string(),
+        }));
+        if fields.is_empty() {
+            fields.push("0".to_string());
+        }
+        let level = self.chain(routine).len();
+        let mut args = (1..level).map(|level| format!("f{}", level)).collect::<Vec<_>>();
+        self.frames.extend(1..level);
+        args.push(format!("&(struct frame{}){{ {} }}", routine, fields.join(", ")));
+
+        let fail = self.fail(limits::too_many_calls(DEFAULT_CALLS, self.chunk.spans[self.pc]));
+        self.statement(format!("if (rp_depth == {}) {};", DEFAULT_CALLS, fail));
+        let note = self.chunk.trace(self.pc, &[self.pc], Diagnostic::new(""));
+        self.errors.push(self.sources.render_notes(&note, false));
+        self.require("rp_calls");
+        self.require("rp_depth");
+        self.statement(format!("rp_depth++, rp_calls = &(struct rp_call){{ {}, rp_calls }};", self.errors.len() - 1));
+        self.statement(format!("{}({});", self.function_name(routine), args.join(", ")));
+        self.statement("rp_depth--, rp_calls = rp_calls->caller;".to_string());
+    }
+
+    /// The C variable for `slot`. Unit variables are named as `Unit_name`.
+    fn variable(&self, slot: u32) -> String {
+        format!("g{}_{}", slot, self.chunk.slots[slot as usize].0.replace('.', "_"))
+    }
+
+    fn statement(&mut self, statement: String) {
+        writeln!(self.body, "    {}", statement).unwrap();
+    }
+
+    fn pop(&mut self) -> (String, Type) {
+        self.stack.pop().expect("the compiler pushes every operand it pops")
+    }
+
+    /// Computes `value` into a new temporary, here, and names it.
+    fn temp(&mut self, value: String, ty: Type) -> String {
+        let name = format!("t{}", self.temps);
+        self.temps += 1;
+        let ty = match ty {
+            Type::Integer => "int64_t",
+            Type::Real => "double",
+        };
+        self.statement(format!("{} {} = {};", ty, name, value));
+        name
+    }
+
+    /// A call of the runtime function `function`, which is then written out.
+    fn call(&mut self, function: &'static str, args: &[String]) -> String {
+        self.require(function);
+        format!("{}({})", function, args.join(", "))
+    }
+
+    fn require(&mut self, function: &'static str) {
+        if !self.used.contains(&function) {
+            self.used.push(function);
+            let (_, calls, _) = RUNTIME.iter().find(|(name, ..)| *name == function).expect("a runtime function");
+            calls.iter().for_each(|called| self.require(called));
+        }
+    }
+
+    /// `diagnostic`, raised by the instruction being written, with the
+    /// notes tracing it as far as that instruction knows.
+    fn render(&self, diagnostic: Diagnostic) -> String {
+        self.sources.render(&self.chunk.trace(self.pc, &[], diagnostic).into(), false)
+    }
+
+    /// A call failing with `diagnostic`.
+    fn fail(&mut self, diagnostic: Diagnostic) -> String {
+        let error = self.render(diagnostic);
+        self.errors.push(error);
+        self.call("rp_fail", &[(self.errors.len() - 1).to_string()])
+    }
+
+    fn fail_here(&mut self, diagnostic: Diagnostic) {
+        for (value, _) in std::mem::take(&mut self.stack) {
+            self.discard(value);
+        }
+        let fail = self.fail(diagnostic);
+        self.statement(format!("{};", fail));
+    }
+
+    /// Evaluates a value the program computes but does not use, as the VM
+    /// does.
+    fn discard(&mut self, value: String) {
+        self.statement(format!("(void){};", value));
+    }
+
+    /// INTEGER arithmetic on the top `operands` values. Checked arithmetic
+    /// may fail, so it is computed where it is.
+    fn integer_op(&mut self, function: &'static str, operands: usize, checked: bool, span: Span) -> (String, Type) {
+        let mut args = self.stack.split_off(self.stack.len() - operands).into_iter().map(|(arg, _)| arg).collect::<Vec<_>>();
+        if !checked {
+            return (self.call(function, &args), Type::Integer);
+        }
+        let error = self.errors.len();
+        self.errors.push(self.render(interpreter::overflow(span)));
+        args.push(error.to_string());
+        let checked = match function {
+            "rp_neg" => self.call("rp_neg_checked", &args),
+            "rp_add" => self.call("rp_add_checked", &args),
+            "rp_sub" => self.call("rp_sub_checked", &args),
+            "rp_mul" => self.call("rp_mul_checked", &args),
+            _ => self.call("rp_div_checked", &args),
+        };
+        (self.temp(checked, Type::Integer), Type::Integer)
+    }
+
+    fn real_op(&mut self, operator: &str) -> (String, Type) {
+        let (right, _) = self.pop();
+        let (left, _) = self.pop();
+        (format!("({} {} {})", left, operator, right), Type::Real)
+    }
+}
+
+fn integer(value: i64) -> String {
+    match value {
+        i64::MIN => "INT64_MIN".to_string(),
+        value if value < 0 => format!("({})", value),
+        value => value.to_string(),
+    }
+}
+
+/// The field of a frame struct holding `local`, named `name`.
+fn field(local: usize, name: &str) -> String {
+    format!("l{}_{}", local, name)
+}
+
+/// A C literal for `value`. Debug formatting gives the fewest digits that
+/// read back as the same number.
+fn real(value: f64) -> String {
+    match value {
+        value if value.is_nan() => "NAN".to_string(),
+        value if value.is_infinite() => if value < 0.0 { "(-INFINITY)" } else { "INFINITY" }.to_string(),
+        value if value.is_sign_negative() => format!("({:?})", value),
+        value => format!("{:?}", value),
+    }
+}
+
+/// A C string literal holding `text`. Bytes outside printable ASCII, and
+/// `?` which could start a trigraph, are escaped.
+fn string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' | b'?' => write!(out, "\\{}", byte as char).unwrap(),
+            b'\n' => out.push_str("\\n"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => write!(out, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Builds `c` with the system C compiler and runs it, returning its stdout,
+/// or its stderr if it failed.
+#[cfg(test)]
+fn build_and_run(c: &str) -> Result<String, String> {
+    let cc = ["cc", "-std=c99", "-pedantic", "-Wall", "-Wextra", "-Werror", "-o", "prog", "prog.c"];
+    super::super::corpus::build_and_run(&[("prog.c", c.as_bytes())], &[&cc, &["./prog"]])
+}
+
+#[test]
+fn builds_programs_that_behave_like_the_interpreter() {
+    super::super::corpus::check(|program, units, sources| {
+        build_and_run(&emit(&super::super::bytecode::compile(program, units), sources))
+    });
+}
+
+#[test]
+fn builds_units_into_the_program() {
+    let base = "UNIT Base; INTERFACE VAR step : INTEGER; IMPLEMENTATION VAR half : REAL;
+        BEGIN step := 3; half := step / 2 END.";
+    let units = [Parser::new(base.as_bytes()).unwrap().parse_unit().unwrap()];
+    let source = "PROGRAM p; USES Base; VAR n : INTEGER; r : REAL; BEGIN n := step * 2; r := n / 4 END.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let sources = SourceMap::new();
+    sources.add("p.pa", source.as_bytes().to_vec());
+    let c = emit(&super::super::bytecode::compile(&program, &units), &sources);
+    assert!(c.contains("static double g1_Base_half = 0.0;"), "{}", c);
+    assert_eq!(build_and_run(&c), Ok("n : INTEGER = 6\nr : REAL = 1.5\n".to_string()));
+}
+
+#[test]
+fn escapes_what_c_would_read_otherwise() {
+    assert_eq!(string("say \"??=\"\\\n\té"), r#""say \"\?\?=\"\\\n\011\303\251""#);
+    assert_eq!(integer(i64::MIN), "INT64_MIN");
+    assert_eq!(real(1e30), "1e30");
+    assert_eq!(real(-0.5), "(-0.5)");
+}
@@ -0,0 +1,6 @@
+//! Translations of compiled programs into other languages, so that they
+//! can be built and run without rusterp.
+
+pub mod c;
+pub mod rust;
+pub mod wasm;
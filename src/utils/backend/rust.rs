@@ -0,0 +1,855 @@
+//! A Cargo crate for a program and the units it uses, to build with no
+//! dependencies:
+//!
+//! ```text
+//! rusterp emit-rust prog.pa -o prog && cargo build --offline --manifest-path prog/Cargo.toml
+//! ```
+//!
+//! Each unit becomes a module with a `Vars` struct for its variables, the
+//! interface ones public, and an `initialize` function for its
+//! initialization part. The program's own variables sit beside them in
+//! `Globals`, which every function takes as `&mut`. Arithmetic is written
+//! the way rusterp does it: wrapping or checked INTEGER methods, with run-time
+//! errors returned as `runtime::Error` and passed up with `?`. An error
+//! prints as rusterp renders it, and the program exits with status 1.
+//!
+//! Each routine becomes a function in the module of the unit declaring its
+//! procedure, or beside `main`, with a struct for its frame. It takes the
+//! globals, the frames of the procedures it is declared in and its own, all
+//! as `&mut`. A call adds the note on it to an error passing through, so
+//! the notes come innermost first. Calls nest at most `DEFAULT_CALLS` deep,
+//! as in the VM by default.
+//!
+//! The language has no arrays, records or VAR parameters, so there are no
+//! vectors or structs for them. It has no output statements either, so the
+//! program prints its global scope once it has run, the way `rusterp scope`
+//! does.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::utils::ast::decl::Type;
+use crate::utils::ast::expr::{Expr, Operation, UnaryOp};
+use crate::utils::ast::ident::Ident;
+use crate::utils::ast::proc::Procedure;
+use crate::utils::ast::program::Program;
+use crate::utils::ast::stmt::{Checks, Compound, Stmt};
+use crate::utils::ast::unit::Unit;
+use crate::utils::bytecode::{self, Names, Resolved, Symbol};
+use crate::utils::err::diagnostic::Diagnostic;
+use crate::utils::host::{self, Host};
+use crate::utils::interpreter::{self, Caller};
+use crate::utils::lexer::{Operators, Span};
+use crate::utils::limits::{self, DEFAULT_CALLS};
+use crate::utils::source::SourceMap;
+#[cfg(test)]
+use crate::utils::parser::Parser;
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "self", "static", "struct",
+    "super", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while",
+    "yield",
+];
+
+const RUNTIME: &str = r#"
+/// Why the program stopped: the index of its message in `ERRORS`. A range
+/// check error shows the value it failed on between that message and the
+/// next. The notes on the calls it passed through follow.
+#[derive(Debug)]
+pub struct Error {
+    message: usize,
+    value: Option<f64>,
+    notes: Vec<usize>,
+}
+
+impl Error {
+    pub fn new(message: usize) -> Self {
+        Self { message, value: None, notes: Vec::new() }
+    }
+
+    /// The error, passing out of a call, with the note on that call.
+    pub fn called(mut self, note: usize) -> Self {
+        self.notes.push(note);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Some(value) => write!(f, "{}{}{}", ERRORS[self.message], value, ERRORS[self.message + 1])?,
+            None => f.write_str(ERRORS[self.message])?,
+        }
+        self.notes.iter().try_for_each(|note| f.write_str(ERRORS[*note]))
+    }
+}
+
+/// Runs `call`, unless `MAX_CALLS` calls are running already: it fails with
+/// `message` then. An error passing out of the call gets `note`.
+pub fn call(message: usize, note: usize, call: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    if CALLS.load(Ordering::Relaxed) == MAX_CALLS {
+        return Err(Error::new(message));
+    }
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    let result = call();
+    CALLS.fetch_sub(1, Ordering::Relaxed);
+    result.map_err(|e| e.called(note))
+}
+
+/// `divisor`, unless it is zero.
+pub fn nonzero<T: Default + PartialEq>(divisor: T, message: usize) -> Result<T, Error> {
+    if divisor == T::default() {
+        Err(Error::new(message))
+    } else {
+        Ok(divisor)
+    }
+}
+
+/// `value` as an INTEGER, if it is a whole number that fits.
+pub fn range_check(value: f64, message: usize) -> Result<i64, Error> {
+    if value.fract() == 0.0 && value.abs() < 9223372036854775808.0 {
+        Ok(value as i64)
+    } else {
+        Err(Error { message, value: Some(value), notes: Vec::new() })
+    }
+}
+"#;
+
+/// The files of the crate for `program` and `units`, as paths relative to
+/// the crate's directory and their contents. `units` are as `units::load`
+/// returns them, and `sources` holds the files of both.
+pub fn emit(program: &Program, units: &[Unit], sources: &SourceMap) -> Vec<(String, String)> {
+    let names = Names::new(program, units, &Host::new());
+    let program_vars = names.slots.iter().filter(|(name, _)| !name.contains('.')).map(|(name, _)| name.to_lowercase());
+    let taken = program_vars.chain(["main", "runtime"].map(String::from)).collect::<Vec<_>>();
+    let modules = units
+        .iter()
+        .map(|unit| {
+            let key = unit.name.key();
+            let module = if taken.contains(&key) || KEYWORDS.contains(&key.as_str()) { key.clone() + "_unit" } else { key.clone() };
+            (key, module)
+        })
+        .collect::<HashMap<_, _>>();
+    let places = names
+        .slots
+        .iter()
+        .map(|(name, _)| match name.split_once('.') {
+            Some((unit, name)) => format!("g.{}.{}", modules[&unit.to_lowercase()], field(name)),
+            None => format!("g.{}", field(name)),
+        })
+        .collect();
+    let module_names = units.iter().map(|unit| modules[&unit.name.key()].clone()).collect();
+    let mut emitter = Emitter {
+        names,
+        sources,
+        places,
+        modules: module_names,
+        used_at: super::super::units::used_at(&program.uses, units),
+        routines: Vec::new(),
+        errors: Vec::new(),
+        pending: Vec::new(),
+        body: String::new(),
+        fails: false,
+        line: None,
+        frames: BTreeSet::new(),
+    };
+
+    let mut initializes = Vec::new();
+    for (index, unit) in units.iter().enumerate() {
+        emitter.names.enter(Some(index));
+        emitter.declared_routines();
+        initializes.push(unit.initialization.as_ref().map(|initialization| emitter.function(initialization)));
+    }
+    emitter.names.enter(None);
+    emitter.declared_routines();
+    let body = emitter.function(&program.block.body);
+
+    let mut files = Vec::new();
+    let mut initializations = String::new();
+    for (index, (unit, initialize)) in units.iter().zip(initializes).enumerate() {
+        let module = &modules[&unit.name.key()];
+        let exported = unit.interface.vars.iter().flat_map(|var| &var.names).map(Ident::key).collect::<Vec<_>>();
+        let prefix = format!("{}.", unit.name.name);
+        let mut vars = String::new();
+        for (name, ty) in emitter.names.slots.iter().filter_map(|(name, ty)| Some((name.strip_prefix(&prefix)?, ty))) {
+            let visibility = if exported.contains(&name.to_lowercase()) { "pub " } else { "" };
+            writeln!(vars, "    {}{}: {},", visibility, field(name), rust_type(*ty)).unwrap();
+        }
+        let routines = emitter.routines_of(Some(index));
+
+        let file = sources.file(unit.span.file);
+        let mut out = format!("//! The unit {}, translated by rusterp from {}.\n", unit.name.name, file.name);
+        let code = initialize.clone().unwrap_or_default() + &routines;
+        match (initialize.is_some() || !routines.is_empty(), code.contains("runtime::")) {
+            (true, true) => out.push_str("\nuse crate::runtime::{self, Error};\nuse crate::Globals;\n"),
+            (true, false) => out.push_str("\nuse crate::runtime::Error;\nuse crate::Globals;\n"),
+            (false, _) => {}
+        }
+        write!(out, "\n/// The variables of {}.\n#[derive(Default)]\npub struct Vars {{\n{}}}\n", unit.name.name, vars).unwrap();
+        if let Some(body) = initialize {
+            write!(
+                out,
+                "\n/// The initialization part of {}.\npub fn initialize({}: &mut Globals) -> Result<(), Error> {{\n{}}}\n",
+                unit.name.name,
+                globals(&body),
+                body
+            )
+            .unwrap();
+            writeln!(initializations, "    {}::initialize(g)?;", module).unwrap();
+        }
+        out.push_str(&routines);
+        files.push((format!("src/{}.rs", module), out));
+    }
+
+    let file = sources.file(program.span.file);
+    let mut main = format!("//! The program {}, translated by rusterp from {}.\n", program.name.name, file.name);
+    main.push_str("\n// Variables may be set and never read, as in the Pascal.\n#![allow(dead_code)]\n\n");
+    for unit in units {
+        writeln!(main, "mod {};", modules[&unit.name.key()]).unwrap();
+    }
+    main.push_str("mod runtime;\n\nuse std::process::ExitCode;\n\nuse runtime::Error;\n");
+    main.push_str("\n/// The variables of the program and of the units it uses.\n#[derive(Default)]\npub struct Globals {\n");
+    for unit in units {
+        let module = &modules[&unit.name.key()];
+        writeln!(main, "    pub {}: {}::Vars,", module, module).unwrap();
+    }
+    for (name, ty) in emitter.names.slots.iter().filter(|(name, _)| !name.contains('.')) {
+        writeln!(main, "    pub {}: {},", field(name), rust_type(*ty)).unwrap();
+    }
+    main.push_str("}\n");
+    main.push_str(&emitter.routines_of(None));
+    write!(
+        main,
+        "\n/// The initialization of the units, then the body of the program.\nfn run({}: &mut Globals) -> Result<(), Error> {{\n{}{}}}\n",
+        globals(&(initializations.clone() + &body)),
+        initializations,
+        body
+    )
+    .unwrap();
+    main.push_str("\nfn main() -> ExitCode {\n    let mut g = Globals::default();\n    if let Err(error) = run(&mut g) {\n");
+    main.push_str("        eprint!(\"{}\", error);\n        return ExitCode::FAILURE;\n    }\n");
+    for (name, symbol) in emitter.names.globals() {
+        match symbol {
+            Symbol::Variable { ty, slot } => {
+                let place = &emitter.places[slot as usize];
+                writeln!(main, "    println!(\"{} : {} = {{}}\", {});", name, ty.name(), place).unwrap();
+            }
+            Symbol::Procedure => writeln!(main, "    println!(\"{} : PROCEDURE\");", name).unwrap(),
+        }
+    }
+    main.push_str("    ExitCode::SUCCESS\n}\n");
+
+    let mut runtime = String::from("//! What the program needs besides the standard library.\n\nuse std::fmt;\n");
+    runtime.push_str("use std::sync::atomic::{AtomicUsize, Ordering};\n");
+    runtime.push_str("\n/// The errors the program can stop with, as rusterp renders them.\nconst ERRORS: &[&str] = &[\n");
+    for error in &emitter.errors {
+        writeln!(runtime, "    {:?},", error).unwrap();
+    }
+    runtime.push_str("];\n");
+    runtime.push_str("\n/// How many calls may be running at once.\n");
+    writeln!(runtime, "const MAX_CALLS: usize = {};", DEFAULT_CALLS).unwrap();
+    runtime.push_str("\n/// How many calls are running.\nstatic CALLS: AtomicUsize = AtomicUsize::new(0);\n");
+    runtime.push_str(RUNTIME);
+
+    let package = match program.name.key() {
+        key if KEYWORDS.contains(&key.as_str()) || ["test", "core", "std", "alloc", "proc_macro"].contains(&key.as_str()) => {
+            key + "_program"
+        }
+        key => key,
+    };
+    let manifest = format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n\n\
+         # A crate of its own, even when written inside another workspace.\n[workspace]\n",
+        package
+    );
+    files.splice(0..0, [("Cargo.toml".to_string(), manifest), ("src/main.rs".to_string(), main)]);
+    files.push(("src/runtime.rs".to_string(), runtime));
+    files
+}
+
+/// How tightly a Rust expression binds, loosest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Sum,
+    Product,
+    Cast,
+    Unary,
+    /// Literals, places, calls and `?`.
+    Atom,
+}
+
+/// A Rust expression computing a value of `ty`.
+struct Code {
+    text: String,
+    prec: Prec,
+    ty: Type,
+}
+
+impl Code {
+    fn new(text: impl Into<String>, prec: Prec, ty: Type) -> Self {
+        Self { text: text.into(), prec, ty }
+    }
+
+    /// The expression, in parentheses unless it binds at least as tightly
+    /// as `prec`.
+    fn at(&self, prec: Prec) -> String {
+        if self.prec >= prec {
+            self.text.clone()
+        } else {
+            format!("({})", self.text)
+        }
+    }
+
+    /// The expression as the receiver of a method call.
+    fn receiver(&self) -> String {
+        match self.text.parse::<i64>() {
+            Ok(_) => format!("{}_i64", self.text),
+            Err(_) => self.at(Prec::Atom),
+        }
+    }
+
+    fn into_real(self) -> Code {
+        match (self.ty, self.text.parse::<i64>()) {
+            (Type::Real, _) => self,
+            (Type::Integer, Ok(literal)) => Code::new(real(literal as f64), Prec::Atom, Type::Real),
+            (Type::Integer, Err(_)) => Code::new(format!("{} as f64", self.at(Prec::Cast)), Prec::Cast, Type::Real),
+        }
+    }
+}
+
+/// The Rust for a routine, once written.
+struct Routine {
+    /// The unit whose module it goes in, or `None` for `main.rs`.
+    unit: Option<usize>,
+    /// The field of each local in its frame struct.
+    fields: Vec<String>,
+    /// Its frame struct and function.
+    code: String,
+}
+
+struct Emitter<'a> {
+    names: Names<'a>,
+    sources: &'a SourceMap,
+    /// The Rust place of the variable in each slot, such as `g.base.step`.
+    places: Vec<String>,
+    /// The module of each unit.
+    modules: Vec<String>,
+    /// Where each unit is used, as `units::used_at` gives it.
+    used_at: Vec<Vec<Ident>>,
+    /// Each routine found, once its code is written.
+    routines: Vec<Option<Routine>>,
+    /// Rendered errors, which the code refers to by index.
+    errors: Vec<String>,
+    /// Values computed in the statement so far and not yet used. Those that
+    /// may fail are still computed when what follows always fails.
+    pending: Vec<String>,
+    /// The body of the function being written.
+    body: String,
+    /// Whether the function being written always fails from here on.
+    fails: bool,
+    /// The source line the last statement was written from.
+    line: Option<(usize, usize)>,
+    /// The levels of the frames the function being written uses, counting
+    /// from 1 for the outermost.
+    frames: BTreeSet<usize>,
+}
+
+impl<'a> Emitter<'a> {
+    /// The body of a function running `compound`, ending in its result.
+    fn function(&mut self, compound: &'a Compound) -> String {
+        self.fails = false;
+        self.line = None;
+        self.compound(compound);
+        if !self.fails {
+            self.body.push_str("    Ok(())\n");
+        }
+        std::mem::take(&mut self.body)
+    }
+
+    fn compound(&mut self, compound: &'a Compound) {
+        for statement in &compound.statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &'a Stmt) {
+        if self.fails {
+            return;
+        }
+        if let Stmt::Assign { span, .. } | Stmt::Call { span, .. } = statement {
+            self.comment(*span);
+        }
+        match statement {
+            Stmt::Compound(compound) => self.compound(compound),
+            Stmt::Assign { target, value, checks, .. } => {
+                let at = value.span();
+                let Ok(value) = self.expr(value, *checks) else { return };
+                match self.names.resolve(target) {
+                    Some((name, Resolved::Global { ty, slot })) => {
+                        let value = self.assigned(value, ty, &name, *checks, at);
+                        self.line(format!("{} = {};", self.places[slot as usize], value));
+                    }
+                    Some((name, Resolved::Local { ty, up, local })) => {
+                        let value = self.assigned(value, ty, &name, *checks, at);
+                        let place = self.local(up, local);
+                        self.line(format!("{} = {};", place, value));
+                    }
+                    Some((name, Resolved::Procedure { .. })) => {
+                        self.pending.push(value.text);
+                        let _ = self.fail(interpreter::not_assignable(&name, target.span));
+                    }
+                    None => {
+                        self.pending.push(value.text);
+                        let _ = self.fail(interpreter::not_found(&target.name, target.span));
+                    }
+                }
+            }
+            Stmt::Call { name, args, checks, span } => {
+                let _ = match self.names.resolve(name) {
+                    Some((_, Resolved::Procedure { procedure, routine, up })) => {
+                        self.call(procedure, routine, up, args, *checks, *span)
+                    }
+                    _ => self.cannot_call(name, args, *checks).map(|_| ()),
+                };
+            }
+            Stmt::Procedure(procedure) => {
+                let routine = self.names.declare_procedure(procedure);
+                self.frame(routine, procedure);
+                self.routine(routine, procedure);
+            }
+            Stmt::Empty { .. } => {}
+        }
+    }
+
+    /// Rust code for `expr`, or `Err` if it always fails, in which case the
+    /// function now ends with that failure.
+    fn expr(&mut self, expr: &Expr, checks: Checks) -> Result<Code, ()> {
+        match expr {
+            Expr::Integer { value: i64::MIN, .. } => Ok(Code::new("i64::MIN", Prec::Atom, Type::Integer)),
+            Expr::Integer { value, .. } => Ok(Code::new(value.to_string(), Prec::Atom, Type::Integer)),
+            Expr::Real { value, .. } => Ok(Code::new(real(*value), Prec::Atom, Type::Real)),
+            Expr::Variable(ident) => match self.names.resolve(ident) {
+                Some((_, Resolved::Global { ty, slot })) => {
+                    Ok(Code::new(self.places[slot as usize].clone(), Prec::Atom, ty))
+                }
+                Some((_, Resolved::Local { ty, up, local })) => Ok(Code::new(self.local(up, local), Prec::Atom, ty)),
+                Some((name, Resolved::Procedure { .. })) => self.fail(interpreter::not_a_variable(&name, ident.span)),
+                None => self.fail(interpreter::not_found(&ident.name, ident.span)),
+            },
+            Expr::Call { name, args, .. } => self.cannot_call(name, args, checks),
+            Expr::Unary { op: UnaryOp::Plus, operand, .. } => self.expr(operand, checks),
+            Expr::Unary { op: UnaryOp::Minus, operand, span } => {
+                let operand = self.expr(operand, checks)?;
+                Ok(match operand.ty {
+                    Type::Real if operand.text.starts_with('-') => Code::new(format!("-({})", operand.text), Prec::Unary, Type::Real),
+                    Type::Real => Code::new(format!("-{}", operand.at(Prec::Unary)), Prec::Unary, Type::Real),
+                    Type::Integer => self.integer(operand, "neg", None, checks, *span),
+                })
+            }
+            Expr::Binary { .. } => {
+                let (first, operations) = expr.left_spine();
+                let mut left = self.expr(first, checks)?;
+                for operation in operations {
+                    left = self.operation(left, operation, checks)?;
+                }
+                Ok(left)
+            }
+        }
+    }
+
+    /// Rust code for `operation`, applied to `left`.
+    fn operation(&mut self, left: Code, operation: Operation, checks: Checks) -> Result<Code, ()> {
+        let Operation { op, right, span } = operation;
+        let ty = bytecode::operation_type(op, left.ty, self.names.type_of(right));
+        let divisor_at = right.span();
+        let real = ty == Type::Real || *op == Operators::FDIVISION;
+        let mut left = left;
+        self.pending.push(left.text.clone());
+        let right = self.expr(right, checks);
+        self.pending.pop();
+        let mut right = right?;
+        if real {
+            left = left.into_real();
+            right = right.into_real();
+        }
+        let integers = !real && left.ty == Type::Integer && right.ty == Type::Integer;
+        let divisor = |emitter: &mut Self, right: Code| {
+            let message = emitter.error(interpreter::division_by_zero(divisor_at));
+            Code::new(format!("runtime::nonzero({}, {})?", right.text, message), Prec::Atom, right.ty)
+        };
+        let (operator, prec) = match op {
+            Operators::FDIVISION => {
+                let right = divisor(self, right);
+                return Ok(Code::new(format!("{} / {}", left.at(Prec::Product), right.text), Prec::Product, Type::Real));
+            }
+            Operators::IDIVISION if integers => {
+                let right = divisor(self, right);
+                return Ok(self.integer(left, "div", Some(right), checks, span));
+            }
+            Operators::IDIVISION => {
+                self.pending.extend([left.text, right.text]);
+                return self.fail(interpreter::real_div_operand(span));
+            }
+            Operators::PLUS if real => ("+", Prec::Sum),
+            Operators::MINUS if real => ("-", Prec::Sum),
+            Operators::MULTIPLICATION if real => ("*", Prec::Product),
+            Operators::PLUS => return Ok(self.integer(left, "add", Some(right), checks, span)),
+            Operators::MINUS => return Ok(self.integer(left, "sub", Some(right), checks, span)),
+            _ => return Ok(self.integer(left, "mul", Some(right), checks, span)),
+        };
+        let text = format!("{} {} {}", left.at(prec), operator, right.at(next(prec)));
+        Ok(Code::new(text, prec, Type::Real))
+    }
+
+    /// INTEGER arithmetic as a method of `left`, wrapping around or failing
+    /// on overflow as `checks` say.
+    fn integer(&mut self, left: Code, method: &str, right: Option<Code>, checks: Checks, span: Span) -> Code {
+        let right = right.map_or(String::new(), |right| right.text);
+        let text = if checks.overflow {
+            let message = self.error(interpreter::overflow(span));
+            format!("{}.checked_{}({}).ok_or(Error::new({}))?", left.receiver(), method, right, message)
+        } else {
+            format!("{}.wrapping_{}({})", left.receiver(), method, right)
+        };
+        Code::new(text, Prec::Atom, Type::Integer)
+    }
+
+    /// `value` converted for the variable or parameter `name` of type `ty`,
+    /// as computed by the expression at `at`.
+    fn assigned(&mut self, value: Code, ty: Type, name: &str, checks: Checks, at: Span) -> String {
+        match (ty, value.ty) {
+            (Type::Real, _) => value.into_real().text,
+            (Type::Integer, Type::Integer) => value.text,
+            (Type::Integer, Type::Real) if checks.range => {
+                let message = self.error_around_value(interpreter::out_of_range(f64::NAN, name, at));
+                format!("runtime::range_check({}, {})?", value.text, message)
+            }
+            (Type::Integer, Type::Real) => format!("{} as i64", value.at(Prec::Cast)),
+        }
+    }
+
+    /// The place of a local, as `Op::LoadLocal` finds it.
+    fn local(&mut self, up: u32, local: u32) -> String {
+        let chain = self.chain();
+        let level = chain.len() - up as usize;
+        self.frames.insert(level);
+        let routine = self.routines[chain[level - 1] as usize].as_ref().expect("a routine is entered once written");
+        format!("f{}.{}", level, routine.fields[local as usize])
+    }
+
+    /// The routines whose frames the code being written sees, the outermost
+    /// first. The frame at level `n` is `f{n}`.
+    fn chain(&self) -> Vec<u32> {
+        let parent = |routine: &u32| self.names.routines[*routine as usize].parent;
+        let mut chain = std::iter::successors(self.names.routine_compiled(), parent).collect::<Vec<_>>();
+        chain.reverse();
+        chain
+    }
+
+    /// A call to `procedure`, written as `routine`, whose frame links to the
+    /// one `up` frames out.
+    fn call(
+        &mut self,
+        procedure: &'a Procedure,
+        routine: u32,
+        up: u32,
+        args: &[Expr],
+        checks: Checks,
+        span: Span,
+    ) -> Result<(), ()> {
+        let params = procedure.params
+            .iter()
+            .flat_map(|param| param.names.iter().map(move |name| (name, param.ty.ty)))
+            .collect::<Vec<_>>();
+        if args.len() != params.len() {
+            let error = host::wrong_argument_count(&procedure.name.name, params.len(), args.len(), span);
+            return self.fail(error).map(|_| ());
+        }
+        let mut values = Vec::new();
+        for (arg, (param, ty)) in args.iter().zip(params) {
+            let value = self.expr(arg, checks)?;
+            let value = self.assigned(value, ty, &param.name, checks, arg.span());
+            self.pending.push(value.clone());
+            values.push(value);
+        }
+        let note = self.note(&procedure.name.name, span);
+        let level = self.chain().len() - up as usize + 1;
+        let callee = self.routines[routine as usize].as_ref().expect("the frame of a routine is written first");
+        let path = match callee.unit {
+            unit if unit == self.names.unit() => String::new(),
+            Some(unit) => format!("crate::{}::", self.modules[unit]),
+            None => "crate::".to_string(),
+        };
+        let locals = &self.names.routines[routine as usize].locals;
+        let zeros = locals[values.len()..].iter().map(|(_, ty)| match ty {
+            Type::Integer => "0".to_string(),
+            Type::Real => "0.0".to_string(),
+        });
+        let fields = callee.fields.iter().zip(values.into_iter().chain(zeros));
+        let fields = fields.map(|(field, value)| format!("{}: {}", field, value)).collect::<Vec<_>>().join(", ");
+        let fields = if fields.is_empty() { String::new() } else { format!(" {} ", fields) };
+        let function = format!("{}p{}_{}", path, routine, procedure.name.key());
+        let mut frames = (1..level).map(|level| format!("f{}", level)).collect::<Vec<_>>();
+        self.frames.extend(1..level);
+        frames.push("&mut frame".to_string());
+        self.line(format!("let mut frame = {}F{} {{{}}};", path, routine, fields));
+        let error = self.error(limits::too_many_calls(DEFAULT_CALLS, span));
+        let call = format!("{}(g, {})", function, frames.join(", "));
+        self.line(format!("runtime::call({}, {}, || {})?;", error, note, call));
+        Ok(())
+    }
+
+    /// Writes the routines of the procedures declared among the declarations
+    /// of the code being written, their frames first, so that they can call
+    /// each other.
+    fn declared_routines(&mut self) {
+        let declared = self.names.declared();
+        for &(routine, procedure) in &declared {
+            self.frame(routine, procedure);
+        }
+        for (routine, procedure) in declared {
+            self.routine(routine, procedure);
+        }
+    }
+
+    /// Writes the frame struct of `routine`, for `procedure` declared in the
+    /// code being written.
+    fn frame(&mut self, routine: u32, procedure: &Procedure) {
+        self.routines.resize_with(self.names.routines.len(), || None);
+        let mut fields: Vec<String> = Vec::new();
+        for (name, _) in &self.names.routines[routine as usize].locals {
+            let mut name = field(name);
+            if fields.contains(&name) {
+                name = format!("{}_{}", name, fields.len());
+            }
+            fields.push(name);
+        }
+        let locals = &self.names.routines[routine as usize].locals;
+        let mut code = format!("\n/// The frame of {}.\npub struct F{} {{\n", procedure.name.name, routine);
+        for (field, (_, ty)) in fields.iter().zip(locals) {
+            writeln!(code, "    pub {}: {},", field, rust_type(*ty)).unwrap();
+        }
+        code.push_str("}\n");
+        self.routines[routine as usize] = Some(Routine { unit: self.names.unit(), fields, code });
+    }
+
+    /// Writes the function of `routine`, compiled from `procedure` declared
+    /// in the code being written, after its frame, leaving the function
+    /// being written as it was.
+    fn routine(&mut self, routine: u32, procedure: &'a Procedure) {
+        let outer = (
+            std::mem::take(&mut self.body),
+            std::mem::take(&mut self.pending),
+            std::mem::take(&mut self.frames),
+            self.fails,
+            self.line,
+        );
+        self.names.enter_procedure(routine, procedure);
+        self.declared_routines();
+        let unit = self.names.unit();
+        let chain = self.chain();
+        let name = &procedure.name.name;
+        let body = self.function(&procedure.block.body);
+        self.names.leave_procedure();
+        let frames = chain.iter().enumerate().map(|(index, routine)| {
+            let used = if self.frames.contains(&(index + 1)) { "" } else { "_" };
+            format!(", {}f{}: &mut F{}", used, index + 1, routine)
+        });
+        let frames = frames.collect::<String>();
+        // Only procedures of units are called from other modules.
+        let visibility = if unit.is_some() && chain.len() == 1 { "pub " } else { "" };
+        let mut code = String::new();
+        write!(
+            code,
+            "\n/// The procedure {}.\n{}fn p{}_{}({}: &mut Globals{}) -> Result<(), Error> {{\n{}}}\n",
+            name,
+            visibility,
+            routine,
+            procedure.name.key(),
+            globals(&body),
+            frames,
+            body
+        )
+        .unwrap();
+        self.routines[routine as usize].as_mut().expect("the frame is written first").code.push_str(&code);
+        (self.body, self.pending, self.frames, self.fails, self.line) = outer;
+    }
+
+    /// The routines written into the module of `unit`, or `main.rs` for
+    /// `None`.
+    fn routines_of(&self, unit: Option<usize>) -> String {
+        let routines = self.routines.iter().flatten().filter(|routine| routine.unit == unit);
+        routines.map(|routine| routine.code.as_str()).collect()
+    }
+
+    /// A call to `name` that is no procedure, or that gives no value where
+    /// one is used: the arguments are computed and then the function fails.
+    fn cannot_call(&mut self, name: &Ident, args: &[Expr], checks: Checks) -> Result<Code, ()> {
+        for arg in args {
+            let arg = self.expr(arg, checks)?;
+            self.pending.push(arg.text);
+        }
+        match self.names.resolve(name) {
+            Some((declared, Resolved::Procedure { .. })) => self.fail(host::no_value(&declared, name.span)),
+            Some((declared, _)) => self.fail(interpreter::not_a_procedure(&declared, name.span)),
+            None => self.fail(interpreter::not_found_to_call(&name.name, name.span)),
+        }
+    }
+
+    /// `diagnostic`, raised by the code being written, with the notes on
+    /// the units whose initialization runs it, if it is theirs.
+    fn traced(&self, diagnostic: Diagnostic) -> Diagnostic {
+        match (self.names.routine_compiled(), self.names.unit()) {
+            (None, Some(unit)) => super::super::units::initializing(diagnostic, &self.used_at[unit]),
+            _ => diagnostic,
+        }
+    }
+
+    /// The index among the errors of the note on a call to `procedure` at
+    /// `span` from the code being written.
+    fn note(&mut self, procedure: &str, span: Span) -> usize {
+        let caller = match (self.names.routine_compiled(), self.names.unit()) {
+            (Some(routine), _) => Caller::Procedure(self.names.routines[routine as usize].name.clone()),
+            (None, Some(unit)) => {
+                Caller::Unit(self.used_at[unit].last().expect("a unit is used by something").name.clone())
+            }
+            (None, None) => Caller::Program,
+        };
+        let note = self.traced(interpreter::called(Diagnostic::new(""), procedure, &caller, span));
+        self.errors.push(self.sources.render_notes(&note, false));
+        self.errors.len() - 1
+    }
+
+    /// Ends the function with `diagnostic`, after computing what is pending
+    /// and may fail first.
+    fn fail(&mut self, diagnostic: Diagnostic) -> Result<Code, ()> {
+        for value in std::mem::take(&mut self.pending) {
+            if value.contains('?') {
+                self.line(format!("let _ = {};", value));
+            }
+        }
+        let message = self.error(diagnostic);
+        writeln!(self.body, "    Err(Error::new({}))", message).unwrap();
+        self.fails = true;
+        Err(())
+    }
+
+    /// The index of `diagnostic` among the errors.
+    fn error(&mut self, diagnostic: Diagnostic) -> usize {
+        self.errors.push(self.sources.render(&self.traced(diagnostic).into(), false));
+        self.errors.len() - 1
+    }
+
+    /// Like `error`, for a message showing a value, which is rendered as
+    /// `NaN` here and split around it.
+    fn error_around_value(&mut self, diagnostic: Diagnostic) -> usize {
+        let message = self.sources.render(&self.traced(diagnostic).into(), false);
+        let (before, after) = message.split_once("NaN").expect("the message shows the value");
+        self.errors.extend([before.to_string(), after.to_string()]);
+        self.errors.len() - 2
+    }
+
+    fn line(&mut self, line: String) {
+        self.pending.clear();
+        writeln!(self.body, "    {}", line).unwrap();
+    }
+
+    /// Heads the code for a statement at `span` with its source line, unless
+    /// the last one was from the same line.
+    fn comment(&mut self, span: Span) {
+        if self.line != Some((span.file, span.line_no)) {
+            self.line = Some((span.file, span.line_no));
+            let file = self.sources.file(span.file);
+            let text = file.text.lines().nth(span.line_no.saturating_sub(1)).unwrap_or("").trim();
+            writeln!(self.body, "    // {}:{}   {}", file.name, span.line_no, text).unwrap();
+        }
+    }
+}
+
+/// The next tighter binding, for the right operand of a left-associative
+/// operator.
+fn next(prec: Prec) -> Prec {
+    match prec {
+        Prec::Sum => Prec::Product,
+        Prec::Product => Prec::Cast,
+        prec => prec,
+    }
+}
+
+/// The name of the `Globals` parameter of a function with `body`, which
+/// may have no use for it when it always fails.
+fn globals(body: &str) -> &'static str {
+    if body.contains("g.") || body.contains("(g)") || body.contains("(g,") {
+        "g"
+    } else {
+        "_g"
+    }
+}
+
+fn rust_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Integer => "i64",
+        Type::Real => "f64",
+    }
+}
+
+/// The Rust field for the Pascal variable `name`, lower case since Pascal
+/// ignores case.
+fn field(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.as_str() {
+        "self" | "super" | "crate" | "_" => name + "_",
+        keyword if KEYWORDS.contains(&keyword) => format!("r#{}", keyword),
+        _ => name,
+    }
+}
+
+/// A Rust literal for `value`. Debug formatting gives the fewest digits
+/// that read back as the same number.
+fn real(value: f64) -> String {
+    match value {
+        value if value.is_nan() => "f64::NAN".to_string(),
+        value if value.is_infinite() => if value < 0.0 { "f64::NEG_INFINITY" } else { "f64::INFINITY" }.to_string(),
+        value => format!("{:?}", value),
+    }
+}
+
+/// Builds the crate of `files` with `build`, run in its directory, into
+/// `binary` and runs that, returning its stdout, or its stderr if it failed.
+#[cfg(test)]
+fn build_and_run(files: &[(String, String)], build: &[&str], binary: &str) -> Result<String, String> {
+    let files = files.iter().map(|(path, contents)| (path.as_str(), contents.as_bytes())).collect::<Vec<_>>();
+    super::super::corpus::build_and_run(&files, &[build, &[binary]])
+}
+
+#[test]
+fn builds_programs_that_behave_like_the_interpreter() {
+    super::super::corpus::check(|program, units, sources| {
+        let rustc = ["rustc", "--edition", "2021", "-D", "warnings", "-o", "prog", "src/main.rs"];
+        build_and_run(&emit(program, units, sources), &rustc, "./prog")
+    });
+}
+
+#[test]
+fn builds_a_crate_with_a_module_per_unit() {
+    let parse = |source: &str| Parser::new(source.as_bytes()).unwrap().parse_unit().unwrap();
+    let units = [
+        parse("UNIT Base; INTERFACE VAR step : INTEGER; IMPLEMENTATION VAR half : REAL; BEGIN step := 3; half := step / 2 END."),
+        parse("UNIT Type; INTERFACE USES Base; VAR count, loop : INTEGER; IMPLEMENTATION BEGIN count := step * 2; loop := 1 END."),
+    ];
+    let source = "PROGRAM Test; USES Base, Type; VAR base : INTEGER; r : REAL; BEGIN base := count + loop; {$R+} r := base / 2 END.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let sources = SourceMap::new();
+    sources.add("test.pa", source.as_bytes().to_vec());
+    let files = emit(&program, &units, &sources);
+    let paths = files.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>();
+    assert_eq!(paths, ["Cargo.toml", "src/main.rs", "src/base_unit.rs", "src/type_unit.rs", "src/runtime.rs"]);
+    assert!(files[0].1.contains("name = \"test_program\""), "{}", files[0].1);
+    assert!(files[2].1.contains("    pub step: i64,\n    half: f64,\n"), "{}", files[2].1);
+    assert!(files[3].1.contains("g.type_unit.r#loop = 1;"), "{}", files[3].1);
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let ran = build_and_run(&files, &[&cargo, "build", "--offline", "-q"], "./target/debug/test_program");
+    assert_eq!(ran, Ok("base : INTEGER = 7\nr : REAL = 3.5\n".to_string()));
+}
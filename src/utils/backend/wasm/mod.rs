@@ -0,0 +1,811 @@
+//! A WebAssembly module for a compiled program, to run in any WebAssembly
+//! host that provides three functions to write with:
+//!
+//! ```text
+//! (import "rusterp" "write" (func (param $stream i32) (param $ptr i32) (param $len i32)))
+//! (import "rusterp" "write_integer" (func (param $stream i32) (param i64)))
+//! (import "rusterp" "write_real" (func (param $stream i32) (param f64)))
+//! ```
+//!
+//! Stream 1 is for output and stream 2 for errors. `write` takes UTF-8 text
+//! from the exported `memory`; `write_real` should print the way rusterp
+//! does, with the fewest digits that read back as the same number and no
+//! exponent. The exported `main` runs the program and returns the exit
+//! status: 0, or 1 once it has written an error as rusterp renders it.
+//!
+//! The bytecode maps onto WebAssembly instruction for instruction, except
+//! for checks, which become an `if` that writes the error and returns. Every
+//! slot becomes a global. The language has no output statements yet, so
+//! `main` writes the global scope at the end, the way `rusterp scope` does.
+//!
+//! Each routine becomes a function that returns a status like `main`. Its
+//! frame is in memory, after the text the module writes, on a stack whose
+//! top is a global: the frame it is declared in first, then its locals, 8
+//! bytes each. A call that fails writes the note on it and fails in turn,
+//! so the notes come innermost first. Another global counts the calls
+//! running, which nest at most `DEFAULT_CALLS` deep as in the VM by default,
+//! and the stack has room for that many of the largest frame.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::utils::ast::decl::Type;
+use crate::utils::bytecode::{Chunk, Op, Symbol};
+use crate::utils::err::diagnostic::Diagnostic;
+use crate::utils::host;
+use crate::utils::interpreter;
+use crate::utils::lexer::Span;
+use crate::utils::limits::{self, DEFAULT_CALLS};
+use crate::utils::source::SourceMap;
+
+#[cfg(test)]
+mod validate;
+
+pub const EXTENSION: &str = "wasm";
+
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const F64: u8 = 0x7C;
+/// The block type of an `if` with no result.
+const EMPTY: u8 = 0x40;
+
+// Instructions, as far as the code uses them.
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0B;
+const RETURN: u8 = 0x0F;
+const CALL: u8 = 0x10;
+const DROP: u8 = 0x1A;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const F64_CONST: u8 = 0x44;
+const I32_LOAD: u8 = 0x28;
+const I64_LOAD: u8 = 0x29;
+const F64_LOAD: u8 = 0x2B;
+const I32_STORE: u8 = 0x36;
+const I64_STORE: u8 = 0x37;
+const F64_STORE: u8 = 0x39;
+const I32_EQZ: u8 = 0x45;
+const I32_EQ: u8 = 0x46;
+const I64_EQZ: u8 = 0x50;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const F64_EQ: u8 = 0x61;
+const F64_LT: u8 = 0x63;
+const I32_ADD: u8 = 0x6A;
+const I32_AND: u8 = 0x71;
+const I64_ADD: u8 = 0x7C;
+const I64_SUB: u8 = 0x7D;
+const I64_MUL: u8 = 0x7E;
+const I64_DIV_S: u8 = 0x7F;
+const I64_AND: u8 = 0x83;
+const I64_XOR: u8 = 0x85;
+const F64_ABS: u8 = 0x99;
+const F64_NEG: u8 = 0x9A;
+const F64_TRUNC: u8 = 0x9D;
+const F64_ADD: u8 = 0xA0;
+const F64_SUB: u8 = 0xA1;
+const F64_MUL: u8 = 0xA2;
+const F64_DIV: u8 = 0xA3;
+const F64_CONVERT_I64_S: u8 = 0xB9;
+/// Saturates and takes NaN to zero, like a cast in Rust.
+const I64_TRUNC_SAT_F64_S: [u8; 2] = [0xFC, 0x06];
+
+// Functions, the imports first.
+const WRITE: u32 = 0;
+const WRITE_INTEGER: u32 = 1;
+const WRITE_REAL: u32 = 2;
+const MAIN: u32 = 3;
+/// The function of routine 0, those of the others following.
+const ROUTINES: u32 = 4;
+
+// The locals of every function: scratch space for the checks, and where the
+// frame of a routine is.
+const A: u32 = 0;
+const B: u32 = 1;
+const R: u32 = 2;
+const X: u32 = 3;
+const FRAME: u32 = 4;
+
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+/// The binary module for `chunk`, which was compiled from `sources`.
+///
+/// # Panics
+///
+/// If `chunk` calls host functions.
+pub fn emit(chunk: &Chunk, sources: &SourceMap) -> Vec<u8> {
+    let mut encoder = Encoder {
+        chunk,
+        sources,
+        code: Vec::new(),
+        functions: Vec::new(),
+        routine: None,
+        stack: Vec::new(),
+        data: Vec::new(),
+        strings: HashMap::new(),
+    };
+    encoder.main();
+    for routine in 0..chunk.routines.len() {
+        encoder.routine(routine);
+    }
+    encoder.module()
+}
+
+struct Encoder<'a> {
+    chunk: &'a Chunk,
+    sources: &'a SourceMap,
+    /// The body of the function being written.
+    code: Vec<u8>,
+    /// The bodies of `main` and the routines written.
+    functions: Vec<Vec<u8>>,
+    /// The routine being written, or `None` for `main`.
+    routine: Option<usize>,
+    /// The types of the values the code leaves on the stack.
+    stack: Vec<Type>,
+    /// The initial contents of memory: every string the code writes.
+    data: Vec<u8>,
+    /// Where each string is in `data`.
+    strings: HashMap<String, u32>,
+}
+
+impl Encoder<'_> {
+    fn main(&mut self) {
+        let chunk = self.chunk;
+        if self.code(chunk.top_level()) {
+            for (name, symbol) in &chunk.globals {
+                match symbol {
+                    Symbol::Variable { ty, slot } => {
+                        self.write(STDOUT, &format!("{} : {} = ", name, ty.name()));
+                        self.i32_const(STDOUT);
+                        self.op(GLOBAL_GET);
+                        self.u32(*slot);
+                        self.call(if *ty == Type::Integer { WRITE_INTEGER } else { WRITE_REAL });
+                        self.write(STDOUT, "\n");
+                    }
+                    Symbol::Procedure => self.write(STDOUT, &format!("{} : PROCEDURE\n", name)),
+                }
+            }
+            self.exit(0);
+        }
+        self.functions.push(std::mem::take(&mut self.code));
+    }
+
+    /// The function of `routine`, which takes its frame from the top of the
+    /// stack of frames.
+    fn routine(&mut self, routine: usize) {
+        self.routine = Some(routine);
+        let locals = &self.chunk.routines[routine].locals;
+        let params = self.chunk.routines[routine].params as usize;
+        self.op(GLOBAL_GET);
+        self.u32(self.stack_pointer());
+        self.local(LOCAL_TEE, FRAME);
+        self.i32_const(frame_size(locals.len()) as i32);
+        self.op(I32_ADD);
+        self.op(GLOBAL_SET);
+        self.u32(self.stack_pointer());
+        for (local, (_, ty)) in locals.iter().enumerate().skip(params) {
+            self.local(LOCAL_GET, FRAME);
+            match ty {
+                Type::Integer => self.i64_const(0),
+                Type::Real => self.f64_const(0.0),
+            }
+            self.store(*ty, local);
+        }
+        self.code(self.chunk.routines[routine].code.clone());
+        self.functions.push(std::mem::take(&mut self.code));
+    }
+
+    /// Writes the instructions in `code`, returning whether the code can
+    /// run past the last of them.
+    fn code(&mut self, code: Range<usize>) -> bool {
+        let chunk = self.chunk;
+        for pc in code {
+            let (op, span) = (&chunk.code[pc], chunk.spans[pc]);
+            let name = |index: u32| chunk.names[index as usize].as_str();
+            match *op {
+                Op::Integer(value) => {
+                    self.i64_const(value);
+                    self.stack.push(Type::Integer);
+                }
+                Op::Real(value) => {
+                    self.f64_const(value);
+                    self.stack.push(Type::Real);
+                }
+                Op::Load(slot) => {
+                    self.op(GLOBAL_GET);
+                    self.u32(slot);
+                    self.stack.push(chunk.slots[slot as usize].1);
+                }
+                Op::Store(slot) => {
+                    self.op(GLOBAL_SET);
+                    self.u32(slot);
+                    self.stack.pop();
+                }
+                Op::LoadLocal { up, local } => {
+                    let ty = self.frame(up, local);
+                    self.memory(if ty == Type::Integer { I64_LOAD } else { F64_LOAD }, 3, 8 + 8 * local);
+                    self.stack.push(ty);
+                }
+                Op::StoreLocal { up, local } => {
+                    let ty = self.stack.pop().expect("a value to store");
+                    let scratch = if ty == Type::Integer { A } else { X };
+                    self.local(LOCAL_SET, scratch);
+                    self.frame(up, local);
+                    self.local(LOCAL_GET, scratch);
+                    self.store(ty, local as usize);
+                }
+                Op::Call { routine, up } => self.call_routine(pc, routine as usize, up),
+                Op::Return => {
+                    self.local(LOCAL_GET, FRAME);
+                    self.op(GLOBAL_SET);
+                    self.u32(self.stack_pointer());
+                    self.exit(0);
+                    return false;
+                }
+                Op::Pop => {
+                    self.op(DROP);
+                    self.stack.pop();
+                }
+                Op::ToReal => {
+                    self.op(F64_CONVERT_I64_S);
+                    self.retype(Type::Real);
+                }
+                Op::Truncate => {
+                    self.ops(&I64_TRUNC_SAT_F64_S);
+                    self.retype(Type::Integer);
+                }
+                Op::RangeCheck(target) => {
+                    let message = self.render(pc, interpreter::out_of_range(f64::NAN, name(target), span));
+                    let (before, after) = message.split_once("NaN").expect("the message shows the value");
+                    // A whole number, and less than 2^63 either way.
+                    self.local(LOCAL_TEE, X);
+                    self.op(F64_TRUNC);
+                    self.local(LOCAL_GET, X);
+                    self.op(F64_EQ);
+                    self.local(LOCAL_GET, X);
+                    self.op(F64_ABS);
+                    self.f64_const(9223372036854775808.0);
+                    self.ops(&[F64_LT, I32_AND, I32_EQZ]);
+                    self.ops(&[IF, EMPTY]);
+                    self.write(STDERR, before);
+                    self.i32_const(STDERR);
+                    self.local(LOCAL_GET, X);
+                    self.call(WRITE_REAL);
+                    self.write(STDERR, after);
+                    self.exit(1);
+                    self.op(END);
+                    self.local(LOCAL_GET, X);
+                    self.ops(&I64_TRUNC_SAT_F64_S);
+                    self.retype(Type::Integer);
+                }
+                Op::NonZero => {
+                    let message = self.render(pc, interpreter::division_by_zero(span));
+                    match self.stack.last() {
+                        Some(Type::Integer) => {
+                            self.local(LOCAL_TEE, B);
+                            self.op(I64_EQZ);
+                            self.fail_if(&message);
+                            self.local(LOCAL_GET, B);
+                        }
+                        _ => {
+                            self.local(LOCAL_TEE, X);
+                            self.f64_const(0.0);
+                            self.op(F64_EQ);
+                            self.fail_if(&message);
+                            self.local(LOCAL_GET, X);
+                        }
+                    }
+                }
+                Op::NegInteger { checked } => {
+                    self.local(LOCAL_SET, A);
+                    if checked {
+                        self.local(LOCAL_GET, A);
+                        self.i64_const(i64::MIN);
+                        self.op(I64_EQ);
+                        self.fail_if(&self.render(pc, interpreter::overflow(span)));
+                    }
+                    self.i64_const(0);
+                    self.local(LOCAL_GET, A);
+                    self.op(I64_SUB);
+                }
+                Op::AddInteger { checked } => self.add_or_sub(I64_ADD, checked, pc, span),
+                Op::SubInteger { checked } => self.add_or_sub(I64_SUB, checked, pc, span),
+                Op::MulInteger { checked } => {
+                    if !checked {
+                        self.op(I64_MUL);
+                    } else {
+                        self.operands();
+                        self.local(LOCAL_GET, A);
+                        self.local(LOCAL_GET, B);
+                        self.op(I64_MUL);
+                        self.local(LOCAL_SET, R);
+                        // Whether r / a would not give back b, dividing only
+                        // where the division cannot trap.
+                        self.local(LOCAL_GET, A);
+                        self.op(I64_EQZ);
+                        self.ops(&[IF, I32]);
+                        self.i32_const(0);
+                        self.op(ELSE);
+                        self.local(LOCAL_GET, A);
+                        self.i64_const(-1);
+                        self.op(I64_EQ);
+                        self.ops(&[IF, I32]);
+                        self.local(LOCAL_GET, B);
+                        self.i64_const(i64::MIN);
+                        self.op(I64_EQ);
+                        self.op(ELSE);
+                        self.local(LOCAL_GET, R);
+                        self.local(LOCAL_GET, A);
+                        self.op(I64_DIV_S);
+                        self.local(LOCAL_GET, B);
+                        self.op(I64_NE);
+                        self.ops(&[END, END]);
+                        self.fail_if(&self.render(pc, interpreter::overflow(span)));
+                        self.local(LOCAL_GET, R);
+                    }
+                    self.stack.pop();
+                }
+                Op::DivInteger { checked } => {
+                    // i64.div_s traps on MIN / -1, which wraps to MIN or
+                    // fails here.
+                    self.operands();
+                    self.local(LOCAL_GET, B);
+                    self.i64_const(-1);
+                    self.op(I64_EQ);
+                    if checked {
+                        self.local(LOCAL_GET, A);
+                        self.i64_const(i64::MIN);
+                        self.ops(&[I64_EQ, I32_AND]);
+                        self.fail_if(&self.render(pc, interpreter::overflow(span)));
+                        self.local(LOCAL_GET, A);
+                        self.local(LOCAL_GET, B);
+                        self.op(I64_DIV_S);
+                    } else {
+                        self.ops(&[IF, I64]);
+                        self.i64_const(0);
+                        self.local(LOCAL_GET, A);
+                        self.op(I64_SUB);
+                        self.op(ELSE);
+                        self.local(LOCAL_GET, A);
+                        self.local(LOCAL_GET, B);
+                        self.op(I64_DIV_S);
+                        self.op(END);
+                    }
+                    self.stack.pop();
+                }
+                Op::NegReal => self.op(F64_NEG),
+                Op::AddReal => self.real(F64_ADD),
+                Op::SubReal => self.real(F64_SUB),
+                Op::MulReal => self.real(F64_MUL),
+                Op::DivReal => self.real(F64_DIV),
+                // Nothing after an instruction that always fails is run.
+                Op::NotFound(target) => return self.fail(pc, interpreter::not_found(name(target), span)),
+                Op::NotAVariable(target) => return self.fail(pc, interpreter::not_a_variable(name(target), span)),
+                Op::NotAssignable(target) => return self.fail(pc, interpreter::not_assignable(name(target), span)),
+                Op::NotFoundToCall(target) => {
+                    return self.fail(pc, interpreter::not_found_to_call(name(target), span))
+                }
+                Op::NotAProcedure(target) => return self.fail(pc, interpreter::not_a_procedure(name(target), span)),
+                Op::NoValue(target) => return self.fail(pc, host::no_value(name(target), span)),
+                Op::RealDivOperand => return self.fail(pc, interpreter::real_div_operand(span)),
+                Op::Host { .. } => unreachable!("host functions are only called from Rust"),
+            }
+        }
+        true
+    }
+
+    /// The global holding the top of the stack of frames.
+    fn stack_pointer(&self) -> u32 {
+        self.chunk.slots.len() as u32
+    }
+
+    /// The global counting the calls running.
+    fn calls(&self) -> u32 {
+        self.chunk.slots.len() as u32 + 1
+    }
+
+    /// Where the frames start in memory: after the text, 8-byte aligned.
+    fn frames(&self) -> u32 {
+        (self.data.len() as u32).next_multiple_of(8)
+    }
+
+    /// Pushes the address of the frame `up` frames out from that of the
+    /// routine being written, and returns the type of `local` in it.
+    fn frame(&mut self, up: u32, local: u32) -> Type {
+        let mut routine = self.routine.expect("locals are only found in routines");
+        self.local(LOCAL_GET, FRAME);
+        for _ in 0..up {
+            self.memory(I32_LOAD, 2, 0);
+            routine = self.chunk.routines[routine].parent.expect("the frame is declared in another") as usize;
+        }
+        self.chunk.routines[routine].locals[local as usize].1
+    }
+
+    /// Stores the value on top of the stack as `local` of the frame under
+    /// it.
+    fn store(&mut self, ty: Type, local: usize) {
+        self.memory(if ty == Type::Integer { I64_STORE } else { F64_STORE }, 3, 8 + 8 * local as u32);
+    }
+
+    fn memory(&mut self, op: u8, align: u32, offset: u32) {
+        self.op(op);
+        self.u32(align);
+        self.u32(offset);
+    }
+
+    /// Calls `routine` from instruction `pc` with the arguments on the
+    /// stack, which go into its frame, and the frame `up` frames out as the
+    /// one it is declared in, unless calls nest too deep already.
+    fn call_routine(&mut self, pc: usize, routine: usize, up: u32) {
+        self.op(GLOBAL_GET);
+        self.u32(self.calls());
+        self.i32_const(DEFAULT_CALLS as i32);
+        self.op(I32_EQ);
+        let message = self.render(pc, limits::too_many_calls(DEFAULT_CALLS, self.chunk.spans[pc]));
+        self.fail_if(&message);
+        self.add_to_calls(1);
+        let callee = &self.chunk.routines[routine];
+        for local in (0..callee.params as usize).rev() {
+            let ty = self.stack.pop().expect("an argument for each parameter");
+            let scratch = if ty == Type::Integer { A } else { X };
+            self.local(LOCAL_SET, scratch);
+            self.op(GLOBAL_GET);
+            self.u32(self.stack_pointer());
+            self.local(LOCAL_GET, scratch);
+            self.store(ty, local);
+        }
+        self.op(GLOBAL_GET);
+        self.u32(self.stack_pointer());
+        match callee.parent {
+            Some(_) => {
+                self.local(LOCAL_GET, FRAME);
+                for _ in 0..up {
+                    self.memory(I32_LOAD, 2, 0);
+                }
+            }
+            None => self.i32_const(0),
+        }
+        self.memory(I32_STORE, 2, 0);
+        self.call(ROUTINES + routine as u32);
+        self.add_to_calls(-1);
+        let note = self.chunk.trace(pc, &[pc], Diagnostic::new(""));
+        let note = self.sources.render_notes(&note, false);
+        self.fail_if(&note);
+    }
+
+    fn add_to_calls(&mut self, count: i32) {
+        self.op(GLOBAL_GET);
+        self.u32(self.calls());
+        self.i32_const(count);
+        self.op(I32_ADD);
+        self.op(GLOBAL_SET);
+        self.u32(self.calls());
+    }
+
+    /// The whole module around the bodies of the functions.
+    fn module(self) -> Vec<u8> {
+        let chunk = self.chunk;
+        let mut module = b"\0asm".to_vec();
+        module.extend(1u32.to_le_bytes());
+
+        let function = |params: &[u8], results: &[u8]| {
+            let mut ty = vec![0x60];
+            ty.extend(vector(params.len(), params.to_vec()));
+            ty.extend(vector(results.len(), results.to_vec()));
+            ty
+        };
+        let types = [function(&[I32, I32, I32], &[]), function(&[I32, I64], &[]), function(&[I32, F64], &[]), function(&[], &[I32])];
+        section(&mut module, 1, vector(types.len(), types.concat()));
+
+        let mut imports = Vec::new();
+        for (index, field) in ["write", "write_integer", "write_real"].into_iter().enumerate() {
+            imports.extend(name("rusterp"));
+            imports.extend(name(field));
+            imports.push(0x00);
+            imports.extend(leb128(index as i64));
+        }
+        section(&mut module, 2, vector(3, imports));
+        // `main` and every routine have the type of `main`.
+        let functions = self.functions.len();
+        section(&mut module, 3, vector(functions, leb128(MAIN as i64).repeat(functions)));
+
+        let frame = chunk.routines.iter().map(|routine| frame_size(routine.locals.len())).max().unwrap_or(0);
+        let pages = (self.frames() + frame * DEFAULT_CALLS as u32).div_ceil(65536);
+        let mut memory = vec![0x00];
+        memory.extend(leb128(pages as i64));
+        section(&mut module, 5, vector(1, memory));
+
+        let mut globals = Vec::new();
+        for (_, ty) in &chunk.slots {
+            match ty {
+                Type::Integer => globals.extend([I64, 0x01, I64_CONST, 0x00, END]),
+                Type::Real => {
+                    globals.extend([F64, 0x01, F64_CONST]);
+                    globals.extend(0f64.to_le_bytes());
+                    globals.push(END);
+                }
+            }
+        }
+        globals.extend([I32, 0x01, I32_CONST]);
+        globals.extend(leb128(self.frames() as i64));
+        globals.push(END);
+        globals.extend([I32, 0x01, I32_CONST, 0x00, END]);
+        section(&mut module, 6, vector(chunk.slots.len() + 2, globals));
+
+        let mut exports = name("main");
+        exports.push(0x00);
+        exports.extend(leb128(MAIN as i64));
+        exports.extend(name("memory"));
+        exports.extend([0x02, 0x00]);
+        section(&mut module, 7, vector(2, exports));
+
+        let mut code = Vec::new();
+        for function in self.functions {
+            // Three i64 locals, one f64 and one i32: A, B, R, X and FRAME.
+            let mut body = vector(3, vec![0x03, I64, 0x01, F64, 0x01, I32]);
+            body.extend(function);
+            body.push(END);
+            code.extend(leb128(body.len() as i64));
+            code.extend(body);
+        }
+        section(&mut module, 10, vector(functions, code));
+
+        let mut data = vec![0x00, I32_CONST, 0x00, END];
+        data.extend(vector(self.data.len(), self.data));
+        section(&mut module, 11, vector(1, data));
+        module
+    }
+
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+
+    fn ops(&mut self, ops: &[u8]) {
+        self.code.extend(ops);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.code.extend(leb128(value as i64));
+    }
+
+    fn local(&mut self, op: u8, local: u32) {
+        self.op(op);
+        self.u32(local);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.op(CALL);
+        self.u32(function);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.op(I32_CONST);
+        self.code.extend(leb128(value as i64));
+    }
+
+    fn i64_const(&mut self, value: i64) {
+        self.op(I64_CONST);
+        self.code.extend(leb128(value));
+    }
+
+    fn f64_const(&mut self, value: f64) {
+        self.op(F64_CONST);
+        self.code.extend(value.to_le_bytes());
+    }
+
+    fn retype(&mut self, ty: Type) {
+        self.stack.pop();
+        self.stack.push(ty);
+    }
+
+    /// Moves the two INTEGER operands on top of the stack into `A` and `B`.
+    fn operands(&mut self) {
+        self.local(LOCAL_SET, B);
+        self.local(LOCAL_SET, A);
+    }
+
+    fn add_or_sub(&mut self, op: u8, checked: bool, pc: usize, span: Span) {
+        self.stack.pop();
+        if !checked {
+            return self.op(op);
+        }
+        self.operands();
+        self.local(LOCAL_GET, A);
+        self.local(LOCAL_GET, B);
+        self.op(op);
+        self.local(LOCAL_SET, R);
+        // The sign of the result is wrong: for a sum, both operands differ
+        // from it in sign; for a difference, a differs from both b and r.
+        self.local(LOCAL_GET, A);
+        self.local(LOCAL_GET, R);
+        self.op(I64_XOR);
+        self.local(LOCAL_GET, if op == I64_ADD { B } else { A });
+        self.local(LOCAL_GET, if op == I64_ADD { R } else { B });
+        self.ops(&[I64_XOR, I64_AND]);
+        self.i64_const(0);
+        self.op(I64_LT_S);
+        self.fail_if(&self.render(pc, interpreter::overflow(span)));
+        self.local(LOCAL_GET, R);
+    }
+
+    fn real(&mut self, op: u8) {
+        self.op(op);
+        self.stack.pop();
+    }
+
+    /// `diagnostic`, raised by instruction `pc`, with the notes tracing it
+    /// as far as that instruction knows.
+    fn render(&self, pc: usize, diagnostic: Diagnostic) -> String {
+        self.sources.render(&self.chunk.trace(pc, &[], diagnostic).into(), false)
+    }
+
+    /// Writes `text` to `stream`.
+    fn write(&mut self, stream: i32, text: &str) {
+        let offset = match self.strings.get(text) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.data.len() as u32;
+                self.data.extend(text.as_bytes());
+                self.strings.insert(text.to_string(), offset);
+                offset
+            }
+        };
+        self.i32_const(stream);
+        self.i32_const(offset as i32);
+        self.i32_const(text.len() as i32);
+        self.call(WRITE);
+    }
+
+    fn exit(&mut self, status: i32) {
+        self.i32_const(status);
+        self.op(RETURN);
+    }
+
+    /// Fails with `message` if the condition on top of the stack holds.
+    fn fail_if(&mut self, message: &str) {
+        self.ops(&[IF, EMPTY]);
+        self.write(STDERR, message);
+        self.exit(1);
+        self.op(END);
+    }
+
+    fn fail(&mut self, pc: usize, diagnostic: Diagnostic) -> bool {
+        let message = self.render(pc, diagnostic);
+        self.write(STDERR, &message);
+        self.exit(1);
+        false
+    }
+}
+
+/// `value` in signed LEB128, which also encodes unsigned values below 2^63.
+fn leb128(mut value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        bytes.push(if done { byte } else { byte | 0x80 });
+        if done {
+            return bytes;
+        }
+    }
+}
+
+/// A vector of `len` items, encoded as `items`.
+fn vector(len: usize, items: Vec<u8>) -> Vec<u8> {
+    let mut bytes = leb128(len as i64);
+    bytes.extend(items);
+    bytes
+}
+
+fn name(name: &str) -> Vec<u8> {
+    vector(name.len(), name.as_bytes().to_vec())
+}
+
+/// The bytes a frame with `locals` takes: the link to the frame it is
+/// declared in, then the locals.
+fn frame_size(locals: usize) -> u32 {
+    8 + 8 * locals as u32
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    module.push(id);
+    module.extend(leb128(contents.len() as i64));
+    module.extend(contents);
+}
+
+/// A host for the tests: runs `main` and prints what it writes.
+#[cfg(test)]
+const HOST: &str = r#"
+const bytes = require('fs').readFileSync(process.argv[2]);
+const streams = { 1: [], 2: [] };
+let memory;
+function real(value) {
+    if (Number.isNaN(value)) return 'NaN';
+    if (!Number.isFinite(value)) return value < 0 ? '-inf' : 'inf';
+    if (value === 0) return Object.is(value, -0) ? '-0' : '0';
+    const sign = value < 0 ? '-' : '';
+    const text = Math.abs(value).toString();
+    const parts = text.match(/^(\d)(?:\.(\d+))?e([+-]\d+)$/);
+    if (!parts) return sign + text;
+    const digits = parts[1] + (parts[2] || ''), exponent = parseInt(parts[3]);
+    return exponent >= 0
+        ? sign + digits + '0'.repeat(exponent - digits.length + 1)
+        : sign + '0.' + '0'.repeat(-exponent - 1) + digits;
+}
+const imports = { rusterp: {
+    write: (stream, ptr, len) => streams[stream].push(Buffer.from(memory.buffer, ptr, len).toString()),
+    write_integer: (stream, value) => streams[stream].push(value.toString()),
+    write_real: (stream, value) => streams[stream].push(real(value)),
+} };
+WebAssembly.instantiate(bytes, imports).then(({ instance }) => {
+    memory = instance.exports.memory;
+    const status = instance.exports.main();
+    process.stdout.write(streams[1].join(''));
+    process.stderr.write(streams[2].join(''));
+    // Exiting once the pipes are written to, not before.
+    process.exitCode = status;
+});
+"#;
+
+/// Validates `module` and runs it with node, returning what it writes, to
+/// stdout or to stderr if it failed.
+#[cfg(test)]
+fn run(module: &[u8]) -> Result<String, String> {
+    if let Err(e) = validate::validate(module) {
+        panic!("the module is not valid: {}", e);
+    }
+    let files = [("host.js", HOST.as_bytes()), ("prog.wasm", module)];
+    super::super::corpus::build_and_run(&files, &[&["node", "host.js", "prog.wasm"]])
+}
+
+#[test]
+fn runs_programs_like_the_interpreter() {
+    super::super::corpus::check(|program, units, sources| run(&emit(&super::super::bytecode::compile(program, units), sources)));
+}
+
+#[test]
+fn validation_finds_what_engines_would_refuse() {
+    let module = |body: &[u8]| {
+        let mut module = b"\0asm".to_vec();
+        module.extend(1u32.to_le_bytes());
+        section(&mut module, 1, vector(1, vec![0x60, 0x00, 0x01, I32]));
+        section(&mut module, 3, vector(1, vec![0x00]));
+        let mut code = vector(0, Vec::new());
+        code.extend(body);
+        section(&mut module, 10, vector(1, vector(code.len(), code)));
+        validate::validate(&module)
+    };
+    assert_eq!(module(&[I32_CONST, 0x07, END]), Ok(()));
+    assert_eq!(module(&[I32_CONST, 0x07, RETURN, DROP, END]), Ok(()));
+    let errors = [
+        (&[I64_CONST, 0x07, END][..], "at byte 27: an instruction takes 0x7F and is given 0x7E"),
+        (&[END], "at byte 25: an instruction has too few operands"),
+        (&[I32_CONST, 0x01, IF, EMPTY, I32_CONST, 0x01, END, END], "at byte 31: a block leaves more values than its results"),
+        (&[LOCAL_GET, 0x00, END], "at byte 26: there is no such local"),
+        (&[0x06, END], "at byte 24: instruction 0x06 is not supported"),
+        (&[I32_CONST, 0x07], "at byte 26: the module ends too early"),
+    ];
+    for (body, error) in errors {
+        assert_eq!(module(body), Err(error.to_string()), "{:?}", body);
+    }
+}
+
+#[test]
+fn encodes_numbers_in_leb128() {
+    assert_eq!(leb128(0), [0x00]);
+    assert_eq!(leb128(63), [0x3F]);
+    assert_eq!(leb128(64), [0xC0, 0x00]);
+    assert_eq!(leb128(-1), [0x7F]);
+    assert_eq!(leb128(-65), [0xBF, 0x7F]);
+    assert_eq!(leb128(624485), [0xE5, 0x8E, 0x26]);
+    assert_eq!(leb128(i64::MIN).len(), 10);
+}
@@ -0,0 +1,584 @@
+//! Checks that a module is valid WebAssembly, so that the tests need no
+//! WebAssembly engine to tell. It follows the validation algorithm in the
+//! appendix of the specification, for the sections and instructions a
+//! module of ours can hold; anything else is reported as unsupported.
+
+use super::{EMPTY, END, F64, I32, I64};
+
+/// The value types, as encoded.
+const F32: u8 = 0x7D;
+
+struct FuncType {
+    params: Vec<u8>,
+    results: Vec<u8>,
+}
+
+struct Global {
+    ty: u8,
+    mutable: bool,
+}
+
+/// What is declared before the code: the types of the functions, imported
+/// ones first, the globals and the number of memory pages.
+#[derive(Default)]
+struct Module {
+    types: Vec<FuncType>,
+    functions: Vec<u32>,
+    globals: Vec<Global>,
+    pages: Option<u64>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, String> {
+        Err(format!("at byte {}: {}", self.at, message.into()))
+    }
+
+    fn done(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.at) {
+            Some(&byte) => {
+                self.at += 1;
+                Ok(byte)
+            }
+            None => self.error("the module ends too early"),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.at < len {
+            return self.error("the module ends too early");
+        }
+        self.at += len;
+        Ok(&self.bytes[self.at - len..self.at])
+    }
+
+    /// An unsigned LEB128 number of at most `bits` bits.
+    fn unsigned(&mut self, bits: u32) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..bits.div_ceil(7) * 7).step_by(7) {
+            let byte = self.u8()?;
+            let payload = (byte & 0x7F) as u64;
+            if shift + 7 > bits && payload >> (bits - shift) != 0 {
+                return self.error("a number is too large");
+            }
+            value |= payload << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        self.error("a number is too long")
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.unsigned(32)? as u32)
+    }
+
+    /// A signed LEB128 number of at most `bits` bits, which is only checked
+    /// for its length.
+    fn signed(&mut self, bits: u32) -> Result<(), String> {
+        for _ in 0..bits.div_ceil(7) {
+            if self.u8()? & 0x80 == 0 {
+                return Ok(());
+            }
+        }
+        self.error("a number is too long")
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        match String::from_utf8(self.bytes(len)?.to_vec()) {
+            Ok(name) => Ok(name),
+            Err(_) => self.error("a name is not UTF-8"),
+        }
+    }
+
+    fn value_type(&mut self) -> Result<u8, String> {
+        match self.u8()? {
+            ty @ (I32 | I64 | F32 | F64) => Ok(ty),
+            ty => self.error(format!("0x{:02X} is not a value type", ty)),
+        }
+    }
+
+    /// The results of a block: none, or one value.
+    fn block_type(&mut self) -> Result<Vec<u8>, String> {
+        if self.bytes.get(self.at) == Some(&EMPTY) {
+            self.at += 1;
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.value_type()?])
+    }
+
+    /// A constant expression of type `ty`: one constant and `end`.
+    fn constant(&mut self, ty: u8) -> Result<(), String> {
+        match (self.u8()?, ty) {
+            (0x41, I32) => self.signed(32)?,
+            (0x42, I64) => self.signed(64)?,
+            (0x44, F64) => {
+                self.bytes(8)?;
+            }
+            _ => return self.error("an initializer is not a constant of its type"),
+        }
+        match self.u8()? {
+            END => Ok(()),
+            _ => self.error("an initializer does not end after its constant"),
+        }
+    }
+}
+
+/// Whether `module` is valid, or where and why it is not.
+pub fn validate(module: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { bytes: module, at: 0 };
+    if reader.bytes(8).ok() != Some(b"\0asm\x01\0\0\0") {
+        return Err("not a WebAssembly module of version 1".to_string());
+    }
+    let mut declared = Module::default();
+    let mut defined = 0;
+    let mut exports = Vec::new();
+    let mut last = 0;
+    while !reader.done() {
+        let id = reader.u8()?;
+        let len = reader.len()?;
+        let start = reader.at;
+        let mut section = Reader { bytes: &module[..start + reader.bytes(len)?.len()], at: start };
+        if id != 0 && id <= last {
+            return section.error(format!("section {} is out of order", id));
+        }
+        last = id.max(last);
+        match id {
+            0 => section.at = section.bytes.len(),
+            1 => {
+                for _ in 0..section.len()? {
+                    if section.u8()? != 0x60 {
+                        return section.error("a type is not a function type");
+                    }
+                    let params = (0..section.len()?).map(|_| section.value_type()).collect::<Result<_, _>>()?;
+                    let results = (0..section.len()?).map(|_| section.value_type()).collect::<Result<Vec<_>, _>>()?;
+                    if results.len() > 1 {
+                        return section.error("a function returns more than one value");
+                    }
+                    declared.types.push(FuncType { params, results });
+                }
+            }
+            2 => {
+                for _ in 0..section.len()? {
+                    section.name()?;
+                    section.name()?;
+                    if section.u8()? != 0x00 {
+                        return section.error("only functions can be imported");
+                    }
+                    let ty = section.u32()?;
+                    declared.function(&section, ty)?;
+                }
+            }
+            3 => {
+                for _ in 0..section.len()? {
+                    let ty = section.u32()?;
+                    declared.function(&section, ty)?;
+                    defined += 1;
+                }
+            }
+            5 => {
+                for _ in 0..section.len()? {
+                    if declared.pages.is_some() {
+                        return section.error("there is more than one memory");
+                    }
+                    let has_max = section.u8()?;
+                    let min = section.unsigned(32)?;
+                    if has_max == 0x01 && section.unsigned(32)? < min {
+                        return section.error("a memory has a maximum below its minimum");
+                    } else if has_max > 0x01 {
+                        return section.error("a memory has malformed limits");
+                    }
+                    if min > 65536 {
+                        return section.error("a memory is larger than 4 GiB");
+                    }
+                    declared.pages = Some(min);
+                }
+            }
+            6 => {
+                for _ in 0..section.len()? {
+                    let ty = section.value_type()?;
+                    let mutable = match section.u8()? {
+                        0x00 => false,
+                        0x01 => true,
+                        _ => return section.error("a global is neither constant nor mutable"),
+                    };
+                    section.constant(ty)?;
+                    declared.globals.push(Global { ty, mutable });
+                }
+            }
+            7 => {
+                for _ in 0..section.len()? {
+                    let name = section.name()?;
+                    if exports.contains(&name) {
+                        return section.error(format!("`{}` is exported twice", name));
+                    }
+                    exports.push(name);
+                    let kind = section.u8()?;
+                    let index = section.u32()? as usize;
+                    let exists = match kind {
+                        0x00 => index < declared.functions.len(),
+                        0x02 => index == 0 && declared.pages.is_some(),
+                        0x03 => index < declared.globals.len(),
+                        _ => return section.error("only functions, memory and globals can be exported"),
+                    };
+                    if !exists {
+                        return section.error(format!("export `{}` refers to nothing", exports.last().unwrap()));
+                    }
+                }
+            }
+            10 => {
+                let count = section.len()?;
+                if count != defined {
+                    return section.error(format!("{} functions are declared and {} defined", defined, count));
+                }
+                let imported = declared.functions.len() - defined;
+                for index in imported..declared.functions.len() {
+                    let len = section.len()?;
+                    let start = section.at;
+                    let end = start + section.bytes(len)?.len();
+                    let mut body = Reader { bytes: &module[..end], at: start };
+                    declared.code(&mut body, declared.functions[index])?;
+                }
+            }
+            11 => {
+                for _ in 0..section.len()? {
+                    if section.u8()? != 0x00 {
+                        return section.error("only active data for memory 0 is supported");
+                    }
+                    let Some(pages) = declared.pages else {
+                        return section.error("there is data without a memory");
+                    };
+                    if section.u8()? != 0x41 {
+                        return section.error("data is placed by something else than an i32.const");
+                    }
+                    let offset = section.unsigned(32)?;
+                    if section.u8()? != END {
+                        return section.error("the offset of data does not end after its constant");
+                    }
+                    let len = section.len()?;
+                    section.bytes(len)?;
+                    if offset + len as u64 > pages * 65536 {
+                        return section.error("data does not fit in memory");
+                    }
+                }
+            }
+            id => return section.error(format!("section {} is not supported", id)),
+        }
+        if !section.done() {
+            return section.error(format!("section {} has bytes left over", id));
+        }
+        reader.at = section.at;
+    }
+    if defined > 0 && last < 10 {
+        return Err("functions are declared and have no code".to_string());
+    }
+    Ok(())
+}
+
+/// A block being validated.
+struct Frame {
+    /// Whether it is a loop, whose label takes no values.
+    is_loop: bool,
+    is_if: bool,
+    results: Vec<u8>,
+    /// The height of the operand stack where the block starts.
+    height: usize,
+    /// Set after an instruction that never completes, past which any
+    /// operand may be popped.
+    unreachable: bool,
+}
+
+/// The operand stack and the blocks of a function body. `None` is an
+/// operand of unknown type, popped from unreachable code.
+struct Operands {
+    values: Vec<Option<u8>>,
+    frames: Vec<Frame>,
+}
+
+impl Operands {
+    fn push(&mut self, ty: u8) {
+        self.values.push(Some(ty));
+    }
+
+    fn pop(&mut self, body: &Reader, expected: Option<u8>) -> Result<Option<u8>, String> {
+        let frame = self.frames.last().expect("a frame for the body");
+        let actual = if self.values.len() == frame.height {
+            if !frame.unreachable {
+                return body.error("an instruction has too few operands");
+            }
+            None
+        } else {
+            self.values.pop().unwrap()
+        };
+        match (actual, expected) {
+            (Some(actual), Some(expected)) if actual != expected => {
+                body.error(format!("an instruction takes 0x{:02X} and is given 0x{:02X}", expected, actual))
+            }
+            (None, expected) => Ok(expected),
+            (actual, _) => Ok(actual),
+        }
+    }
+
+    fn pop_all(&mut self, body: &Reader, types: &[u8]) -> Result<(), String> {
+        for &ty in types.iter().rev() {
+            self.pop(body, Some(ty))?;
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, is_loop: bool, is_if: bool, results: Vec<u8>) {
+        self.frames.push(Frame { is_loop, is_if, results, height: self.values.len(), unreachable: false });
+    }
+
+    /// Ends the innermost block, checking that it leaves its results.
+    fn leave(&mut self, body: &Reader) -> Result<Frame, String> {
+        let results = self.frames.last().expect("a frame for the body").results.clone();
+        self.pop_all(body, &results)?;
+        let frame = self.frames.pop().unwrap();
+        if self.values.len() != frame.height {
+            return body.error("a block leaves more values than its results");
+        }
+        Ok(frame)
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.frames.last_mut().expect("a frame for the body");
+        self.values.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    /// The types a branch to label `depth` carries.
+    fn label(&self, body: &Reader, depth: u32) -> Result<Vec<u8>, String> {
+        match self.frames.len().checked_sub(depth as usize + 1).map(|index| &self.frames[index]) {
+            Some(frame) if frame.is_loop => Ok(Vec::new()),
+            Some(frame) => Ok(frame.results.clone()),
+            None => body.error(format!("there is no label {}", depth)),
+        }
+    }
+}
+
+impl Module {
+    fn function(&mut self, section: &Reader, ty: u32) -> Result<(), String> {
+        if ty as usize >= self.types.len() {
+            return section.error(format!("type {} is not declared", ty));
+        }
+        self.functions.push(ty);
+        Ok(())
+    }
+
+    /// Validates the body of a function of type `ty`: its locals, then its
+    /// instructions up to the `end` of the function.
+    fn code(&self, body: &mut Reader, ty: u32) -> Result<(), String> {
+        let ty = &self.types[ty as usize];
+        let mut locals = ty.params.clone();
+        for _ in 0..body.len()? {
+            let count = body.len()?;
+            let ty = body.value_type()?;
+            if locals.len() + count > 50000 {
+                return body.error("a function has too many locals");
+            }
+            locals.extend(std::iter::repeat_n(ty, count));
+        }
+        let mut operands = Operands { values: Vec::new(), frames: Vec::new() };
+        operands.enter(false, false, ty.results.clone());
+        while !operands.frames.is_empty() {
+            self.instruction(body, &mut operands, &locals, &ty.results)?;
+        }
+        if !body.done() {
+            return body.error("a function has instructions after its end");
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, body: &mut Reader, operands: &mut Operands, locals: &[u8], returns: &[u8]) -> Result<(), String> {
+        let at = body.at;
+        let op = body.u8()?;
+        // Numeric instructions that only take and give values.
+        let (params, result): (&[u8], Option<u8>) = match op {
+            0x45 | 0x67..=0x69 => (&[I32], Some(I32)),
+            0x46..=0x4F => (&[I32, I32], Some(I32)),
+            0x50 => (&[I64], Some(I32)),
+            0x51..=0x5A => (&[I64, I64], Some(I32)),
+            0x61..=0x66 => (&[F64, F64], Some(I32)),
+            0x6A..=0x78 => (&[I32, I32], Some(I32)),
+            0x79..=0x7B => (&[I64], Some(I64)),
+            0x7C..=0x8A => (&[I64, I64], Some(I64)),
+            0x99..=0x9F => (&[F64], Some(F64)),
+            0xA0..=0xA6 => (&[F64, F64], Some(F64)),
+            0xA7 => (&[I64], Some(I32)),
+            0xAA | 0xAB => (&[F64], Some(I32)),
+            0xAC | 0xAD => (&[I32], Some(I64)),
+            0xB0 | 0xB1 => (&[F64], Some(I64)),
+            0xB7 | 0xB8 => (&[I32], Some(F64)),
+            0xB9 | 0xBA => (&[I64], Some(F64)),
+            0xFC => match body.u32()? {
+                0x02 | 0x03 => (&[F64], Some(I32)),
+                0x06 | 0x07 => (&[F64], Some(I64)),
+                sub => return body.error(format!("instruction 0xFC {} is not supported", sub)),
+            },
+            _ => (&[], None),
+        };
+        if let Some(result) = result {
+            operands.pop_all(body, params)?;
+            operands.push(result);
+            return Ok(());
+        }
+
+        let local = |body: &mut Reader| match locals.get(body.len()?) {
+            Some(&ty) => Ok(ty),
+            None => body.error("there is no such local"),
+        };
+        let global = |body: &mut Reader| match self.globals.get(body.len()?) {
+            Some(global) => Ok(global),
+            None => body.error("there is no such global"),
+        };
+        // The alignment, which may not be more than natural, and the offset.
+        let memory = |body: &mut Reader, size: u32| {
+            if self.pages.is_none() {
+                return body.error("memory is used and there is none");
+            }
+            if body.u32()? > size.trailing_zeros() {
+                return body.error("an access is aligned to more than its size");
+            }
+            body.u32().map(|_| ())
+        };
+        match op {
+            0x00 => operands.unreachable(),
+            0x01 => {}
+            0x02 | 0x03 => {
+                let results = body.block_type()?;
+                operands.enter(op == 0x03, false, results);
+            }
+            0x04 => {
+                let results = body.block_type()?;
+                operands.pop(body, Some(I32))?;
+                operands.enter(false, true, results);
+            }
+            0x05 => {
+                let frame = operands.leave(body)?;
+                if !frame.is_if {
+                    return body.error("`else` is not in an `if`");
+                }
+                operands.enter(false, false, frame.results);
+            }
+            END => {
+                let frame = operands.leave(body)?;
+                // An `if` without `else` leaves what it got, which is nothing.
+                if frame.is_if && !frame.results.is_empty() {
+                    return body.error("an `if` with a result has no `else`");
+                }
+                if !operands.frames.is_empty() {
+                    for ty in frame.results {
+                        operands.push(ty);
+                    }
+                }
+            }
+            0x0C => {
+                let depth = body.u32()?;
+                let label = operands.label(body, depth)?;
+                operands.pop_all(body, &label)?;
+                operands.unreachable();
+            }
+            0x0D => {
+                let depth = body.u32()?;
+                let label = operands.label(body, depth)?;
+                operands.pop(body, Some(I32))?;
+                operands.pop_all(body, &label)?;
+                for ty in label {
+                    operands.push(ty);
+                }
+            }
+            0x0F => {
+                operands.pop_all(body, returns)?;
+                operands.unreachable();
+            }
+            0x10 => {
+                let Some(&ty) = self.functions.get(body.len()?) else {
+                    return body.error("there is no such function");
+                };
+                let ty = &self.types[ty as usize];
+                operands.pop_all(body, &ty.params)?;
+                for &ty in &ty.results {
+                    operands.push(ty);
+                }
+            }
+            0x1A => {
+                operands.pop(body, None)?;
+            }
+            0x1B => {
+                operands.pop(body, Some(I32))?;
+                let second = operands.pop(body, None)?;
+                let first = operands.pop(body, second)?;
+                if let Some(ty) = first.or(second) {
+                    operands.push(ty);
+                } else {
+                    operands.values.push(None);
+                }
+            }
+            0x20 => {
+                let ty = local(body)?;
+                operands.push(ty);
+            }
+            0x21 | 0x22 => {
+                let ty = local(body)?;
+                operands.pop(body, Some(ty))?;
+                if op == 0x22 {
+                    operands.push(ty);
+                }
+            }
+            0x23 => {
+                let ty = global(body)?.ty;
+                operands.push(ty);
+            }
+            0x24 => {
+                let global = global(body)?;
+                if !global.mutable {
+                    return body.error("a constant global is set");
+                }
+                operands.pop(body, Some(global.ty))?;
+            }
+            0x28 | 0x29 | 0x2B => {
+                let (size, ty) = [(4, I32), (8, I64), (0, 0), (8, F64)][op as usize - 0x28];
+                memory(body, size)?;
+                operands.pop(body, Some(I32))?;
+                operands.push(ty);
+            }
+            0x36 | 0x37 | 0x39 => {
+                let (size, ty) = [(4, I32), (8, I64), (0, 0), (8, F64)][op as usize - 0x36];
+                memory(body, size)?;
+                operands.pop(body, Some(ty))?;
+                operands.pop(body, Some(I32))?;
+            }
+            0x41 => {
+                body.signed(32)?;
+                operands.push(I32);
+            }
+            0x42 => {
+                body.signed(64)?;
+                operands.push(I64);
+            }
+            0x44 => {
+                body.bytes(8)?;
+                operands.push(F64);
+            }
+            _ => {
+                body.at = at;
+                return body.error(format!("instruction 0x{:02X} is not supported", op));
+            }
+        }
+        Ok(())
+    }
+}
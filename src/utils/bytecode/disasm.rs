@@ -0,0 +1,182 @@
+//! A readable listing of a chunk. Each run of instructions compiled from
+//! one source line is headed by that line:
+//!
+//! ```text
+//! ; test.pa:5   b := a * 12;
+//!     3  load             0 a
+//!     4  push.i           12
+//!     5  mul.i
+//!     6  store            1 b
+//! ```
+//!
+//! The code of each routine follows that of the top level, headed by its
+//! name.
+
+use std::fmt::Write;
+
+use super::{Chunk, Op, Symbol};
+use crate::utils::source::SourceMap;
+
+/// Lists the slots, the globals, the routines and then the code of `chunk`,
+/// which was compiled from `sources`.
+pub fn disassemble(chunk: &Chunk, sources: &SourceMap) -> String {
+    let mut out = String::new();
+    out.push_str("slots:\n");
+    for (slot, (name, ty)) in chunk.slots.iter().enumerate() {
+        writeln!(out, "{:>5}  {} : {}", slot, name, ty.name()).unwrap();
+    }
+    out.push_str("globals:\n");
+    for (name, symbol) in &chunk.globals {
+        match symbol {
+            Symbol::Variable { slot, .. } => writeln!(out, "       {} in slot {}", name, slot).unwrap(),
+            Symbol::Procedure => writeln!(out, "       {} : PROCEDURE", name).unwrap(),
+        }
+    }
+    if !chunk.routines.is_empty() {
+        out.push_str("routines:\n");
+    }
+    for (index, routine) in chunk.routines.iter().enumerate() {
+        match routine.parent {
+            Some(parent) => writeln!(out, "{:>5}  {}, declared in {}", index, routine.name, parent).unwrap(),
+            None => writeln!(out, "{:>5}  {}", index, routine.name).unwrap(),
+        }
+        for (local, (name, ty)) in routine.locals.iter().enumerate() {
+            let param = if local < routine.params as usize { ", parameter" } else { "" };
+            writeln!(out, "         {:>3}  {} : {}{}", local, name, ty.name(), param).unwrap();
+        }
+    }
+    out.push_str("code:\n");
+    let mut line = None;
+    let mut routine = None;
+    for (pc, (op, span)) in chunk.code.iter().zip(&chunk.spans).enumerate() {
+        if let Some(index) = chunk.routines.iter().position(|routine| routine.code.start == pc) {
+            writeln!(out, "; routine {} {}", index, chunk.routines[index].name).unwrap();
+            routine = Some(index);
+            line = None;
+        }
+        if line != Some((span.file, span.line_no)) {
+            line = Some((span.file, span.line_no));
+            let file = sources.file(span.file);
+            let text = file.text.lines().nth(span.line_no.saturating_sub(1)).unwrap_or("").trim();
+            writeln!(out, "; {}:{}   {}", file.name, span.line_no, text).unwrap();
+        }
+        let (mnemonic, operand) = describe(*op, chunk, routine);
+        writeln!(out, "{:>5}  {:<16} {}", pc, mnemonic, operand).unwrap();
+    }
+    out.lines().map(str::trim_end).collect::<Vec<_>>().join("\n") + "\n"
+}
+
+/// The mnemonic of `op`, in the code of `routine` or of the top level for
+/// `None`, and its operands spelled out.
+fn describe(op: Op, chunk: &Chunk, routine: Option<usize>) -> (&'static str, String) {
+    let slot = |slot: u32| format!("{} {}", slot, chunk.slots[slot as usize].0);
+    let local = |up: u32, local: u32| {
+        let parent = |frame: usize| chunk.routines[frame].parent.map(|parent| parent as usize);
+        let frame = (0..up).fold(routine, |frame, _| frame.and_then(parent));
+        let name = frame.map_or("?", |frame| chunk.routines[frame].locals[local as usize].0.as_str());
+        format!("{} {} {}", up, local, name)
+    };
+    let name = |name: u32| chunk.names[name as usize].clone();
+    let checked = |checked: bool| if checked { "checked" } else { "" }.to_string();
+    match op {
+        Op::Integer(value) => ("push.i", value.to_string()),
+        Op::Real(value) => ("push.r", format!("{:?}", value)),
+        Op::Load(index) => ("load", slot(index)),
+        Op::Store(index) => ("store", slot(index)),
+        Op::Pop => ("pop", String::new()),
+        Op::ToReal => ("to.r", String::new()),
+        Op::Truncate => ("trunc", String::new()),
+        Op::RangeCheck(target) => ("range.check", name(target)),
+        Op::NonZero => ("nonzero", String::new()),
+        Op::NegInteger { checked: c } => ("neg.i", checked(c)),
+        Op::AddInteger { checked: c } => ("add.i", checked(c)),
+        Op::SubInteger { checked: c } => ("sub.i", checked(c)),
+        Op::MulInteger { checked: c } => ("mul.i", checked(c)),
+        Op::DivInteger { checked: c } => ("div.i", checked(c)),
+        Op::NegReal => ("neg.r", String::new()),
+        Op::AddReal => ("add.r", String::new()),
+        Op::SubReal => ("sub.r", String::new()),
+        Op::MulReal => ("mul.r", String::new()),
+        Op::DivReal => ("div.r", String::new()),
+        Op::NotFound(target) => ("fail.unknown", name(target)),
+        Op::NotAVariable(target) => ("fail.not_var", name(target)),
+        Op::NotAssignable(target) => ("fail.assign", name(target)),
+        Op::LoadLocal { up, local: index } => ("load.local", local(up, index)),
+        Op::StoreLocal { up, local: index } => ("store.local", local(up, index)),
+        Op::Call { routine: callee, up } => {
+            ("call", format!("{} {} {}", up, callee, chunk.routines[callee as usize].name))
+        }
+        Op::Return => ("return", String::new()),
+        Op::NotFoundToCall(target) => ("fail.unknown_call", name(target)),
+        Op::NotAProcedure(target) => ("fail.not_proc", name(target)),
+        Op::NoValue(target) => ("fail.no_value", name(target)),
+        Op::RealDivOperand => ("fail.div_real", String::new()),
+        Op::Host { name: target, args, .. } => ("call.host", format!("{} {}", name(target), args)),
+    }
+}
+
+#[test]
+fn lists_the_code_under_its_source_lines() {
+    use crate::utils::parser::Parser;
+    let source = "PROGRAM p;\nVAR a : INTEGER; r : REAL;\nBEGIN\n  a := 2;\n  {$Q+} r := a * 1.5 - a\nEND.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let sources = SourceMap::new();
+    sources.add("p.pa", source.as_bytes().to_vec());
+    let listing = disassemble(&super::compile(&program, &[]), &sources);
+    assert_eq!(listing, "\
+slots:
+    0  a : INTEGER
+    1  r : REAL
+globals:
+       a in slot 0
+       r in slot 1
+code:
+; p.pa:4   a := 2;
+    0  push.i           2
+    1  store            0 a
+; p.pa:5   {$Q+} r := a * 1.5 - a
+    2  load             0 a
+    3  to.r
+    4  push.r           1.5
+    5  mul.r
+    6  load             0 a
+    7  to.r
+    8  sub.r
+    9  store            1 r
+");
+}
+
+#[test]
+fn lists_routines_after_the_top_level() {
+    use crate::utils::parser::Parser;
+    let source = "PROGRAM p;\nPROCEDURE Outer(n : INTEGER); VAR m : REAL;\n  PROCEDURE Inner; BEGIN m := n END;\n\
+        BEGIN Inner() END;\nBEGIN\n  Outer(1)\nEND.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let sources = SourceMap::new();
+    sources.add("p.pa", source.as_bytes().to_vec());
+    let listing = disassemble(&super::compile(&program, &[]), &sources);
+    assert_eq!(listing, "\
+slots:
+globals:
+       Outer : PROCEDURE
+routines:
+    0  Outer
+           0  n : INTEGER, parameter
+           1  m : REAL
+    1  Inner, declared in 0
+code:
+; p.pa:6   Outer(1)
+    0  push.i           1
+    1  call             0 0 Outer
+; routine 0 Outer
+; p.pa:4   BEGIN Inner() END;
+    2  call             0 1 Inner
+    3  return
+; routine 1 Inner
+; p.pa:3   PROCEDURE Inner; BEGIN m := n END;
+    4  load.local       1 0 n
+    5  to.r
+    6  store.local      1 1 m
+    7  return
+");
+}
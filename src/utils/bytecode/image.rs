@@ -0,0 +1,670 @@
+//! Compiled programs saved to disk, to be run without parsing them again.
+//!
+//! An image starts with a fixed header:
+//!
+//! ```text
+//! offset  size  contents
+//!      0     4  magic `RPBC`
+//!      4     2  format version, little-endian
+//!      6     4  length of the payload in bytes
+//!     10     4  CRC-32 of the payload
+//!     14        payload
+//! ```
+//!
+//! The payload holds the source files, so that errors can show the code
+//! they are in, then the chunk. Numbers are little-endian and strings are
+//! their length as a `u32` followed by their UTF-8 bytes.
+
+use std::sync::Arc;
+
+use super::{Chunk, Initialization, Op, Routine, Symbol};
+use crate::utils::ast::decl::Type;
+use crate::utils::ast::ident::Ident;
+use crate::utils::err::diagnostic::Diagnostic;
+use crate::utils::lexer::Span;
+use crate::utils::source::SourceMap;
+
+pub const MAGIC: &[u8; 4] = b"RPBC";
+
+/// Bump this whenever the layout of the payload or the meaning of an
+/// instruction changes, so that old images are rejected instead of run.
+pub const FORMAT_VERSION: u16 = 4;
+
+/// The file extension of images.
+pub const EXTENSION: &str = "pbc";
+
+const HEADER_LEN: usize = 14;
+
+/// A chunk and the sources it was compiled from.
+#[derive(Debug)]
+pub struct Image {
+    pub chunk: Chunk,
+    pub sources: Arc<SourceMap>,
+}
+
+impl Image {
+    /// # Panics
+    ///
+    /// If the chunk calls host functions, which only exist while the
+    /// program that registered them runs.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Writer::default();
+        payload.u32(self.sources.len() as u32);
+        for id in 0..self.sources.len() {
+            let file = self.sources.file(id);
+            payload.str(&file.name);
+            payload.bytes(&file.bytes);
+        }
+        let chunk = &self.chunk;
+        payload.u32(chunk.names.len() as u32);
+        for name in &chunk.names {
+            payload.str(name);
+        }
+        payload.u32(chunk.slots.len() as u32);
+        for (name, ty) in &chunk.slots {
+            payload.str(name);
+            payload.ty(*ty);
+        }
+        payload.u32(chunk.globals.len() as u32);
+        for (name, symbol) in &chunk.globals {
+            payload.str(name);
+            match symbol {
+                Symbol::Variable { ty, slot } => {
+                    payload.u8(0);
+                    payload.ty(*ty);
+                    payload.u32(*slot);
+                }
+                Symbol::Procedure => payload.u8(1),
+            }
+        }
+        payload.u32(chunk.code.len() as u32);
+        for (op, span) in chunk.code.iter().zip(&chunk.spans) {
+            payload.op(*op);
+            payload.span(*span);
+        }
+        payload.u32(chunk.routines.len() as u32);
+        for routine in &chunk.routines {
+            payload.str(&routine.name);
+            payload.u32(routine.code.start as u32);
+            payload.u32(routine.code.end as u32);
+            payload.u32(routine.locals.len() as u32);
+            for (name, ty) in &routine.locals {
+                payload.str(name);
+                payload.ty(*ty);
+            }
+            payload.u32(routine.params);
+            match routine.parent {
+                None => payload.u8(0),
+                Some(parent) => {
+                    payload.u8(1);
+                    payload.u32(parent);
+                }
+            }
+        }
+        payload.u32(chunk.initializations.len() as u32);
+        for initialization in &chunk.initializations {
+            payload.u32(initialization.code.start as u32);
+            payload.u32(initialization.code.end as u32);
+            payload.u32(initialization.used_at.len() as u32);
+            for name in &initialization.used_at {
+                payload.str(&name.name);
+                payload.span(name.span);
+            }
+        }
+
+        let payload = payload.0;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Reads an image back, checking that it is whole, of this format
+    /// version and unchanged since it was written.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Diagnostic> {
+        let truncated = |expected: usize| {
+            Diagnostic::new(format!(
+                "Bytecode file is truncated: it has {} of {} bytes.",
+                bytes.len(),
+                expected
+            ))
+            .with_help("compile the program again")
+        };
+        if !bytes.starts_with(MAGIC) {
+            return Err(match MAGIC.starts_with(bytes) {
+                true if !bytes.is_empty() => truncated(HEADER_LEN),
+                _ => Diagnostic::new("Not a bytecode file.")
+                    .with_help("bytecode files are written by `rusterp compile`"),
+            });
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(truncated(HEADER_LEN));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(
+                Diagnostic::new(format!(
+                    "Bytecode format {} is not supported, this is format {}.",
+                    version, FORMAT_VERSION
+                ))
+                .with_help("compile the program again with this version of rusterp")
+            );
+        }
+        let length = u32::from_le_bytes(bytes[6..10].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes[10..14].try_into().unwrap());
+        let payload = &bytes[HEADER_LEN..];
+        if payload.len() < length {
+            return Err(truncated(HEADER_LEN + length));
+        }
+        if payload.len() > length {
+            return Err(corrupt(format!("{} extra bytes follow the payload", payload.len() - length)));
+        }
+        if crc32(payload) != checksum {
+            return Err(corrupt("its checksum does not match"));
+        }
+        Reader { bytes: payload, at: 0 }.image()
+    }
+}
+
+fn corrupt(what: impl std::fmt::Display) -> Diagnostic {
+    Diagnostic::new(format!("Bytecode file is corrupt: {}.", what)).with_help("compile the program again")
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+    }
+
+    fn ty(&mut self, ty: Type) {
+        self.u8(match ty {
+            Type::Integer => 0,
+            Type::Real => 1,
+        })
+    }
+
+    fn span(&mut self, span: Span) {
+        for value in [span.start, span.end, span.line_no, span.column, span.file] {
+            self.u32(value as u32);
+        }
+    }
+
+    /// An opcode byte, then its operands if it has any.
+    fn op(&mut self, op: Op) {
+        let (code, operand) = encode(op);
+        self.u8(code);
+        match operand {
+            Operand::None => {}
+            Operand::Flag(value) => self.u8(value as u8),
+            Operand::Word(value) => self.u64(value),
+            Operand::Index(value) => self.u32(value),
+            Operand::Indexes(values) => values.into_iter().for_each(|value| self.u32(value)),
+        }
+    }
+}
+
+enum Operand {
+    None,
+    Flag(bool),
+    Word(u64),
+    Index(u32),
+    Indexes(Vec<u32>),
+}
+
+/// The opcode and operand of `op`. The opcodes are part of the format:
+/// changing one means bumping `FORMAT_VERSION`.
+fn encode(op: Op) -> (u8, Operand) {
+    let checked = Operand::Flag;
+    match op {
+        Op::Integer(value) => (0, Operand::Word(value as u64)),
+        Op::Real(value) => (1, Operand::Word(value.to_bits())),
+        Op::Load(slot) => (2, Operand::Index(slot)),
+        Op::Store(slot) => (3, Operand::Index(slot)),
+        Op::Pop => (4, Operand::None),
+        Op::ToReal => (5, Operand::None),
+        Op::Truncate => (6, Operand::None),
+        Op::RangeCheck(name) => (7, Operand::Index(name)),
+        Op::NonZero => (8, Operand::None),
+        Op::NegInteger { checked: c } => (9, checked(c)),
+        Op::AddInteger { checked: c } => (10, checked(c)),
+        Op::SubInteger { checked: c } => (11, checked(c)),
+        Op::MulInteger { checked: c } => (12, checked(c)),
+        Op::DivInteger { checked: c } => (13, checked(c)),
+        Op::NegReal => (14, Operand::None),
+        Op::AddReal => (15, Operand::None),
+        Op::SubReal => (16, Operand::None),
+        Op::MulReal => (17, Operand::None),
+        Op::DivReal => (18, Operand::None),
+        Op::NotFound(name) => (19, Operand::Index(name)),
+        Op::NotAVariable(name) => (21, Operand::Index(name)),
+        Op::NotAssignable(name) => (22, Operand::Index(name)),
+        Op::NotFoundToCall(name) => (23, Operand::Index(name)),
+        Op::RealDivOperand => (24, Operand::None),
+        Op::LoadLocal { up, local } => (25, Operand::Indexes(vec![up, local])),
+        Op::StoreLocal { up, local } => (26, Operand::Indexes(vec![up, local])),
+        Op::Call { routine, up } => (27, Operand::Indexes(vec![routine, up])),
+        Op::Return => (28, Operand::None),
+        Op::NotAProcedure(name) => (29, Operand::Index(name)),
+        Op::NoValue(name) => (30, Operand::Index(name)),
+        Op::Host { .. } => unreachable!("calls to host functions are not saved"),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+type Read<T> = Result<T, Diagnostic>;
+
+impl Reader<'_> {
+    fn image(mut self) -> Read<Image> {
+        let sources = Arc::new(SourceMap::new());
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            let bytes = self.bytes()?.to_vec();
+            sources.add(name, bytes);
+        }
+        let mut chunk = Chunk::default();
+        for _ in 0..self.u32()? {
+            chunk.names.push(self.str()?);
+        }
+        for _ in 0..self.u32()? {
+            chunk.slots.push((self.str()?, self.ty()?));
+        }
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            let symbol = match self.u8()? {
+                0 => Symbol::Variable { ty: self.ty()?, slot: self.slot(&chunk)? },
+                1 => Symbol::Procedure,
+                tag => return Err(corrupt(format!("unknown symbol kind {}", tag))),
+            };
+            chunk.globals.push((name, symbol));
+        }
+        for _ in 0..self.u32()? {
+            let op = self.op(&chunk)?;
+            let span = self.span(&sources)?;
+            chunk.code.push(op);
+            chunk.spans.push(span);
+        }
+        for index in 0..self.u32()? {
+            let name = self.str()?;
+            let code = self.u32()? as usize..self.u32()? as usize;
+            let mut locals = Vec::new();
+            for _ in 0..self.u32()? {
+                locals.push((self.str()?, self.ty()?));
+            }
+            let params = self.u32()?;
+            if params as usize > locals.len() {
+                return Err(corrupt(format!("routine {} has more parameters than locals", index)));
+            }
+            let parent = match self.u8()? {
+                0 => None,
+                1 => match self.u32()? {
+                    parent if parent < index => Some(parent),
+                    _ => return Err(corrupt(format!("routine {} is not declared in a routine before it", index))),
+                },
+                tag => return Err(corrupt(format!("{} is not a flag", tag))),
+            };
+            chunk.routines.push(Routine { name, code, locals, params, parent });
+        }
+        // The routines follow the top level one after another, to the end.
+        let mut end = chunk.code.len();
+        for routine in chunk.routines.iter().rev() {
+            if routine.code.end != end || routine.code.start >= end {
+                return Err(corrupt("a routine lies outside its place in the code"));
+            }
+            end = routine.code.start;
+        }
+        for _ in 0..self.u32()? {
+            let (start, end) = (self.u32()? as usize, self.u32()? as usize);
+            if start > end || end > chunk.top_level().end {
+                return Err(corrupt("a unit's initialization lies outside the code"));
+            }
+            let mut used_at = Vec::new();
+            for _ in 0..self.u32()? {
+                used_at.push(Ident::new(self.str()?, self.span(&sources)?));
+            }
+            chunk.initializations.push(Initialization { code: start..end, used_at });
+        }
+        if self.at != self.bytes.len() {
+            return Err(corrupt("it has data past its end"));
+        }
+        verify(&chunk)?;
+        Ok(Image { chunk, sources })
+    }
+
+    fn take(&mut self, len: usize) -> Read<&[u8]> {
+        let bytes = self.bytes
+            .get(self.at..self.at.saturating_add(len))
+            .ok_or_else(|| corrupt("a length runs past its end"))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Read<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Read<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Read<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Read<&[u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Read<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| corrupt("a name is not UTF-8"))
+    }
+
+    fn ty(&mut self) -> Read<Type> {
+        match self.u8()? {
+            0 => Ok(Type::Integer),
+            1 => Ok(Type::Real),
+            tag => Err(corrupt(format!("unknown type {}", tag))),
+        }
+    }
+
+    fn span(&mut self, sources: &SourceMap) -> Read<Span> {
+        let mut values = [0; 5];
+        for value in &mut values {
+            *value = self.u32()? as usize;
+        }
+        let [start, end, line_no, column, file] = values;
+        if file >= sources.len() || end > sources.file(file).bytes.len() || start > end {
+            return Err(corrupt("an instruction points outside its source"));
+        }
+        // Lines and columns count from 1.
+        if line_no == 0 || column == 0 {
+            return Err(corrupt("an instruction points before the start of a line"));
+        }
+        Ok(Span::new(start, end, line_no, column).in_file(file))
+    }
+
+    /// A slot index, checked against the slots read so far.
+    fn slot(&mut self, chunk: &Chunk) -> Read<u32> {
+        match self.u32()? {
+            slot if (slot as usize) < chunk.slots.len() => Ok(slot),
+            slot => Err(corrupt(format!("there is no slot {}", slot))),
+        }
+    }
+
+    fn name(&mut self, chunk: &Chunk) -> Read<u32> {
+        match self.u32()? {
+            name if (name as usize) < chunk.names.len() => Ok(name),
+            name => Err(corrupt(format!("there is no name {}", name))),
+        }
+    }
+
+    fn checked(&mut self) -> Read<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(corrupt(format!("{} is not a flag", value))),
+        }
+    }
+
+    fn op(&mut self, chunk: &Chunk) -> Read<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Integer(self.u64()? as i64),
+            1 => Op::Real(f64::from_bits(self.u64()?)),
+            2 => Op::Load(self.slot(chunk)?),
+            3 => Op::Store(self.slot(chunk)?),
+            4 => Op::Pop,
+            5 => Op::ToReal,
+            6 => Op::Truncate,
+            7 => Op::RangeCheck(self.name(chunk)?),
+            8 => Op::NonZero,
+            9 => Op::NegInteger { checked: self.checked()? },
+            10 => Op::AddInteger { checked: self.checked()? },
+            11 => Op::SubInteger { checked: self.checked()? },
+            12 => Op::MulInteger { checked: self.checked()? },
+            13 => Op::DivInteger { checked: self.checked()? },
+            14 => Op::NegReal,
+            15 => Op::AddReal,
+            16 => Op::SubReal,
+            17 => Op::MulReal,
+            18 => Op::DivReal,
+            19 => Op::NotFound(self.name(chunk)?),
+            21 => Op::NotAVariable(self.name(chunk)?),
+            22 => Op::NotAssignable(self.name(chunk)?),
+            23 => Op::NotFoundToCall(self.name(chunk)?),
+            24 => Op::RealDivOperand,
+            25 => Op::LoadLocal { up: self.u32()?, local: self.u32()? },
+            26 => Op::StoreLocal { up: self.u32()?, local: self.u32()? },
+            27 => Op::Call { routine: self.u32()?, up: self.u32()? },
+            28 => Op::Return,
+            29 => Op::NotAProcedure(self.name(chunk)?),
+            30 => Op::NoValue(self.name(chunk)?),
+            code => return Err(corrupt(format!("unknown opcode {}", code))),
+        })
+    }
+}
+
+/// Checks that every instruction finds the operands it takes on the stack,
+/// of the types it takes, and the locals and routines it refers to, so that
+/// no image can make the VM misbehave.
+fn verify(chunk: &Chunk) -> Read<()> {
+    verify_code(chunk, None, chunk.top_level())?;
+    for (index, routine) in chunk.routines.iter().enumerate() {
+        if chunk.code[routine.code.end - 1] != Op::Return {
+            return Err(corrupt(format!("routine {} does not end by returning", index)));
+        }
+        verify_code(chunk, Some(index), routine.code.clone())?;
+    }
+    Ok(())
+}
+
+/// Checks `code`, that of `routine` or of the top level for `None`, run
+/// with nothing on the stack. Instructions after one that always fails, or
+/// that returns, are never run.
+fn verify_code(chunk: &Chunk, routine: Option<usize>, code: std::ops::Range<usize>) -> Read<()> {
+    use Type::{Integer, Real};
+    // The routine whose frame is `up` frames out from this one's.
+    let parent = |frame: usize| chunk.routines[frame].parent.map(|parent| parent as usize);
+    let frame = |up: u32| (0..up).fold(routine, |frame, _| frame.and_then(parent));
+    let mut stack = Vec::new();
+    for pc in code {
+        let missing = || corrupt(format!("instruction {} does not find its operands", pc));
+        let misplaced = || corrupt(format!("instruction {} does not fit the routine it is in", pc));
+        let slot = |slot: u32| chunk.slots[slot as usize].1;
+        let local = |up: u32, local: u32| {
+            let locals = &chunk.routines[frame(up).ok_or_else(misplaced)?].locals;
+            locals.get(local as usize).map(|(_, ty)| *ty).ok_or_else(misplaced)
+        };
+        let (takes, gives): (&[Type], Option<Type>) = match chunk.code[pc] {
+            Op::Integer(_) => (&[], Some(Integer)),
+            Op::Real(_) => (&[], Some(Real)),
+            Op::Load(index) => (&[], Some(slot(index))),
+            Op::Store(index) if slot(index) == Integer => (&[Integer], None),
+            Op::Store(_) => (&[Real], None),
+            Op::LoadLocal { up, local: index } => (&[], Some(local(up, index)?)),
+            Op::StoreLocal { up, local: index } if local(up, index)? == Integer => (&[Integer], None),
+            Op::StoreLocal { .. } => (&[Real], None),
+            Op::Call { routine: callee, up } => {
+                let callee = chunk.routines.get(callee as usize).ok_or_else(misplaced)?;
+                if frame(up) != callee.parent.map(|parent| parent as usize) {
+                    return Err(misplaced());
+                }
+                let params = callee.locals[..callee.params as usize].iter().map(|(_, ty)| *ty).collect::<Vec<_>>();
+                if stack.len() < params.len() || stack[stack.len() - params.len()..] != *params {
+                    return Err(missing());
+                }
+                stack.truncate(stack.len() - params.len());
+                continue;
+            }
+            Op::Return if routine.is_none() || !stack.is_empty() => return Err(misplaced()),
+            Op::Return => break,
+            Op::Pop | Op::NonZero if stack.is_empty() => return Err(missing()),
+            Op::Pop => {
+                stack.pop();
+                continue;
+            }
+            Op::NonZero => continue,
+            Op::ToReal => (&[Integer], Some(Real)),
+            Op::Truncate | Op::RangeCheck(_) => (&[Real], Some(Integer)),
+            Op::NegInteger { .. } => (&[Integer], Some(Integer)),
+            Op::AddInteger { .. } | Op::SubInteger { .. } | Op::MulInteger { .. } | Op::DivInteger { .. } => {
+                (&[Integer, Integer], Some(Integer))
+            }
+            Op::NegReal => (&[Real], Some(Real)),
+            Op::AddReal | Op::SubReal | Op::MulReal | Op::DivReal => (&[Real, Real], Some(Real)),
+            Op::NotFound(_)
+            | Op::NotAVariable(_)
+            | Op::NotAssignable(_)
+            | Op::NotFoundToCall(_)
+            | Op::NotAProcedure(_)
+            | Op::NoValue(_)
+            | Op::RealDivOperand => break,
+            Op::Host { .. } => unreachable!("images hold no calls to host functions"),
+        };
+        if stack.len() < takes.len() || stack[stack.len() - takes.len()..] != *takes {
+            return Err(missing());
+        }
+        stack.truncate(stack.len() - takes.len());
+        stack.extend(gives);
+    }
+    Ok(())
+}
+
+/// CRC-32 as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+fn compiled(source: &str) -> Image {
+    use crate::utils::parser::Parser;
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let sources = Arc::new(SourceMap::new());
+    sources.add("p.pa", source.as_bytes().to_vec());
+    Image { chunk: super::compile(&program, &[]), sources }
+}
+
+#[test]
+fn reads_back_what_it_writes() {
+    let image = compiled("PROGRAM p; VAR i : INTEGER; r : REAL; PROCEDURE P; BEGIN END;
+        PROCEDURE Q(a : REAL); VAR k : INTEGER; PROCEDURE R; BEGIN k := a END; BEGIN R() END;
+        BEGIN {$Q+} i := -(2 + 3) * 4 DIV 2; {$R+} r := 0.1 / 3; i := r; k := i; Q(i); P() END.");
+    let read = Image::from_bytes(&image.to_bytes()).unwrap();
+    assert_eq!(read.chunk, image.chunk);
+    assert_eq!(read.sources.len(), 1);
+    assert_eq!(read.sources.file(0).name, "p.pa");
+    assert_eq!(read.sources.file(0).bytes, image.sources.file(0).bytes);
+
+    use crate::utils::parser::Parser;
+    use crate::utils::preprocessor::Preprocessor;
+    let sources = Arc::new(SourceMap::new());
+    let main = sources.add("p.pa", b"PROGRAM p; USES u; BEGIN END.".to_vec());
+    let unit = sources.add("u.pa", b"UNIT u; INTERFACE VAR x : INTEGER; IMPLEMENTATION BEGIN x := 1 END.".to_vec());
+    let parse = |file| Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file)).unwrap();
+    let program = parse(main).parse().unwrap();
+    let units = [parse(unit).parse_unit().unwrap()];
+    let image = Image { chunk: super::compile(&program, &units), sources: sources.clone() };
+    let read = Image::from_bytes(&image.to_bytes()).unwrap();
+    assert_eq!(read.chunk, image.chunk);
+    assert_eq!(read.chunk.initializations[0].used_at[0].span, program.uses[0].span);
+}
+
+#[test]
+fn rejects_damaged_files() {
+    let bytes = compiled("PROGRAM p; VAR i : INTEGER; BEGIN i := 1 + 2 END.").to_bytes();
+    let error = |bytes: &[u8]| Image::from_bytes(bytes).unwrap_err().message().to_string();
+
+    for len in 1..bytes.len() {
+        assert!(error(&bytes[..len]).starts_with("Bytecode file is truncated"), "{}", len);
+    }
+    assert_eq!(error(b""), "Not a bytecode file.");
+    assert_eq!(error(b"PROGRAM p;"), "Not a bytecode file.");
+
+    let mut newer = bytes.clone();
+    newer[4] = 5;
+    assert_eq!(error(&newer), "Bytecode format 5 is not supported, this is format 4.");
+
+    for at in HEADER_LEN..bytes.len() {
+        let mut flipped = bytes.clone();
+        flipped[at] ^= 0x10;
+        assert_eq!(error(&flipped), "Bytecode file is corrupt: its checksum does not match.", "{}", at);
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_eq!(error(&longer), "Bytecode file is corrupt: 1 extra bytes follow the payload.");
+}
+
+#[test]
+fn rejects_spans_outside_of_lines() {
+    for (line_no, column) in [(0, 1), (1, 0)] {
+        let mut damaged = compiled("PROGRAM p; VAR i : INTEGER; BEGIN i := 1 END.");
+        damaged.chunk.spans[0].line_no = line_no;
+        damaged.chunk.spans[0].column = column;
+        let error = Image::from_bytes(&damaged.to_bytes()).unwrap_err();
+        assert_eq!(error.message(), "Bytecode file is corrupt: an instruction points before the start of a line.");
+    }
+}
+
+#[test]
+fn rejects_code_that_would_misuse_the_stack() {
+    let mut image = compiled("PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN i := 1 END.");
+    let valid = image.chunk.clone();
+    for code in [vec![Op::AddInteger { checked: false }], vec![Op::Real(1.0), Op::Store(0)], vec![Op::Pop]] {
+        image.chunk.spans = vec![image.chunk.spans[0]; code.len()];
+        image.chunk.code = code;
+        let error = Image::from_bytes(&image.to_bytes()).unwrap_err();
+        assert!(error.message().ends_with("does not find its operands."), "{}", error.message());
+    }
+    image.chunk = valid.clone();
+    image.chunk.code.insert(0, Op::NotFoundToCall(0));
+    image.chunk.code.push(Op::Pop);
+    image.chunk.spans.extend([image.chunk.spans[0]; 2]);
+    image.chunk.names.push("P".to_string());
+    assert!(Image::from_bytes(&image.to_bytes()).is_ok());
+
+    let mut image = compiled("PROGRAM p; VAR i : INTEGER; PROCEDURE P(a : INTEGER); BEGIN i := a END; BEGIN P(1) END.");
+    let valid = image.chunk.clone();
+    let damages: [fn(&mut Chunk); 4] = [
+        |chunk| chunk.code[0] = Op::Real(1.0),
+        |chunk| chunk.code[1] = Op::Call { routine: 1, up: 0 },
+        |chunk| chunk.code[2] = Op::LoadLocal { up: 0, local: 1 },
+        |chunk| chunk.code[1] = Op::Return,
+    ];
+    for damage in damages {
+        image.chunk = valid.clone();
+        damage(&mut image.chunk);
+        assert!(Image::from_bytes(&image.to_bytes()).is_err(), "{:?}", image.chunk.code);
+    }
+}
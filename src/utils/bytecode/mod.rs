@@ -0,0 +1,1065 @@
+//! Compiles a program to bytecode for the `vm`.
+//!
+//! Every variable gets a numbered slot, and names are resolved once, here,
+//! to the slot they stand for where they are written. Statements run one
+//! after another, with no jumps, so what a name stands for at each point is
+//! known without running anything: a procedure declared among the
+//! statements hides a variable from there on, as it does when the tree is
+//! walked. A name that would fail to resolve compiles to an instruction
+//! raising the error the interpreter would.
+//!
+//! A procedure is compiled once, where it is declared, to a routine of its
+//! own. Its body sees the names around it as they are there, procedures
+//! declared among the statements after it aside, so names in it resolve
+//! once too. Its parameters and variables are locals in a frame of its own,
+//! made by each call, which links to the frame of the procedure it is
+//! declared in.
+//!
+//! Types are known too, so arithmetic is compiled to INTEGER or REAL
+//! instructions and INTEGER operands of REAL arithmetic are converted where
+//! they are computed. Calls to host functions are checked against their
+//! parameters here, and fail to compile when they do not match.
+
+pub mod disasm;
+pub mod image;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use super::ast::decl::{Decl, Type, VarDecl};
+use super::ast::expr::{Expr, Operation, UnaryOp};
+use super::ast::ident::Ident;
+use super::ast::proc::Procedure;
+use super::ast::program::Program;
+use super::ast::stmt::{Checks, Compound, Stmt};
+use super::ast::unit::Unit;
+use super::err::diagnostic::{Diagnostic, Diagnostics};
+use super::host::{self, Host, HostFunction};
+use super::interpreter::{self, Caller};
+use super::lexer::{Operators, Span};
+use super::limits::{self, DEFAULT_CODE};
+
+/// One instruction. Operands are popped from the stack and results pushed
+/// onto it. `checked` marks INTEGER arithmetic that fails on overflow
+/// instead of wrapping around.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Integer(i64),
+    Real(f64),
+    /// Pushes the value in a slot.
+    Load(u32),
+    /// Pops a value into a slot.
+    Store(u32),
+    Pop,
+    /// Converts the INTEGER on top of the stack to REAL.
+    ToReal,
+    /// Converts the REAL on top of the stack to INTEGER, truncating it.
+    Truncate,
+    /// Like `Truncate`, but fails unless the REAL is a whole number that
+    /// fits. The operand names the variable it is assigned to.
+    RangeCheck(u32),
+    /// Fails if the number on top of the stack, a divisor, is zero.
+    NonZero,
+    NegInteger { checked: bool },
+    AddInteger { checked: bool },
+    SubInteger { checked: bool },
+    MulInteger { checked: bool },
+    DivInteger { checked: bool },
+    NegReal,
+    AddReal,
+    SubReal,
+    MulReal,
+    DivReal,
+    /// Fails: the name the operand names, read or assigned to, is not
+    /// declared.
+    NotFound(u32),
+    /// Fails: the procedure the operand names is read as a variable.
+    NotAVariable(u32),
+    /// Fails: the procedure the operand names is assigned to.
+    NotAssignable(u32),
+    /// Pushes local `local` of the frame `up` frames out from that of the
+    /// routine running. Following the link of a frame, to the frame of the
+    /// procedure it is declared in, goes one frame out.
+    LoadLocal { up: u32, local: u32 },
+    /// Pops a value into a local, as `LoadLocal` finds it.
+    StoreLocal { up: u32, local: u32 },
+    /// Calls `routine`, with its arguments on top of the stack, the first
+    /// deepest. Its frame links to the frame `up` frames out from the
+    /// caller's, or to none when that is past the outermost.
+    Call { routine: u32, up: u32 },
+    /// Returns from the routine running to the instruction after its call.
+    Return,
+    /// Fails: the procedure the operand names is not declared.
+    NotFoundToCall(u32),
+    /// Fails: the variable the operand names is called.
+    NotAProcedure(u32),
+    /// Fails: the procedure the operand names is called for a value.
+    NoValue(u32),
+    /// Fails: a REAL operand of DIV.
+    RealDivOperand,
+    /// Calls the host function with index `function`, which `name` names,
+    /// with its `args` arguments on top of the stack, the first deepest.
+    /// Pushes its result if it is a function.
+    Host { name: u32, function: u32, args: u32 },
+}
+
+/// What a name declared at the top level of a program or unit stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    Variable { ty: Type, slot: u32 },
+    Procedure,
+}
+
+/// A compiled program and the units it uses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The source each instruction was compiled from, for its errors.
+    pub spans: Vec<Span>,
+    /// The names instructions refer to by index.
+    pub names: Vec<String>,
+    /// The variable in each slot and its type. Slots start out as zero. The
+    /// names of variables in units are qualified, as in `Unit.name`.
+    pub slots: Vec<(String, Type)>,
+    /// The program's globals as they are once it has run, by name as
+    /// declared, sorted regardless of case.
+    pub globals: Vec<(String, Symbol)>,
+    /// The code initializing each unit that has an initialization part.
+    pub initializations: Vec<Initialization>,
+    /// The routines procedures are compiled to, whose code follows that of
+    /// the top level.
+    pub routines: Vec<Routine>,
+}
+
+/// The code of a procedure.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Routine {
+    /// The name of the procedure, as declared.
+    pub name: String,
+    /// Where it is in `Chunk::code`. It ends with `Op::Return`.
+    pub code: Range<usize>,
+    /// The parameters of the procedure, then its variables, with their
+    /// types. Parameters start out as the arguments, variables as zero.
+    pub locals: Vec<(String, Type)>,
+    /// How many of `locals` are parameters.
+    pub params: u32,
+    /// The routine of the procedure it is declared in, whose frame it
+    /// links to, or `None` for one declared at the top level.
+    pub parent: Option<u32>,
+}
+
+/// The code initializing a unit, for tracing errors raised in it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Initialization {
+    /// Where it is in `Chunk::code`.
+    pub code: Range<usize>,
+    /// What led to the unit being initialized, as `units::used_at` gives it.
+    pub used_at: Vec<Ident>,
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op, span: Span) {
+        self.code.push(op);
+        self.spans.push(span);
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let index = self.names.iter().position(|known| known == name).unwrap_or_else(|| {
+            self.names.push(name.to_string());
+            self.names.len() - 1
+        });
+        index as u32
+    }
+
+    /// The code run at the top level: the initializations of the units, then
+    /// the body of the program.
+    pub fn top_level(&self) -> Range<usize> {
+        0..self.routines.first().map_or(self.code.len(), |routine| routine.code.start)
+    }
+
+    /// `error`, raised by the instruction at `pc` in the routines called by
+    /// the instructions at `calls`, the outermost call first. It gets notes
+    /// on those calls, the innermost first, then on the units whose
+    /// initialization led to the outermost.
+    pub fn trace(&self, pc: usize, calls: &[usize], mut error: Diagnostic) -> Diagnostic {
+        for &call in calls.iter().rev() {
+            let Op::Call { routine, .. } = self.code[call] else {
+                panic!("instruction {} is not a call", call);
+            };
+            let procedure = &self.routines[routine as usize].name;
+            error = interpreter::called(error, procedure, &self.caller(call), self.spans[call]);
+        }
+        let outermost = calls.first().copied().unwrap_or(pc);
+        match self.initializations.iter().find(|initialization| initialization.code.contains(&outermost)) {
+            Some(initialization) => super::units::initializing(error, &initialization.used_at),
+            None => error,
+        }
+    }
+
+    /// What the code at `pc` is, as the caller of a procedure.
+    fn caller(&self, pc: usize) -> Caller {
+        if let Some(routine) = self.routines.iter().find(|routine| routine.code.contains(&pc)) {
+            return Caller::Procedure(routine.name.clone());
+        }
+        match self.initializations.iter().find(|initialization| initialization.code.contains(&pc)) {
+            Some(initialization) => {
+                Caller::Unit(initialization.used_at.last().expect("a unit is used by something").name.clone())
+            }
+            None => Caller::Program,
+        }
+    }
+}
+
+/// Compiles the initialization of `units`, in order, followed by the body
+/// of `program`. `units` are as `units::load` returns them.
+///
+/// # Panics
+///
+/// If a unit used by the program or by another unit is missing, or if the
+/// program does not compile, as `compile_with_host` tells.
+pub fn compile(program: &Program, units: &[Unit]) -> Chunk {
+    compile_with_host(program, units, &Host::new()).expect("the program compiles")
+}
+
+/// Like `compile`, for a program that may call the functions of `host`.
+/// Fails if it calls a procedure with the wrong number of arguments, calls
+/// a function of the host with the wrong arguments or uses a procedure of
+/// the host as a value, or if it compiles to more than `DEFAULT_CODE`
+/// instructions.
+pub fn compile_with_host(program: &Program, units: &[Unit], host: &Host) -> Result<Chunk, Diagnostics> {
+    compile_with_limit(program, units, host, DEFAULT_CODE)
+}
+
+/// Like `compile_with_host`, failing once the program compiles to more than
+/// `code` instructions.
+pub fn compile_with_limit(program: &Program, units: &[Unit], host: &Host, code: usize) -> Result<Chunk, Diagnostics> {
+    let mut compiler = Compiler {
+        chunk: Chunk::default(),
+        names: Names::new(program, units, host),
+        errors: Diagnostics::new(),
+        routines: Vec::new(),
+        compiled: 0,
+        max_code: code,
+        full: false,
+    };
+    let used_at = super::units::used_at(&program.uses, units);
+    for ((current, unit), used_at) in units.iter().enumerate().zip(used_at) {
+        compiler.names.enter(Some(current));
+        compiler.declared_routines();
+        if let Some(initialization) = &unit.initialization {
+            let start = compiler.chunk.code.len();
+            compiler.compound(initialization);
+            let code = start..compiler.chunk.code.len();
+            compiler.chunk.initializations.push(Initialization { code, used_at });
+        }
+    }
+    compiler.names.enter(None);
+    compiler.declared_routines();
+    compiler.compound(&program.block.body);
+
+    let Compiler { mut chunk, names, errors, routines, .. } = compiler;
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    chunk.globals = names.globals();
+    chunk.slots = names.slots;
+    chunk.routines = names.routines;
+    for (routine, (code, spans)) in chunk.routines.iter_mut().zip(routines) {
+        routine.code = chunk.code.len()..chunk.code.len() + code.len();
+        chunk.code.extend(code);
+        chunk.spans.extend(spans);
+    }
+    Ok(chunk)
+}
+
+/// Names declared in a program, unit or frame, keyed by `ident::key`, with
+/// the name as declared.
+type Scope<'a> = HashMap<String, (String, Entry<'a>)>;
+
+/// What a name in a `Scope` stands for.
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    /// A variable in a slot, or a parameter or variable among the locals of
+    /// a frame.
+    Variable { ty: Type, index: u32 },
+    Procedure { procedure: &'a Procedure, routine: u32 },
+}
+
+/// What a name stands for in the code being compiled.
+#[derive(Debug, Clone, Copy)]
+pub enum Resolved<'a> {
+    /// A variable of a program or unit.
+    Global { ty: Type, slot: u32 },
+    /// A parameter or variable of a procedure, as `Op::LoadLocal` finds it.
+    Local { ty: Type, up: u32, local: u32 },
+    /// A procedure compiled to `routine`, whose frame links to the one `up`
+    /// frames out, as in `Op::Call`.
+    Procedure { procedure: &'a Procedure, routine: u32, up: u32 },
+}
+
+/// The procedures declared among the declarations of a scope or frame, with
+/// their routines.
+type Declared<'a> = Vec<(u32, &'a Procedure)>;
+
+/// The frame of a routine around the code being compiled.
+struct Frame<'a> {
+    routine: u32,
+    scope: Scope<'a>,
+    declared: Declared<'a>,
+}
+
+/// What each name stands for in a program and the units it uses, at the
+/// point the code being compiled has reached. Backends that work from the
+/// tree resolve names with it too.
+pub struct Names<'a> {
+    /// The variable in each slot and its type, as in `Chunk::slots`.
+    pub slots: Vec<(String, Type)>,
+    /// The routines found so far, as in `Chunk::routines` but with no code.
+    pub routines: Vec<Routine>,
+    /// The scope of each unit, then that of the program.
+    scopes: Vec<Scope<'a>>,
+    /// The procedures among the declarations of each scope.
+    declared: Vec<Declared<'a>>,
+    /// Keys of the names each unit exports.
+    exports: Vec<HashSet<String>>,
+    /// Indexes of the units each unit, then the program, uses.
+    uses: Vec<Vec<usize>>,
+    /// Index into `scopes` of the code being compiled.
+    current: usize,
+    /// The functions of the host, for names nothing else declares.
+    host: Host,
+    /// The frames around the code being compiled, the outermost first: those
+    /// of the procedures its own is declared in, then its own.
+    frames: Vec<Frame<'a>>,
+}
+
+impl<'a> Names<'a> {
+    /// The names of `program` and `units`, and the functions of `host`,
+    /// with the program's code being compiled.
+    ///
+    /// # Panics
+    ///
+    /// If a unit used by the program or by another unit is missing.
+    pub fn new(program: &'a Program, units: &'a [Unit], host: &Host) -> Self {
+        let index = |name: &Ident| {
+            units
+                .iter()
+                .position(|unit| unit.name == *name)
+                .unwrap_or_else(|| panic!("unit `{}` is used but was not loaded", name.name))
+        };
+        let mut names = Self {
+            slots: Vec::new(),
+            routines: Vec::new(),
+            scopes: Vec::new(),
+            declared: Vec::new(),
+            exports: Vec::new(),
+            uses: Vec::new(),
+            current: units.len(),
+            host: host.clone(),
+            frames: Vec::new(),
+        };
+        for unit in units {
+            let mut scope = Scope::new();
+            for var in &unit.interface.vars {
+                names.declare_var(&mut scope, var, Some(&unit.name));
+            }
+            let declared = names.declare(&mut scope, &unit.implementation.declarations, Some(&unit.name));
+            names.scopes.push(scope);
+            names.declared.push(declared);
+            let interface = &unit.interface;
+            names.exports.push(
+                interface.vars
+                    .iter()
+                    .flat_map(|var| &var.names)
+                    .chain(interface.procedures.iter().map(|heading| &heading.name))
+                    .map(Ident::key)
+                    .collect(),
+            );
+            names.uses.push(interface.uses.iter().chain(&unit.implementation.uses).map(index).collect());
+        }
+        let mut scope = Scope::new();
+        let declared = names.declare(&mut scope, &program.block.declarations, None);
+        names.scopes.push(scope);
+        names.declared.push(declared);
+        names.exports.push(HashSet::new());
+        names.uses.push(program.uses.iter().map(index).collect());
+        names
+    }
+
+    /// Declares `declarations` in `scope`, that of `unit` or of the program,
+    /// and returns the procedures among them.
+    fn declare(&mut self, scope: &mut Scope<'a>, declarations: &'a [Decl], unit: Option<&Ident>) -> Declared<'a> {
+        let mut declared = Vec::new();
+        for declaration in declarations {
+            match declaration {
+                Decl::Var(var) => self.declare_var(scope, var, unit),
+                Decl::Procedure(procedure) => declared.push(self.declare_routine(scope, procedure, None)),
+            }
+        }
+        declared
+    }
+
+    /// Declares `procedure` in `scope`, that of the routine `parent` or of a
+    /// program or unit for `None`, with a new routine for it.
+    fn declare_routine(
+        &mut self,
+        scope: &mut Scope<'a>,
+        procedure: &'a Procedure,
+        parent: Option<u32>,
+    ) -> (u32, &'a Procedure) {
+        let routine = self.routines.len() as u32;
+        let mut locals = procedure.params
+            .iter()
+            .flat_map(|param| param.names.iter().map(|name| (name.name.clone(), param.ty.ty)))
+            .collect::<Vec<_>>();
+        let params = locals.len() as u32;
+        for declaration in &procedure.block.declarations {
+            if let Decl::Var(var) = declaration {
+                locals.extend(var.names.iter().map(|name| (name.name.clone(), var.ty.ty)));
+            }
+        }
+        let name = procedure.name.name.clone();
+        self.routines.push(Routine { name, code: 0..0, locals, params, parent });
+        let entry = Entry::Procedure { procedure, routine };
+        scope.insert(procedure.name.key(), (procedure.name.name.clone(), entry));
+        (routine, procedure)
+    }
+
+    /// Declares the variables of `var` in `scope`, each in a slot of its own.
+    fn declare_var(&mut self, scope: &mut Scope<'a>, var: &VarDecl, unit: Option<&Ident>) {
+        for name in &var.names {
+            let index = self.slots.len() as u32;
+            let qualified = match unit {
+                Some(unit) => format!("{}.{}", unit.name, name.name),
+                None => name.name.clone(),
+            };
+            self.slots.push((qualified, var.ty.ty));
+            scope.insert(name.key(), (name.name.clone(), Entry::Variable { ty: var.ty.ty, index }));
+        }
+    }
+
+    /// Moves on to the code of the unit with index `unit`, or of the
+    /// program for `None`.
+    pub fn enter(&mut self, unit: Option<usize>) {
+        self.current = unit.unwrap_or(self.scopes.len() - 1);
+    }
+
+    /// The unit whose code is being compiled, or `None` for the program.
+    pub fn unit(&self) -> Option<usize> {
+        Some(self.current).filter(|&current| current + 1 < self.scopes.len())
+    }
+
+    /// The routine whose body is being compiled, or `None` at the top level.
+    pub fn routine_compiled(&self) -> Option<u32> {
+        self.frames.last().map(|frame| frame.routine)
+    }
+
+    /// The procedures declared among the declarations of the scope or frame
+    /// being compiled, with their routines. Their bodies are compiled there,
+    /// before the code that may call them.
+    pub fn declared(&self) -> Declared<'a> {
+        match self.frames.last() {
+            Some(frame) => frame.declared.clone(),
+            None => self.declared[self.current].clone(),
+        }
+    }
+
+    /// Declares a procedure met among the statements of the code being
+    /// compiled, in its frame or at the top level, and returns its routine,
+    /// whose body is compiled there. It hides what its name stood for from
+    /// here on.
+    pub fn declare_procedure(&mut self, procedure: &'a Procedure) -> u32 {
+        let parent = self.routine_compiled();
+        let mut scope = match self.frames.last_mut() {
+            Some(frame) => std::mem::take(&mut frame.scope),
+            None => std::mem::take(&mut self.scopes[self.current]),
+        };
+        let (routine, _) = self.declare_routine(&mut scope, procedure, parent);
+        match self.frames.last_mut() {
+            Some(frame) => frame.scope = scope,
+            None => self.scopes[self.current] = scope,
+        }
+        routine
+    }
+
+    /// Moves on to the body of `procedure`, compiled to `routine`, from
+    /// where it is declared.
+    pub fn enter_procedure(&mut self, routine: u32, procedure: &'a Procedure) {
+        let mut scope = Scope::new();
+        let mut index = 0;
+        let mut local = |scope: &mut Scope<'a>, name: &Ident, ty: Type| {
+            scope.insert(name.key(), (name.name.clone(), Entry::Variable { ty, index }));
+            index += 1;
+        };
+        for param in &procedure.params {
+            for name in &param.names {
+                local(&mut scope, name, param.ty.ty);
+            }
+        }
+        let mut declared = Vec::new();
+        for declaration in &procedure.block.declarations {
+            match declaration {
+                Decl::Var(var) => {
+                    for name in &var.names {
+                        local(&mut scope, name, var.ty.ty);
+                    }
+                }
+                Decl::Procedure(procedure) => declared.push(self.declare_routine(&mut scope, procedure, Some(routine))),
+            }
+        }
+        self.frames.push(Frame { routine, scope, declared });
+    }
+
+    /// Goes back to where the procedure `enter_procedure` moved into is
+    /// declared.
+    pub fn leave_procedure(&mut self) {
+        self.frames.pop();
+    }
+
+    /// What `ident` stands for in the code being compiled, and its name as
+    /// declared: the frames around the code first, the innermost first, then
+    /// its own globals, then what the units it uses export, the unit named
+    /// last first.
+    pub fn resolve(&self, ident: &Ident) -> Option<(String, Resolved<'a>)> {
+        let key = ident.key();
+        let level = self.frames.len();
+        for (index, frame) in self.frames.iter().enumerate().rev() {
+            if let Some((name, entry)) = frame.scope.get(&key) {
+                let up = (level - 1 - index) as u32;
+                let resolved = match *entry {
+                    Entry::Variable { ty, index: local } => Resolved::Local { ty, up, local },
+                    Entry::Procedure { procedure, routine } => Resolved::Procedure { procedure, routine, up },
+                };
+                return Some((name.clone(), resolved));
+            }
+        }
+        let scope = match self.scopes[self.current].contains_key(&key) {
+            true => self.current,
+            false => *self.uses[self.current].iter().rev().find(|&&unit| self.exports[unit].contains(&key))?,
+        };
+        let (name, entry) = &self.scopes[scope][&key];
+        let resolved = match *entry {
+            Entry::Variable { ty, index: slot } => Resolved::Global { ty, slot },
+            Entry::Procedure { procedure, routine } => Resolved::Procedure { procedure, routine, up: level as u32 },
+        };
+        Some((name.clone(), resolved))
+    }
+
+    /// The host function `ident` names in the code being compiled, and its
+    /// index, unless something the code can see has that name.
+    pub fn host(&self, ident: &Ident) -> Option<(u32, HostFunction)> {
+        match self.resolve(ident) {
+            Some(_) => None,
+            None => self.host.find(&ident.name).map(|(index, function)| (index, function.clone())),
+        }
+    }
+
+    /// The type of `expr`, as computed by the code `expr` compiles to.
+    pub fn type_of(&self, expr: &Expr) -> Type {
+        let result = |name: &Ident| self.host(name).and_then(|(_, function)| function.result);
+        match expr {
+            Expr::Real { .. } => Type::Real,
+            Expr::Variable(ident) => match self.resolve(ident) {
+                Some((_, Resolved::Global { ty, .. } | Resolved::Local { ty, .. })) => ty,
+                _ => result(ident).unwrap_or(Type::Integer),
+            },
+            Expr::Call { name, .. } => result(name).unwrap_or(Type::Integer),
+            Expr::Unary { operand, .. } => self.type_of(operand),
+            Expr::Binary { .. } => {
+                let (first, operations) = expr.left_spine();
+                let first = self.type_of(first);
+                operations
+                    .into_iter()
+                    .fold(first, |left, operation| operation_type(operation.op, left, self.type_of(operation.right)))
+            }
+            Expr::Integer { .. } => Type::Integer,
+        }
+    }
+
+    /// The program's globals as the code compiled so far leaves them, as in
+    /// `Chunk::globals`.
+    pub fn globals(&self) -> Vec<(String, Symbol)> {
+        let mut globals = self.scopes.last().into_iter().flatten().collect::<Vec<_>>();
+        globals.sort_by_key(|(key, _)| *key);
+        globals
+            .into_iter()
+            .map(|(_, (name, entry))| {
+                let symbol = match *entry {
+                    Entry::Variable { ty, index } => Symbol::Variable { ty, slot: index },
+                    Entry::Procedure { .. } => Symbol::Procedure,
+                };
+                (name.clone(), symbol)
+            })
+            .collect()
+    }
+}
+
+struct Compiler<'a> {
+    chunk: Chunk,
+    names: Names<'a>,
+    /// Calls to host functions that do not match them.
+    errors: Diagnostics,
+    /// The code of each routine and its spans, once compiled.
+    routines: Vec<(Vec<Op>, Vec<Span>)>,
+    /// How many instructions `routines` holds.
+    compiled: usize,
+    /// How many instructions the program may compile to.
+    max_code: usize,
+    /// Whether the code went past `max_code`, after which no more
+    /// statements are compiled.
+    full: bool,
+}
+
+impl<'a> Compiler<'a> {
+    /// Compiles the procedures declared among the declarations of the code
+    /// being compiled.
+    fn declared_routines(&mut self) {
+        for (routine, procedure) in self.names.declared() {
+            self.routine(routine, procedure);
+        }
+    }
+
+    /// Compiles the body of `procedure`, declared here, to `routine`.
+    fn routine(&mut self, routine: u32, procedure: &'a Procedure) {
+        let outer = (std::mem::take(&mut self.chunk.code), std::mem::take(&mut self.chunk.spans));
+        self.names.enter_procedure(routine, procedure);
+        self.declared_routines();
+        self.compound(&procedure.block.body);
+        self.names.leave_procedure();
+        self.chunk.emit(Op::Return, procedure.block.body.span);
+        let code = std::mem::replace(&mut self.chunk.code, outer.0);
+        let spans = std::mem::replace(&mut self.chunk.spans, outer.1);
+        self.compiled += code.len();
+        self.routines.resize_with(self.names.routines.len(), Default::default);
+        self.routines[routine as usize] = (code, spans);
+        self.check_size(procedure.name.span);
+    }
+
+    /// Fails, once, if the code compiled so far is past the limit, taken
+    /// there by what is at `span`.
+    fn check_size(&mut self, span: Span) {
+        if !self.full && self.compiled + self.chunk.code.len() > self.max_code {
+            self.full = true;
+            self.errors.push(limits::too_much_code(self.max_code, span));
+        }
+    }
+
+    fn compound(&mut self, compound: &'a Compound) {
+        for statement in &compound.statements {
+            if self.full {
+                return;
+            }
+            self.statement(statement);
+            self.check_size(statement.span());
+        }
+    }
+
+    fn statement(&mut self, statement: &'a Stmt) {
+        match statement {
+            Stmt::Compound(compound) => self.compound(compound),
+            Stmt::Assign { target, value, checks, .. } => {
+                let ty = self.expr(value, *checks);
+                let at = value.span();
+                match self.names.resolve(target) {
+                    Some((name, Resolved::Global { ty: to, slot })) => {
+                        self.convert(ty, to, &name, *checks, at);
+                        self.chunk.emit(Op::Store(slot), target.span);
+                    }
+                    Some((name, Resolved::Local { ty: to, up, local })) => {
+                        self.convert(ty, to, &name, *checks, at);
+                        self.chunk.emit(Op::StoreLocal { up, local }, target.span);
+                    }
+                    Some((name, Resolved::Procedure { .. })) => {
+                        let name = self.chunk.name(&name);
+                        self.chunk.emit(Op::NotAssignable(name), target.span);
+                    }
+                    None => {
+                        let name = self.chunk.name(&target.name);
+                        self.chunk.emit(Op::NotFound(name), target.span);
+                    }
+                }
+            }
+            Stmt::Call { name, args, checks, span } => match self.names.host(name) {
+                Some((index, function)) => {
+                    self.host_call(index, &function, args, *checks, *span);
+                    if function.result.is_some() {
+                        self.chunk.emit(Op::Pop, *span);
+                    }
+                }
+                None => match self.names.resolve(name) {
+                    Some((_, Resolved::Procedure { procedure, routine, up })) => {
+                        self.call(procedure, routine, up, args, *checks, *span)
+                    }
+                    _ => self.cannot_call(name, args, *checks),
+                },
+            },
+            Stmt::Procedure(procedure) => {
+                let routine = self.names.declare_procedure(procedure);
+                self.routine(routine, procedure);
+            }
+            Stmt::Empty { .. } => {}
+        }
+    }
+
+    /// Compiles `expr` and returns its type.
+    fn expr(&mut self, expr: &Expr, checks: Checks) -> Type {
+        let checked = checks.overflow;
+        match expr {
+            Expr::Integer { value, span, .. } => {
+                self.chunk.emit(Op::Integer(*value), *span);
+                Type::Integer
+            }
+            Expr::Real { value, span, .. } => {
+                self.chunk.emit(Op::Real(*value), *span);
+                Type::Real
+            }
+            Expr::Variable(ident) => match self.names.resolve(ident) {
+                Some((_, Resolved::Global { ty, slot })) => {
+                    self.chunk.emit(Op::Load(slot), ident.span);
+                    ty
+                }
+                Some((_, Resolved::Local { ty, up, local })) => {
+                    self.chunk.emit(Op::LoadLocal { up, local }, ident.span);
+                    ty
+                }
+                Some((name, Resolved::Procedure { .. })) => {
+                    let name = self.chunk.name(&name);
+                    self.chunk.emit(Op::NotAVariable(name), ident.span);
+                    Type::Integer
+                }
+                None => match self.names.host(ident) {
+                    Some((index, function)) => self.host_value(index, &function, &[], checks, ident.span),
+                    None => {
+                        let name = self.chunk.name(&ident.name);
+                        self.chunk.emit(Op::NotFound(name), ident.span);
+                        Type::Integer
+                    }
+                },
+            },
+            Expr::Call { name, args, span } => match self.names.host(name) {
+                Some((index, function)) => self.host_value(index, &function, args, checks, *span),
+                None => {
+                    self.cannot_call(name, args, checks);
+                    Type::Integer
+                }
+            },
+            Expr::Unary { op, operand, span } => {
+                let ty = self.expr(operand, checks);
+                match (op, ty) {
+                    (UnaryOp::Plus, _) => {}
+                    (UnaryOp::Minus, Type::Integer) => self.chunk.emit(Op::NegInteger { checked }, *span),
+                    (UnaryOp::Minus, Type::Real) => self.chunk.emit(Op::NegReal, *span),
+                }
+                ty
+            }
+            Expr::Binary { .. } => {
+                let (first, operations) = expr.left_spine();
+                let mut left = (self.expr(first, checks), first.span());
+                for operation in operations {
+                    left = (self.operation(left, operation, checks), operation.span);
+                }
+                left.0
+            }
+        }
+    }
+
+    /// Compiles `operation`, applied to an operand of the type and span
+    /// `left` compiled just before, and returns its type.
+    fn operation(&mut self, left: (Type, Span), operation: Operation, checks: Checks) -> Type {
+        let checked = checks.overflow;
+        let Operation { op, right, span } = operation;
+        let right_type = self.names.type_of(right);
+        let ty = operation_type(op, left.0, right_type);
+        let real = ty == Type::Real || *op == Operators::FDIVISION;
+        if left.0 == Type::Integer && real {
+            self.chunk.emit(Op::ToReal, left.1);
+        }
+        if self.expr(right, checks) == Type::Integer && real {
+            self.chunk.emit(Op::ToReal, right.span());
+        }
+        let integers = !real && left.0 == Type::Integer && right_type == Type::Integer;
+        let op = match op {
+            Operators::FDIVISION => {
+                self.chunk.emit(Op::NonZero, right.span());
+                Op::DivReal
+            }
+            Operators::IDIVISION if integers => {
+                self.chunk.emit(Op::NonZero, right.span());
+                Op::DivInteger { checked }
+            }
+            Operators::IDIVISION => Op::RealDivOperand,
+            Operators::PLUS if real => Op::AddReal,
+            Operators::MINUS if real => Op::SubReal,
+            Operators::MULTIPLICATION if real => Op::MulReal,
+            Operators::PLUS => Op::AddInteger { checked },
+            Operators::MINUS => Op::SubInteger { checked },
+            _ => Op::MulInteger { checked },
+        };
+        self.chunk.emit(op, span);
+        ty
+    }
+
+    /// Converts a value of type `ty`, computed by the expression at `at`, to
+    /// the type `to` of the variable or parameter `name` it is assigned to.
+    fn convert(&mut self, ty: Type, to: Type, name: &str, checks: Checks, at: Span) {
+        match (ty, to) {
+            (Type::Integer, Type::Real) => self.chunk.emit(Op::ToReal, at),
+            (Type::Real, Type::Integer) if checks.range => {
+                let name = self.chunk.name(name);
+                self.chunk.emit(Op::RangeCheck(name), at);
+            }
+            (Type::Real, Type::Integer) => self.chunk.emit(Op::Truncate, at),
+            _ => {}
+        }
+    }
+
+    /// Compiles a call to `procedure`, compiled to `routine`, whose frame
+    /// links to the one `up` frames out.
+    fn call(
+        &mut self,
+        procedure: &'a Procedure,
+        routine: u32,
+        up: u32,
+        args: &[Expr],
+        checks: Checks,
+        span: Span,
+    ) {
+        let params = procedure.params
+            .iter()
+            .flat_map(|param| param.names.iter().map(move |name| (name, param.ty.ty)))
+            .collect::<Vec<_>>();
+        if args.len() != params.len() {
+            self.errors.push(host::wrong_argument_count(&procedure.name.name, params.len(), args.len(), span));
+        }
+        for (arg, (param, to)) in args.iter().zip(params) {
+            let ty = self.expr(arg, checks);
+            self.convert(ty, to, &param.name, checks, arg.span());
+        }
+        self.chunk.emit(Op::Call { routine, up }, span);
+    }
+
+    /// Compiles a call to something that is no procedure, or that gives no
+    /// value where one is used: its arguments are computed, then it fails.
+    fn cannot_call(&mut self, name: &Ident, args: &[Expr], checks: Checks) {
+        for arg in args {
+            self.expr(arg, checks);
+            self.chunk.emit(Op::Pop, arg.span());
+        }
+        let op = match self.names.resolve(name) {
+            Some((declared, Resolved::Procedure { .. })) => Op::NoValue(self.chunk.name(&declared)),
+            Some((declared, _)) => Op::NotAProcedure(self.chunk.name(&declared)),
+            None => Op::NotFoundToCall(self.chunk.name(&name.name)),
+        };
+        self.chunk.emit(op, name.span);
+    }
+
+    /// Compiles a call to the host function with `index`, whose arguments
+    /// are converted to the types of its parameters.
+    fn host_call(&mut self, index: u32, function: &HostFunction, args: &[Expr], checks: Checks, span: Span) {
+        if args.len() != function.params.len() {
+            self.errors.push(host::wrong_argument_count(&function.name, function.params.len(), args.len(), span));
+        }
+        for (arg, param) in args.iter().zip(&function.params) {
+            match (self.expr(arg, checks), param) {
+                (Type::Integer, Type::Real) => self.chunk.emit(Op::ToReal, arg.span()),
+                (Type::Real, Type::Integer) => self.errors.push(host::real_argument(function, arg.span())),
+                _ => {}
+            }
+        }
+        let name = self.chunk.name(&function.name);
+        self.chunk.emit(Op::Host { name, function: index, args: args.len() as u32 }, span);
+    }
+
+    /// Like `host_call`, for a call whose value is used. Returns its type.
+    fn host_value(&mut self, index: u32, function: &HostFunction, args: &[Expr], checks: Checks, span: Span) -> Type {
+        if function.result.is_none() {
+            self.errors.push(host::no_value(&function.name, span));
+        }
+        self.host_call(index, function, args, checks, span);
+        function.result.unwrap_or(Type::Integer)
+    }
+}
+
+/// The type of `left op right`.
+pub fn operation_type(op: &Operators, left: Type, right: Type) -> Type {
+    match (op, left, right) {
+        (Operators::FDIVISION, _, _) => Type::Real,
+        (Operators::IDIVISION, _, _) => Type::Integer,
+        (_, Type::Integer, Type::Integer) => Type::Integer,
+        _ => Type::Real,
+    }
+}
+
+#[test]
+fn resolves_names_to_slots_and_converts_operands() {
+    use super::parser::Parser;
+    let source = "PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN r := i + 1.5; {$R+} i := r / 2; P(i); k := 1 END.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let chunk = compile(&program, &[]);
+    assert_eq!(
+        chunk.code,
+        [
+            Op::Load(0),
+            Op::ToReal,
+            Op::Real(1.5),
+            Op::AddReal,
+            Op::Store(1),
+            Op::Load(1),
+            Op::Integer(2),
+            Op::ToReal,
+            Op::NonZero,
+            Op::DivReal,
+            Op::RangeCheck(0),
+            Op::Store(0),
+            Op::Load(0),
+            Op::Pop,
+            Op::NotFoundToCall(1),
+            Op::Integer(1),
+            Op::NotFound(2),
+        ]
+    );
+    assert_eq!(chunk.names, ["i", "P", "k"]);
+    assert_eq!(chunk.slots, [("i".to_string(), Type::Integer), ("r".to_string(), Type::Real)]);
+    assert_eq!(chunk.spans.len(), chunk.code.len());
+}
+
+#[test]
+fn checks_calls_to_the_host() {
+    use super::interpreter::Value;
+    use super::parser::Parser;
+
+    let mut host = Host::new();
+    host.procedure("Log", &[Type::Integer, Type::Real], |_| Ok(()))
+        .function("Half", &[Type::Real], Type::Real, |args| Ok(Value::Real(args[0].as_real() / 2.0)))
+        .function("Twice", &[Type::Integer], Type::Integer, |args| Ok(Value::Integer(args[0].as_real() as i64 * 2)));
+    let compile = |body: &str| {
+        let source = format!("PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN {} END.", body);
+        let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+        compile_with_host(&program, &[], &host)
+    };
+    let chunk = compile("Log(i, i); r := Half(1) + Twice(i)").unwrap();
+    assert_eq!(
+        chunk.code,
+        [
+            Op::Load(0),
+            Op::Load(0),
+            Op::ToReal,
+            Op::Host { name: 0, function: 0, args: 2 },
+            Op::Integer(1),
+            Op::ToReal,
+            Op::Host { name: 1, function: 1, args: 1 },
+            Op::Load(0),
+            Op::Host { name: 2, function: 2, args: 1 },
+            Op::ToReal,
+            Op::AddReal,
+            Op::Store(1),
+        ]
+    );
+    let errors = [
+        ("Log(1)", "`Log` takes 2 arguments, but 1 was given."),
+        ("i := Twice(r)", "`Twice` takes an INTEGER here, not a REAL."),
+        ("i := Log(1, 2)", "`Log` is a procedure, it gives no value."),
+        ("r := Half", "`Half` takes 1 argument, but 0 were given."),
+    ];
+    for (body, message) in errors {
+        let errors = compile(body).unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), [message], "{}", body);
+    }
+}
+
+#[test]
+fn checks_the_arguments_of_calls() {
+    use super::parser::Parser;
+    let errors = |source: &str| {
+        let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+        let errors = compile_with_host(&program, &[], &Host::new()).unwrap_err();
+        errors.iter().map(|e| e.message().to_string()).collect::<Vec<_>>()
+    };
+    // Calls are checked where they are, whether they run or not.
+    let source = "PROGRAM p; PROCEDURE P(a : INTEGER); BEGIN END;
+        PROCEDURE Never; BEGIN P(1, 2) END; BEGIN P(); PROCEDURE Q; BEGIN END; Q(1) END.";
+    assert_eq!(
+        errors(source),
+        [
+            "`P` takes 1 argument, but 2 were given.",
+            "`P` takes 1 argument, but 0 were given.",
+            "`Q` takes 0 arguments, but 1 was given.",
+        ]
+    );
+}
+
+#[test]
+fn compiles_a_routine_for_each_procedure_declared() {
+    use super::parser::Parser;
+    let compile = |source: &str| compile(&Parser::new(source.as_bytes()).unwrap().parse().unwrap(), &[]);
+
+    let source = "PROGRAM p; VAR i : INTEGER; PROCEDURE Show(a : INTEGER); BEGIN i := a END;
+        BEGIN Show(1); Show(2); PROCEDURE i; BEGIN END; Show(3) END.";
+    let chunk = compile(source);
+    assert_eq!(
+        chunk.code,
+        [
+            Op::Integer(1),
+            Op::Call { routine: 0, up: 0 },
+            Op::Integer(2),
+            Op::Call { routine: 0, up: 0 },
+            Op::Integer(3),
+            Op::Call { routine: 0, up: 0 },
+            Op::LoadLocal { up: 0, local: 0 },
+            Op::Store(0),
+            Op::Return,
+            Op::Return,
+        ]
+    );
+    assert_eq!(chunk.top_level(), 0..6);
+    assert_eq!(chunk.routines.iter().map(|routine| routine.code.clone()).collect::<Vec<_>>(), [6..9, 9..10]);
+
+    let source = "PROGRAM p; PROCEDURE Outer(n : INTEGER); VAR m : REAL;
+        PROCEDURE Inner; BEGIN m := n END; BEGIN Inner() END; BEGIN Outer(1) END.";
+    let chunk = compile(source);
+    assert_eq!(
+        chunk.code[2..],
+        [
+            Op::Call { routine: 1, up: 0 },
+            Op::Return,
+            Op::LoadLocal { up: 1, local: 0 },
+            Op::ToReal,
+            Op::StoreLocal { up: 1, local: 1 },
+            Op::Return,
+        ]
+    );
+    let outer = Routine {
+        name: "Outer".to_string(),
+        code: 2..4,
+        locals: vec![("n".to_string(), Type::Integer), ("m".to_string(), Type::Real)],
+        params: 1,
+        parent: None,
+    };
+    assert_eq!(chunk.routines[0], outer);
+    assert_eq!(chunk.routines[1].parent, Some(0));
+}
+
+#[test]
+fn compiles_nested_procedures_to_code_linear_in_their_size() {
+    use super::parser::Parser;
+    // Each procedure calls the one it declares around a procedure of its
+    // own declared among its statements.
+    let nested = |depth: usize| {
+        let mut source = "PROGRAM p;".to_string();
+        for level in 0..depth {
+            source.push_str(&format!(" PROCEDURE P{};", level));
+        }
+        source.push_str(" PROCEDURE Last; BEGIN END;");
+        for level in (0..depth).rev() {
+            source.push_str(&format!(" BEGIN P{0}(); PROCEDURE D; BEGIN END; P{0}() END;", level + 1));
+        }
+        source = source.replace(&format!("P{}()", depth), "Last()");
+        source.push_str(" BEGIN P0() END.");
+        compile(&Parser::new(source.as_bytes()).unwrap().parse().unwrap(), &[])
+    };
+    for depth in [10, 40] {
+        let chunk = nested(depth);
+        assert_eq!(chunk.routines.len(), 2 * depth + 1);
+        assert!(chunk.code.len() <= 5 * depth + 2, "{} ops at depth {}", chunk.code.len(), depth);
+    }
+}
@@ -0,0 +1,183 @@
+//! Programs that every way of running one must agree on, and what checks
+//! an engine or a backend against the tree walker on each of them.
+
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::ast::program::Program;
+use super::ast::unit::Unit;
+use super::interpreter::Interpreter;
+use super::limits::{self, DEFAULT_CALLS, DEFAULT_DEPTH};
+use super::parser::Parser;
+use super::preprocessor::Preprocessor;
+use super::source::SourceMap;
+
+/// Bodies of a program with INTEGERs `i` and `j`, a REAL `r` and a
+/// procedure `P(a : INTEGER)`, each on a line of its own.
+pub const BODIES: &[&str] = &[
+    "i := 2 + 3 * 4; r := i / 4; i := r * 2 + i DIV 3",
+    "i := (0 - 7) DIV 2; r := -i * 1.5 - -r; j := -(i - j)",
+    "i := 7 / 2; r := i; {$R+} j := 10 / 4 * 2",
+    "{$R+} j := 7 / 2",
+    "{$R+} i := 1e19",
+    "{$R+} r := 0.1 + 0.2; i := r * 10",
+    "i := 1e30; j := -1e30; r := 3.99; r := r * 1e300 * 1e10",
+    "r := 1e30; r := -r; r := 1 / 3; r := 0.0000001 * 3; r := 0 * -1.0",
+    "r := 1 / 3; r := 2 - (r - 1) * (r + 1) / -r",
+    "i := 9223372036854775807; i := i + 1; j := i - 1; j := j * 2; i := -i",
+    "{$Q+} i := 9223372036854775807; i := i + 1",
+    "{$Q+} i := 0 - 9223372036854775807 - 1; i := -i",
+    "{$Q+} i := 0 - 9223372036854775807 - 1; i := i DIV -1",
+    "{$Q+} i := 0 - 9223372036854775807 - 1; j := -1; i := j * i",
+    "{$Q+} i := 3037000500; i := i * i",
+    "{$Q+} i := 3037000499; j := i * -i; i := 0 * i",
+    "{$Q+} i := 0 - 9223372036854775807; i := i - 2",
+    "{$Q+} i := 0 - 9223372036854775807; i := i - 2 + (j - 1) * 3",
+    "{$Q+} i := 9223372036854775807; j := i - 1 + 1; i := 0 - i - 1",
+    "i := 0 - 9223372036854775807 - 1; i := i DIV -1",
+    "i := 1; i := i DIV (i - 1)",
+    "r := 1.5 / 0",
+    "r := 1 / (0.5 - 0.5)",
+    "r := 3.0 DIV 2",
+    "r := 3.0 DIV 0",
+    "i := k",
+    "k := 1 + 2",
+    "{$Q+} k := i + 1",
+    "i := P",
+    "P := 1",
+    "r := 1; {$Q+} P(r DIV 0)",
+    "P(1 / 0)",
+    "i := 2; j := i * Max(i, j DIV 1) + 1; i := 3",
+    "i := 1; PROCEDURE i; BEGIN END; j := 2",
+    "i := 1; PROCEDURE i; BEGIN END; j := i",
+    "PROCEDURE Q; BEGIN x := 1 END; BEGIN i := 3; BEGIN j := i * i END END; ;",
+    "P(i + 1); P(1.5); {$R+} P(1.5)",
+    "i := P(1)",
+    "j(1 + 1)",
+    "Z(i DIV 1)",
+    "{$Q+} P(9223372036854775807 + 1)",
+    "PROCEDURE Q(n : INTEGER; x : REAL); VAR m : INTEGER; BEGIN m := n * 2; r := x + m; i := 6 DIV n END;\n\
+     Q(3, 1.5); j := i; Q(0, 1)",
+    "PROCEDURE Q(i : REAL); BEGIN r := i; P(j) END; Q(2); {$Q+} Q(i - 1 + 2)",
+    "PROCEDURE Outer(n : INTEGER); VAR m : INTEGER;\n\
+       PROCEDURE Inner(d : INTEGER); BEGIN m := m + n DIV d END;\n\
+     BEGIN m := 1; Inner(1); i := m; Inner(n - n) END;\n\
+     Outer(4)",
+    "PROCEDURE Q; BEGIN i := i + 1 END; Q(); Q(); PROCEDURE i; BEGIN END; Q()",
+    "PROCEDURE Q; BEGIN PROCEDURE Seven; BEGIN j := 7 END; Seven(); r := j / 2 END; Q(); i := j",
+    "PROCEDURE Q(x : REAL); BEGIN {$R+} i := x * 2 END; Q(1.5); Q(0.25)",
+    "PROCEDURE Q(n : INTEGER); BEGIN i := n; Q(n + 1) END; Q(1)",
+    "PROCEDURE Q; PROCEDURE Deeper(x : REAL); BEGIN r := x; j := j + 1; Q() END; BEGIN Deeper(j / 2) END; Q()",
+];
+
+/// Units, in the order they are initialized, and programs using them.
+pub const WITH_UNITS: &[(&[&str], &str)] = &[
+    (
+        &[BASE, COUNTER],
+        "PROGRAM p; USES Base, Counter; VAR n : INTEGER; r : REAL;\nBEGIN\nn := count + step; r := ratio\nEND.",
+    ),
+    (&[BASE, COUNTER], "PROGRAM p; USES Base, Counter; VAR n : INTEGER;\nBEGIN\nn := secret\nEND."),
+    (&[BASE, COUNTER], "PROGRAM p; USES Base, Counter; VAR n : INTEGER;\nBEGIN\nn := hidden\nEND."),
+    (&[BASE, SHAPES], "PROGRAM p; USES Shapes; VAR n : INTEGER;\nBEGIN\nSquare(4); n := area; Square(step - 3)\nEND."),
+    (&[BROKEN, OUTER], "PROGRAM p; USES Outer; VAR n : INTEGER;\nBEGIN\nn := 1\nEND."),
+    (&[BASE, STUCK], "PROGRAM p; USES Stuck;\nBEGIN\nEND."),
+];
+
+const BASE: &str = "UNIT Base; INTERFACE VAR step : INTEGER;\nIMPLEMENTATION VAR hidden : REAL;\nBEGIN step := 3; hidden := step / 2 END.";
+const COUNTER: &str = "UNIT Counter; INTERFACE USES Base; VAR count : INTEGER; ratio : REAL;
+IMPLEMENTATION VAR secret : INTEGER;
+BEGIN secret := 1; count := step; ratio := count / 4 END.";
+const SHAPES: &str = "UNIT Shapes; INTERFACE USES Base; VAR area : INTEGER; PROCEDURE Square(side : INTEGER);
+IMPLEMENTATION PROCEDURE Square(side : INTEGER); BEGIN area := side * side DIV side END;
+BEGIN Square(step) END.";
+const BROKEN: &str = "UNIT Broken; INTERFACE VAR x : INTEGER; PROCEDURE Divide(by : INTEGER);
+IMPLEMENTATION PROCEDURE Divide(by : INTEGER); BEGIN x := 1 DIV by END;
+BEGIN Divide(1); Divide(x - 1) END.";
+const OUTER: &str = "UNIT Outer; INTERFACE USES Broken;\nIMPLEMENTATION\nBEGIN x := 2 END.";
+const STUCK: &str = "UNIT Stuck; INTERFACE USES Base; VAR z : INTEGER;\nIMPLEMENTATION\nBEGIN z := step DIV z END.";
+
+/// Runs every program of the corpus with `run`, which gets the program,
+/// the units it uses and their sources, and checks that it prints the
+/// global scope as `rusterp scope` does, or fails with the error the tree
+/// walker renders.
+pub fn check(mut run: impl FnMut(&Program, &[Unit], &SourceMap) -> Result<String, String>) {
+    for body in BODIES {
+        let source = format!(
+            "PROGRAM t; VAR i, j : INTEGER; r : REAL; PROCEDURE P(a : INTEGER); BEGIN END;\nBEGIN\n{}\nEND.",
+            body
+        );
+        check_one(&[], &source, &mut run);
+    }
+    for (units, source) in WITH_UNITS {
+        check_one(units, source, &mut run);
+    }
+}
+
+fn check_one(units: &[&str], source: &str, run: &mut impl FnMut(&Program, &[Unit], &SourceMap) -> Result<String, String>) {
+    let sources = Arc::new(SourceMap::new());
+    let parse = |name: String, source: &str| {
+        let file = sources.add(name, source.as_bytes().to_vec());
+        Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file)).unwrap()
+    };
+    let program = parse("t.pa".to_string(), source).parse().unwrap();
+    let units = units
+        .iter()
+        .enumerate()
+        .map(|(i, unit)| parse(format!("unit{}.pa", i + 1), unit).parse_unit().unwrap())
+        .collect::<Vec<_>>();
+    let expected = limits::with_stack(DEFAULT_DEPTH, DEFAULT_CALLS, || {
+        let interpreter = Interpreter::with_units(program.clone(), units.clone());
+        interpreter
+            .interprete()
+            .map(|()| interpreter.globals().iter().map(|(name, binding)| format!("{} : {}\n", name, binding)).collect())
+            .map_err(|e| sources.render(&e.into(), false))
+    });
+    assert_eq!(run(&program, &units, &sources), expected, "{}", source);
+}
+
+/// Writes `files` to a new directory, runs each of `commands` there and
+/// returns what the last one wrote: its stdout, or its stderr if it failed.
+/// The others build what the last one runs, and must succeed. The directory
+/// is removed afterwards, even when they do not.
+pub fn build_and_run(files: &[(&str, &[u8])], commands: &[&[&str]]) -> Result<String, String> {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Removes its directory when dropped, so a failing build step does not leave it behind.
+    struct Dir(std::path::PathBuf);
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    let name = format!("rusterp-{}-{}", std::process::id(), RUNS.fetch_add(1, Ordering::Relaxed));
+    let removed = Dir(std::env::temp_dir().join(name));
+    let dir = &removed.0;
+    for (path, contents) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    let (run, build) = commands.split_last().expect("a command to run");
+    let output = |command: &[&str]| {
+        let program = match command[0] {
+            relative if relative.starts_with("./") => dir.join(relative).into_os_string(),
+            program => program.into(),
+        };
+        Command::new(program)
+            .args(&command[1..])
+            .current_dir(dir)
+            .env_remove("CARGO_TARGET_DIR")
+            .output()
+            .unwrap_or_else(|e| panic!("cannot run `{}`, which this test needs: {}", command[0], e))
+    };
+    for command in build {
+        let built = output(command);
+        let text = files.iter().map(|(path, contents)| format!("// {}\n{}", path, String::from_utf8_lossy(contents))).collect::<String>();
+        assert!(built.status.success(), "{}\n{}", String::from_utf8_lossy(&built.stderr), text);
+    }
+    let ran = output(run);
+    let text = |bytes: Vec<u8>| String::from_utf8(bytes).unwrap();
+    if ran.status.success() { Ok(text(ran.stdout)) } else { Err(text(ran.stderr)) }
+}
@@ -0,0 +1,332 @@
+use std::fmt::{self, Display};
+
+use crate::utils::lexer::Span;
+use crate::utils::limits::Limit;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const GREEN: &str = "\x1b[1;32m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// An error tied to a region of the source, rendered in the style of rustc:
+///
+/// ```text
+/// error: Expected `;` found `END`
+///  --> test.pa:4:4
+///   |
+/// 4 |    END
+///   |    ^^^ expected `;` before this
+///   |
+///   = help: statements are separated by `;`
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    message: String,
+    span: Option<Span>,
+    /// Boxed, as is `help`, to keep errors small enough to return.
+    label: Option<Box<str>>,
+    help: Option<Box<str>>,
+    /// The limit the error is for hitting, if any.
+    limit: Option<Limit>,
+    /// The first of the notes shown after it, each holding the next.
+    note: Option<Box<Diagnostic>>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            span: None,
+            label: None,
+            help: None,
+            limit: None,
+            note: None,
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Text printed next to the carets.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into().into_boxed_str());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into().into_boxed_str());
+        self
+    }
+
+    /// Marks the error as stopping the program for hitting `limit`.
+    pub fn with_limit(mut self, limit: Limit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Adds `note`, shown after the diagnostic and the notes added before
+    /// it, as rustc shows where an error comes from.
+    pub fn with_note(mut self, note: Diagnostic) -> Self {
+        let mut last = &mut self.note;
+        while let Some(next) = last {
+            last = &mut next.note;
+        }
+        *last = Some(Box::new(note));
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn help(&self) -> Option<&str> {
+        self.help.as_deref()
+    }
+
+    pub fn limit(&self) -> Option<Limit> {
+        self.limit
+    }
+
+    /// The notes, in the order they were added.
+    pub fn notes(&self) -> impl Iterator<Item = &Diagnostic> {
+        std::iter::successors(self.note.as_deref(), |note| note.note.as_deref())
+    }
+
+    /// Renders the diagnostic with the offending line of `source` and a caret
+    /// underline. `name` is the file name shown in the ` --> ` line.
+    pub fn render(&self, name: &str, source: &str, color: bool) -> String {
+        self.render_with(color, |_| (name, source))
+    }
+
+    /// Like `render`, followed by the notes: `file` gives the name and text
+    /// of the file a span is in.
+    pub fn render_with<'a>(&self, color: bool, file: impl Fn(Option<Span>) -> (&'a str, &'a str)) -> String {
+        let (name, source) = file(self.span);
+        self.render_as("error", RED, name, source, color) + &self.render_notes_with(color, file)
+    }
+
+    /// The notes alone, as `render_with` renders them after the diagnostic.
+    pub fn render_notes_with<'a>(&self, color: bool, file: impl Fn(Option<Span>) -> (&'a str, &'a str)) -> String {
+        let mut out = String::new();
+        for note in self.notes() {
+            let (name, source) = file(note.span);
+            out.push_str(&note.render_as("note", GREEN, name, source, color));
+        }
+        out
+    }
+
+    /// Renders the diagnostic alone, headed by `level` in `level_color`.
+    fn render_as(&self, level: &str, level_color: &'static str, name: &str, source: &str, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (red, blue, bold, reset) = (paint(level_color), paint(BLUE), paint(BOLD), paint(RESET));
+
+        let mut out = format!("{red}{level}{reset}{bold}: {}{reset}\n", self.message);
+        let span = match self.span {
+            Some(span) => span,
+            None => {
+                if let Some(help) = &self.help {
+                    out.push_str(&format!("  {blue}={reset} {bold}help{reset}: {}\n", help));
+                }
+                return out;
+            }
+        };
+
+        let line = source.lines().nth(span.line_no.saturating_sub(1)).unwrap_or("");
+        let gutter = span.line_no.to_string();
+        let pad = " ".repeat(gutter.len());
+        let indent: String = line
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // Spans that run past the end of the line are cut at the line end.
+        let room = line.chars().count().saturating_sub(span.column.saturating_sub(1)).max(1);
+        let width = source
+            .get(span.start..span.end)
+            .map_or(span.len(), |text| text.chars().count());
+        let carets = "^".repeat(width.clamp(1, room));
+
+        out.push_str(&format!("{pad}{blue}-->{reset} {}:{}:{}\n", name, span.line_no, span.column));
+        out.push_str(&format!("{pad} {blue}|{reset}\n"));
+        out.push_str(&format!("{blue}{gutter} |{reset} {}\n", line));
+        out.push_str(&format!("{pad} {blue}|{reset} {indent}{red}{carets}"));
+        if let Some(label) = &self.label {
+            out.push_str(&format!(" {}", label));
+        }
+        out.push_str(&format!("{reset}\n"));
+        if let Some(help) = &self.help {
+            out.push_str(&format!("{pad} {blue}|{reset}\n"));
+            out.push_str(&format!("{pad} {blue}={reset} {bold}help{reset}: {}\n", help));
+        }
+        out
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f, "{} Position: line_no: {}, column: {}", self.message, span.line_no, span.column
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
+
+/// Every error produced by one pass over a source file, in source order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.0.iter()
+    }
+
+    /// Renders every diagnostic, followed by a summary line when there is
+    /// more than one.
+    pub fn render(&self, name: &str, source: &str, color: bool) -> String {
+        self.render_with(color, |_| (name, source))
+    }
+
+    /// Like `render`, for diagnostics in more than one file: `file` gives
+    /// the name and text of the file a span is in.
+    pub fn render_with<'a>(&self, color: bool, file: impl Fn(Option<Span>) -> (&'a str, &'a str)) -> String {
+        let mut out = self.0
+            .iter()
+            .map(|d| d.render_with(color, &file))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.0.len() > 1 {
+            let summary = Diagnostic::new(format!("aborting due to {} previous errors", self.0.len()));
+            let (name, source) = file(None);
+            out.push('\n');
+            out.push_str(&summary.render(name, source, color));
+        }
+        out
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in &self.0 {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self(vec![diagnostic])
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[test]
+fn render_points_at_span() {
+    let source = "PROGRAM p;\nBEGIN\n   x = 1\nEND.";
+    let diagnostic = Diagnostic::new("Expected `:=` found `=`")
+        .with_span(Span::new(20, 21, 3, 6))
+        .with_label("expected `:=`")
+        .with_help("assignment is written `:=`");
+    let rendered = diagnostic.render("test.pa", source, false);
+    assert_eq!(
+        rendered,
+        "error: Expected `:=` found `=`\n \
+         --> test.pa:3:6\n  \
+         |\n\
+         3 |    x = 1\n  \
+         |      ^ expected `:=`\n  \
+         |\n  \
+         = help: assignment is written `:=`\n"
+    );
+}
+
+#[test]
+fn render_survives_a_span_at_column_zero() {
+    let rendered = Diagnostic::new("Division by zero.").with_span(Span::new(0, 1, 1, 0)).render("test.pa", "x", false);
+    assert!(rendered.contains("1 | x\n  | ^\n"), "{}", rendered);
+}
+
+#[test]
+fn render_follows_with_notes() {
+    let (main, unit) = ("PROGRAM p; USES Base;\nBEGIN END.", "UNIT Base;\nBEGIN\n  i := 1 DIV 0\nEND.");
+    let diagnostic = Diagnostic::new("Division by zero.")
+        .with_span(Span::new(24, 31, 3, 8).in_file(1))
+        .with_label("divides by zero")
+        .with_note(
+            Diagnostic::new("in the initialization of unit `Base`, used by the program")
+                .with_span(Span::new(16, 20, 1, 17))
+                .with_label("used here"),
+        );
+    assert_eq!(diagnostic.notes().count(), 1);
+    let rendered = diagnostic.render_with(false, |span| match span.map(|span| span.file) {
+        Some(1) => ("base.pa", unit),
+        _ => ("main.pa", main),
+    });
+    assert_eq!(
+        rendered,
+        "error: Division by zero.\n \
+         --> base.pa:3:8\n  \
+         |\n\
+         3 |   i := 1 DIV 0\n  \
+         |        ^^^^^^^ divides by zero\n\
+         note: in the initialization of unit `Base`, used by the program\n \
+         --> main.pa:1:17\n  \
+         |\n\
+         1 | PROGRAM p; USES Base;\n  \
+         |                 ^^^^ used here\n"
+    );
+}
@@ -1,9 +1,10 @@
-
+pub mod diagnostic;
 
 pub mod functions {
     use crate::utils::lexer::Token;
+    use super::diagnostic::Diagnostic;
 
-    pub fn better_error(str: String, t: &Token) -> String{
-        format!("{} Postion line_no:{} column: {}", str, t.line_no(), t.column())
+    pub fn better_error(str: String, t: &Token) -> Diagnostic {
+        Diagnostic::new(str).with_span(t.span())
     }
-}
\ No newline at end of file
+}
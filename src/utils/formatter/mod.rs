@@ -0,0 +1,748 @@
+use super::ast::block::Block;
+use super::ast::decl::{Decl, Type, VarDecl};
+use super::ast::expr::Expr;
+use super::ast::ident::Ident;
+use super::ast::proc::Procedure;
+use super::ast::program::Program;
+use super::ast::stmt::{Checks, Compound, Stmt};
+use super::ast::unit::Unit;
+use super::err::diagnostic::Diagnostics;
+use super::lexer::{Operators, Span, Token, TokenType, SIGN_OPERAND_PRECEDENCE};
+use super::parser::Parser;
+use super::preprocessor::Preprocessor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeywordCase {
+    Upper,
+    Lower,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Spaces per level of nesting.
+    pub indent: usize,
+    pub keyword_case: KeywordCase,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            keyword_case: KeywordCase::Upper,
+        }
+    }
+}
+
+/// Pretty-prints a whole program or unit in canonical layout, keeping its
+/// comments.
+///
+/// Every statement and declaration gets its own line, and the parse tree of
+/// the output is the same as that of `source`. Formatting the output again
+/// gives back the same text.
+///
+/// Directives are kept as they are written, and so is code dropped by a
+/// conditional directive. Included files are left alone.
+pub fn format(source: impl AsRef<[u8]>, options: &FormatOptions) -> Result<String, Diagnostics> {
+    let bytes = source.as_ref();
+    let mut lexer = Preprocessor::new(bytes).keep_comments().without_includes();
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    loop {
+        let token = lexer.get_next_token()?;
+        let eof = matches!(token.token_type(), TokenType::EOF);
+        match token.token_type() {
+            TokenType::COMMENT(text) => comments.push(Comment { text: text.clone(), span: token.span() }),
+            _ => tokens.push(token),
+        }
+        if eof {
+            break;
+        }
+    }
+    let mut parser = Parser::with_preprocessor(Preprocessor::new(bytes).without_includes())?;
+    let unit = match tokens[0].token_type() {
+        TokenType::UNIT => Some(parser.parse_unit()?),
+        _ => None,
+    };
+    let program = match unit {
+        Some(_) => None,
+        None => Some(parser.parse()?),
+    };
+
+    let mut printer = Printer::new(options, tokens, comments);
+    if let Some(unit) = &unit {
+        printer.unit(unit);
+    }
+    if let Some(program) = &program {
+        printer.program(program);
+    }
+    Ok(printer.finish())
+}
+
+/// Prints `program` from its tree alone, as after the tree was rewritten.
+/// There are no comments to keep, and directives are written where the
+/// checks they switch change.
+pub fn print_program(program: &Program, options: &FormatOptions) -> String {
+    let mut printer = Printer::new(options, Vec::new(), Vec::new());
+    printer.checks = Some(Checks::default());
+    printer.program(program);
+    printer.finish()
+}
+
+/// Like `print_program`, for a unit.
+pub fn print_unit(unit: &Unit, options: &FormatOptions) -> String {
+    let mut printer = Printer::new(options, Vec::new(), Vec::new());
+    printer.checks = Some(Checks::default());
+    printer.unit(unit);
+    printer.finish()
+}
+
+#[derive(Debug, Clone)]
+struct Comment {
+    text: String,
+    span: Span,
+}
+
+/// Writes the program out line by line. Comments are not part of the tree,
+/// so the printer walks the token stream alongside it: before writing a
+/// token it finds that token in the stream and writes out every comment
+/// that came before it.
+struct Printer<'a> {
+    options: &'a FormatOptions,
+    tokens: Vec<Token>,
+    /// Index of the first token not written yet.
+    cursor: usize,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    out: String,
+    line: String,
+    depth: usize,
+    /// Source line of the last thing written, so that a comment following
+    /// it on the same line stays there.
+    last_line: usize,
+    /// When printing from the tree alone, the checks the directives written
+    /// so far switch on.
+    checks: Option<Checks>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(options: &'a FormatOptions, tokens: Vec<Token>, comments: Vec<Comment>) -> Self {
+        Self {
+            options,
+            tokens,
+            cursor: 0,
+            comments,
+            next_comment: 0,
+            out: String::new(),
+            line: String::new(),
+            depth: 0,
+            last_line: 0,
+            checks: None,
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.flush_comments(usize::MAX);
+        self.new_line();
+        self.out
+    }
+
+    fn keyword(&self, word: &str) -> String {
+        keyword(word, self.options)
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.line.is_empty() {
+            self.line = " ".repeat(self.depth * self.options.indent);
+        }
+        self.line.push_str(text);
+    }
+
+    fn new_line(&mut self) {
+        if !self.line.is_empty() {
+            self.out.push_str(self.line.trim_end());
+            self.out.push('\n');
+            self.line.clear();
+        }
+    }
+
+    fn blank_line(&mut self) {
+        self.new_line();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// Finds the next token of the same kind as `kind` in the stream.
+    fn find(&mut self, kind: &TokenType) -> Option<Span> {
+        let offset = self.tokens[self.cursor..]
+            .iter()
+            .position(|t| t.token_type().equal(kind))?;
+        self.cursor += offset + 1;
+        Some(self.tokens[self.cursor - 1].span())
+    }
+
+    /// Finds the token starting at `span` in the stream.
+    fn find_at(&mut self, span: Span) {
+        if let Some(offset) = self.tokens[self.cursor..].iter().position(|t| t.span().start >= span.start) {
+            self.cursor += offset + 1;
+        }
+    }
+
+    /// Finds the next token of kind `kind` and writes out the comments
+    /// before it.
+    fn anchor(&mut self, kind: &TokenType) -> Option<Span> {
+        let span = self.find(kind)?;
+        self.flush_comments(span.start);
+        self.last_line = span.line_no;
+        Some(span)
+    }
+
+    fn anchor_at(&mut self, span: Span) {
+        self.find_at(span);
+        self.flush_comments(span.start);
+        self.last_line = span.line_no;
+    }
+
+    /// Moves past the tokens that end by byte offset `end`, as after writing
+    /// a node that spans up to there.
+    fn skip_to(&mut self, end: usize) {
+        while let Some(token) = self.tokens.get(self.cursor) {
+            if token.span().end > end {
+                break;
+            }
+            self.last_line = token.line_no();
+            self.cursor += 1;
+        }
+    }
+
+    /// Writes every comment that starts before byte offset `before`.
+    fn flush_comments(&mut self, before: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment).cloned() {
+            if comment.span.start >= before {
+                break;
+            }
+            let text = comment.text;
+            if comment.span.line_no != self.last_line || self.line.is_empty() {
+                self.new_line();
+                self.write(&text);
+            } else {
+                self.write(" ");
+                self.write(&text);
+            }
+            self.last_line = comment.span.line_no + text.matches('\n').count();
+            // Anything written after a `//` comment would be part of it.
+            if text.starts_with("//") {
+                self.new_line();
+            }
+            self.next_comment += 1;
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        self.anchor(&TokenType::PROGRAM);
+        self.new_line();
+        self.write(&format!("{} {}", self.keyword("PROGRAM"), program.name.name));
+        self.anchor(&TokenType::SEMICOLON);
+        self.write(";");
+        self.uses(&program.uses);
+        self.block(&program.block);
+        self.anchor(&TokenType::DOT);
+        self.write(".");
+    }
+
+    fn unit(&mut self, unit: &Unit) {
+        self.anchor(&TokenType::UNIT);
+        self.new_line();
+        self.write(&format!("{} {}", self.keyword("UNIT"), unit.name.name));
+        self.anchor(&TokenType::SEMICOLON);
+        self.write(";");
+        self.blank_line();
+
+        self.anchor(&TokenType::INTERFACE);
+        self.new_line();
+        self.write(&self.keyword("INTERFACE"));
+        self.uses(&unit.interface.uses);
+        self.vars(unit.interface.vars.iter());
+        for heading in &unit.interface.procedures {
+            self.heading(heading.span, &heading.name, &heading.params);
+        }
+        self.blank_line();
+
+        self.anchor(&TokenType::IMPLEMENTATION);
+        self.new_line();
+        self.write(&self.keyword("IMPLEMENTATION"));
+        self.uses(&unit.implementation.uses);
+        self.declarations(&unit.implementation.declarations);
+        match &unit.initialization {
+            Some(initialization) => self.compound(initialization),
+            None => {
+                if let Some(end) = self.find(&TokenType::END) {
+                    self.flush_comments(end.start);
+                    self.last_line = end.line_no;
+                }
+                self.new_line();
+                self.write(&self.keyword("END"));
+            }
+        }
+        self.anchor(&TokenType::DOT);
+        self.write(".");
+    }
+
+    fn uses(&mut self, uses: &[Ident]) {
+        let Some(last) = uses.last() else {
+            return;
+        };
+        self.anchor(&TokenType::USES);
+        self.new_line();
+        let names = uses.iter().map(|name| name.name.as_str()).collect::<Vec<_>>();
+        self.write(&format!("{} {};", self.keyword("USES"), names.join(", ")));
+        self.skip_to(last.span.end);
+        self.anchor(&TokenType::SEMICOLON);
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.declarations(&block.declarations);
+        self.compound(&block.body);
+    }
+
+    /// A VAR section, then procedures each followed by a blank line.
+    fn declarations(&mut self, declarations: &[Decl]) {
+        self.vars(declarations.iter().filter_map(|decl| match decl {
+            Decl::Var(var) => Some(var),
+            Decl::Procedure(_) => None,
+        }));
+        for decl in declarations {
+            if let Decl::Procedure(procedure) = decl {
+                self.procedure(procedure);
+                self.anchor(&TokenType::SEMICOLON);
+                self.write(";");
+                self.blank_line();
+            }
+        }
+    }
+
+    fn vars<'v>(&mut self, vars: impl Iterator<Item = &'v VarDecl>) {
+        let mut vars = vars.peekable();
+        if vars.peek().is_some() {
+            self.anchor(&TokenType::VAR);
+            self.new_line();
+            self.write(&self.keyword("VAR"));
+            self.depth += 1;
+            for var in vars {
+                for (i, name) in var.names.iter().enumerate() {
+                    // Comments on the line of the last `;` stay there.
+                    self.anchor_at(name.span);
+                    if i == 0 {
+                        self.new_line();
+                    } else {
+                        self.write(", ");
+                    }
+                    self.write(&name.name);
+                }
+                self.anchor_at(var.ty.span);
+                self.write(&format!(" : {};", self.type_name(var.ty.ty)));
+                self.anchor(&TokenType::SEMICOLON);
+            }
+            self.depth -= 1;
+            self.blank_line();
+        }
+    }
+
+    fn compound(&mut self, compound: &Compound) {
+        self.anchor_at(compound.span);
+        self.new_line();
+        self.write(&self.keyword("BEGIN"));
+        self.depth += 1;
+        let statements = &compound.statements;
+        for (i, statement) in statements.iter().enumerate() {
+            let last = i + 1 == statements.len();
+            if let Stmt::Procedure(_) = statement {
+                if i > 0 {
+                    self.blank_line();
+                }
+            }
+            self.statement(statement, last);
+            if !last {
+                self.anchor(&TokenType::SEMICOLON);
+                self.write(";");
+            }
+        }
+        if let Some(end) = self.find(&TokenType::END) {
+            self.flush_comments(end.start);
+            self.last_line = end.line_no;
+        }
+        self.depth -= 1;
+        self.new_line();
+        self.write(&self.keyword("END"));
+    }
+
+    fn statement(&mut self, statement: &Stmt, last: bool) {
+        match statement {
+            Stmt::Compound(compound) => self.compound(compound),
+            Stmt::Procedure(procedure) => self.procedure(procedure),
+            Stmt::Empty { span } => {
+                // An empty statement sits where the next token is, which
+                // for the last one in a block is its END.
+                self.flush_comments(span.start);
+                if !last {
+                    self.new_line();
+                }
+            }
+            Stmt::Assign { checks, .. } | Stmt::Call { checks, .. } => {
+                let span = statement.span();
+                self.anchor_at(span);
+                self.new_line();
+                if self.checks.is_some_and(|written| written != *checks) {
+                    let sign = |on: bool| if on { '+' } else { '-' };
+                    self.write(&format!("{{$R{},Q{}}} ", sign(checks.range), sign(checks.overflow)));
+                    self.checks = Some(*checks);
+                }
+                let text = self.statement_text(statement);
+                self.write(&text);
+                self.skip_to(span.end);
+            }
+        }
+    }
+
+    fn procedure(&mut self, procedure: &Procedure) {
+        self.heading(procedure.span, &procedure.name, &procedure.params);
+        self.block(&procedure.block);
+    }
+
+    /// Writes `PROCEDURE name(params);` for the procedure at `span`.
+    fn heading(&mut self, span: Span, name: &Ident, params: &[VarDecl]) {
+        self.anchor_at(span);
+        self.new_line();
+        let mut header = format!("{} {}", self.keyword("PROCEDURE"), name.name);
+        if !params.is_empty() {
+            let params = params
+                .iter()
+                .map(|param| {
+                    let names = param.names.iter().map(|name| name.name.as_str()).collect::<Vec<_>>();
+                    format!("{} : {}", names.join(", "), self.type_name(param.ty.ty))
+                })
+                .collect::<Vec<_>>();
+            header.push_str(&format!("({})", params.join("; ")));
+            self.find(&TokenType::RPAREN);
+        }
+        header.push(';');
+        self.write(&header);
+        self.anchor(&TokenType::SEMICOLON);
+    }
+
+    fn statement_text(&self, statement: &Stmt) -> String {
+        match statement {
+            Stmt::Assign { target, value, .. } => format!("{} := {}", target.name, self.expr(value)),
+            Stmt::Call { name, args, .. } => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect::<Vec<_>>();
+                format!("{}({})", name.name, args.join(", "))
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn expr(&self, node: &Expr) -> String {
+        expression(node, self.options)
+    }
+
+    fn type_name(&self, ty: Type) -> String {
+        self.keyword(ty.name())
+    }
+}
+
+/// Prints an expression with only the parentheses needed to parse back to
+/// the same tree.
+pub fn expression(node: &Expr, options: &FormatOptions) -> String {
+    operand(node, options, 0, None)
+}
+
+/// Prints `node` where the parser reads an expression of at least
+/// precedence `min`, followed by a binary operator of precedence `next`.
+///
+/// Binary operators only need their own precedence checked. A sign is
+/// different: its operand is a whole term, so it would swallow any
+/// multiplicative operator written after it.
+fn operand(node: &Expr, options: &FormatOptions, min: u8, next: Option<u8>) -> String {
+    match node {
+        Expr::Integer { text: Some(text), .. } | Expr::Real { text: Some(text), .. } => text.clone(),
+        Expr::Integer { value, .. } => value.to_string(),
+        // Debug always writes a `.` or an exponent, which keeps the literal
+        // a REAL when it is read back.
+        Expr::Real { value, .. } => format!("{:?}", value),
+        Expr::Variable(ident) => ident.name.clone(),
+        Expr::Call { name, args, .. } => {
+            let args = args.iter().map(|arg| expression(arg, options)).collect::<Vec<_>>();
+            format!("{}({})", name.name, args.join(", "))
+        }
+        Expr::Binary { .. } => {
+            // Printed along the left spine. Each operation is the left
+            // operand of the one after it, and printed where that one needs
+            // it; the last is printed where `node` is. An operation in
+            // parentheses opens them before the first operand.
+            let (first, operations) = node.left_spine();
+            let precedences = operations.iter().map(|operation| operation.op.precedence()).collect::<Vec<_>>();
+            let places = precedences.iter().skip(1).map(|&after| (after, Some(after))).chain([(min, next)]);
+            let parens = precedences.iter().zip(places).map(|(&precedence, (min, next))| match precedence < min {
+                true => (true, None),
+                false => (false, next),
+            });
+            let parens = parens.collect::<Vec<_>>();
+            let mut text = "(".repeat(parens.iter().filter(|(parens, _)| *parens).count());
+            text.push_str(&operand(first, options, precedences[0], Some(precedences[0])));
+            for ((operation, precedence), (parens, next)) in operations.iter().zip(precedences).zip(parens) {
+                let symbol = match operation.op {
+                    Operators::IDIVISION => keyword("DIV", options),
+                    op => op.symbol().to_string(),
+                };
+                text.push_str(&format!(" {} {}", symbol, operand(operation.right, options, precedence + 1, next)));
+                if parens {
+                    text.push(')');
+                }
+            }
+            text
+        }
+        Expr::Unary { op, operand: value, .. } => {
+            let parens = next.is_some_and(|next| next >= SIGN_OPERAND_PRECEDENCE);
+            let next = if parens { None } else { next };
+            let value = operand(value, options, SIGN_OPERAND_PRECEDENCE, next);
+            // Keep `- -a` from running together.
+            let space = if value.starts_with(['+', '-']) { " " } else { "" };
+            let text = format!("{}{}{}", op.symbol(), space, value);
+            if parens { format!("({})", text) } else { text }
+        }
+    }
+}
+
+fn keyword(word: &str, options: &FormatOptions) -> String {
+    match options.keyword_case {
+        KeywordCase::Upper => word.to_uppercase(),
+        KeywordCase::Lower => word.to_lowercase(),
+    }
+}
+
+#[test]
+fn formats_whole_programs() {
+    let source = "\
+{ header }
+PROGRAM Demo;  VAR a, b : INTEGER; y : REAL;
+BEGIN {main}
+  a := 2; b := - - a * 3 DIV 4;
+  BEGIN y := (a + b) END;
+  PROCEDURE P(x, z : INTEGER; r : REAL); VAR t : INTEGER; BEGIN t := x END;
+  { trailing }
+END.";
+    let formatted = format(source, &FormatOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+{ header }
+PROGRAM Demo;
+VAR
+    a, b : INTEGER;
+    y : REAL;
+
+BEGIN {main}
+    a := 2;
+    b := - -a * 3 DIV 4;
+    BEGIN
+        y := a + b
+    END;
+
+    PROCEDURE P(x, z : INTEGER; r : REAL);
+    VAR
+        t : INTEGER;
+
+    BEGIN
+        t := x
+    END;
+    { trailing }
+END.
+");
+}
+
+#[test]
+fn keeps_every_comment_form() {
+    let source = "PROGRAM p; (* vars *) VAR a : INTEGER;\nBEGIN\n  a := 1 // one\n  ; a := 2 { two }\nEND.";
+    let formatted = format(source, &FormatOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+PROGRAM p; (* vars *)
+VAR
+    a : INTEGER;
+
+BEGIN
+    a := 1 // one
+    ;
+    a := 2 { two }
+END.
+");
+    assert_eq!(format(&formatted, &FormatOptions::default()).unwrap(), formatted);
+}
+
+#[test]
+fn keeps_comments_after_declarations_on_their_line() {
+    let source = "PROGRAM p;\nVAR a : INTEGER; { after a }\n  b : INTEGER; (* vars *)r : REAL;\nBEGIN\nEND.";
+    let formatted = format(source, &FormatOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+PROGRAM p;
+VAR
+    a : INTEGER; { after a }
+    b : INTEGER; (* vars *)
+    r : REAL;
+
+BEGIN
+END.
+");
+    assert_eq!(format(&formatted, &FormatOptions::default()).unwrap(), formatted);
+}
+
+#[test]
+fn keeps_numbers_as_written() {
+    let source = "PROGRAM p; VAR r : REAL; BEGIN r := $FF + 2.5E-3 * 1e-300 - 007 END.";
+    let formatted = format(source, &FormatOptions::default()).unwrap();
+    assert!(formatted.contains("    r := $FF + 2.5E-3 * 1e-300 - 007\n"), "{}", formatted);
+}
+
+#[test]
+fn keeps_directives_and_dropped_code() {
+    let source = "PROGRAM p; VAR a : INTEGER;\nBEGIN\n{$IFDEF DEBUG}\n  a := 1;\n  {$I debug.inc}\n{$ELSE}\n  a := 2;\n{$ENDIF}\n{$R+} a := 3 DIV 1\nEND.";
+    let formatted = format(source, &FormatOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+PROGRAM p;
+VAR
+    a : INTEGER;
+
+BEGIN
+    {$IFDEF DEBUG}
+    a := 1;
+  {$I debug.inc}
+    {$ELSE}
+    a := 2;
+    {$ENDIF}
+    {$R+}
+    a := 3 DIV 1
+END.
+");
+    assert_eq!(format(&formatted, &FormatOptions::default()).unwrap(), formatted);
+}
+
+#[test]
+fn formats_units_and_uses_clauses() {
+    let unit = "unit Shapes; interface uses Base; { shared }\nvar area : integer; procedure Square(side : integer);
+        implementation procedure Square(side : integer); begin area := side * side end; end.";
+    let formatted = format(unit, &FormatOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+UNIT Shapes;
+
+INTERFACE
+USES Base; { shared }
+VAR
+    area : INTEGER;
+
+PROCEDURE Square(side : INTEGER);
+
+IMPLEMENTATION
+PROCEDURE Square(side : INTEGER);
+BEGIN
+    area := side * side
+END;
+
+END.
+");
+    assert_eq!(format(&formatted, &FormatOptions::default()).unwrap(), formatted);
+
+    let program = format("program p; uses Shapes,Base; begin end.", &FormatOptions::default()).unwrap();
+    assert_eq!(program, "PROGRAM p;\nUSES Shapes, Base;\nBEGIN\nEND.\n");
+}
+
+#[test]
+fn keywords_take_the_chosen_case_and_names_keep_theirs() {
+    let source = "program Demo; var Count : integer; begin count := Count div 2 End.";
+    let options = FormatOptions { indent: 2, keyword_case: KeywordCase::Upper };
+    assert_eq!(
+        format(source, &options).unwrap(),
+        "PROGRAM Demo;\nVAR\n  Count : INTEGER;\n\nBEGIN\n  count := Count DIV 2\nEND.\n"
+    );
+}
+
+#[test]
+fn formatting_keeps_meaning_and_is_idempotent() {
+    let options = FormatOptions { indent: 2, keyword_case: KeywordCase::Upper };
+    let sources = [
+        "PROGRAM p; VAR a : INTEGER; BEGIN a := 1 + 2 * 3 - 4 END.",
+        "PROGRAM p; VAR a, b, c : INTEGER; BEGIN BEGIN a := 1; b := a - - a; END; c := (a + b) * (a - b) {why} END. {eof}",
+        "PROGRAM p; VAR x : REAL; BEGIN x := 3.5; ; x := x * x END.",
+        "PROGRAM p; PROCEDURE q; BEGIN END; PROCEDURE r(a : INTEGER); BEGIN q() END; BEGIN r(1) END.",
+    ];
+    for source in sources {
+        let formatted = format(source, &options).unwrap();
+        let parse = |s: &str| Parser::new(s.as_bytes()).unwrap().parse().unwrap();
+        assert_eq!(parse(&formatted), parse(source), "{}", formatted);
+        assert_eq!(format(&formatted, &options).unwrap(), formatted);
+    }
+}
+
+/// Operators the expression property test builds trees from.
+#[cfg(test)]
+const GENERATED_OPERATORS: &[Operators] = &[
+    Operators::PLUS,
+    Operators::MINUS,
+    Operators::MULTIPLICATION,
+    Operators::FDIVISION,
+    Operators::IDIVISION,
+];
+
+#[test]
+fn printed_expressions_parse_back_to_the_same_tree() {
+    use super::ast::expr::UnaryOp;
+    use super::ast::ident::Ident;
+
+    // xorshift64, so the test needs no dependencies and every run is the same.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move |bound: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize % bound
+    };
+    fn generate(next: &mut impl FnMut(usize) -> usize, depth: usize) -> Expr {
+        let span = Span::default();
+        match next(if depth == 0 { 3 } else { 6 }) {
+            0 => Expr::Integer { value: next(100) as i64, text: None, span },
+            1 => Expr::Real { value: next(1000) as f64 / 8.0, text: None, span },
+            2 => Expr::Variable(Ident::new(["a", "b", "c"][next(3)], span)),
+            3 => {
+                let sign = [UnaryOp::Plus, UnaryOp::Minus][next(2)];
+                Expr::unary(sign, span, generate(next, depth - 1))
+            }
+            _ => {
+                let op = GENERATED_OPERATORS[next(GENERATED_OPERATORS.len())].clone();
+                let left = generate(next, depth - 1);
+                Expr::binary(op, left, generate(next, depth - 1))
+            }
+        }
+    }
+    let options = FormatOptions::default();
+    for _ in 0..2000 {
+        let tree = generate(&mut next, 6);
+        let printed = expression(&tree, &options);
+        let parsed = Parser::new(printed.as_bytes()).unwrap().parse_expression();
+        assert_eq!(parsed, Ok(tree), "{}", printed);
+    }
+}
+
+#[test]
+fn prints_minimal_parentheses() {
+    let cases = [
+        ("(1 + 2) + 3", "1 + 2 + 3"),
+        ("1 + (2 + 3)", "1 + (2 + 3)"),
+        ("(a - b) DIV c", "(a - b) DIV c"),
+        ("((a DIV b) DIV c)", "a DIV b DIV c"),
+        ("a DIV (-b) DIV c", "a DIV (-b) DIV c"),
+        ("(-a) + b", "-a + b"),
+        ("-(a + b)", "-(a + b)"),
+        ("a - (-(-b))", "a - - -b"),
+        ("-(f((a + b), -(c)))", "-f(a + b, -c)"),
+    ];
+    for (source, expected) in cases {
+        let tree = Parser::new(source.as_bytes()).unwrap().parse_expression().unwrap();
+        assert_eq!(expression(&tree, &FormatOptions::default()), expected);
+    }
+}
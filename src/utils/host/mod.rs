@@ -0,0 +1,178 @@
+//! Procedures and functions written in Rust that programs can call.
+//!
+//! They are registered before a program is compiled, so that the compiler
+//! resolves calls to them and checks their arguments. Their names sit
+//! outside the program and its units: a name the program or a unit it
+//! uses declares hides a host function of the same name.
+//!
+//! A host goes with the programs compiled against it, which may run on
+//! any thread, so what it registers must be `Send` and `Sync`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::ast::decl::Type;
+use super::ast::ident;
+use super::err::diagnostic::Diagnostic;
+use super::interpreter::Value;
+use super::lexer::Span;
+
+/// What a host function is called with: the arguments, converted to the
+/// types of its parameters. It gives its result, or `None` for a
+/// procedure, or a message for the error the call fails with.
+type Call = dyn Fn(&[Value]) -> Result<Option<Value>, String> + Send + Sync;
+
+/// A procedure or function of the host, with its parameter types and the
+/// type of its result, `None` for a procedure.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub result: Option<Type>,
+    call: Arc<Call>,
+}
+
+impl HostFunction {
+    /// Calls the function with `args` of the types of its parameters and
+    /// checks the type of what it gives back. `span` is that of the call,
+    /// for its errors.
+    pub fn call(&self, args: &[Value], span: Span) -> Result<Option<Value>, Diagnostic> {
+        let result = (self.call)(args).map_err(|message| failed(&self.name, message, span))?;
+        match (self.result, result) {
+            (Some(Type::Real), Some(Value::Integer(value))) => Ok(Some(Value::Real(value as f64))),
+            (Some(ty), Some(value)) if value.ty() == ty => Ok(Some(value)),
+            (None, None) => Ok(None),
+            (_, result) => Err(wrong_result(self, result, span)),
+        }
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("result", &self.result)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The procedures and functions a host offers to programs.
+#[derive(Debug, Clone, Default)]
+pub struct Host {
+    functions: Vec<HostFunction>,
+}
+
+impl Host {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a procedure taking arguments of the types `params`,
+    /// replacing anything registered under the same name in any case.
+    pub fn procedure(
+        &mut self,
+        name: &str,
+        params: &[Type],
+        procedure: impl Fn(&[Value]) -> Result<(), String> + Send + Sync + 'static,
+    ) -> &mut Self {
+        let call = move |args: &[Value]| procedure(args).map(|()| None);
+        self.register(name, params, None, Arc::new(call))
+    }
+
+    /// Registers a function taking arguments of the types `params` and
+    /// giving a value of type `result`, replacing anything registered
+    /// under the same name in any case. It may give an INTEGER for a REAL.
+    pub fn function(
+        &mut self,
+        name: &str,
+        params: &[Type],
+        result: Type,
+        function: impl Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> &mut Self {
+        let call = move |args: &[Value]| function(args).map(Some);
+        self.register(name, params, Some(result), Arc::new(call))
+    }
+
+    fn register(&mut self, name: &str, params: &[Type], result: Option<Type>, call: Arc<Call>) -> &mut Self {
+        let function = HostFunction { name: name.to_string(), params: params.to_vec(), result, call };
+        match self.find(name) {
+            Some((index, _)) => self.functions[index as usize] = function,
+            None => self.functions.push(function),
+        }
+        self
+    }
+
+    /// The function registered under `name` in any case, and its index.
+    pub fn find(&self, name: &str) -> Option<(u32, &HostFunction)> {
+        let key = ident::key(name);
+        self.functions
+            .iter()
+            .enumerate()
+            .find(|(_, function)| ident::key(&function.name) == key)
+            .map(|(index, function)| (index as u32, function))
+    }
+
+    /// The function with `index`, as `find` gives it.
+    ///
+    /// # Panics
+    ///
+    /// If there is none.
+    pub fn get(&self, index: u32) -> &HostFunction {
+        &self.functions[index as usize]
+    }
+}
+
+/// The error a call to a host function fails with when it gives `message`.
+fn failed(name: &str, message: String, span: Span) -> Diagnostic {
+    Diagnostic::new(message)
+        .with_span(span)
+        .with_label(format!("in this call to `{}`", name))
+}
+
+fn wrong_result(function: &HostFunction, result: Option<Value>, span: Span) -> Diagnostic {
+    let gave = match result {
+        Some(value) => format!("the {} {}", value.ty().name(), value),
+        None => "nothing".to_string(),
+    };
+    let promised = match function.result {
+        Some(Type::Integer) => "an INTEGER",
+        Some(Type::Real) => "a REAL",
+        None => "nothing",
+    };
+    Diagnostic::new(format!("`{}` gave {} instead of {}.", function.name, gave, promised))
+        .with_span(span)
+        .with_label("in this call")
+        .with_help("the host function does not give the type it was registered with")
+}
+
+// The errors of calls that do not match what they call. The compiler finds
+// them in calls to host functions, before the program runs; calls to the
+// program's own procedures raise them when they run.
+
+pub fn wrong_argument_count(name: &str, takes: usize, given: usize, span: Span) -> Diagnostic {
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    Diagnostic::new(format!(
+        "`{}` takes {} argument{}, but {} {} given.",
+        name,
+        takes,
+        plural(takes),
+        given,
+        if given == 1 { "was" } else { "were" }
+    ))
+    .with_span(span)
+    .with_label(format!("expected {} argument{}", takes, plural(takes)))
+}
+
+pub fn real_argument(function: &HostFunction, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("`{}` takes an INTEGER here, not a REAL.", function.name))
+        .with_span(span)
+        .with_label("this is a REAL")
+        .with_help("a REAL argument is only taken by a REAL parameter")
+}
+
+pub fn no_value(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("`{}` is a procedure, it gives no value.", name))
+        .with_span(span)
+        .with_label("used as a value")
+}
@@ -1,177 +1,964 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+use super::ast::decl::{Decl, Type};
+use super::ast::expr::{Expr, Operation, UnaryOp};
+use super::ast::ident::{self, Ident};
+use super::ast::proc::Procedure;
 use super::ast::program::Program;
-use super::err::functions::better_error;
-use super::lexer:: {Token, TokenType, Operators};
-use super::ast::ast::AST;
+use super::ast::stmt::{Checks, Compound, Stmt};
+use super::ast::unit::Unit;
+use super::err::diagnostic::{Diagnostic, Diagnostics};
+use super::host::{self, Host, HostFunction};
+use super::lexer::{Operators, Span};
+use super::limits::{self, DEFAULT_CALLS, DEFAULT_DEPTH};
 use super::parser::Parser;
+use super::units;
+
+/// Names declared at the top level of a program or unit, or in a frame.
+/// Keyed by `ident::key`, so lookups ignore case. Each entry keeps the name
+/// as it was declared, and the version of the scope it was declared at: a
+/// procedure sees the scope it is declared in as it was there, so what a
+/// name is declared again as hides the older entry only from code declared
+/// after it.
+#[derive(Default)]
+struct Scope {
+    entries: RefCell<HashMap<String, Vec<Entry>>>,
+    version: Cell<usize>,
+}
+
+/// The version a name was declared at, the name and what it stands for.
+type Entry = (usize, String, Binding);
+
+impl Scope {
+    /// The version of the scope now. Each `bind` moves it on.
+    fn version(&self) -> usize {
+        self.version.get()
+    }
+
+    /// Adds `bindings` at a new version, replacing any with the same name in
+    /// any case from there on.
+    fn bind(&self, bindings: impl IntoIterator<Item = (String, Binding)>) {
+        let version = self.version.get() + 1;
+        self.version.set(version);
+        let mut entries = self.entries.borrow_mut();
+        for (name, binding) in bindings {
+            let entries = entries.entry(ident::key(&name)).or_default();
+            match entries.last_mut() {
+                Some(last) if last.0 == version => *last = (version, name, binding),
+                _ => entries.push((version, name, binding)),
+            }
+        }
+    }
+
+    /// The version the entry for `key` seen at `version` was declared at.
+    fn declared(&self, key: &str, version: usize) -> Option<usize> {
+        let entries = self.entries.borrow();
+        entries.get(key)?.iter().rev().map(|entry| entry.0).find(|&declared| declared <= version)
+    }
+
+    /// Runs `f` on the name and binding of the entry for `key` declared at
+    /// `declared`.
+    fn with<R>(&self, key: &str, declared: usize, f: impl FnOnce(&str, &mut Binding) -> R) -> R {
+        let mut entries = self.entries.borrow_mut();
+        let entries = entries.get_mut(key).expect("the name is declared");
+        let (_, name, binding) = entries.iter_mut().find(|entry| entry.0 == declared).expect("the entry is declared");
+        f(name, binding)
+    }
+
+    /// The names as they are now, with what they stand for.
+    fn latest(&self) -> Vec<(String, Binding)> {
+        let entries = self.entries.borrow();
+        let latest = entries.values().filter_map(|entries| entries.last());
+        latest.map(|(_, name, binding)| (name.clone(), binding.clone())).collect()
+    }
+}
 
 pub struct Interpreter{
     program: Program,
+    globals: Scope,
+    /// The units the program uses, directly or not, in the order they are
+    /// initialized.
+    units: Vec<UnitScope>,
+    /// Indexes into `units` of the units named in the program's USES.
+    uses: Vec<usize>,
+    /// The unit whose code is running, or `None` for the program.
+    current: Cell<Option<usize>>,
+    /// The frame of the procedure whose code is running, `None` at the top
+    /// level.
+    frame: RefCell<Option<Rc<Frame>>>,
+    /// The functions of the host, for names nothing else declares.
+    host: Host,
+    /// How many calls may be running at once.
+    max_calls: usize,
+    /// How many calls are running.
+    calls: Cell<usize>,
+}
+
+struct UnitScope {
+    unit: Unit,
+    /// Everything the unit declares, exported or not.
+    globals: Scope,
+    /// Keys of the names its interface declares.
+    exports: HashSet<String>,
+    /// Indexes into `units` of the units it uses.
+    uses: Vec<usize>,
+    /// What led to it being initialized, as `units::used_at` gives it.
+    used_at: Vec<Ident>,
+}
+
+/// The parameters and variables of a procedure being run, and the
+/// procedures it declares.
+struct Frame {
+    /// The name of the procedure, for tracing errors.
+    name: String,
+    scope: Scope,
+    /// The frame of the procedure it is declared in, `None` for one declared
+    /// at the top level.
+    parent: Option<Rc<Frame>>,
+    /// The version of the scope it is declared in that its code sees.
+    seen: usize,
+    /// The unit it is declared in, or `None` for the program.
+    unit: Option<usize>,
+}
+
+/// Where a name was found, with the version of the scope its entry was
+/// declared at.
+enum Found<'a> {
+    /// In the frame of a procedure being run.
+    Frame(Rc<Frame>, usize),
+    /// Among the globals of the program, for `None`, or of a unit.
+    Global(&'a Scope, Option<usize>, usize),
+}
+
+impl Found<'_> {
+    fn scope(&self) -> &Scope {
+        match self {
+            Self::Frame(frame, _) => &frame.scope,
+            Self::Global(scope, _, _) => scope,
+        }
+    }
+
+    fn declared(&self) -> usize {
+        match self {
+            Self::Frame(_, declared) | Self::Global(_, _, declared) => *declared,
+        }
+    }
+}
+
+/// What a name in scope stands for.
+#[derive(Debug, Clone)]
+pub enum Binding {
+    Variable { ty: Type, value: Value },
+    Procedure(Rc<Procedure>),
+}
+
+/// A value computed at run time. Arithmetic on INTEGERs stays INTEGER,
+/// except for `/`; anything involving a REAL is REAL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Real(f64),
+}
+
+impl Value {
+    pub fn zero(ty: Type) -> Self {
+        match ty {
+            Type::Integer => Self::Integer(0),
+            Type::Real => Self::Real(0.0),
+        }
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Self::Integer(_) => Type::Integer,
+            Self::Real(_) => Type::Real,
+        }
+    }
+
+    pub fn as_real(self) -> f64 {
+        match self {
+            Self::Integer(value) => value as f64,
+            Self::Real(value) => value,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::Real(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer(value) => write!(f, "{}", value),
+            Self::Real(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Variable { ty, value } => write!(f, "{} = {}", ty.name(), value),
+            Self::Procedure(_) => write!(f, "PROCEDURE"),
+        }
+    }
 }
 
 impl Interpreter {
-    pub fn new<'a>(bytes: &'a [u8]) -> Result<Self, String> {
-        Ok(Self {
-            program : Parser::new(bytes)?.program()?
+    /// Parses `bytes` as a program. The units it uses are looked up in the
+    /// working directory.
+    pub fn new(bytes: &[u8]) -> Result<Self, Diagnostics> {
+        let mut parser = Parser::new(bytes)?;
+        let program = parser.parse()?;
+        let units = units::load(&program.uses, 0, parser.sources(), &[], DEFAULT_DEPTH)?;
+        Ok(Self::with_units(program, units))
+    }
+
+    /// An interpreter with an empty global scope and no statements, for
+    /// feeding input piece by piece.
+    pub fn session() -> Self {
+        Self::from_program(Program::default())
+    }
+
+    /// An interpreter for a program parsed beforehand, with its globals
+    /// declared. The program may not use any units.
+    pub fn from_program(program: Program) -> Self {
+        Self::with_units(program, Vec::new())
+    }
+
+    /// An interpreter for a program and the units it uses, as `units::load`
+    /// returns them.
+    ///
+    /// # Panics
+    ///
+    /// If a unit used by the program or by another unit is missing.
+    pub fn with_units(program: Program, units: Vec<Unit>) -> Self {
+        let index = |name: &Ident| {
+            units
+                .iter()
+                .position(|unit| unit.name == *name)
+                .unwrap_or_else(|| panic!("unit `{}` is used but was not loaded", name.name))
+        };
+        let uses = program.uses.iter().map(index).collect();
+        let scopes = units
+            .iter()
+            .map(|unit| {
+                let globals = Scope::default();
+                let vars = unit.interface.vars.iter().map(|var| Decl::Var(var.clone()));
+                globals.bind(declarations(&vars.collect::<Vec<_>>()));
+                globals.bind(declarations(&unit.implementation.declarations));
+                let interface = &unit.interface;
+                let exports = interface.vars
+                    .iter()
+                    .flat_map(|var| &var.names)
+                    .chain(interface.procedures.iter().map(|heading| &heading.name))
+                    .map(Ident::key)
+                    .collect();
+                let uses = interface.uses.iter().chain(&unit.implementation.uses).map(index).collect();
+                (globals, exports, uses)
+            })
+            .collect::<Vec<_>>();
+        let used_at = units::used_at(&program.uses, &units);
+        let units = units
+            .into_iter()
+            .zip(scopes)
+            .zip(used_at)
+            .map(|((unit, (globals, exports, uses)), used_at)| UnitScope { unit, globals, exports, uses, used_at })
+            .collect();
+        let interpreter = Self {
+            program,
+            globals: Scope::default(),
+            units,
+            uses,
+            current: Cell::new(None),
+            frame: RefCell::new(None),
+            host: Host::new(),
+            max_calls: DEFAULT_CALLS,
+            calls: Cell::new(0),
+        };
+        interpreter.declare(&interpreter.program.block.declarations);
+        interpreter
+    }
+
+    /// Lets the code call the functions of `host`. Calls to them are
+    /// checked as they run, with the errors the compiler gives.
+    pub fn with_host(mut self, host: &Host) -> Self {
+        self.host = host.clone();
+        self
+    }
+
+    /// Lets at most `calls` calls run at once, instead of `DEFAULT_CALLS`.
+    pub fn with_max_calls(mut self, calls: usize) -> Self {
+        self.max_calls = calls;
+        self
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Adds `declarations` to the global scope, replacing any with the same
+    /// name. Variables start out as zero.
+    pub fn declare(&self, declarations: &[Decl]) {
+        self.define(self::declarations(declarations))
+    }
+
+    /// Adds `bindings` to the global scope, replacing any with the same name
+    /// in any case for the code declared after them.
+    pub fn define(&self, bindings: impl IntoIterator<Item = (String, Binding)>) {
+        self.globals.bind(bindings)
+    }
+
+    /// Declares `procedure` where the code running now is: in the frame of
+    /// the procedure running, or among the globals.
+    fn define_procedure(&self, procedure: &Procedure) {
+        let binding = [(procedure.name.name.clone(), Binding::Procedure(Rc::new(procedure.clone())))];
+        match &*self.frame.borrow() {
+            Some(frame) => frame.scope.bind(binding),
+            None => self.own_scope().bind(binding),
+        }
+    }
+
+    /// The globals of the program or unit whose code is running.
+    fn own_scope(&self) -> &Scope {
+        match self.current.get() {
+            None => &self.globals,
+            Some(unit) => &self.units[unit].globals,
+        }
+    }
+
+    /// Where `key` is found, seen from the code running now: the frame of
+    /// the procedure running and those of the procedures it is declared in,
+    /// innermost first, then its own globals, then what the units it uses
+    /// export, the unit named last first. Each scope it is declared in is
+    /// seen as it was where the procedure was declared.
+    fn find(&self, key: &str) -> Option<Found<'_>> {
+        let mut seen = None;
+        let mut frame = self.frame.borrow().clone();
+        while let Some(current) = frame {
+            let version = seen.unwrap_or_else(|| current.scope.version());
+            if let Some(declared) = current.scope.declared(key, version) {
+                return Some(Found::Frame(current, declared));
+            }
+            seen = Some(current.seen);
+            frame = current.parent.clone();
+        }
+        let own = self.own_scope();
+        if let Some(declared) = own.declared(key, seen.unwrap_or_else(|| own.version())) {
+            return Some(Found::Global(own, self.current.get(), declared));
+        }
+        let uses = match self.current.get() {
+            None => &self.uses,
+            Some(unit) => &self.units[unit].uses,
+        };
+        uses.iter().rev().filter(|&&unit| self.units[unit].exports.contains(key)).find_map(|&unit| {
+            let globals = &self.units[unit].globals;
+            let declared = globals.declared(key, globals.version())?;
+            Some(Found::Global(globals, Some(unit), declared))
         })
     }
 
-    pub fn interprete(&self) -> Result<(), String> {
-        self.visit_begin(&self.program.statements())
+    /// What `ident` stands for in the code running now, and where.
+    fn lookup(&self, ident: &Ident) -> Option<(Found<'_>, String, Binding)> {
+        let key = ident.key();
+        let found = self.find(&key)?;
+        let entry = |name: &str, binding: &mut Binding| (name.to_string(), binding.clone());
+        let (name, binding) = found.scope().with(&key, found.declared(), entry);
+        Some((found, name, binding))
     }
 
-    pub fn print_global_scope(&self) {
-        self.program.scope()
-    }
-
-
-    fn visit_begin(&self, begin: &AST<Token>) -> Result<(), String> {
-        if let TokenType::BEGIN = begin.view().token_type() {
-        } else {
-            return Err(
-                    better_error(
-                    format!("Expected BEGIN found {:?}",begin),
-                    begin.view()
-                    )
-            );
-        }
-        for i in begin.children() {
-            match i.view().token_type() {
-               TokenType::ASSIGN => {
-                    match i.left().unwrap().view().token_type(){
-                       TokenType::IDENTIFIER(_) => {
-                
-                           self.program.set_var(i.left().unwrap().view(), self.visit_node(i.right().unwrap())?)?
-                        } 
-                           
-                       e => return Err(
-                            better_error(
-                                format!("Expected Identifier found {:?}",e),
-                                i.view()
-                            )
-                       )
-
-                   };
-                   
-               }
-
-               TokenType::BEGIN => {
-                   self.visit_begin(i)?;
-               }
-
-
-               TokenType::PROCEDURE(p) => {
-                    match p {
-                        Some(_) => {
-                            self.program.set_proc(i.view().clone())?;
-                        }
-                        None => unreachable!()
-                    }
-               }
+    /// What the code running now is, as the caller of a procedure.
+    fn caller(&self) -> Caller {
+        match (&*self.frame.borrow(), self.current.get()) {
+            (Some(frame), _) => Caller::Procedure(frame.name.clone()),
+            (None, Some(unit)) => {
+                Caller::Unit(self.units[unit].used_at.last().expect("a unit is used by something").name.clone())
+            }
+            (None, None) => Caller::Program,
+        }
+    }
+
+    /// The global scope with names as declared, sorted regardless of case.
+    pub fn globals(&self) -> Vec<(String, Binding)> {
+        let mut globals = self.globals.latest();
+        globals.sort_by_key(|(name, _)| ident::key(name));
+        globals
+    }
+
+    /// Runs `statements` against the global scope.
+    pub fn execute(&self, statements: &[Stmt]) -> Result<(), Diagnostic> {
+        statements.iter().try_for_each(|statement| self.visit_statement(statement))
+    }
 
-               TokenType::EMPTY => {}
-               
-               _ => {self.visit_node(i)?;},
+    /// Evaluates `expr` against the global scope, with no checks switched
+    /// on.
+    pub fn evaluate(&self, expr: &Expr) -> Result<Value, Diagnostic> {
+        self.visit_expr(expr, Checks::default())
+    }
+
+    /// Initializes the units, in order, then runs the program.
+    pub fn interprete(&self) -> Result<(), Diagnostic> {
+        for (index, unit) in self.units.iter().enumerate() {
+            if let Some(initialization) = &unit.unit.initialization {
+                self.current.set(Some(index));
+                let result = self.visit_compound(initialization);
+                self.current.set(None);
+                result.map_err(|error| units::initializing(error, &unit.used_at))?;
             }
+        }
+        self.visit_compound(&self.program.block.body)
+    }
 
-       }
-        Ok(())
+    pub fn print_global_scope(&self) {
+        for (name, binding) in self.globals() {
+            println!("{} : {}", name, binding);
+        }
     }
 
-    fn visit_node(&self, node: &AST<Token>) -> Result<f64, String>{
-        use Operators::*;
-        match node.view().token_type() {
-            TokenType::Integer(num) => return Ok(num.clone()),
-            TokenType::IDENTIFIER(str) => {
-                match self.program.get_num(&str){
-                    Some(int) => {
-                        let res = Ok(int);
-                        
-                        return res;
-                    }
-                    None => return Err(format!("Variable `{}` not found.", str)),
-                     
-                }
+
+    fn visit_compound(&self, compound: &Compound) -> Result<(), Diagnostic> {
+        self.execute(&compound.statements)
+    }
+
+    fn visit_statement(&self, statement: &Stmt) -> Result<(), Diagnostic> {
+        match statement {
+            Stmt::Compound(compound) => self.visit_compound(compound),
+            Stmt::Assign { target, value, checks, .. } => {
+                let at = value.span();
+                let value = self.visit_expr(value, *checks)?;
+                self.set_var(target, value, *checks, at)
             }
-            TokenType::Operator(ref op) => {
-                match *op{
-                    PLUS => {
-                        if node.children().len() == 1 {
-                            return Ok(self.visit_node(node.left().unwrap())?)
-                        } else {
-                            return Ok(self.visit_node(node.left().unwrap())? + self.visit_node(node.right().unwrap())?)
-                        }
-                    }
-            
-                    MINUS => {
-                        if node.children().len() == 1 {
-                            return Ok(-self.visit_node(node.left().unwrap())?);
-                        } else { 
-                            
-                            return Ok(self.visit_node(node.left().unwrap())? - self.visit_node(node.right().unwrap())?)
-                        }
+            Stmt::Call { name, args, checks, span } => match self.host_function(name) {
+                Some(function) => self.call_host(function, args, *checks, *span).map(|_| ()),
+                None => match self.lookup(name) {
+                    Some((found, _, Binding::Procedure(procedure))) => {
+                        self.call(found, &procedure, args, *checks, *span)
                     }
-                    
-                    MULTIPLICATION => return Ok(self.visit_node(node.left().unwrap())? * self.visit_node(node.right().unwrap())?),
-                    
-                    IDIVISION => return Ok((self.visit_node(node.left().unwrap())? / self.visit_node(node.right().unwrap())?).floor()),
-                    FDIVISION => return Ok(self.visit_node(node.left().unwrap())? / self.visit_node(node.right().unwrap())?),
-
-                }
+                    _ => self.cannot_call(name, args, *checks).map(|_| ()),
+                },
+            },
+            Stmt::Procedure(procedure) => {
+                self.define_procedure(procedure);
+                Ok(())
             }
-            TokenType::PROCEDURECALL(proc) => {
-                let mut params_simplified = Vec::with_capacity(0);
-                if proc.params.is_some() {
-                    unsafe{
-                        params_simplified.reserve(proc.params.as_ref().unwrap_unchecked().len());
-                        for i in proc.params.as_ref().unwrap_unchecked() {
-                            params_simplified.push(self.visit_node(i)?)
-                        }
+            Stmt::Empty { .. } => Ok(()),
+        }
+    }
+
+    fn visit_expr(&self, expr: &Expr, checks: Checks) -> Result<Value, Diagnostic> {
+        match expr {
+            Expr::Integer { value, .. } => Ok(Value::Integer(*value)),
+            Expr::Real { value, .. } => Ok(Value::Real(*value)),
+            Expr::Variable(ident) => match self.host_function(ident) {
+                Some(function) => self.host_value(function, &[], checks, ident.span),
+                None => self.get_var(ident),
+            },
+            Expr::Call { name, args, span } => match self.host_function(name) {
+                Some(function) => self.host_value(function, args, checks, *span),
+                None => self.cannot_call(name, args, checks),
+            },
+            Expr::Unary { op, operand, span } => {
+                let value = self.visit_expr(operand, checks)?;
+                match (op, value) {
+                    (UnaryOp::Plus, _) => Ok(value),
+                    (UnaryOp::Minus, Value::Real(value)) => Ok(Value::Real(-value)),
+                    (UnaryOp::Minus, Value::Integer(value)) => {
+                        integer(value.checked_neg(), value.wrapping_neg(), checks, *span)
                     }
                 }
-                todo!()
             }
-            t => { return Err(format!("Cannot interprete token: {:?}.",t)) }
-        }
-    }
-
-    fn rewrite(node: &AST<Token>) -> Result<String, String> {
-        use Operators::*;
-        match node.view().token_type() {
-            TokenType::Integer(num) => return Ok(format!("{}",num)),
-            TokenType::END => return Ok(format!("END \n")),
-            TokenType::IDENTIFIER(ref s) => return Ok(s.clone()),
-            TokenType::SEMICOLON => return Ok(format!(";")),
-            TokenType::ASSIGN => return Ok(format!("{} := {};", Self::rewrite(node.left().unwrap())?, Self::rewrite(node.right().unwrap())?)),
-            TokenType::DOT => return Ok(format!(".")),
-            TokenType::EMPTY => return Ok(format!("")),
-            TokenType::Operator(ref op) => {
-                match (op, node.children().len()){
-                    (PLUS,2 )=> return Ok(format!("({} + {})",Self::rewrite(node.left().unwrap())?,Self::rewrite(node.right().unwrap())?)),
-                    (PLUS,1)=> return Ok(format!("({})",Self::rewrite(node.left().unwrap())?)),
-                    (MINUS,2) => return Ok(format!("({} - {})",Self::rewrite(node.left().unwrap())?,Self::rewrite(node.right().unwrap())?)),
-                    (MINUS,1) => return Ok(format!("(-{})",Self::rewrite(node.left().unwrap())?)),
-                    (MULTIPLICATION, 2) => return Ok(format!("({} * {})",Self::rewrite(node.left().unwrap())?,Self::rewrite(node.right().unwrap())?)),
-                    (IDIVISION, 2) => return Ok(format!("({} DIV {})",Self::rewrite(node.left().unwrap())?,Self::rewrite(node.right().unwrap())?)),
-                    (FDIVISION, 2) => return Ok(format!("({} / {})",Self::rewrite(node.left().unwrap())?,Self::rewrite(node.right().unwrap())?)),
-                    _ => unreachable!()
+            Expr::Binary { .. } => {
+                let (first, operations) = expr.left_spine();
+                let mut left = self.visit_expr(first, checks)?;
+                for operation in operations {
+                    let right = self.visit_expr(operation.right, checks)?;
+                    left = apply(operation, left, right, checks)?;
                 }
+                Ok(left)
             }
-            TokenType::BEGIN => return {
-                let mut res = String::from("BEGIN\n");
-                for i in node.children() {
-                    res.push_str(&*Self::rewrite(i)?);
-                    res.push('\n');
-                }
-                res.push_str("END");
-                Ok(res)
-            },
-            _ => { unreachable!() }
         }
     }
 
-    pub fn spit(&self) -> Result<String, String>{
-        Self::rewrite(&self.program.statements())
+    /// Calls `procedure`, found where `found` says, with the values of
+    /// `args` as its parameters. `span` is that of the call.
+    fn call(
+        &self,
+        found: Found,
+        procedure: &Procedure,
+        args: &[Expr],
+        checks: Checks,
+        span: Span,
+    ) -> Result<(), Diagnostic> {
+        let name = &procedure.name.name;
+        let params = procedure.params
+            .iter()
+            .flat_map(|param| param.names.iter().map(move |name| (name, param.ty.ty)))
+            .collect::<Vec<_>>();
+        if args.len() != params.len() {
+            return Err(host::wrong_argument_count(name, params.len(), args.len(), span));
+        }
+        let mut bindings = Vec::new();
+        for (arg, (param, ty)) in args.iter().zip(params) {
+            let value = assigned(ty, self.visit_expr(arg, checks)?, &param.name, checks, arg.span())?;
+            bindings.push((param.name.clone(), Binding::Variable { ty, value }));
+        }
+        if self.calls.get() >= self.max_calls {
+            return Err(limits::too_many_calls(self.max_calls, span));
+        }
+        let scope = Scope::default();
+        scope.bind(bindings);
+        scope.bind(declarations(&procedure.block.declarations));
+        let seen = found.declared();
+        let (unit, parent) = match found {
+            Found::Frame(frame, _) => (frame.unit, Some(frame)),
+            Found::Global(_, unit, _) => (unit, None),
+        };
+        let caller = self.caller();
+        let frame = Frame { name: name.clone(), scope, parent, seen, unit };
+        let outer = (self.frame.replace(Some(Rc::new(frame))), self.current.replace(unit));
+        self.calls.set(self.calls.get() + 1);
+        let result = self.visit_compound(&procedure.block.body);
+        self.calls.set(self.calls.get() - 1);
+        self.frame.replace(outer.0);
+        self.current.set(outer.1);
+        result.map_err(|error| called(error, name, &caller, span))
+    }
+
+    /// Fails on a call to something that is no procedure, or that gives no
+    /// value where one is used, once its arguments are computed.
+    fn cannot_call(&self, name: &Ident, args: &[Expr], checks: Checks) -> Result<Value, Diagnostic> {
+        for arg in args {
+            self.visit_expr(arg, checks)?;
+        }
+        Err(match self.lookup(name) {
+            Some((_, declared, Binding::Procedure(_))) => host::no_value(&declared, name.span),
+            Some((_, declared, Binding::Variable { .. })) => not_a_procedure(&declared, name.span),
+            None => not_found_to_call(&name.name, name.span),
+        })
+    }
+
+    /// The host function `ident` names, unless something the code running
+    /// now can see has that name.
+    fn host_function(&self, ident: &Ident) -> Option<&HostFunction> {
+        match self.find(&ident.key()) {
+            Some(_) => None,
+            None => self.host.find(&ident.name).map(|(_, function)| function),
+        }
+    }
+
+    /// Calls `function` with the values of `args`, converted to the types
+    /// of its parameters. `span` is that of the call.
+    fn call_host(
+        &self,
+        function: &HostFunction,
+        args: &[Expr],
+        checks: Checks,
+        span: Span,
+    ) -> Result<Option<Value>, Diagnostic> {
+        if args.len() != function.params.len() {
+            return Err(host::wrong_argument_count(&function.name, function.params.len(), args.len(), span));
+        }
+        let args = args
+            .iter()
+            .zip(&function.params)
+            .map(|(arg, param)| match (self.visit_expr(arg, checks)?, param) {
+                (Value::Real(_), Type::Integer) => Err(host::real_argument(function, arg.span())),
+                (value, Type::Real) => Ok(Value::Real(value.as_real())),
+                (value, Type::Integer) => Ok(value),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        function.call(&args, span)
+    }
+
+    /// Like `call_host`, for a call whose value is used.
+    fn host_value(
+        &self,
+        function: &HostFunction,
+        args: &[Expr],
+        checks: Checks,
+        span: Span,
+    ) -> Result<Value, Diagnostic> {
+        if function.result.is_none() {
+            return Err(host::no_value(&function.name, span));
+        }
+        let value = self.call_host(function, args, checks, span)?;
+        Ok(value.expect("`HostFunction::call` checks that a function gives a value"))
+    }
+
+    fn get_var(&self, ident: &Ident) -> Result<Value, Diagnostic> {
+        match self.lookup(ident) {
+            Some((_, _, Binding::Variable { value, .. })) => Ok(value),
+            Some((_, name, Binding::Procedure(_))) => Err(not_a_variable(&name, ident.span)),
+            None => Err(not_found(&ident.name, ident.span)),
+        }
+    }
+
+    /// Stores `value`, computed by the expression at `at`, converting it to
+    /// the type of the variable.
+    fn set_var(&self, ident: &Ident, value: Value, checks: Checks, at: Span) -> Result<(), Diagnostic> {
+        let key = ident.key();
+        let Some(found) = self.find(&key) else {
+            return Err(not_found(&ident.name, ident.span));
+        };
+        found.scope().with(&key, found.declared(), |name, binding| match binding {
+            Binding::Variable { ty, value: slot } => {
+                *slot = assigned(*ty, value, name, checks, at)?;
+                Ok(())
+            }
+            Binding::Procedure(_) => Err(not_assignable(name, ident.span)),
+        })
+    }
+}
+
+/// `left op right`, for `operation`.
+fn apply(operation: Operation, left: Value, right: Value, checks: Checks) -> Result<Value, Diagnostic> {
+    let Operation { op, right: divisor, span } = operation;
+    let divisor = divisor.span();
+    match (op, left, right) {
+        (Operators::FDIVISION, _, _) if right.as_real() == 0.0 => Err(division_by_zero(divisor)),
+        (Operators::FDIVISION, _, _) => Ok(Value::Real(left.as_real() / right.as_real())),
+        (_, Value::Integer(a), Value::Integer(b)) => match op {
+            Operators::PLUS => integer(a.checked_add(b), a.wrapping_add(b), checks, span),
+            Operators::MINUS => integer(a.checked_sub(b), a.wrapping_sub(b), checks, span),
+            Operators::MULTIPLICATION => integer(a.checked_mul(b), a.wrapping_mul(b), checks, span),
+            Operators::IDIVISION if b == 0 => Err(division_by_zero(divisor)),
+            _ => integer(a.checked_div(b), a.wrapping_div(b), checks, span),
+        },
+        (Operators::IDIVISION, _, _) => Err(real_div_operand(span)),
+        (_, _, _) => {
+            let (a, b) = (left.as_real(), right.as_real());
+            Ok(Value::Real(match op {
+                Operators::PLUS => a + b,
+                Operators::MINUS => a - b,
+                _ => a * b,
+            }))
+        }
+    }
+}
+
+/// `value`, computed by the expression at `at`, converted to `ty` to be
+/// assigned to the variable or parameter `name`.
+fn assigned(ty: Type, value: Value, name: &str, checks: Checks, at: Span) -> Result<Value, Diagnostic> {
+    match (ty, value) {
+        (Type::Real, value) => Ok(Value::Real(value.as_real())),
+        (Type::Integer, Value::Integer(value)) => Ok(Value::Integer(value)),
+        (Type::Integer, Value::Real(value)) if !checks.range || fits_integer(value) => Ok(Value::Integer(value as i64)),
+        (Type::Integer, Value::Real(value)) => Err(out_of_range(value, name, at)),
     }
+}
 
+/// The bindings `declarations` make. Variables start out as zero.
+fn declarations(declarations: &[Decl]) -> Vec<(String, Binding)> {
+    declarations
+        .iter()
+        .flat_map(|declaration| match declaration {
+            Decl::Var(var) => var.names
+                .iter()
+                .map(|name| (name.name.clone(), Binding::Variable { ty: var.ty.ty, value: Value::zero(var.ty.ty) }))
+                .collect(),
+            Decl::Procedure(procedure) => {
+                vec![(procedure.name.name.clone(), Binding::Procedure(Rc::new(procedure.clone())))]
+            }
+        })
+        .collect()
 }
 
+/// The result of INTEGER arithmetic: `checked` when overflow is checked,
+/// `wrapped` when it is not.
+fn integer(checked: Option<i64>, wrapped: i64, checks: Checks, span: Span) -> Result<Value, Diagnostic> {
+    match checked {
+        None if checks.overflow => Err(overflow(span)),
+        _ => Ok(Value::Integer(checked.unwrap_or(wrapped))),
+    }
+}
+
+/// Whether the REAL `value` is a whole number that fits in an INTEGER.
+pub fn fits_integer(value: f64) -> bool {
+    // 2^63 is the first REAL past the largest INTEGER.
+    value.fract() == 0.0 && value.abs() < 9223372036854775808.0
+}
+
+// The run-time errors, shared with the VM so that both report the same.
+
+pub fn not_found(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("Variable `{}` not found.", name))
+        .with_span(span)
+        .with_label("not declared in this scope")
+        .with_help(format!("declare `{}` in a VAR section", name))
+}
+
+pub fn not_a_variable(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("`{}` is a procedure, not a variable.", name))
+        .with_span(span)
+        .with_label("not a variable")
+}
+
+pub fn not_assignable(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("Cannot assign to procedure `{}`.", name))
+        .with_span(span)
+        .with_label("not a variable")
+}
+
+pub fn not_found_to_call(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("Procedure `{}` not found.", name))
+        .with_span(span)
+        .with_label("not declared in this scope")
+}
+
+pub fn not_a_procedure(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("`{}` is a variable, not a procedure.", name))
+        .with_span(span)
+        .with_label("not a procedure")
+}
+
+/// `span` is that of the divisor.
+pub fn division_by_zero(span: Span) -> Diagnostic {
+    Diagnostic::new("Division by zero.")
+        .with_span(span)
+        .with_label("this is zero")
+}
+
+pub fn real_div_operand(span: Span) -> Diagnostic {
+    Diagnostic::new("Operands of DIV must be INTEGER.")
+        .with_span(span)
+        .with_label("a REAL operand")
+        .with_help("divide REAL numbers with `/`")
+}
+
+pub fn overflow(span: Span) -> Diagnostic {
+    Diagnostic::new("Integer overflow.")
+        .with_span(span)
+        .with_label("does not fit in an INTEGER")
+        .with_help("overflow is checked because of `{$Q+}`")
+}
+
+/// What made a call to a procedure, for tracing errors raised in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    Program,
+    /// The initialization of the unit, by the name it is used by.
+    Unit(String),
+    Procedure(String),
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Program => write!(f, "the program"),
+            Self::Unit(name) => write!(f, "the initialization of unit `{}`", name),
+            Self::Procedure(name) => write!(f, "procedure `{}`", name),
+        }
+    }
+}
+
+/// Adds to `error`, raised in `procedure`, a note on the call to it at
+/// `span`. Notes on the calls further out follow.
+pub fn called(error: Diagnostic, procedure: &str, caller: &Caller, span: Span) -> Diagnostic {
+    let note = Diagnostic::new(format!("in procedure `{}`, called by {}", procedure, caller))
+        .with_span(span)
+        .with_label("called here");
+    error.with_note(note)
+}
+
+/// `name` is the INTEGER variable `value` is assigned to.
+pub fn out_of_range(value: f64, name: &str, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("Range check error: {} does not fit in INTEGER `{}`.", value, name))
+        .with_span(span)
+        .with_label("not a whole number in range")
+        .with_help("range is checked because of `{$R+}`; without it the value is truncated")
+}
+
+#[test]
+fn evaluates_expressions() {
+    use Value::{Integer, Real};
+    let cases = [
+        ("8 / 4 / 2", Real(1.0)),
+        ("8 DIV 4 * 2", Integer(4)),
+        ("8 DIV (4 * 2)", Integer(1)),
+        ("2 * 3 * 4 DIV 5", Integer(4)),
+        ("10 - 4 - 3", Integer(3)),
+        ("2 + 3 * 4", Integer(14)),
+        ("(2 + 3) * 4", Integer(20)),
+        ("7 / 2", Real(3.5)),
+        ("7 DIV 2", Integer(3)),
+        ("(0 - 7) DIV 2", Integer(-3)),
+        ("-2 * 3 + 10", Integer(4)),
+        ("- - 5 - 1", Integer(4)),
+        ("1 - -1", Integer(2)),
+        ("100 / 10 / 5 * 3", Real(6.0)),
+        ("3.5 * 2 - 1 / 4", Real(6.75)),
+        ("2 * 1.5", Real(3.0)),
+    ];
+    let interpreter = Interpreter::session();
+    for (source, expected) in cases {
+        let expr = Parser::new(source.as_bytes()).unwrap().parse_expression().unwrap();
+        assert_eq!(interpreter.evaluate(&expr), Ok(expected), "{}", source);
+    }
+}
+
+#[test]
+fn names_ignore_case() {
+    let source = "program p; var Total, b : integer; procedure Show; begin end;
+        begin TOTAL := 2; total := Total * 3; B := total end.";
+    let interpreter = Interpreter::new(source.as_bytes()).unwrap();
+    interpreter.interprete().unwrap();
+    let globals = interpreter
+        .globals()
+        .iter()
+        .map(|(name, binding)| format!("{} : {}", name, binding))
+        .collect::<Vec<_>>();
+    assert_eq!(globals, ["b : INTEGER = 6", "Show : PROCEDURE", "Total : INTEGER = 6"]);
+
+    let statements = Parser::new(b"SHOW := 1").unwrap().parse_statements().unwrap();
+    let error = interpreter.execute(&statements).unwrap_err();
+    assert_eq!(error.message(), "Cannot assign to procedure `Show`.");
+}
+
+#[test]
+fn directives_switch_checks() {
+    let run = |statements: &str| {
+        let source = format!("PROGRAM p; VAR i : INTEGER; r : REAL; BEGIN {} END.", statements);
+        let interpreter = Interpreter::new(source.as_bytes()).unwrap();
+        interpreter.interprete().map(|()| {
+            interpreter.globals().iter().map(|(name, binding)| format!("{} : {}", name, binding)).collect::<Vec<_>>()
+        })
+    };
+    let error = |statements: &str| run(statements).unwrap_err().message().to_string();
+
+    assert_eq!(run("i := 9223372036854775807; i := i + 1").unwrap()[0], "i : INTEGER = -9223372036854775808");
+    assert_eq!(error("{$Q+} i := 9223372036854775807; i := i + 1"), "Integer overflow.");
+    assert_eq!(error("i := 3037000500; {$OVERFLOWCHECKS ON} i := i * i"), "Integer overflow.");
+    assert!(run("{$Q+} i := 9223372036854775807; {$Q-} i := i + 1").is_ok());
+
+    assert_eq!(run("i := 7 / 2; r := i").unwrap(), ["i : INTEGER = 3", "r : REAL = 3"]);
+    assert_eq!(run("{$R+} i := 10 / 4 * 2").unwrap()[0], "i : INTEGER = 5");
+    assert_eq!(error("{$R+} i := 7 / 2"), "Range check error: 3.5 does not fit in INTEGER `i`.");
+    assert_eq!(error("{$R+} i := 1e19"), "Range check error: 10000000000000000000 does not fit in INTEGER `i`.");
+
+    assert_eq!(error("i := 1 DIV (2 - 2)"), "Division by zero.");
+    assert_eq!(error("r := 1.5 / 0"), "Division by zero.");
+    assert_eq!(error("r := 3.0 DIV 2"), "Operands of DIV must be INTEGER.");
+}
+
+#[test]
+fn units_export_their_interface_only() {
+    let unit = |source: &str| Parser::new(source.as_bytes()).unwrap().parse_unit().unwrap();
+    let base = unit("UNIT Base; INTERFACE VAR step : INTEGER; IMPLEMENTATION BEGIN step := 3 END.");
+    let counter = unit(
+        "UNIT Counter; INTERFACE USES Base; VAR count : INTEGER;
+        IMPLEMENTATION VAR secret : INTEGER; BEGIN secret := 1; count := step * 10 + secret END.",
+    );
+    let run = |body: &str| {
+        let source = format!("PROGRAM p; USES Counter; VAR n : INTEGER; BEGIN {} END.", body);
+        let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+        let interpreter = Interpreter::with_units(program, vec![base.clone(), counter.clone()]);
+        interpreter.interprete().map(|()| {
+            interpreter.globals().iter().map(|(name, binding)| format!("{} : {}", name, binding)).collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(run("n := count + 1").unwrap(), ["n : INTEGER = 32"]);
+    assert_eq!(run("n := secret").unwrap_err().message(), "Variable `secret` not found.");
+    assert_eq!(run("n := step").unwrap_err().message(), "Variable `step` not found.");
+}
+
+#[test]
+fn calls_the_host_like_the_vm() {
+    use super::bytecode;
+    use super::vm::Vm;
+
+    let mut host = Host::new();
+    host.function("Half", &[Type::Real], Type::Real, |args| Ok(Value::Real(args[0].as_real() / 2.0)))
+        .function("Answer", &[], Type::Integer, |_| Ok(Value::Integer(42)))
+        .procedure("Check", &[Type::Integer], |args| match args[0] {
+            Value::Integer(value) if value > 0 => Ok(()),
+            value => Err(format!("{} is not positive.", value)),
+        });
+    let run = |body: &str| {
+        let source = format!("PROGRAM p; VAR i, answer : INTEGER; r : REAL;\nBEGIN\n{}\nEND.", body);
+        let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+        let chunk = bytecode::compile_with_host(&program, &[], &host).unwrap();
+        let slots = chunk.slots.iter().map(|(_, ty)| Value::zero(*ty)).collect();
+        let mut vm = Vm::with_host(&chunk, slots, &host);
+        let from_vm = vm.run().map(|()| format!("{:?}", vm.globals())).map_err(|error| error.to_string());
+        let interpreter = Interpreter::from_program(program).with_host(&host);
+        let tree = interpreter.interprete().map(|()| {
+            let globals = interpreter.globals().into_iter().map(|(name, binding)| match binding {
+                Binding::Variable { value, .. } => (name, Some(value)),
+                Binding::Procedure(_) => (name, None),
+            });
+            format!("{:?}", globals.collect::<Vec<_>>())
+        });
+        assert_eq!(tree.map_err(|error| error.to_string()), from_vm, "{}", body);
+    };
+    run("i := Answer + 1; r := Half(i); Check(i); Half(3)");
+    run("answer := Answer + 1");
+    run("i := 1; Check(i - 5); i := 2");
+    run("r := Half(Answer DIV 4) * 2");
+
+    // What the compiler rejects fails when it runs.
+    let source = b"PROGRAM p; VAR i : INTEGER; BEGIN i := 1; i := Check(Half(1, 2)) END.";
+    let program = Parser::new(source).unwrap().parse().unwrap();
+    let interpreter = Interpreter::from_program(program).with_host(&host);
+    assert_eq!(interpreter.interprete().unwrap_err().message(), "`Check` is a procedure, it gives no value.");
+    assert!(matches!(interpreter.globals()[0].1, Binding::Variable { value: Value::Integer(1), .. }));
+}
+
+#[test]
+fn calls_procedures_in_the_scope_they_are_declared_in() {
+    let run = |body: &str| {
+        let source = format!(
+            "PROGRAM p; VAR i : INTEGER; r : REAL;
+            PROCEDURE Outer(n : INTEGER); VAR i : INTEGER;
+              PROCEDURE Inner(x : REAL); BEGIN i := n; r := x + i END;
+            BEGIN i := 5; Inner(n * 2); r := r + i END;
+            PROCEDURE Other; VAR n : INTEGER; BEGIN n := 9; Outer(1) END;
+            BEGIN {} END.",
+            body
+        );
+        let interpreter = Interpreter::new(source.as_bytes()).unwrap();
+        interpreter.interprete().map(|()| {
+            interpreter.globals().iter().map(|(name, binding)| format!("{} : {}", name, binding)).collect::<Vec<_>>()
+        })
+    };
+    let globals = run("i := 3; Other()").unwrap();
+    assert_eq!(globals, ["i : INTEGER = 3", "Other : PROCEDURE", "Outer : PROCEDURE", "r : REAL = 4"]);
+    assert_eq!(run("Outer(2.5)").unwrap()[3], "r : REAL = 8");
+
+    let error = |body: &str| run(body).unwrap_err().message().to_string();
+    assert_eq!(error("{$R+} Outer(2.5)"), "Range check error: 2.5 does not fit in INTEGER `n`.");
+    assert_eq!(error("Outer(1, 2)"), "`Outer` takes 1 argument, but 2 were given.");
+    assert_eq!(error("Inner(1)"), "Procedure `Inner` not found.");
+    assert_eq!(error("i := Other()"), "`Other` is a procedure, it gives no value.");
+    assert_eq!(error("r(1)"), "`r` is a variable, not a procedure.");
+}
+
+#[test]
+fn stops_calls_nested_past_their_limit() {
+    let source = "PROGRAM p; VAR i : INTEGER; PROCEDURE Deeper; BEGIN i := i + 1; BEGIN Deeper() END END;
+        BEGIN Deeper() END.";
+    let depth = |calls: usize| {
+        limits::with_stack(DEFAULT_DEPTH, calls, || {
+            let interpreter = Interpreter::new(source.as_bytes()).unwrap().with_max_calls(calls);
+            let error = interpreter.interprete().unwrap_err();
+            assert_eq!(error.limit(), Some(limits::Limit::Calls));
+            match interpreter.globals()[1].1 {
+                Binding::Variable { value: Value::Integer(i), .. } => i,
+                _ => unreachable!("`i` is an INTEGER"),
+            }
+        })
+    };
+    assert_eq!(depth(DEFAULT_CALLS), DEFAULT_CALLS as i64);
+    assert_eq!(depth(3), 3);
+}
@@ -1,33 +1,76 @@
-use std::iter::Peekable;
-use std::slice::Iter;
-use super::ast::proc::{Procedure, ProcedureCall};
+use std::borrow::Cow;
+
+use super::err::diagnostic::Diagnostic;
+use super::source::FileId;
+
+/// A region of the source text. `start` and `end` are byte offsets,
+/// `line_no` and `column` locate the first character of the region. Columns
+/// count characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line_no: usize,
+    pub column: usize,
+    /// The file the region is in, as numbered by its `SourceMap`. Spans made
+    /// with `new` are in file 0, the file being compiled.
+    pub file: FileId,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line_no: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line_no,
+            column,
+            file: 0,
+        }
+    }
+
+    pub fn in_file(self, file: FileId) -> Self {
+        Self { file, ..self }
+    }
+
+    /// Returns a span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start {
+            return other.to(self);
+        }
+        Span {
+            end: self.end.max(other.end),
+            ..self
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Token {
     token_type: TokenType,
-    line_no: usize,
-    column: usize,
+    span: Span,
 }
 
-impl Token {
-    pub fn new(token_type: TokenType, lexer: &Lexer, len: Option<usize>) -> Self{
-        let offset = if len.is_some(){ 
-            unsafe{len.unwrap_unchecked()}
-        } else {
-            0
-        };
-        Self {
-            token_type,
-            line_no: lexer.line_no,
-            column: lexer.column - offset
-        }
+/// Tokens are equal when they have the same type and value, wherever they
+/// are in the source.
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.token_type == other.token_type
     }
+}
 
-    pub fn new_with_details(token_type: TokenType, line_no: usize, column: usize) -> Self{
+impl Token {
+    pub fn new(token_type: TokenType, span: Span) -> Self{
         Self {
             token_type,
-            line_no,
-            column
+            span
         }
     }
 
@@ -39,20 +82,27 @@ impl Token {
         &mut self.token_type
     }
 
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
     pub fn line_no(&self) -> usize{
-        self.line_no
+        self.span.line_no
     }
     
     pub fn column(&self) -> usize {
-        self.column
+        self.span.column
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     EOF,
-    Integer(f64),
-    Real(usize),
+    /// A number, and its text as written.
+    IntegerConst(i64, String),
+    RealConst(f64, String),
+    INTEGER,
+    REAL,
     Operator(Operators),
     LPAREN,
     COLON,
@@ -63,43 +113,55 @@ pub enum TokenType {
     SEMICOLON,
     DOT,
     ASSIGN,
-    EMPTY,
     PROGRAM,
     COMMA,
     VAR,
-    PROCEDURE(Option<Box<Procedure>>),
-    PROCEDURECALL(Box<ProcedureCall>),
+    PROCEDURE,
+    UNIT,
+    INTERFACE,
+    IMPLEMENTATION,
+    USES,
+    /// Only produced by `Lexer::with_comments`. The text includes the
+    /// delimiters.
+    COMMENT(String),
+    /// A comment whose text starts with `$`, such as `{$R+}`. Unlike other
+    /// comments it is always produced; the text includes the delimiters.
+    DIRECTIVE(String),
 }
 
 impl TokenType{
     pub fn equal(&self, other: &Self) -> bool {
-        match (self,other) {
-            (Self::EOF, Self::EOF) => true,
-            (Self::Integer(_),Self::Integer(_)) => true,      
-            (Self::Real(_),Self::Real(_)) => true,
-            (Self::Operator(_),Self::Operator(_)) => true,    
-            (Self::LPAREN, Self::LPAREN) => true,
-            (Self::COLON, Self::COLON) => true,
-            (Self::RPAREN, Self::RPAREN) => true,
-            (Self::BEGIN, Self::BEGIN) => true,
-            (Self::END, Self::END) => true,
-            (Self::IDENTIFIER(_),Self::IDENTIFIER(_)) => true,
-            (Self::SEMICOLON, Self::SEMICOLON) => true,       
-            (Self::DOT, Self::DOT) => true,
-            (Self::ASSIGN, Self::ASSIGN) => true,
-            (Self::EMPTY, Self::EMPTY) => true,
-            (Self::PROGRAM, Self::PROGRAM) => true,
-            (Self::COMMA, Self::COMMA) => true,
-            (Self::VAR, Self::VAR) => true,
-            (Self::PROCEDURE(_),Self::PROCEDURE(_)) => true,
-            _ => false
-        }
+        std::mem::discriminant(self) == std::mem::discriminant(other)
     }
-    
-    pub fn is_procedure(&self) -> bool{
+
+    /// How the token is spelled in source, for use in diagnostics.
+    pub fn describe(&self) -> String {
         match self {
-            Self::PROCEDURE(_) => true,
-            _ => false
+            Self::EOF => "end of file".to_string(),
+            Self::IntegerConst(_, text) => format!("integer `{}`", text),
+            Self::RealConst(_, text) => format!("real `{}`", text),
+            Self::INTEGER => "`INTEGER`".to_string(),
+            Self::REAL => "`REAL`".to_string(),
+            Self::Operator(o) => format!("`{}`", o.symbol()),
+            Self::LPAREN => "`(`".to_string(),
+            Self::COLON => "`:`".to_string(),
+            Self::RPAREN => "`)`".to_string(),
+            Self::BEGIN => "`BEGIN`".to_string(),
+            Self::END => "`END`".to_string(),
+            Self::IDENTIFIER(i) => format!("identifier `{}`", i),
+            Self::SEMICOLON => "`;`".to_string(),
+            Self::DOT => "`.`".to_string(),
+            Self::ASSIGN => "`:=`".to_string(),
+            Self::PROGRAM => "`PROGRAM`".to_string(),
+            Self::COMMA => "`,`".to_string(),
+            Self::VAR => "`VAR`".to_string(),
+            Self::PROCEDURE => "`PROCEDURE`".to_string(),
+            Self::UNIT => "`UNIT`".to_string(),
+            Self::INTERFACE => "`INTERFACE`".to_string(),
+            Self::IMPLEMENTATION => "`IMPLEMENTATION`".to_string(),
+            Self::USES => "`USES`".to_string(),
+            Self::COMMENT(_) => "comment".to_string(),
+            Self::DIRECTIVE(text) => format!("directive `{}`", text),
         }
     }
 }
@@ -113,46 +175,160 @@ pub enum Operators {
     MULTIPLICATION,
 }
 
+impl Operators {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::PLUS => "+",
+            Self::MINUS => "-",
+            Self::IDIVISION => "DIV",
+            Self::FDIVISION => "/",
+            Self::MULTIPLICATION => "*",
+        }
+    }
+
+    /// How tightly the operator binds as a binary operator; higher binds
+    /// tighter. Every binary operator is left-associative.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Self::PLUS | Self::MINUS => 1,
+            Self::MULTIPLICATION | Self::FDIVISION | Self::IDIVISION => 2,
+        }
+    }
+}
+
+/// A sign applies to a whole term, as in Pascal: `-a * b` is `-(a * b)`.
+/// The operand of a unary `+` or `-` is parsed at this precedence.
+pub const SIGN_OPERAND_PRECEDENCE: u8 = 2;
+
 pub struct Lexer<'a> {
-    stream: Peekable<Iter<'a, u8>>,
+    bytes: Cow<'a, [u8]>,
     current_char: Option<char>,
     next_char: Option<char>,
+    /// Length in bytes of `current_char` in the source.
+    current_len: usize,
+    /// Whether `current_char` stands for a sequence of bytes that is not
+    /// valid UTF-8. It is read as U+FFFD so that lexing can go on.
+    invalid: bool,
+    /// Byte offset of `current_char`.
+    pos: usize,
     line_no: usize,
-    column: usize
+    column: usize,
+    start: Span,
+    keep_comments: bool,
+    file: FileId,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(bytes: &'a [u8]) -> Self {
-        let mut iter = bytes.iter().peekable();
-        let current_char = match iter.next() {
-            Some(val) => Some(*val as char),
-            None => None,
-        };
-        let next_char = match iter.peek(){
-            Some(val) => Some(**val as char),
-            None => None
+        Self::from_cow(Cow::Borrowed(bytes))
+    }
+
+    /// A lexer over bytes it keeps, such as those of an included file.
+    pub fn owned(bytes: Vec<u8>) -> Lexer<'static> {
+        Lexer::from_cow(Cow::Owned(bytes))
+    }
+
+    fn from_cow(bytes: Cow<'a, [u8]>) -> Self {
+        let mut lexer = Self {
+            bytes,
+            current_char: None,
+            next_char: None,
+            current_len: 0,
+            invalid: false,
+            pos: 0,
+            line_no: 1,
+            column: 1,
+            start: Span::new(0, 0, 1, 1),
+            keep_comments: false,
+            file: 0,
         };
+        lexer.load();
+        lexer
+    }
+
+    /// A lexer that hands out comments as `COMMENT` tokens instead of
+    /// skipping them, for tools that need to keep them.
+    pub fn with_comments(bytes: &'a [u8]) -> Self {
+        Self::new(bytes).keep_comments()
+    }
+
+    pub fn keep_comments(self) -> Self {
         Self {
-            stream: iter,
-            current_char,
-            next_char,
-            line_no: 1,
-            column: 1
+            keep_comments: true,
+            ..self
         }
     }
 
+    /// Tags every span with `file`, the number of the source in its
+    /// `SourceMap`.
+    pub fn in_file(self, file: FileId) -> Self {
+        Self { file, ..self }
+    }
+
+    /// The source text from byte offset `start` to `end`, with invalid
+    /// UTF-8 replaced.
+    pub fn text(&self, start: usize, end: usize) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes[start..end])
+    }
+
     fn advance(&mut self) {
-        self.column += 1;
-        self.current_char = match self.stream.next() {
-            Some(val) => Some(*val as char),
-            None => None,
+        if let Some(char) = self.current_char {
+            self.pos += self.current_len;
+            if char == '\n' {
+                self.line_no += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.load();
+    }
+
+    /// Decodes the characters at `pos`.
+    fn load(&mut self) {
+        let current = decode(&self.bytes[self.pos..]);
+        (self.current_char, self.current_len) = match current {
+            None => (None, 0),
+            Some(Ok(char)) => (Some(char), char.len_utf8()),
+            Some(Err(len)) => (Some(char::REPLACEMENT_CHARACTER), len),
         };
-        self.next_char = match self.stream.peek() {
-            Some( val) => Some(**val as char),
-            None => None,
+        self.invalid = matches!(current, Some(Err(_)));
+        self.next_char = decode(&self.bytes[self.pos + self.current_len..])
+            .map(|next| next.unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+
+    /// The error for an invalid UTF-8 sequence at the current character.
+    fn invalid_utf8(&self) -> Diagnostic {
+        let bytes = &self.bytes[self.pos..self.pos + self.current_len];
+        let escaped = bytes.iter().map(|byte| format!("\\x{:02X}", byte)).collect::<String>();
+        Diagnostic::new(format!("Invalid UTF-8 sequence `{}` in source.", escaped))
+            .with_span(self.here(self.current_len))
+            .with_label("not valid UTF-8")
+            .with_help("source files must be encoded as UTF-8")
+    }
+
+    /// The span of the `len` bytes at the current character.
+    fn here(&self, len: usize) -> Span {
+        Span::new(self.pos, self.pos + len, self.line_no, self.column).in_file(self.file)
+    }
+
+    /// Marks the current character as the first one of the next token.
+    fn mark(&mut self) {
+        self.start = self.here(0);
+    }
+
+    /// The span from the last `mark` up to the current character.
+    fn span(&self) -> Span {
+        Span {
+            end: self.pos,
+            ..self.start
         }
     }
 
+    fn token(&self, token_type: TokenType) -> Token {
+        Token::new(token_type, self.span())
+    }
+
     fn id(&mut self) -> Token {
         let mut result = String::new();
         while let Some(char) = self.current_char {
@@ -163,31 +339,27 @@ impl<'a> Lexer<'a> {
                 break
             }
         }
-        let len = result.len();
-        match &*result {
-            "BEGIN" => Token::new(TokenType::BEGIN, &self, Some(result.len())),
-            "END" => Token::new(TokenType::END, &self, Some(result.len())),
-            "DIV" => Token::new(TokenType::Operator(Operators::IDIVISION), &self, Some(result.len())),
-            "PROGRAM" => Token::new(TokenType::PROGRAM, &self, Some(result.len())),
-            "PROCEDURE" => Token::new(TokenType::PROCEDURE(
-                None
-            ),&self, Some(result.len())),
-            "VAR" => Token::new(TokenType::VAR, &self, Some(result.len())),
-            "REAL" => Token::new(TokenType::Real(0), &self, Some(result.len())),
-            "INTEGER" => Token::new(TokenType::Integer(0.0), &self, Some(result.len())),
-            _ => Token::new(TokenType::IDENTIFIER(result), &self, Some(len))
+        match &*result.to_ascii_uppercase() {
+            "BEGIN" => self.token(TokenType::BEGIN),
+            "END" => self.token(TokenType::END),
+            "DIV" => self.token(TokenType::Operator(Operators::IDIVISION)),
+            "PROGRAM" => self.token(TokenType::PROGRAM),
+            "PROCEDURE" => self.token(TokenType::PROCEDURE),
+            "VAR" => self.token(TokenType::VAR),
+            "REAL" => self.token(TokenType::REAL),
+            "INTEGER" => self.token(TokenType::INTEGER),
+            "UNIT" => self.token(TokenType::UNIT),
+            "INTERFACE" => self.token(TokenType::INTERFACE),
+            "IMPLEMENTATION" => self.token(TokenType::IMPLEMENTATION),
+            "USES" => self.token(TokenType::USES),
+            _ => self.token(TokenType::IDENTIFIER(result))
         }
 
     }
 
     fn skip_whitespace(&mut self) {
         while let Some(char) = self.current_char {
-            if char == '\n'{
-                self.column = 0;
-                self.line_no += 1;
-                self.advance()
-            }
-            else if char.is_ascii_whitespace() {
+            if char.is_ascii_whitespace() {
                 self.advance()
             } else {
                 break;
@@ -195,159 +367,379 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn integer(&mut self) -> Result<Token, String>{
-        let mut int = String::new();
-        int.push(self.current_char.unwrap());
-        self.advance();
-        let mut dot_count = 0;
-        while let Some(val) = self.current_char {
-            if val.is_numeric() {
-                int.push(val);
-                self.advance()
-            } else if val == '.'{
-                if dot_count == 0 {
-                    int.push(val);
-                    dot_count += 1;
-                    self.advance();
-                } else {
-                    return Err(format!("Cannot have two dots in integer. Position line_no: {}, column: {}",self.line_no, self.column))
-                }
-            } else {
-                break;
+    fn digits(&mut self) -> String {
+        let mut digits = String::new();
+        while let Some(char) = self.current_char.filter(char::is_ascii_digit) {
+            digits.push(char);
+            self.advance();
+        }
+        digits
+    }
+
+    /// Lexes an unsigned number the way Pascal writes them: digits, then
+    /// an optional fraction and an optional exponent, as in `42`, `3.14`,
+    /// `1e10` or `2.5E-3`. A `.` that is not followed by a digit is not part
+    /// of the number, so `3.` is `3` followed by `.`.
+    fn number(&mut self) -> Result<Token, Diagnostic> {
+        let mut text = self.digits();
+        let mut real = false;
+        let digit_follows = |lexer: &Self| lexer.next_char.is_some_and(|c| c.is_ascii_digit());
+        if self.current_char == Some('.') && digit_follows(self) {
+            self.advance();
+            text.push('.');
+            text.push_str(&self.digits());
+            real = true;
+            if self.current_char == Some('.') && digit_follows(self) {
+                return Err(
+                    Diagnostic::new("Cannot have two dots in a number.")
+                        .with_span(self.here(1))
+                        .with_help("a number can contain at most one `.`")
+                )
             }
         }
-        return Ok(
-            Token::new(
-                TokenType::Integer(int.parse::<f64>().unwrap()), &self, Some(int.len())
+        // An `e` followed by a letter starts a word, as in `2end`.
+        let word_follows = self.next_char.is_some_and(|c| c.is_alphabetic() || c == '_');
+        if let Some(e @ ('e' | 'E')) = self.current_char.filter(|_| !word_follows) {
+            self.advance();
+            text.push(e);
+            if let Some(sign @ ('+' | '-')) = self.current_char {
+                text.push(sign);
+                self.advance();
+            }
+            let exponent = self.digits();
+            if exponent.is_empty() {
+                return Err(
+                    Diagnostic::new("Expected digits in the exponent of a number.")
+                        .with_span(self.span())
+                        .with_help("an exponent is written like `1e10` or `2.5E-3`")
+                )
+            }
+            text.push_str(&exponent);
+            real = true;
+        }
+        if real {
+            // The text is made of digits, one `.` and an exponent, which
+            // always parses; only its size can be wrong.
+            let value = text.parse::<f64>().unwrap_or(f64::INFINITY);
+            let out_of_range = Diagnostic::new(format!("Real literal `{}` is out of range.", text)).with_span(self.span());
+            if value.is_infinite() {
+                return Err(
+                    out_of_range
+                        .with_label("does not fit in a REAL")
+                        .with_help(format!("the largest REAL is about {:e}", f64::MAX))
+                )
+            }
+            let mantissa = text.split(['e', 'E']).next().unwrap_or_default();
+            if value == 0.0 && mantissa.contains(|c| matches!(c, '1'..='9')) {
+                return Err(
+                    out_of_range
+                        .with_label("too small for a REAL, which would make it zero")
+                        .with_help(format!("the smallest REAL above zero is about {:e}", f64::from_bits(1)))
+                )
+            }
+            return Ok(self.token(TokenType::RealConst(value, text)));
+        }
+        self.integer_const(text.parse(), text)
+    }
+
+    /// Lexes a hexadecimal integer such as `$FF`.
+    fn hex(&mut self) -> Result<Token, Diagnostic> {
+        self.advance();
+        let mut digits = String::new();
+        while let Some(char) = self.current_char.filter(char::is_ascii_hexdigit) {
+            digits.push(char);
+            self.advance();
+        }
+        if digits.is_empty() {
+            return Err(
+                Diagnostic::new("Expected hexadecimal digits after `$`.")
+                    .with_span(self.span())
+                    .with_help("a hexadecimal number is written like `$FF`")
             )
-        );
+        }
+        self.integer_const(i64::from_str_radix(&digits, 16), format!("${}", digits))
     }
 
-    pub fn skip_comment(&mut self) {
-        while self.current_char != Some('}') {
-            self.advance()
+    fn integer_const(&self, value: Result<i64, std::num::ParseIntError>, text: String) -> Result<Token, Diagnostic> {
+        match value {
+            Ok(value) => Ok(self.token(TokenType::IntegerConst(value, text))),
+            Err(_) => Err(
+                Diagnostic::new("Integer literal is too large.")
+                    .with_span(self.span())
+                    .with_label("does not fit in an INTEGER")
+                    .with_help(format!("the largest INTEGER is {}", i64::MAX))
+            ),
         }
-        self.advance();
     }
 
-    pub fn get_current_character(&mut self) -> char {
-        self.skip_whitespace();
-        self.current_char.as_ref().unwrap_or(&' ').clone()
+    /// Lexes a comment: `{ ... }`, `(* ... *)`, or `//` up to the end of
+    /// the line. Comments do not nest; the first closing delimiter of the
+    /// same kind ends one, so `{ (* }` is a whole comment. A brace or
+    /// parenthesis comment starting with `$` is a directive.
+    fn comment(&mut self) -> Result<Token, Diagnostic> {
+        let (open, close) = match self.current_char {
+            Some('{') => ("{", "}"),
+            Some('(') => ("(*", "*)"),
+            _ => ("//", "\n"),
+        };
+        let mut text = String::new();
+        for _ in 0..open.len() {
+            text.extend(self.current_char);
+            self.advance();
+        }
+        // Bad bytes are reported once the comment is over, so that lexing
+        // picks up again after it.
+        let mut invalid = None;
+        loop {
+            match self.current_char {
+                None if close == "\n" => break,
+                None => return Err(
+                    Diagnostic::new("Unterminated comment.")
+                        .with_span(Span { end: self.start.start + open.len(), ..self.start })
+                        .with_label("comment starts here")
+                        .with_help(format!("close the comment with `{}`", close))
+                ),
+                Some('\n') if close == "\n" => break,
+                Some(char) => {
+                    if self.invalid && invalid.is_none() {
+                        invalid = Some(self.invalid_utf8());
+                    }
+                    text.push(char);
+                    self.advance();
+                    // `(*)` opens a comment without closing it.
+                    if text.len() >= open.len() + close.len() && text.ends_with(close) {
+                        break;
+                    }
+                }
+            }
+        }
+        match invalid {
+            Some(error) => Err(error),
+            None if close != "\n" && text[open.len()..].starts_with('$') => {
+                Ok(self.token(TokenType::DIRECTIVE(text)))
+            }
+            None => Ok(self.token(TokenType::COMMENT(text))),
+        }
+    }
+
+    pub fn get_next_token(&mut self) -> Result<Token, Diagnostic> {
+        loop {
+            let token = self.lex()?;
+            if self.keep_comments || !matches!(token.token_type(), TokenType::COMMENT(_)) {
+                return Ok(token);
+            }
+        }
     }
 
-    pub fn get_next_token(&mut self) -> Result<Token, String> {
+    fn lex(&mut self) -> Result<Token, Diagnostic> {
         self.skip_whitespace();
+        self.mark();
+        if self.invalid {
+            let error = self.invalid_utf8();
+            self.advance();
+            return Err(error);
+        }
         match self.current_char {
             Some('+') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::Operator(Operators::PLUS), &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::Operator(Operators::PLUS)))
             }
             Some('-') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::Operator(Operators::MINUS), &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::Operator(Operators::MINUS)))
             }
             Some('*') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::Operator(Operators::MULTIPLICATION), &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::Operator(Operators::MULTIPLICATION)))
             }
+            Some('/') if self.next_char == Some('/') => self.comment(),
+            Some('(') if self.next_char == Some('*') => self.comment(),
+            Some('{') => self.comment(),
             Some('/') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::Operator(Operators::FDIVISION), &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::Operator(Operators::FDIVISION)))
             }
             Some('(') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::LPAREN, &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::LPAREN))
             }
             Some(')') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::RPAREN, &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::RPAREN))
             }
             Some('.') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::DOT, &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::DOT))
             }
             Some(',') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::COMMA, &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::COMMA))
             }
             Some(';') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::SEMICOLON, &self, Some(1)
-                    )
-                )
+                Ok(self.token(TokenType::SEMICOLON))
             }
             Some(':') if self.next_char == Some('=') => {
                 self.advance();
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::ASSIGN, &self, Some(2)
-                    )
-                )
+                Ok(self.token(TokenType::ASSIGN))
             }
             Some(':') => {
                 self.advance();
-                Ok(
-                    Token::new(
-                        TokenType::COLON, &self, Some(1)
-                    )
-                )
-            }
-            Some('{') => {
-                self.advance();
-                self.skip_comment();
-                return self.get_next_token();
+                Ok(self.token(TokenType::COLON))
             }
+            Some('$') => self.hex(),
 
             Some(char) => {
-                if char.is_numeric() {
-                    Ok(self.integer()?)
+                if char.is_ascii_digit() {
+                    self.number()
                 } else if char.is_alphabetic() || char == '_' {
                     Ok(self.id())
                 } 
                 else {
-                    Err(format!("Cannot parse {}. Position: line_no: {}, column: {}", char, self.line_no, self.column))
+                    self.advance();
+                    Err(
+                        Diagnostic::new(format!("Cannot parse {}.", char))
+                            .with_span(self.span())
+                            .with_help("this character is not part of any token")
+                    )
                 }
             }
-            None => Ok(
-                Token::new(
-                    TokenType::EOF, &self, Some(1)
-                )
-            )
+            None => Ok(self.token(TokenType::EOF))
         }
     }
 }
+
+/// Decodes the first character of `bytes`. A sequence that is not valid
+/// UTF-8 comes back as `Err` with its length, as `str::from_utf8` counts it.
+fn decode(bytes: &[u8]) -> Option<Result<char, usize>> {
+    let head = &bytes[..bytes.len().min(4)];
+    let valid = match std::str::from_utf8(head) {
+        Ok(valid) => valid,
+        Err(e) if e.valid_up_to() > 0 => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(e) => return Some(Err(e.error_len().unwrap_or(head.len()))),
+    };
+    valid.chars().next().map(Ok)
+}
+
+#[test]
+fn spans() {
+    let mut lexer = Lexer::new("BEGIN\n  ab := 3.5\nEND".as_bytes());
+    let spans = std::iter::from_fn(|| {
+        let token = lexer.get_next_token().unwrap();
+        (!matches!(token.token_type(), TokenType::EOF)).then(|| token.span())
+    }).collect::<Vec<_>>();
+    assert_eq!(spans, vec![
+        Span::new(0, 5, 1, 1),
+        Span::new(8, 10, 2, 3),
+        Span::new(11, 13, 2, 6),
+        Span::new(14, 17, 2, 9),
+        Span::new(18, 21, 3, 1),
+    ]);
+}
+
+#[test]
+fn numbers() {
+    let lex = |source: &str| {
+        let mut lexer = Lexer::new(source.as_bytes());
+        std::iter::from_fn(|| {
+            let token = lexer.get_next_token().unwrap();
+            (!matches!(token.token_type(), TokenType::EOF)).then(|| token.token_type().clone())
+        }).collect::<Vec<_>>()
+    };
+    use TokenType::*;
+    let cases = [
+        ("42", vec![IntegerConst(42, "42".into())]),
+        ("2.75", vec![RealConst(2.75, "2.75".into())]),
+        ("1e10", vec![RealConst(1e10, "1e10".into())]),
+        ("2.5E-3", vec![RealConst(2.5e-3, "2.5E-3".into())]),
+        ("7e+2", vec![RealConst(700.0, "7e+2".into())]),
+        ("0e-400", vec![RealConst(0.0, "0e-400".into())]),
+        ("$FF $1a", vec![IntegerConst(255, "$FF".into()), IntegerConst(26, "$1a".into())]),
+        ("3.", vec![IntegerConst(3, "3".into()), DOT]),
+        ("1..2", vec![IntegerConst(1, "1".into()), DOT, DOT, IntegerConst(2, "2".into())]),
+        ("2end", vec![IntegerConst(2, "2".into()), END]),
+        ("2ex", vec![IntegerConst(2, "2".into()), IDENTIFIER("ex".to_string())]),
+        ("9223372036854775807", vec![IntegerConst(i64::MAX, "9223372036854775807".into())]),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(lex(source), expected, "{}", source);
+    }
+
+    let errors = [
+        ("9223372036854775808", "Integer literal is too large."),
+        ("$10000000000000000", "Integer literal is too large."),
+        ("1e400", "Real literal `1e400` is out of range."),
+        ("1e-400", "Real literal `1e-400` is out of range."),
+        ("1e+", "Expected digits in the exponent of a number."),
+        ("1e", "Expected digits in the exponent of a number."),
+        ("1E;", "Expected digits in the exponent of a number."),
+        ("$", "Expected hexadecimal digits after `$`."),
+        ("1.2.3", "Cannot have two dots in a number."),
+    ];
+    for (source, message) in errors {
+        let error = Lexer::new(source.as_bytes()).get_next_token().unwrap_err();
+        assert_eq!(error.message(), message, "{}", source);
+    }
+}
+
+#[test]
+fn comments() {
+    let source = "{ one\n two } a (* 2 * 3 *) b // to the end\nc (*}*) { (* }";
+    let mut lexer = Lexer::with_comments(source.as_bytes());
+    let tokens = std::iter::from_fn(|| {
+        let token = lexer.get_next_token().unwrap();
+        (!matches!(token.token_type(), TokenType::EOF)).then_some(token)
+    }).collect::<Vec<_>>();
+    let text = tokens
+        .iter()
+        .map(|t| match t.token_type() {
+            TokenType::COMMENT(text) => text.clone(),
+            TokenType::IDENTIFIER(name) => name.clone(),
+            other => other.describe(),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(text, ["{ one\n two }", "a", "(* 2 * 3 *)", "b", "// to the end", "c", "(*}*)", "{ (* }"]);
+    // Newlines inside comments still count.
+    assert_eq!((tokens[5].line_no(), tokens[5].column()), (3, 1));
+
+    let names = |source: &str| {
+        let mut lexer = Lexer::new(source.as_bytes());
+        std::iter::from_fn(|| match lexer.get_next_token().unwrap().token_type() {
+            TokenType::IDENTIFIER(name) => Some(name.clone()),
+            _ => None,
+        }).collect::<Vec<_>>()
+    };
+    assert_eq!(names("a { b } c (* d *) e // f"), ["a", "c", "e"]);
+
+    for (source, column) in [("a\n  { open", 3), ("(* open *", 1), ("x (*)", 3)] {
+        let mut lexer = Lexer::new(source.as_bytes());
+        let error = std::iter::from_fn(|| Some(lexer.get_next_token())).find_map(Result::err).unwrap();
+        assert_eq!(error.message(), "Unterminated comment.");
+        assert_eq!(error.span().unwrap().column, column, "{}", source);
+    }
+}
+
+#[test]
+fn columns_count_characters() {
+    let source = "{ ünïcødé }\nnäme := 1 { ok } x";
+    let mut lexer = Lexer::new(source.as_bytes());
+    let name = lexer.get_next_token().unwrap();
+    assert_eq!(name.token_type(), &TokenType::IDENTIFIER("näme".to_string()));
+    assert_eq!(name.span(), Span::new(16, 21, 2, 1));
+    let assign = lexer.get_next_token().unwrap();
+    assert_eq!(assign.span(), Span::new(22, 24, 2, 6));
+    lexer.get_next_token().unwrap();
+    assert_eq!(lexer.get_next_token().unwrap().column(), 18);
+
+    let mut lexer = Lexer::new(b"a \xFF\xFE b { \xC3 } c");
+    lexer.get_next_token().unwrap();
+    let error = lexer.get_next_token().unwrap_err();
+    assert_eq!(error.message(), "Invalid UTF-8 sequence `\\xFF` in source.");
+    assert_eq!(error.span(), Some(Span::new(2, 3, 1, 3)));
+    assert_eq!(lexer.get_next_token().unwrap_err().span(), Some(Span::new(3, 4, 1, 4)));
+    assert_eq!(lexer.get_next_token().unwrap().token_type(), &TokenType::IDENTIFIER("b".to_string()));
+    let error = lexer.get_next_token().unwrap_err();
+    assert_eq!(error.span(), Some(Span::new(9, 10, 1, 10)));
+    assert_eq!(lexer.get_next_token().unwrap().token_type(), &TokenType::IDENTIFIER("c".to_string()));
+}
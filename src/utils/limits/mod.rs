@@ -0,0 +1,196 @@
+//! Bounds on what compiling and running a program may use, so that programs
+//! nobody has checked can be run safely.
+//!
+//! Source code may only nest so deep, `DEFAULT_DEPTH` unless set otherwise:
+//! every pass over the syntax tree recurses into it, and would overflow the
+//! stack on a tree deep enough. Code that goes over a tree runs on a thread
+//! of its own, `with_stack`, whose stack is sized for the depth allowed.
+//! Runs of binary operators such as `a + b + c` do not count, since passes
+//! go along them without recursing.
+//! Calls may only nest so deep, `DEFAULT_CALLS` unless set otherwise, for
+//! the same reason: the tree walker and compiled programs recurse into every
+//! call, and a procedure that calls itself, which with no `IF` it does
+//! forever, would overflow their stack. `with_stack` makes room for those
+//! calls too.
+//! What a program compiles to may only be so large, `DEFAULT_CODE`
+//! instructions unless set otherwise, so that compiling a program takes
+//! memory in proportion to what it may run.
+//! The other limits are off unless whoever runs the program sets them.
+//! Programs have no loops, so each runs in time and memory proportional to
+//! its size and the calls it nests. The limits cap both, and time spent in
+//! host functions.
+
+use std::time::{Duration, Instant};
+
+use super::err::diagnostic::Diagnostic;
+use super::interpreter::Value;
+use super::lexer::Span;
+
+/// How deeply code may nest by default.
+pub const DEFAULT_DEPTH: usize = 1000;
+
+/// How deeply calls may nest by default.
+pub const DEFAULT_CALLS: usize = 1000;
+
+/// How many instructions a program may compile to by default.
+pub const DEFAULT_CODE: usize = 1_000_000;
+
+/// The stack a level of nesting may take in the passes over the tree. A
+/// debug build takes up to about 16 KiB.
+const STACK_PER_LEVEL: usize = 64 << 10;
+
+/// The stack a call may take in the tree walker, besides the levels of
+/// nesting around it. A debug build takes about 5 KiB.
+const STACK_PER_CALL: usize = 16 << 10;
+
+/// The stack for the rest of what `with_stack` runs.
+const BASE_STACK: usize = 8 << 20;
+
+/// Runs `f`, which goes over syntax trees nested at most `depth` levels
+/// deep and walks at most `calls` nested calls, on a thread with the stack
+/// that takes. The stack is only reserved: memory is used as it grows.
+///
+/// # Panics
+///
+/// If the thread cannot be started, which a `depth` or `calls` too large
+/// for the address space makes it, and if `f` does.
+pub fn with_stack<T: Send>(depth: usize, calls: usize, f: impl FnOnce() -> T + Send) -> T {
+    let stack = depth
+        .saturating_mul(STACK_PER_LEVEL)
+        .saturating_add(calls.saturating_mul(STACK_PER_CALL))
+        .saturating_add(BASE_STACK);
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(stack)
+            .spawn_scoped(scope, f)
+            .expect("cannot start a thread with the stack nested code needs")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Which limit a program hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The source nests deeper than `Limits::depth`.
+    Depth,
+    /// The program nested more calls than `Limits::calls`.
+    Calls,
+    /// The program compiles to more instructions than `Limits::code`.
+    Code,
+    /// The program ran more instructions than it may.
+    Instructions,
+    /// The values the program holds take more memory than it may use.
+    Memory,
+    /// The program ran for longer than it may.
+    Time,
+}
+
+/// The limits on compiling and running a program. `None` means no limit,
+/// as by default for those that can be off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply the source may nest: parentheses, signs, operators of a
+    /// higher precedence than the one before them, blocks and procedures
+    /// all count. Compiling reserves 64 KiB of stack for each level.
+    pub depth: usize,
+    /// How many calls may be running at once.
+    pub calls: usize,
+    /// How many instructions the program, its procedures and the units it
+    /// uses included, may compile to.
+    pub code: usize,
+    /// How many instructions the program may run.
+    pub instructions: Option<u64>,
+    /// How many bytes the variables of the program, the locals of the
+    /// procedures it calls and its stack of intermediate values may take.
+    pub memory: Option<usize>,
+    /// How long the program may run, host functions included. A host
+    /// function is not interrupted; the program stops once it returns.
+    pub time: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_DEPTH,
+            calls: DEFAULT_CALLS,
+            code: DEFAULT_CODE,
+            instructions: None,
+            memory: None,
+            time: None,
+        }
+    }
+}
+
+/// How often, in instructions, a run looks at the time.
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+/// Keeps track of what a run of the virtual machine has used, against its
+/// limits.
+pub struct Clock {
+    limits: Limits,
+    started: Instant,
+    executed: u64,
+    /// How many variables the program has, each holding a value.
+    slots: usize,
+}
+
+impl Clock {
+    pub fn start(limits: &Limits, slots: usize) -> Self {
+        Self { limits: *limits, started: Instant::now(), executed: 0, slots }
+    }
+
+    /// Counts one more instruction, at `span`, run with `stack` values on
+    /// the stack and among the locals of frames.
+    pub fn tick(&mut self, stack: usize, span: impl Fn() -> Span) -> Result<(), Diagnostic> {
+        self.executed += 1;
+        if let Some(instructions) = self.limits.instructions {
+            if self.executed > instructions {
+                let message = format!("The program ran more than {} instructions.", instructions);
+                return Err(stopped(message, Limit::Instructions, span()));
+            }
+        }
+        if let Some(memory) = self.limits.memory {
+            if (self.slots + stack) * std::mem::size_of::<Value>() > memory {
+                let message = format!("The program needs more than {} bytes of memory.", memory);
+                return Err(stopped(message, Limit::Memory, span()));
+            }
+        }
+        if self.executed.is_multiple_of(TIME_CHECK_INTERVAL) {
+            self.check_time(span)?;
+        }
+        Ok(())
+    }
+
+    /// Fails if the run has taken longer than it may, stopping at `span`.
+    pub fn check_time(&self, span: impl Fn() -> Span) -> Result<(), Diagnostic> {
+        match self.limits.time {
+            Some(time) if self.started.elapsed() > time => {
+                Err(stopped(format!("The program ran for longer than {:?}.", time), Limit::Time, span()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The error of a call made while `calls` calls are running, at `span`.
+pub fn too_many_calls(calls: usize, span: Span) -> Diagnostic {
+    stopped(format!("The program nested more than {} calls.", calls), Limit::Calls, span)
+}
+
+/// The error of code that takes what it compiles to past `code`
+/// instructions, at `span`.
+pub fn too_much_code(code: usize, span: Span) -> Diagnostic {
+    Diagnostic::new(format!("The program compiles to more than {} instructions.", code))
+        .with_span(span)
+        .with_label("compiled past the limit here")
+        .with_help("split the program up")
+        .with_limit(Limit::Code)
+}
+
+fn stopped(message: String, limit: Limit, span: Span) -> Diagnostic {
+    Diagnostic::new(message)
+        .with_span(span)
+        .with_label("stopped here")
+        .with_limit(limit)
+}
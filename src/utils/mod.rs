@@ -2,4 +2,17 @@ pub mod interpreter;
 pub mod lexer;
 pub mod ast;
 pub mod parser;
-pub mod err;
\ No newline at end of file
+pub mod err;
+pub mod formatter;
+pub mod repl;
+pub mod preprocessor;
+pub mod source;
+pub mod units;
+pub mod bytecode;
+pub mod vm;
+pub mod optimizer;
+pub mod backend;
+pub mod host;
+pub mod limits;
+#[cfg(test)]
+pub mod corpus;
@@ -0,0 +1,372 @@
+//! Simplifies a program before it runs, without changing what it does.
+//!
+//! - Operations on constants are done here: `10 * 4 + 2` becomes `42`.
+//! - Operations that leave a value as it is go: `a * 1`, `a - 0`, `+a`.
+//! - Statements the program can never reach, after one that always fails,
+//!   are removed, and so are empty statements. Nested `BEGIN ... END`
+//!   blocks are merged into the block around them.
+//!
+//! Whatever would fail at run time still does, at the same place and with
+//! the same error: an operation on constants is left alone if it divides
+//! by zero, overflows while overflow is checked, or gives a REAL that no
+//! literal can write, such as infinity. Simplifying `a + 0` to `a` is only
+//! done when `a` is an INTEGER, since for a REAL it turns -0 into 0.
+//!
+//! There is no constant propagation and no removal of untaken branches:
+//! the language has neither `CONST` declarations nor `IF` statements.
+
+use std::collections::HashMap;
+
+use super::ast::decl::{Decl, Type};
+use super::ast::expr::{Expr, UnaryOp};
+use super::ast::program::Program;
+use super::ast::proc::Procedure;
+use super::ast::stmt::{Checks, Compound, Stmt};
+use super::ast::unit::Unit;
+use super::ast::visit_mut::{self, MutVisitor};
+use super::interpreter::Value;
+use super::lexer::{Operators, Span};
+
+pub fn optimize(program: &mut Program) {
+    Optimizer::default().visit_program(program)
+}
+
+pub fn optimize_unit(unit: &mut Unit) {
+    Optimizer::default().visit_unit(unit)
+}
+
+#[derive(Default)]
+struct Optimizer {
+    /// The types of the variables declared where the code being simplified
+    /// is, by `ident::key`. Names from elsewhere are not known.
+    types: HashMap<String, Type>,
+    /// The checks in force in the statement being simplified.
+    checks: Checks,
+    /// Set when an operation on constants is kept because it fails.
+    fails: bool,
+    /// Set after a statement that always fails.
+    unreachable: bool,
+}
+
+impl Optimizer {
+    fn declare<'d>(&mut self, declarations: impl IntoIterator<Item = &'d Decl>) {
+        for declaration in declarations {
+            match declaration {
+                Decl::Var(var) => {
+                    for name in &var.names {
+                        self.types.insert(name.key(), var.ty.ty);
+                    }
+                }
+                Decl::Procedure(procedure) => {
+                    self.types.remove(&procedure.name.key());
+                }
+            }
+        }
+    }
+
+    /// The type `expr` has when it is computed, if it is known.
+    fn type_of(&self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::Integer { .. } => Some(Type::Integer),
+            Expr::Real { .. } => Some(Type::Real),
+            Expr::Variable(ident) => self.types.get(&ident.key()).copied(),
+            Expr::Call { .. } => None,
+            Expr::Unary { operand, .. } => self.type_of(operand),
+            Expr::Binary { .. } => {
+                let (first, operations) = expr.left_spine();
+                let first = self.type_of(first);
+                operations.into_iter().fold(first, |left, operation| match operation.op {
+                    Operators::FDIVISION => Some(Type::Real),
+                    Operators::IDIVISION => Some(Type::Integer),
+                    _ => match (left?, self.type_of(operation.right)?) {
+                        (Type::Integer, Type::Integer) => Some(Type::Integer),
+                        _ => Some(Type::Real),
+                    },
+                })
+            }
+        }
+    }
+
+    /// Simplifies `expr`, whose children already are. What is left of it
+    /// is moved rather than copied, so that simplifying each operation of
+    /// a long run takes no longer than the operation itself.
+    fn simplify(&mut self, expr: &mut Expr) {
+        let simplified = match expr {
+            Expr::Unary { op: UnaryOp::Plus, operand, .. } => Some(std::mem::take(&mut **operand)),
+            Expr::Unary { op: UnaryOp::Minus, operand, span } => match (constant(operand), &mut **operand) {
+                (Some(Value::Integer(value)), _) => match value.checked_neg() {
+                    None if self.checks.overflow => self.fail(),
+                    negated => literal(Value::Integer(negated.unwrap_or(value)), *span),
+                },
+                (Some(Value::Real(value)), _) => literal(Value::Real(-value), *span),
+                (None, Expr::Unary { op: UnaryOp::Minus, operand, .. })
+                    if !self.checks.overflow || self.type_of(operand) == Some(Type::Real) =>
+                {
+                    Some(std::mem::take(&mut **operand))
+                }
+                _ => None,
+            },
+            Expr::Binary { op, left, right, span } => match (constant(left), constant(right)) {
+                (Some(a), Some(b)) => match self.fold(op, a, b) {
+                    Some(value) => literal(value, *span),
+                    None => None,
+                },
+                (None, Some(b)) if self.identity(op, left, b, true) => Some(std::mem::take(&mut **left)),
+                (Some(a), None) if self.identity(op, right, a, false) => Some(std::mem::take(&mut **right)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(simplified) = simplified {
+            *expr = simplified;
+        }
+    }
+
+    /// `a op b`, as it is computed at run time, or `None` if that fails or
+    /// cannot be written as a literal.
+    fn fold(&mut self, op: &Operators, a: Value, b: Value) -> Option<Value> {
+        let overflow = self.checks.overflow;
+        let value = match (op, a, b) {
+            (Operators::FDIVISION, _, _) if b.as_real() == 0.0 => return self.fail(),
+            (Operators::FDIVISION, _, _) => Value::Real(a.as_real() / b.as_real()),
+            (_, Value::Integer(a), Value::Integer(b)) => {
+                let (checked, wrapped) = match op {
+                    Operators::PLUS => (a.checked_add(b), a.wrapping_add(b)),
+                    Operators::MINUS => (a.checked_sub(b), a.wrapping_sub(b)),
+                    Operators::MULTIPLICATION => (a.checked_mul(b), a.wrapping_mul(b)),
+                    _ if b == 0 => return self.fail(),
+                    _ => (a.checked_div(b), a.wrapping_div(b)),
+                };
+                match checked {
+                    None if overflow => return self.fail(),
+                    _ => Value::Integer(checked.unwrap_or(wrapped)),
+                }
+            }
+            (Operators::IDIVISION, _, _) => return self.fail(),
+            (_, _, _) => Value::Real(match op {
+                Operators::PLUS => a.as_real() + b.as_real(),
+                Operators::MINUS => a.as_real() - b.as_real(),
+                _ => a.as_real() * b.as_real(),
+            }),
+        };
+        Some(value)
+    }
+
+    /// Whether `x op c`, or `c op x` if `x` is not on the `left`, is always
+    /// `x` itself, of the same type.
+    fn identity(&self, op: &Operators, x: &Expr, c: Value, left: bool) -> bool {
+        // The type of `x` is only looked at for a constant that may leave
+        // it as it is: going down a long run for every operation would not.
+        let ty = || self.type_of(x);
+        let is = |value: f64| c.as_real() == value && !c.as_real().is_sign_negative();
+        // A REAL constant makes the result REAL, so `x` must be one too.
+        let keeps_type = || c.ty() == Type::Integer || ty() == Some(Type::Real);
+        match op {
+            Operators::PLUS => c == Value::Integer(0) && ty() == Some(Type::Integer),
+            Operators::MINUS => left && is(0.0) && keeps_type(),
+            Operators::MULTIPLICATION => is(1.0) && keeps_type(),
+            Operators::IDIVISION => left && c == Value::Integer(1) && ty() == Some(Type::Integer),
+            Operators::FDIVISION => left && is(1.0) && ty() == Some(Type::Real),
+        }
+    }
+
+    fn fail<T>(&mut self) -> Option<T> {
+        self.fails = true;
+        None
+    }
+}
+
+impl MutVisitor for Optimizer {
+    fn visit_program(&mut self, program: &mut Program) {
+        self.declare(&program.block.declarations);
+        visit_mut::walk_program(self, program)
+    }
+
+    fn visit_unit(&mut self, unit: &mut Unit) {
+        let vars = unit.interface.vars.iter().map(|var| Decl::Var(var.clone())).collect::<Vec<_>>();
+        self.declare(&vars);
+        self.declare(&unit.implementation.declarations);
+        visit_mut::walk_unit(self, unit)
+    }
+
+    /// The body of a procedure has its own variables, and whether it is
+    /// reached does not depend on the code around it.
+    fn visit_procedure(&mut self, procedure: &mut Procedure) {
+        let outer = Optimizer::default();
+        let outer = std::mem::replace(self, outer);
+        let params = procedure.params.iter().map(|param| Decl::Var(param.clone())).collect::<Vec<_>>();
+        self.declare(&params);
+        self.declare(&procedure.block.declarations);
+        visit_mut::walk_procedure(self, procedure);
+        *self = outer;
+    }
+
+    fn visit_compound(&mut self, compound: &mut Compound) {
+        for mut statement in std::mem::take(&mut compound.statements) {
+            if self.unreachable {
+                break;
+            }
+            self.visit_stmt(&mut statement);
+            match statement {
+                Stmt::Empty { .. } => {}
+                Stmt::Compound(inner) => compound.statements.extend(inner.statements),
+                statement => compound.statements.push(statement),
+            }
+        }
+    }
+
+    fn visit_stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Assign { checks, .. } | Stmt::Call { checks, .. } => self.checks = *checks,
+            Stmt::Procedure(procedure) => {
+                self.types.remove(&procedure.name.key());
+            }
+            _ => {}
+        }
+        visit_mut::walk_stmt(self, stmt);
+        if std::mem::take(&mut self.fails) {
+            self.unreachable = true;
+        }
+    }
+
+    /// A run of binary operators is simplified along its left spine, each
+    /// operation once the ones before it are.
+    fn visit_expr(&mut self, expr: &mut Expr) {
+        if let Expr::Binary { .. } = expr {
+            let (mut simplified, operations) = std::mem::take(expr).into_left_spine();
+            self.visit_expr(&mut simplified);
+            for (op, mut right, span) in operations {
+                self.visit_expr(&mut right);
+                simplified = Expr::from_left_spine(simplified, [(op, right, span)]);
+                self.simplify(&mut simplified);
+            }
+            *expr = simplified;
+            return;
+        }
+        visit_mut::walk_expr(self, expr);
+        self.simplify(expr);
+    }
+}
+
+/// The value of `expr` if it is a literal, with or without a minus sign.
+fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Integer { value, .. } => Some(Value::Integer(*value)),
+        Expr::Real { value, .. } => Some(Value::Real(*value)),
+        Expr::Unary { op: UnaryOp::Minus, operand, .. } => match **operand {
+            Expr::Integer { value, .. } => Some(Value::Integer(value.wrapping_neg())),
+            Expr::Real { value, .. } => Some(Value::Real(-value)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A literal for `value` at `span`. Negative numbers are written with a
+/// minus sign, as the parser reads them. There is none for the smallest
+/// INTEGER, whose magnitude does not fit, nor for infinities and NaN.
+fn literal(value: Value, span: Span) -> Option<Expr> {
+    let (negative, magnitude) = match value {
+        Value::Integer(value) if value < 0 => (true, Expr::Integer { value: value.checked_neg()?, text: None, span }),
+        Value::Integer(value) => (false, Expr::Integer { value, text: None, span }),
+        Value::Real(value) if !value.is_finite() => return None,
+        Value::Real(value) => (value.is_sign_negative(), Expr::Real { value: value.abs(), text: None, span }),
+    };
+    Some(match negative {
+        true => Expr::Unary { op: UnaryOp::Minus, operand: Box::new(magnitude), span },
+        false => magnitude,
+    })
+}
+
+#[cfg(test)]
+fn parse(source: &str) -> Program {
+    super::parser::Parser::new(source.as_bytes()).unwrap().parse().unwrap()
+}
+
+#[test]
+fn folds_constants_and_drops_what_does_nothing() {
+    let cases = [
+        ("a := 10 * 4 + 2", "a := 42"),
+        ("a := -(2 - 5) * 2", "a := 6"),
+        ("r := 1 / 4 + 2 * 0.5", "r := 1.25"),
+        ("r := 0 - 0.5", "r := -0.5"),
+        ("a := 7 DIV 2 - 4", "a := -1"),
+        ("a := +a * 1 - 0", "a := a"),
+        ("a := (0 + a) DIV 1", "a := a"),
+        ("r := r * 1.0 / 1 - 0.0", "r := r"),
+        ("a := - -a", "a := a"),
+        ("r := (a + 1) * (2 + 3)", "r := (a + 1) * 5"),
+        ("BEGIN a := 1; ; BEGIN a := 2 END END; ;", "a := 1; a := 2"),
+    ];
+    let program = |body: &str| parse(&format!("PROGRAM p; VAR a : INTEGER; r : REAL; BEGIN {} END.", body));
+    for (body, simplified) in cases {
+        let mut optimized = program(body);
+        optimize(&mut optimized);
+        assert_eq!(optimized, program(simplified), "{}", body);
+    }
+}
+
+#[test]
+fn keeps_what_fails_or_changes_the_value() {
+    let kept = [
+        "a := 1 DIV 0",
+        "r := 1.5 / 0",
+        "r := 3.0 DIV 2",
+        "{$Q+} a := 9223372036854775807 + 1",
+        "{$Q+} a := 9223372036854775807 * 2",
+        "r := 1e300 * 1e300",
+        "r := r + 0",
+        "r := 0 + r",
+        "a := 0 * a",
+        "a := a / 1",
+        "r := r DIV 1",
+        "r := a * 1.0",
+        "{$Q+} a := - -a",
+        "a := b + 0",
+    ];
+    for body in kept {
+        let source = format!("PROGRAM p; VAR a : INTEGER; r : REAL; BEGIN {} END.", body);
+        let mut optimized = parse(&source);
+        optimize(&mut optimized);
+        assert_eq!(optimized, parse(&source), "{}", body);
+    }
+
+    let mut optimized = parse("PROGRAM p; VAR a : INTEGER; BEGIN a := 1; a := 2 DIV 0; a := 3; BEGIN a := 4 END END.");
+    optimize(&mut optimized);
+    assert_eq!(optimized, parse("PROGRAM p; VAR a : INTEGER; BEGIN a := 1; a := 2 DIV 0 END."));
+}
+
+#[test]
+fn optimized_programs_do_the_same() {
+    use super::interpreter::Interpreter;
+    let bodies = [
+        "a := 10 * 4 + 2; r := a / 3 * (2 - 0.5)",
+        "a := 9223372036854775807 + 1; r := -(a * 1) - 0",
+        "{$Q+} a := 9223372036854775807 + 0; a := a * 1; a := 0 - a - 1",
+        "{$Q+} a := 9223372036854775807 * 2",
+        "{$R+} a := 10 / 4 * 2; a := 7 / 2 + 0",
+        "r := -0.0; r := r * 1 - 0; a := -(-(5))",
+        "r := 1 / (0.5 - 0.5)",
+        "a := 0 - 9223372036854775807 - 1; a := -a; a := a DIV -1",
+        "{$Q+} a := -(0 - 9223372036854775807 - 1)",
+        "a := 1; a := a DIV (1 - 1); a := 5",
+        "a := x + 0 * 1",
+        "P(1 + 2, 3 DIV 0)",
+        "BEGIN a := 1; ; BEGIN r := a / 4 END END; a := a + 0",
+    ];
+    for body in bodies {
+        let source = format!(
+            "PROGRAM p; VAR a : INTEGER; r : REAL; PROCEDURE P(x, y : INTEGER); BEGIN END; BEGIN {} END.",
+            body
+        );
+        let run = |program: Program| {
+            let interpreter = Interpreter::from_program(program);
+            interpreter.interprete().map(|()| {
+                interpreter.globals().iter().map(|(name, binding)| format!("{} : {}", name, binding)).collect::<Vec<_>>()
+            })
+        };
+        let mut optimized = parse(&source);
+        optimize(&mut optimized);
+        assert_eq!(run(optimized), run(parse(&source)), "{}", body);
+    }
+}
@@ -1,85 +1,283 @@
-use std::collections::HashMap;
-
-use crate::utils::ast::proc::ProcedureCall;
-
 use super::ast::block::Block;
+use super::ast::decl::{Decl, Type, TypeSpec, VarDecl};
+use super::ast::expr::{Expr, UnaryOp};
+use super::ast::ident::Ident;
 use super::ast::proc::Procedure;
-use super::lexer::{Lexer, Operators, Token, TokenType};
-use super::ast::ast::AST;
 use super::ast::program::Program;
+use super::ast::stmt::{Compound, Stmt};
+use super::ast::unit::{Heading, Implementation, Interface, Unit};
+use std::sync::Arc;
+
+use super::lexer::{Operators, Span, Token, TokenType, SIGN_OPERAND_PRECEDENCE};
+use super::preprocessor::Preprocessor;
+use super::source::SourceMap;
 use super::err::functions::better_error;
+use super::err::diagnostic::{Diagnostic, Diagnostics};
+use super::limits::{Limit, DEFAULT_DEPTH};
+
+/// Stop recording syntax errors after this many; past that point they are
+/// almost always fallout from earlier ones.
+const MAX_ERRORS: usize = 20;
 
 pub struct Parser<'a> {
     current_token: Token,
-    lexer: Lexer<'a>,
+    /// Span of the token before `current_token`, where the node being
+    /// parsed ends.
+    previous: Span,
+    tokens: Preprocessor<'a>,
     brackets_open: usize,
+    /// How deeply the code being parsed nests, up to `max_depth`.
+    depth: usize,
+    max_depth: usize,
+    /// Set once an error gives up on the rest of the input. Errors after it
+    /// are fallout, and dropped.
+    stopped: bool,
+    errors: Diagnostics,
+    /// File and byte offset of the token the last error was reported at. A
+    /// second error at the same place is a cascade and is dropped.
+    last_error_at: Option<(usize, usize)>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(bytes: &'a [u8]) -> Result<Self, String> {
-        let mut lexer = Lexer::new(bytes);
-        let current_token = lexer.get_next_token()?;
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Diagnostic> {
+        Self::with_preprocessor(Preprocessor::new(bytes))
+    }
+
+    pub fn with_preprocessor(mut tokens: Preprocessor<'a>) -> Result<Self, Diagnostic> {
+        let current_token = tokens.get_next_token()?;
         Ok(Self {
-            lexer,
+            tokens,
             current_token,
+            previous: Span::default(),
             brackets_open: 0,
+            depth: 0,
+            max_depth: DEFAULT_DEPTH,
+            stopped: false,
+            errors: Diagnostics::new(),
+            last_error_at: None,
         })
     }
-    
-    fn eat(&mut self, token: TokenType) -> Result<(), String> {
+
+    /// Fails with an error once code nests deeper than `depth`, rather
+    /// than `DEFAULT_DEPTH`.
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Parses a whole program and fails with every syntax error found.
+    pub fn parse(&mut self) -> Result<Program, Diagnostics> {
+        let (program, errors) = self.parse_partial();
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Parses a whole program, recovering from syntax errors. The returned
+    /// program holds everything that could be parsed; statements and
+    /// declarations that failed to parse are left out.
+    pub fn parse_partial(&mut self) -> (Program, Diagnostics) {
+        let program = self.program();
+        (program, std::mem::take(&mut self.errors))
+    }
+
+    /// Parses a unit that makes up the whole input.
+    pub fn parse_unit(&mut self) -> Result<Unit, Diagnostics> {
+        let unit = self.unit();
+        self.finish(unit)
+    }
+
+    /// Parses a lone expression that makes up the whole input.
+    pub fn parse_expression(&mut self) -> Result<Expr, Diagnostics> {
+        let expr = self.expr();
+        self.finish(expr)
+    }
+
+    /// Parses statements separated by `;` that make up the whole input.
+    pub fn parse_statements(&mut self) -> Result<Vec<Stmt>, Diagnostics> {
+        let statements = self.statement_nodes();
+        self.finish(Ok(statements))
+    }
+
+    /// Parses a `VAR` section that makes up the whole input. The `;` after
+    /// the last declaration may be left out.
+    pub fn parse_declarations(&mut self) -> Result<Vec<Decl>, Diagnostics> {
+        let declarations = self.eat(TokenType::VAR).and_then(|_| {
+            let mut vars = Vec::new();
+            while let TokenType::IDENTIFIER(_) = self.current_token.token_type() {
+                vars.push(Decl::Var(self.vardeclarations(false)?))
+            }
+            Ok(vars)
+        });
+        self.finish(declarations)
+    }
+
+    /// Checks that the whole input was consumed and hands back `result`
+    /// along with every error recorded on the way.
+    fn finish<T>(&mut self, result: Result<T, Diagnostic>) -> Result<T, Diagnostics> {
+        let value = match result {
+            Err(e) => {
+                self.report(e);
+                None
+            }
+            Ok(value) => {
+                self.end_of_input();
+                Some(value)
+            }
+        };
+        let errors = std::mem::take(&mut self.errors);
+        match value {
+            Some(value) if errors.is_empty() => Ok(value),
+            _ => Err(errors),
+        }
+    }
+
+    /// Reports whatever is left of the input.
+    fn end_of_input(&mut self) {
+        if !matches!(self.current_token.token_type(), TokenType::EOF) {
+            let error = better_error(
+                format!("Unexpected {} found", self.current_token.token_type().describe()),
+                &self.current_token
+            ).with_label("expected end of input");
+            self.report(error)
+        }
+    }
+
+    pub fn errors(&self) -> &Diagnostics {
+        &self.errors
+    }
+
+    /// The file being parsed and the files it included so far.
+    pub fn sources(&self) -> &Arc<SourceMap> {
+        self.tokens.sources()
+    }
+
+    fn report(&mut self, error: Diagnostic) {
+        let span = self.current_token.span();
+        let at = (span.file, span.start);
+        if self.stopped || self.last_error_at == Some(at) {
+            return;
+        }
+        self.last_error_at = Some(at);
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error);
+        } else if self.errors.len() == MAX_ERRORS {
+            self.errors.push(Diagnostic::new("too many syntax errors, giving up on reporting the rest"));
+        }
+    }
+
+    /// Panic-mode recovery: skips tokens until one that can start or end a
+    /// statement or declaration.
+    fn synchronize(&mut self) {
+        self.brackets_open = 0;
+        loop {
+            match self.current_token.token_type() {
+                TokenType::SEMICOLON
+                | TokenType::END
+                | TokenType::BEGIN
+                | TokenType::VAR
+                | TokenType::PROCEDURE
+                | TokenType::IMPLEMENTATION
+                | TokenType::EOF => return,
+                _ => {}
+            }
+            if let Err(e) = self.advance() {
+                self.report(e)
+            }
+        }
+    }
+
+    fn recover(&mut self, error: Diagnostic) {
+        let too_deep = error.limit() == Some(Limit::Depth);
+        self.report(error);
+        if too_deep {
+            self.stop();
+        } else {
+            self.synchronize();
+        }
+    }
+
+    /// Skips the rest of the input. Code nested too deeply is not recovered
+    /// from: the statement it is in could only be resumed at the same depth.
+    fn stop(&mut self) {
+        self.stopped = true;
+        while !matches!(self.current_token.token_type(), TokenType::EOF) {
+            let _ = self.advance();
+        }
+    }
+
+    /// Moves to the next token and hands back the one moved past.
+    fn advance(&mut self) -> Result<Token, Diagnostic> {
+        let next = self.get_next_token()?;
+        let token = std::mem::replace(&mut self.current_token, next);
+        self.previous = token.span();
+        Ok(token)
+    }
+
+    /// The span from `start` to the end of the last token moved past.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous)
+    }
+
+    fn eat(&mut self, token: TokenType) -> Result<(), Diagnostic> {
         if self.current_token.token_type().equal(&token) {
-            self.current_token = self.get_next_token()?;
-            return Ok(());
+            self.advance()?;
+            Ok(())
         } else {
-            return Err(format!(
-                "Expected {:?} found {:?}. Position: line_no: {}, column: {}",
-                token, self.current_token, self.current_token.line_no(), self.current_token.column()
-            ));
+            Err(self.expected(&token))
         }
     }
 
-    fn get_next_token(&mut self) -> Result<Token, String> {
-        self.lexer.get_next_token()
+    fn expected(&self, token: &TokenType) -> Diagnostic {
+        better_error(
+            format!("Expected {} found {}", token.describe(), self.current_token.token_type().describe()),
+            &self.current_token
+        )
+        .with_label(format!("expected {} here", token.describe()))
+        .with_help(eat_help(token))
+    }
+
+    fn get_next_token(&mut self) -> Result<Token, Diagnostic> {
+        self.tokens.get_next_token()
     }
 
-    fn integer(&mut self) -> Result<AST<Token>, String> {
+    /// Parses an operand: a number, a variable, a function call, a signed
+    /// operand or an expression in parentheses.
+    fn prefix(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.current_token.span();
         match self.current_token.token_type() {
-            TokenType::Integer(_) => {
-                let mut old_token = self.get_next_token()?;
-                std::mem::swap(&mut old_token, &mut self.current_token);
-                return Ok(AST::new(
-                    old_token
-                ))
+            TokenType::IntegerConst(value, text) => {
+                let (value, text) = (*value, Some(text.clone()));
+                self.advance()?;
+                return Ok(Expr::Integer { value, text, span: start });
             }
-            TokenType::IDENTIFIER(_) => {
-                let mut old_token = self.get_next_token()?;
-                std::mem::swap(&mut old_token, &mut self.current_token);
-                return Ok(
-                    AST::new(old_token)
-                );
+            TokenType::RealConst(value, text) => {
+                let (value, text) = (*value, Some(text.clone()));
+                self.advance()?;
+                return Ok(Expr::Real { value, text, span: start });
             }
-            TokenType::Operator(ref o) => match o {
-                Operators::MINUS => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-
-                    return Ok(
-                        AST::unary(
-                            old_token,self.term()?
-                        )
-                    )
- 
+            TokenType::IDENTIFIER(_) => {
+                let name = self.identifier()?;
+                if let TokenType::LPAREN = self.current_token.token_type() {
+                    let args = self.arguments()?;
+                    return Ok(Expr::Call { span: self.span_from(name.span), name, args });
                 }
-                Operators::PLUS => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-
-                    return Ok(
-                        AST::unary(old_token, self.term()?)
-                    )
+                return Ok(Expr::Variable(name));
+            }
+            TokenType::Operator(ref o) => {
+                let op = match o {
+                    Operators::MINUS => Some(UnaryOp::Minus),
+                    Operators::PLUS => Some(UnaryOp::Plus),
+                    _ => None,
+                };
+                if let Some(op) = op {
+                    self.advance()?;
+                    let operand = self.binary(SIGN_OPERAND_PRECEDENCE)?;
+                    return Ok(Expr::unary(op, start, operand));
                 }
-                _ => {}
-            },
+            }
             TokenType::LPAREN => {
                 self.eat(TokenType::LPAREN)?;
                 self.brackets_open += 1;
@@ -90,109 +288,194 @@ impl<'a> Parser<'a> {
             }
             _ => {}
         }
-        return Err(
+        Err(
             better_error(
-                format!("Expected INTEGER found {:?}", self.current_token.token_type()),
+                format!("Expected expression found {}", self.current_token.token_type().describe()),
                 &self.current_token
             )
-        );
+            .with_label("expected an expression")
+        )
     }
 
+    /// Precedence climbing: parses an operand followed by binary operators
+    /// of precedence `min` or higher. The right operand of each operator is
+    /// parsed one level higher, which makes every operator left-associative.
+    ///
+    /// Operators of the same level are read in a loop, so a long run of
+    /// them does not count as nesting; it grows the left spine of the tree,
+    /// which passes go along without recursing.
+    fn binary(&mut self, min: u8) -> Result<Expr, Diagnostic> {
+        self.nested(|parser| {
+            let mut result = parser.prefix()?;
+            while let TokenType::Operator(ref op) = parser.current_token.token_type() {
+                let precedence = op.precedence();
+                if precedence < min {
+                    break;
+                }
+                let op = op.clone();
+                parser.advance()?;
+                let right = parser.binary(precedence + 1)?;
+                result = Expr::binary(op, result, right);
+            }
+            Ok(result)
+        })
+    }
 
+    /// Parses something nested in what is being parsed, failing if that
+    /// is too deep.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, Diagnostic>) -> Result<T, Diagnostic> {
+        let outer = self.depth;
+        let result = self.deeper().and_then(|()| parse(self));
+        self.depth = outer;
+        result
+    }
 
-    fn term(&mut self) -> Result<AST<Token>, String> {
-        let mut result = self.integer()?;
-
+    /// Goes one level deeper, up to the end of the enclosing `nested`.
+    fn deeper(&mut self) -> Result<(), Diagnostic> {
+        if self.depth == self.max_depth {
+            return Err(
+                better_error(format!("Code is nested more than {} levels deep.", self.max_depth), &self.current_token)
+                    .with_label("nested too deeply")
+                    .with_help("split the code up, computing parts of expressions into variables")
+                    .with_limit(Limit::Depth)
+            );
+        }
+        self.depth += 1;
+        Ok(())
+    }
 
-        while let TokenType::Operator(ref op) = self.current_token.token_type() {
-            match *op {
-                Operators::MULTIPLICATION => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-                    result = AST::full_self(result, old_token, self.term()?)
-                }
-                Operators::FDIVISION => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-                    self.current_token = self.get_next_token()?;
-                    result = AST::full_self(result, old_token, self.integer()?)
+    fn program(&mut self) -> Program {
+        let start = self.current_token.span();
+        let name = match self.program_header() {
+            Ok(name) => name,
+            Err(e) => {
+                self.recover(e);
+                if let TokenType::SEMICOLON = self.current_token.token_type() {
+                    self.advance_or_report();
                 }
-                Operators::IDIVISION => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-                    result = AST::full_self(result, old_token, self.integer()?)
+                Ident::default()
+            }
+        };
+        let uses = match self.uses_clause() {
+            Ok(uses) => uses,
+            Err(e) => {
+                self.recover_declaration(e);
+                Vec::new()
+            }
+        };
+        let block = match self.block() {
+            Ok(block) => {
+                match self.eat(TokenType::DOT) {
+                    Ok(()) => self.end_of_input(),
+                    Err(e) => self.report(e),
                 }
-                _ => { break; }
+                block
             }
+            Err(e) => {
+                self.recover(e);
+                Block::default()
+            }
+        };
+        Program {
+            name,
+            uses,
+            block,
+            span: self.span_from(start),
         }
-        Ok(result)
     }
 
-    pub fn program(&mut self) -> Result<Program, String> {
+    fn program_header(&mut self) -> Result<Ident, Diagnostic> {
         self.eat(TokenType::PROGRAM)?;
         let name = match &self.current_token.token_type() {
-            TokenType::IDENTIFIER(s) => {s.clone()},
+            TokenType::IDENTIFIER(_) => self.identifier()?,
             e => return Err(
                 better_error(
-                format!("Did not find name of the program found {:?}", e),
+                format!("Did not find name of the program found {}", e.describe()),
                 &self.current_token
                 )
             )
         };
-        self.current_token = self.get_next_token()?;
         self.eat(TokenType::SEMICOLON)?;
-        Ok(Program::new(
-            name, 
-            self.block()?
-        ))
+        Ok(name)
     }
 
-    fn declarations(&mut self) -> Result<HashMap<String, Token>, String> {
-        self.eat(TokenType::VAR)?;
-        let mut map = HashMap::new();
-        while let TokenType::IDENTIFIER(_) = self.current_token.token_type() {
-            map.extend(self.vardeclarations(true)?)
+    /// Moves past the current token, recording a lexer error if the next
+    /// one cannot be read.
+    fn advance_or_report(&mut self) {
+        if let Err(e) = self.advance() {
+            self.recover(e)
         }
-        Ok(map)
     }
 
-    fn vardeclarations(&mut self, semi_required: bool) -> Result<HashMap<String, Token>, String> {
-        let mut idents = Vec::new();
-        match &self.current_token.token_type() {
-            TokenType::IDENTIFIER( i) => {idents.push(i.clone())}
-            e => return Err(
-                better_error(
-                    format!("Expected Identifier found {:?}", e),
-                    &self.current_token
-                )
-            )
+    /// An optional `USES a, b;`.
+    fn uses_clause(&mut self) -> Result<Vec<Ident>, Diagnostic> {
+        let mut uses: Vec<Ident> = Vec::new();
+        if let TokenType::USES = self.current_token.token_type() {
+            self.advance()?;
+            loop {
+                let name = self.identifier()?;
+                if uses.contains(&name) {
+                    self.errors.push(
+                        Diagnostic::new(format!("Unit `{}` is named twice after USES.", name.name))
+                            .with_span(name.span)
+                            .with_label("already named")
+                    );
+                } else {
+                    uses.push(name);
+                }
+                if let TokenType::COMMA = self.current_token.token_type() {
+                    self.advance()?;
+                } else {
+                    break;
+                }
+            }
+            self.eat(TokenType::SEMICOLON)?;
         }
-        self.current_token = self.get_next_token()?;
-        while let TokenType::COMMA = self.current_token.token_type() {
-            self.eat(TokenType::COMMA)?;
-            match &self.current_token.token_type() {
-                TokenType::IDENTIFIER(ref i) => {idents.push(i.clone())}
-                e => return Err(better_error(
-                    format!("Expected Identifier found {:?}", e),
-                    &self.current_token
-                ))
+        Ok(uses)
+    }
+
+    /// An optional `VAR` section.
+    fn vars(&mut self) -> Result<Vec<VarDecl>, Diagnostic> {
+        let mut vars = Vec::new();
+        if let TokenType::VAR = self.current_token.token_type() {
+            self.advance()?;
+            while let TokenType::IDENTIFIER(_) = self.current_token.token_type() {
+                match self.vardeclarations(true) {
+                    Ok(declared) => vars.push(declared),
+                    Err(e) => self.recover_declaration(e),
+                }
             }
-            self.current_token = self.get_next_token()?;
+        }
+        Ok(vars)
+    }
 
+    /// An optional `VAR` section followed by procedure declarations, each
+    /// ending in `;`.
+    fn declarations(&mut self) -> Result<Vec<Decl>, Diagnostic> {
+        let mut declarations = self.vars()?.into_iter().map(Decl::Var).collect::<Vec<_>>();
+        while let TokenType::PROCEDURE = self.current_token.token_type() {
+            match self.procedure() {
+                Ok(procedure) => {
+                    declarations.extend(procedure.map(Decl::Procedure));
+                    if let Err(e) = self.eat(TokenType::SEMICOLON) {
+                        self.recover_declaration(e);
+                    }
+                }
+                Err(e) => self.recover_declaration(e),
+            }
         }
-        
-        if let TokenType::COLON = self.current_token.token_type() { 
-            self.current_token = self.get_next_token()?;   
-        } else {
-           return Err(better_error(
-            format!("Expected `:` found {:?}", self.current_token),
-            &self.current_token
-        ))
+        Ok(declarations)
+    }
+
+    fn recover_declaration(&mut self, error: Diagnostic) {
+        self.recover(error);
+        if let TokenType::SEMICOLON = self.current_token.token_type() {
+            self.advance_or_report();
         }
+    }
 
-        
-        let mut data_type = self.get_next_token()?;
-        std::mem::swap(&mut self.current_token, &mut data_type);
+    fn vardeclarations(&mut self, semi_required: bool) -> Result<VarDecl, Diagnostic> {
+        let declared = self.names_with_type("a variable declaration is written `name : TYPE;`")?;
         if !semi_required {
             if let TokenType::SEMICOLON = self.current_token.token_type()  {
                 self.eat(TokenType::SEMICOLON)?;
@@ -200,44 +483,73 @@ impl<'a> Parser<'a> {
         }else {
             self.eat(TokenType::SEMICOLON)?;
         }
-        Ok(idents.into_iter().map(|e| {(e, data_type.clone())}).collect::<HashMap<_,_>>())
+        Ok(declared)
+    }
+
+    /// Parses `a, b : TYPE`, the shape shared by variable declarations and
+    /// parameters. `help` shows how it is written when the `:` is missing.
+    fn names_with_type(&mut self, help: &str) -> Result<VarDecl, Diagnostic> {
+        let start = self.current_token.span();
+        let mut names = vec![self.identifier()?];
+        while let TokenType::COMMA = self.current_token.token_type() {
+            self.eat(TokenType::COMMA)?;
+            names.push(self.identifier()?);
+        }
+
+        if let TokenType::COLON = self.current_token.token_type() {
+            self.advance()?;
+        } else {
+           return Err(better_error(
+            format!("Expected `:` found {}", self.current_token.token_type().describe()),
+            &self.current_token
+        ).with_help(help))
+        }
+
+        let ty = self.type_spec()?;
+        Ok(VarDecl {
+            names,
+            ty,
+            span: self.span_from(start),
+        })
     }
 
-    fn block(&mut self) -> Result<Block, String> {
-        Ok(Block::new(
-            self.declarations()?,
-            self.compound()?
-        ))
+    fn type_spec(&mut self) -> Result<TypeSpec, Diagnostic> {
+        let ty = match self.current_token.token_type() {
+            TokenType::INTEGER => Type::Integer,
+            TokenType::REAL => Type::Real,
+            e => return Err(
+                better_error(format!("Expected a type found {}", e.describe()), &self.current_token)
+                    .with_label("expected a type")
+                    .with_help("the types are INTEGER and REAL")
+            ),
+        };
+        let token = self.advance()?;
+        Ok(TypeSpec { ty, span: token.span() })
     }
 
+    fn block(&mut self) -> Result<Block, Diagnostic> {
+        let start = self.current_token.span();
+        let declarations = self.declarations()?;
+        let body = self.compound()?;
+        Ok(Block {
+            declarations,
+            body,
+            span: self.span_from(start),
+        })
+    }
 
-    pub fn expr(&mut self) -> Result<AST<Token>, String> {
-        let mut result = self.term()?;
 
-        while let TokenType::Operator(ref op) = self.current_token.token_type() {
-            use Operators::*;
-            match *op {
-                PLUS => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-                    result = AST::full_self(result, old_token, self.term()?)
-                }
-                MINUS => {
-                    let mut old_token = self.get_next_token()?;
-                    std::mem::swap(&mut old_token, &mut self.current_token);
-                    result = AST::full_self(result, old_token, self.term()?)
-                }
-                
-                _ => { break; },
-            }
-        }
+    pub fn expr(&mut self) -> Result<Expr, Diagnostic> {
+        let result = self.binary(0)?;
         if let TokenType::RPAREN = self.current_token.token_type() {
             if self.brackets_open == 0 {
                 return Err(
-                    format!(
-                        "Unexpected token `)` found. Position: line_no: {}, column: {}", 
-                        self.current_token.line_no(), self.current_token.column()
+                    better_error(
+                        "Unexpected token `)` found.".to_string(),
+                        &self.current_token
                     )
+                    .with_label("unmatched `)`")
+                    .with_help("remove this `)` or add a matching `(`")
                 );
             }
         }
@@ -245,213 +557,350 @@ impl<'a> Parser<'a> {
         Ok(result)
     }
 
-    fn compound(&mut self) -> Result<AST<Token>, String> {
-        let new_begin = self.current_token.clone();
+    fn compound(&mut self) -> Result<Compound, Diagnostic> {
+        self.nested(Self::compound_body)
+    }
+
+    fn compound_body(&mut self) -> Result<Compound, Diagnostic> {
+        let start = self.current_token.span();
         self.eat(TokenType::BEGIN)?;
-        let nodes = self.statement_nodes()?;
-        let node = AST::new_with_children(new_begin, nodes);
-        self.eat(TokenType::END)?;
-        Ok(node)
+        let statements = self.statement_nodes();
+        if let Err(e) = self.eat(TokenType::END) {
+            self.report(e);
+            // Skip whatever is left of the block so the enclosing one does
+            // not mistake it for its own statements.
+            while !matches!(self.current_token.token_type(), TokenType::END | TokenType::EOF) {
+                self.advance_or_report();
+                self.synchronize();
+                if let TokenType::SEMICOLON | TokenType::BEGIN | TokenType::VAR | TokenType::PROCEDURE = self.current_token.token_type() {
+                    self.advance_or_report();
+                }
+            }
+            if let TokenType::END = self.current_token.token_type() {
+                self.advance_or_report();
+            }
+        }
+        Ok(Compound {
+            statements,
+            span: self.span_from(start),
+        })
     }
 
-    fn statement_nodes(&mut self) -> Result<Vec<AST<Token>>, String> {
+    fn statement_nodes(&mut self) -> Vec<Stmt> {
         let mut nodes = Vec::new();
-        nodes.push(self.statement()?);
-        while let TokenType::SEMICOLON = self.current_token.token_type(){
-            self.eat(TokenType::SEMICOLON)?;
-            nodes.push(self.statement()?);
+        let mut parsed = self.recovering_statement(&mut nodes);
+        loop {
+            match self.current_token.token_type() {
+                TokenType::SEMICOLON => {
+                    self.advance_or_report();
+                }
+                // A statement directly after another one is missing its `;`.
+                // Report that (unless the previous statement already failed)
+                // and carry on as if it was there.
+                TokenType::IDENTIFIER(_) | TokenType::BEGIN | TokenType::PROCEDURE => {
+                    if parsed {
+                        let missing = self.expected(&TokenType::SEMICOLON);
+                        self.report(missing);
+                    }
+                }
+                _ => break
+            }
+            parsed = self.recovering_statement(&mut nodes);
         }
-        Ok(nodes)
+        nodes
+    }
 
+    fn recovering_statement(&mut self, nodes: &mut Vec<Stmt>) -> bool {
+        match self.statement() {
+            Ok(node) => {
+                nodes.push(node);
+                true
+            }
+            Err(e) => {
+                self.recover(e);
+                false
+            }
+        }
     }
 
-    fn statement(&mut self) -> Result<AST<Token>, String> {
+    fn statement(&mut self) -> Result<Stmt, Diagnostic> {
         match self.current_token.token_type() {
-            TokenType::BEGIN => self.compound(),
+            TokenType::BEGIN => Ok(Stmt::Compound(self.compound()?)),
             TokenType::IDENTIFIER(_) => {
-                if self.lexer.get_current_character() == '(' {
-                    return self.procedure_call();
+                let name = self.identifier()?;
+                if let TokenType::LPAREN = self.current_token.token_type() {
+                    return self.procedure_call(name);
                 }
-                self.assignment_statement()
+                self.assignment_statement(name)
             },
-            TokenType::PROCEDURE(_) => self.procedure(),
-            _  => Ok(
-                    AST::new(
-                        Token::new_with_details(
-                            TokenType::EMPTY, 
-                            self.current_token.line_no(), 
-                            self.current_token.column()
-                        ) 
-                    )
-                )
-        } 
+            TokenType::PROCEDURE => {
+                let start = self.current_token.span();
+                match self.procedure()? {
+                    Some(procedure) => Ok(Stmt::Procedure(procedure)),
+                    None => Ok(Stmt::Empty { span: self.span_from(start) }),
+                }
+            }
+            _ => {
+                let next = self.current_token.span();
+                Ok(Stmt::Empty { span: Span { end: next.start, ..next } })
+            }
+        }
     }
 
-    fn procedure_call(&mut self) -> Result<AST<Token>, String> {
-        let column = self.current_token.column();
-        let line_no = self.current_token.line_no();
-        let procedure_name =  match self.current_token.token_type() {
-            TokenType::IDENTIFIER(name) => name.clone(),
-            e => return Err(
-                better_error(
-                    format!("Expected identifier found {:?}", e), &self.current_token 
-                )
-            )
-        };
-        self.current_token = self.get_next_token()?;
+    /// Parses the arguments of a call to `name`, which was just read.
+    fn procedure_call(&mut self, name: Ident) -> Result<Stmt, Diagnostic> {
+        let checks = self.tokens.checks();
+        let args = self.arguments()?;
+        Ok(Stmt::Call {
+            span: self.span_from(name.span),
+            name,
+            args,
+            checks,
+        })
+    }
+
+    /// Parses the arguments of a call, with their parentheses.
+    fn arguments(&mut self) -> Result<Vec<Expr>, Diagnostic> {
         self.eat(TokenType::LPAREN)?;
         self.brackets_open += 1;
-        let parameters = if let TokenType::RPAREN = self.current_token.token_type() {
-            None
+        let args = if let TokenType::RPAREN = self.current_token.token_type() {
+            Vec::new()
         } else {
             self.procedure_parameters()?
         };
         self.eat(TokenType::RPAREN)?;
         self.brackets_open -= 1;
-        Ok(
-            AST::new(
-                Token::new_with_details(
-                    TokenType::PROCEDURECALL(
-                        Box::new(
-                            ProcedureCall {
-                                name: procedure_name,
-                                params: parameters
-                            }
-                        )
-                    ), line_no, column
-                )
-            )
-        )
+        Ok(args)
     }
 
-    fn procedure_parameters(&mut self) -> Result<Option<Vec<AST<Token>>>, String> {
-        let mut tokens = Vec::new();
-        tokens.push(self.expr()?);
+    fn procedure_parameters(&mut self) -> Result<Vec<Expr>, Diagnostic> {
+        let mut args = vec![self.expr()?];
         while let TokenType::COMMA = *self.current_token.token_type() {
-            tokens.push(self.expr()?)
+            self.eat(TokenType::COMMA)?;
+            args.push(self.expr()?)
         }
-        Ok(Some(tokens))
+        Ok(args)
     }
 
 
-    fn procedure(&mut self) -> Result<AST<Token>, String> {
-        let line_no =  self.current_token.line_no();
-        let column = self.current_token.column();
-        self.current_token = self.get_next_token()?;
-        let name = match &self.current_token.token_type() {
-            TokenType::IDENTIFIER(string) => string.clone(),
-            _ => return Err(better_error(
-                format!("Expected identifier found {:?}", self.current_token),
-                &self.current_token
-            ))
+    fn heading(&mut self) -> Result<Heading, Diagnostic> {
+        let start = self.current_token.span();
+        self.eat(TokenType::PROCEDURE)?;
+        let name = self.identifier()?;
+        let params = self.get_parameters()?;
+        Ok(Heading {
+            name,
+            params,
+            span: self.span_from(start),
+        })
+    }
+
+    /// A procedure, or `None` for one whose heading has an error: its block
+    /// is skipped then, so that it is not taken for the code around it.
+    fn procedure(&mut self) -> Result<Option<Procedure>, Diagnostic> {
+        self.nested(Self::procedure_body)
+    }
+
+    fn procedure_body(&mut self) -> Result<Option<Procedure>, Diagnostic> {
+        let heading = match self.heading() {
+            Ok(heading) => heading,
+            Err(e) => {
+                self.skip_procedure(e);
+                return Ok(None);
+            }
         };
-        let parameters = self.get_parameters()?;
         let block = self.block()?;
-        Ok(AST::new(
-            Token::new_with_details(
-                TokenType::PROCEDURE(
-                    Some(Box::new(
-                        Procedure::new(name,parameters,block)?
-                    ))
-                ),
-                line_no, 
-                column)
-            )
-        )
+        let procedure = Procedure {
+            name: heading.name,
+            params: heading.params,
+            block,
+            span: self.span_from(heading.span),
+        };
+        if let Some(param) = procedure.param_names().find(|param| procedure.block.declares(&param.name)) {
+            return Err(
+                Diagnostic::new(format!(
+                    "Duplicate identifier `{}` found in Procedure {}.",
+                    param.name, procedure.name.name
+                ))
+                .with_span(param.span)
+            );
+        }
+        Ok(Some(procedure))
     }
 
-    fn procedure_declarations(&mut self) -> Result<Vec<(String, Token)>, String> {
-        let mut idents = Vec::new();
-        match &self.current_token.token_type() {
-            TokenType::IDENTIFIER( i) => {idents.push(i.clone())}
-            e => return Err(format!("Expected Identifier found {:?}", e))
+    /// Reports `error`, in the heading of a procedure, then skips the rest
+    /// of the heading and the block after it.
+    fn skip_procedure(&mut self, error: Diagnostic) {
+        self.recover(error);
+        while let TokenType::SEMICOLON = self.current_token.token_type() {
+            self.advance_or_report();
+            self.synchronize();
         }
-        self.current_token = self.get_next_token()?;
-        while let TokenType::COMMA = self.current_token.token_type() {
-            self.eat(TokenType::COMMA)?;
-            match &self.current_token.token_type() {
-                TokenType::IDENTIFIER(ref i) => {idents.push(i.clone())}
-                e => return Err(format!("Expected Identifier found {:?}", e))
+        if let TokenType::VAR | TokenType::PROCEDURE | TokenType::BEGIN = self.current_token.token_type() {
+            if let Err(e) = self.block() {
+                self.recover(e);
             }
-            self.current_token = self.get_next_token()?;
-
         }
-        
-        if let TokenType::COLON = self.current_token.token_type() { 
-            self.current_token = self.get_next_token()?;   
-        } else {
-           return Err(
-            better_error(
-                format!("Expected `:` found {:?}", self.current_token),&self.current_token
-                )
-            )
+    }
+
+    fn unit(&mut self) -> Result<Unit, Diagnostic> {
+        let start = self.current_token.span();
+        self.eat(TokenType::UNIT)?;
+        let name = self.identifier()?;
+        self.eat(TokenType::SEMICOLON)?;
+
+        self.eat(TokenType::INTERFACE)?;
+        let uses = self.uses_clause()?;
+        let vars = self.vars()?;
+        let mut procedures = Vec::new();
+        while let TokenType::PROCEDURE = self.current_token.token_type() {
+            match self.heading() {
+                Ok(heading) => procedures.push(heading),
+                Err(e) => self.recover_declaration(e),
+            }
         }
+        let interface = Interface { uses, vars, procedures };
 
-        
-        let mut data_type = self.get_next_token()?;
-        std::mem::swap(&mut self.current_token, &mut data_type);
+        self.eat(TokenType::IMPLEMENTATION)?;
+        let implementation = Implementation {
+            uses: self.uses_clause()?,
+            declarations: self.declarations()?,
+        };
+        let initialization = match self.current_token.token_type() {
+            TokenType::BEGIN => Some(self.compound()?),
+            _ => {
+                self.eat(TokenType::END)?;
+                None
+            }
+        };
+        self.eat(TokenType::DOT)?;
+        let unit = Unit {
+            name,
+            interface,
+            implementation,
+            initialization,
+            span: self.span_from(start),
+        };
+        self.check_implementation(&unit);
+        Ok(unit)
+    }
 
+    /// Every procedure of the interface must be implemented as declared, and
+    /// nothing it declares may be declared again.
+    fn check_implementation(&mut self, unit: &Unit) {
+        let declarations = &unit.implementation.declarations;
+        for heading in &unit.interface.procedures {
+            let body = declarations.iter().find_map(|decl| match decl {
+                Decl::Procedure(procedure) if procedure.name == heading.name => Some(procedure),
+                _ => None,
+            });
+            match body {
+                None => self.errors.push(
+                    Diagnostic::new(format!(
+                        "Procedure `{}` is declared in the interface but not implemented.",
+                        heading.name.name
+                    ))
+                    .with_span(heading.span)
+                    .with_label("declared here")
+                    .with_help(format!("implement it in the IMPLEMENTATION part of `{}`", unit.name.name))
+                ),
+                Some(procedure) if procedure.params != heading.params => self.errors.push(
+                    Diagnostic::new(format!(
+                        "Parameters of `{}` differ from its declaration in the interface.",
+                        heading.name.name
+                    ))
+                    .with_span(procedure.name.span)
+                    .with_label("declared differently in the interface")
+                ),
+                Some(_) => {}
+            }
+        }
+        for name in unit.interface.vars.iter().flat_map(|var| &var.names) {
+            let redeclared = declarations.iter().find_map(|decl| match decl {
+                Decl::Var(var) => var.names.iter().find(|other| *other == name),
+                Decl::Procedure(procedure) => Some(&procedure.name).filter(|other| *other == name),
+            });
+            if let Some(redeclared) = redeclared {
+                self.errors.push(
+                    Diagnostic::new(format!("Duplicate identifier `{}` in unit `{}`.", name.name, unit.name.name))
+                        .with_span(redeclared.span)
+                        .with_label("already declared in the interface")
+                );
+            }
+        }
+    }
+
+    fn procedure_declarations(&mut self) -> Result<VarDecl, Diagnostic> {
+        let declared = self.names_with_type("a parameter is written `name : TYPE`")?;
         if let TokenType::SEMICOLON = self.current_token.token_type()  {
             self.eat(TokenType::SEMICOLON)?;
         }
-        
-        Ok(idents.into_iter().map(|e| {(e, data_type.clone())}).collect::<Vec<_>>())
-    
+        Ok(declared)
     }
 
-    fn get_parameters(&mut self) -> Result<Option<Vec<(String, Token)>>, String> {
-        self.current_token = self.get_next_token()?;
+    fn get_parameters(&mut self) -> Result<Vec<VarDecl>, Diagnostic> {
         match &self.current_token.token_type() {
-            TokenType::SEMICOLON => return Ok(None),
+            TokenType::SEMICOLON => {
+                self.advance()?;
+                Ok(Vec::new())
+            }
             TokenType::LPAREN => {
-                let mut vec = Vec::new();
-                self.current_token = self.get_next_token()?;
+                let mut params = Vec::new();
+                self.advance()?;
                 while let TokenType::IDENTIFIER(_) = self.current_token.token_type() {
-                    vec.extend(
-                        self.procedure_declarations()?.into_iter()
-                    );
+                    params.push(self.procedure_declarations()?);
                 }
                 self.eat(TokenType::RPAREN)?;
                 self.eat(TokenType::SEMICOLON)?;
-                Ok(Some(vec))
+                Ok(params)
             }
-            
-            e => return Err(better_error(
-                format!("Expected `,` or `(` found {:?}",e),
+
+            e => Err(better_error(
+                format!("Expected `;` or `(` found {}",e.describe()),
                 &self.current_token
             ))
         }
     }
 
-    fn identifier(&mut self) -> Result<AST<Token>, String>{
-        let mut next_token = self.get_next_token()?;
-        std::mem::swap(&mut self.current_token, &mut next_token);
-        match next_token.token_type() {
-            TokenType::IDENTIFIER(_) => {
-                Ok(AST::new(next_token.clone()))
+    fn identifier(&mut self) -> Result<Ident, Diagnostic> {
+        match self.current_token.token_type() {
+            TokenType::IDENTIFIER(name) => {
+                let name = name.clone();
+                let token = self.advance()?;
+                Ok(Ident::new(name, token.span()))
             }
             token => {
                 Err(better_error(
-                    format!("Expected identifier found {:?}", token),
+                    format!("Expected identifier found {}", token.describe()),
                     &self.current_token
                 ))
             }
         }
     }
 
-    fn assignment_statement(&mut self) -> Result<AST<Token>, String> {
-        let left = self.identifier()?;
-        let center = self.current_token.clone();
+    /// Parses the rest of an assignment to `target`, which was just read.
+    fn assignment_statement(&mut self, target: Ident) -> Result<Stmt, Diagnostic> {
+        let checks = self.tokens.checks();
         self.eat(TokenType::ASSIGN)?;
-        let right = self.expr()?;
-        let mut children = Vec::with_capacity(2);
-        children.push(left);
-        children.push(right);
-        Ok(AST::new_with_children(center, children))
+        let value = self.expr()?;
+        Ok(Stmt::Assign {
+            span: self.span_from(target.span),
+            target,
+            value,
+            checks,
+        })
     }
+}
 
-
-
+fn eat_help(expected: &TokenType) -> String {
+    match expected {
+        TokenType::SEMICOLON => "statements and declarations are separated by `;`".to_string(),
+        TokenType::END => "every `BEGIN` needs a matching `END`".to_string(),
+        TokenType::RPAREN => "every `(` needs a matching `)`".to_string(),
+        TokenType::DOT => "a program or unit ends with `.` after its last `END`".to_string(),
+        TokenType::ASSIGN => "assignment is written `name := expression`".to_string(),
+        e => format!("insert {} before this token", e.describe()),
+    }
 }
 
 impl<'a> Iterator for Parser<'a> {
@@ -459,7 +908,7 @@ impl<'a> Iterator for Parser<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let TokenType::EOF = self.current_token.token_type() {
-            return None;
+            None
         } else {
             let mut return_token = self.get_next_token().unwrap();
             std::mem::swap(&mut self.current_token, &mut return_token);
@@ -475,3 +924,193 @@ fn generate() {
     let tokens = interp.into_iter().collect::<Vec<_>>();
     assert_eq!(tokens.len(), 9);
 }
+
+#[test]
+fn reports_every_syntax_error() {
+    let source = "PROGRAM p;\nVAR a, b : INTEGER;\n c INTEGER;\nBEGIN\n a := 2 +;\n b := 3\n a := b\nEND.";
+    let (program, errors) = Parser::new(source.as_bytes()).unwrap().parse_partial();
+    let lines = errors.iter().map(|e| e.span().unwrap().line_no).collect::<Vec<_>>();
+    assert_eq!(lines, vec![3, 5, 7]);
+    // `b := 3` and `a := b` still make it into the partial program.
+    assert_eq!(program.block.body.statements.len(), 2);
+
+    // The block of a procedure whose heading has an error is its own.
+    let errors = |source: &str| {
+        let (program, errors) = Parser::new(source.as_bytes()).unwrap().parse_partial();
+        let errors = errors.iter().map(|e| (e.message().to_string(), e.span().unwrap().line_no)).collect::<Vec<_>>();
+        (errors, program.block.body.statements.len())
+    };
+    let body = "BEGIN\n a := 2 +;\n b := 3\n a := b\nEND.";
+    let expected = |heading: &str| {
+        vec![
+            (heading.to_string(), 3),
+            ("Expected expression found `;`".to_string(), 5),
+            ("Expected `;` found identifier `a`".to_string(), 7),
+        ]
+    };
+    let declared = format!("PROGRAM p;\nVAR a, b : INTEGER;\nPROCEDURE Q(x INTEGER); BEGIN x := 1 END;\n{}", body);
+    assert_eq!(errors(&declared), (expected("Expected `:` found `INTEGER`"), 2));
+    let declared = format!("PROGRAM p;\nVAR a, b : INTEGER;\nPROCEDURE Q(a : INTEGER; b REAL) BEGIN END;\n{}", body);
+    assert_eq!(errors(&declared), (expected("Expected `:` found `REAL`"), 2));
+    let source =
+        "PROGRAM p; VAR a : INTEGER;\nBEGIN\n PROCEDURE (x : INTEGER); VAR y : INTEGER; BEGIN y := 1 END;\n a := 1\nEND.";
+    assert_eq!(errors(source).0.len(), 1);
+    assert_eq!(errors(source).1, 2);
+}
+
+#[test]
+fn limits_cascading_errors() {
+    let source = format!("PROGRAM p;\nVAR a : INTEGER;\nBEGIN\n{}END.", "a := ) ;\n".repeat(100));
+    let errors = Parser::new(source.as_bytes()).unwrap().parse().unwrap_err();
+    assert_eq!(errors.len(), MAX_ERRORS + 1);
+}
+
+#[test]
+fn requires_the_end_of_a_program() {
+    let errors = |source: &str| {
+        let errors = Parser::new(source.as_bytes()).unwrap().parse().unwrap_err();
+        errors.iter().map(|e| (e.message().to_string(), e.span().unwrap().start)).collect::<Vec<_>>()
+    };
+    assert_eq!(errors("PROGRAM p; BEGIN END"), [("Expected `.` found end of file".to_string(), 20)]);
+    assert_eq!(errors("PROGRAM p; BEGIN END. x := 1"), [("Unexpected identifier `x` found".to_string(), 22)]);
+    assert!(Parser::new(b"UNIT u; INTERFACE IMPLEMENTATION END").unwrap().parse_unit().is_err());
+}
+
+#[test]
+fn nodes_span_their_source() {
+    let source = "PROGRAM p;\nVAR a : INTEGER;\nBEGIN\n  a := (1 + a) * 2;\n  P(a, 3)\nEND.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let text = |span: Span| &source[span.start..span.end];
+    assert_eq!(text(program.span), source);
+    let statements = &program.block.body.statements;
+    assert_eq!(text(program.block.body.span), "BEGIN\n  a := (1 + a) * 2;\n  P(a, 3)\nEND");
+    assert_eq!(text(statements[0].span()), "a := (1 + a) * 2");
+    assert_eq!(text(statements[1].span()), "P(a, 3)");
+    let Stmt::Assign { value: Expr::Binary { left, .. }, .. } = &statements[0] else {
+        panic!("{:?} is not an assignment of a product", statements[0]);
+    };
+    assert_eq!(text(left.span()), "1 + a");
+    assert_eq!(statements[1].span().line_no, 5);
+}
+
+#[test]
+fn calls_span_their_arguments() {
+    let source = "PROGRAM p;\nVAR a : INTEGER;\nBEGIN\n  a := (1 + a) * Max(a, Min( 2 ))\nEND.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let text = |span: Span| &source[span.start..span.end];
+    let Stmt::Assign { value: Expr::Binary { right, .. }, .. } = &program.block.body.statements[0] else {
+        panic!("{:?} is not an assignment of a product", program.block.body.statements[0]);
+    };
+    assert_eq!(text(right.span()), "Max(a, Min( 2 ))");
+    let Expr::Call { name, args, .. } = &**right else {
+        panic!("{:?} is not a call", right);
+    };
+    assert_eq!(text(name.span), "Max");
+    assert_eq!(args.iter().map(|arg| text(arg.span())).collect::<Vec<_>>(), ["a", "Min( 2 )"]);
+}
+
+#[test]
+fn binary_operators_are_left_associative_by_precedence() {
+    let cases = [
+        ("a - b - c", "(a - b) - c"),
+        ("a * b * c", "(a * b) * c"),
+        ("a / b / c", "(a / b) / c"),
+        ("a DIV b * c", "(a DIV b) * c"),
+        ("a * b / c DIV d", "((a * b) / c) DIV d"),
+        ("a + b * c - d", "(a + (b * c)) - d"),
+        ("a / b + c / d", "(a / b) + (c / d)"),
+        ("-a * b + c", "(-(a * b)) + c"),
+        ("a - -b - c", "(a - (-b)) - c"),
+        ("a * -b", "a * (-b)"),
+    ];
+    let parse = |source: &str| Parser::new(source.as_bytes()).unwrap().parse_expression().unwrap();
+    for (source, grouped) in cases {
+        assert_eq!(parse(source), parse(grouped), "{}", source);
+    }
+}
+
+#[test]
+fn parses_units_and_checks_their_implementation() {
+    let source = "UNIT Shapes;
+        INTERFACE USES Base; VAR area : INTEGER; PROCEDURE Square(side : INTEGER);
+        IMPLEMENTATION VAR calls : INTEGER;
+        PROCEDURE Square(side : INTEGER); BEGIN area := side * side END;
+        BEGIN calls := 0 END.";
+    let unit = Parser::new(source.as_bytes()).unwrap().parse_unit().unwrap();
+    assert_eq!(unit.name.name, "Shapes");
+    assert_eq!(unit.interface.uses.len(), 1);
+    assert_eq!(unit.interface.procedures[0].name.name, "Square");
+    assert_eq!(unit.implementation.declarations.len(), 2);
+    assert!(unit.initialization.is_some());
+
+    let errors = |source: &str| {
+        Parser::new(source.as_bytes())
+            .unwrap()
+            .parse_unit()
+            .unwrap_err()
+            .iter()
+            .map(|error| error.message().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        errors("UNIT u; INTERFACE PROCEDURE P; PROCEDURE Q(a : INTEGER); IMPLEMENTATION PROCEDURE Q; BEGIN END; END."),
+        [
+            "Procedure `P` is declared in the interface but not implemented.",
+            "Parameters of `Q` differ from its declaration in the interface.",
+        ]
+    );
+    assert_eq!(
+        errors("UNIT u; INTERFACE VAR x : INTEGER; IMPLEMENTATION VAR X : REAL; END."),
+        ["Duplicate identifier `x` in unit `u`."]
+    );
+    assert_eq!(
+        Parser::new(b"PROGRAM p; USES a, b, A; BEGIN END.").unwrap().parse().unwrap_err().iter().next().unwrap().message(),
+        "Unit `A` is named twice after USES."
+    );
+}
+
+#[test]
+fn stops_at_code_nested_too_deeply() {
+    super::limits::with_stack(DEFAULT_DEPTH, 0, || {
+        let program = |body: String| format!("PROGRAM p; VAR a : INTEGER; BEGIN {} END.", body);
+        let parens = |depth: usize| format!("a := {}a{}", "(".repeat(depth), ")".repeat(depth));
+        let signs = |depth: usize| format!("a := {}a", "-".repeat(depth));
+        let blocks = |depth: usize| format!("{}a := 1{}", "BEGIN ".repeat(depth), " END".repeat(depth));
+        let procedures = |depth: usize| format!("PROCEDURE q; {}BEGIN END{}", "PROCEDURE q; ".repeat(depth), "; BEGIN END".repeat(depth));
+        for nest in [parens, signs, blocks, procedures] {
+            let shallow = program(nest(DEFAULT_DEPTH - 5));
+            assert!(Parser::new(shallow.as_bytes()).unwrap().parse().is_ok(), "{}", shallow);
+            let deep = program(nest(DEFAULT_DEPTH * 100));
+            let errors = Parser::new(deep.as_bytes()).unwrap().parse().unwrap_err();
+            assert_eq!(errors.len(), 1, "{}", deep);
+            let error = errors.iter().next().unwrap();
+            assert_eq!(error.message(), format!("Code is nested more than {} levels deep.", DEFAULT_DEPTH));
+            assert_eq!(error.limit(), Some(Limit::Depth));
+        }
+
+        let shallow = program(parens(10));
+        let error = Parser::new(shallow.as_bytes()).unwrap().with_max_depth(10).parse().unwrap_err();
+        assert_eq!(error.iter().next().unwrap().message(), "Code is nested more than 10 levels deep.");
+    })
+}
+
+#[test]
+fn runs_of_operators_do_not_nest() {
+    let sum = format!("PROGRAM p; VAR a : INTEGER; BEGIN a := 1{} END.", " + a * 2 - 1".repeat(DEFAULT_DEPTH * 100));
+    let program = Parser::new(sum.as_bytes()).unwrap().with_max_depth(5).parse().unwrap();
+    let Stmt::Assign { value, .. } = &program.block.body.statements[0] else {
+        panic!("{:?} is not an assignment", program.block.body.statements[0]);
+    };
+    let (first, operations) = value.left_spine();
+    assert_eq!(*first, Expr::Integer { value: 1, text: None, span: Span::default() });
+    assert_eq!(operations.len(), DEFAULT_DEPTH * 200);
+    assert_eq!(program.clone(), program);
+    // Printed as `rusterp ast` prints it, a run takes space in proportion
+    // to its length.
+    let printed = |length: usize| {
+        let sum = format!("PROGRAM p; VAR a : INTEGER; BEGIN a := 1{} END.", " + a * 2 - 1".repeat(length));
+        format!("{:#?}", Parser::new(sum.as_bytes()).unwrap().parse().unwrap()).len()
+    };
+    let (short, long) = (printed(100), printed(1000));
+    assert!(long < short * 11, "{} bytes, then {}", short, long);
+}
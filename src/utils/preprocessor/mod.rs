@@ -0,0 +1,491 @@
+//! Compiler directives: comments starting with `$`, such as `{$R+}`.
+//!
+//! The preprocessor sits between the lexer and the parser. It carries out
+//! the directives it meets and hands on the tokens that are left:
+//!
+//! - `{$R+}` and `{$R-}` switch range checks on and off from that point on,
+//!   `{$Q+}` and `{$Q-}` overflow checks. Several switches can be given at
+//!   once, as in `{$R+,Q-}`, and `{$RANGECHECKS ON}` and
+//!   `{$OVERFLOWCHECKS OFF}` are the long forms. Both start out off.
+//! - `{$I file}` or `{$INCLUDE file}` reads the tokens of `file`, looked up
+//!   next to the file the directive is in, in place of the directive.
+//! - `{$DEFINE name}` and `{$UNDEF name}` set and clear a symbol, and
+//!   `{$IFDEF name}`, `{$IFNDEF name}`, `{$ELSE}` and `{$ENDIF}` keep or drop
+//!   the code between them depending on it. Symbols ignore case.
+//!
+//! Tokens read from an included file carry its number in their spans, so
+//! errors in it are reported at its own lines.
+
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::ast::ident;
+use super::ast::stmt::Checks;
+use super::err::diagnostic::Diagnostic;
+use super::lexer::{Lexer, Span, Token, TokenType};
+use super::source::{FileId, SourceMap};
+
+pub struct Preprocessor<'a> {
+    /// The file being read and the files that included it, innermost last.
+    frames: Vec<Frame<'a>>,
+    sources: Arc<SourceMap>,
+    /// Keyed by `ident::key`.
+    defines: HashSet<String>,
+    /// The `$IFDEF`s that are not closed yet, innermost last.
+    conditions: Vec<Condition>,
+    checks: Checks,
+    keep_comments: bool,
+    includes: bool,
+    /// Comments to hand out before reading on.
+    pending: VecDeque<Token>,
+    /// With `keep_comments`, where the code being dropped starts.
+    dropped_from: Option<Span>,
+}
+
+struct Frame<'a> {
+    lexer: Lexer<'a>,
+    file: FileId,
+    /// The file's canonical path, to notice a file including itself.
+    path: Option<PathBuf>,
+    /// How many conditions were open when the file was entered. The file
+    /// may not close those.
+    conditions: usize,
+}
+
+struct Condition {
+    /// The `$IFDEF` or `$IFNDEF`.
+    span: Span,
+    /// Whether the code being read is kept.
+    active: bool,
+    /// Whether the code around the `$IFDEF` is kept.
+    outer: bool,
+    seen_else: bool,
+}
+
+impl<'a> Preprocessor<'a> {
+    /// Preprocesses `bytes`, which are not from a file. Includes are looked
+    /// up in the working directory.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let sources = Arc::new(SourceMap::new());
+        let file = sources.add("<input>", bytes.to_vec());
+        Self::with_lexer(sources, file, Lexer::new(bytes))
+    }
+
+    /// Preprocesses `file` of `sources`, adding the files it includes.
+    pub fn with_sources(sources: Arc<SourceMap>, file: FileId) -> Preprocessor<'static> {
+        let bytes = sources.file(file).bytes.clone();
+        Preprocessor::with_lexer(sources, file, Lexer::owned(bytes))
+    }
+
+    fn with_lexer(sources: Arc<SourceMap>, file: FileId, lexer: Lexer<'a>) -> Self {
+        let path = sources.identity(Path::new(&sources.file(file).name));
+        Self {
+            frames: vec![Frame {
+                lexer: lexer.keep_comments().in_file(file),
+                file,
+                path,
+                conditions: 0,
+            }],
+            sources,
+            defines: HashSet::new(),
+            conditions: Vec::new(),
+            checks: Checks::default(),
+            keep_comments: false,
+            includes: true,
+            pending: VecDeque::new(),
+            dropped_from: None,
+        }
+    }
+
+    /// Hands out comments as `COMMENT` tokens, as `Lexer::with_comments`
+    /// does. Directives come out as comments too, and so does code dropped
+    /// by a conditional, all of it as one comment.
+    pub fn keep_comments(self) -> Self {
+        Self {
+            keep_comments: true,
+            ..self
+        }
+    }
+
+    /// Leaves `$I` directives alone, for tools that work on the text of a
+    /// single file.
+    pub fn without_includes(self) -> Self {
+        Self {
+            includes: false,
+            ..self
+        }
+    }
+
+    /// Defines `symbol` as `{$DEFINE symbol}` at the top of the file would.
+    pub fn define(&mut self, symbol: &str) {
+        self.defines.insert(ident::key(symbol));
+    }
+
+    /// The files read so far.
+    pub fn sources(&self) -> &Arc<SourceMap> {
+        &self.sources
+    }
+
+    /// The checks switched on by the directives read so far.
+    pub fn checks(&self) -> Checks {
+        self.checks
+    }
+
+    pub fn get_next_token(&mut self) -> Result<Token, Diagnostic> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Ok(token);
+            }
+            let frame = self.frames.last_mut().expect("the file being compiled is never left");
+            let token = match frame.lexer.get_next_token() {
+                Ok(token) => token,
+                // Dropped code does not even have to lex.
+                Err(_) if !self.active() => continue,
+                Err(e) => return Err(e),
+            };
+            match token.token_type() {
+                TokenType::DIRECTIVE(text) => {
+                    let text = text.clone();
+                    let was_active = self.active();
+                    self.directive(&text, token.span())?;
+                    // A directive in dropped code is part of the comment
+                    // made of that code.
+                    if self.keep_comments && (was_active || self.active()) {
+                        self.pending.push_back(Token::new(TokenType::COMMENT(text), token.span()));
+                    }
+                }
+                TokenType::EOF => {
+                    let frame = self.frames.last().expect("the file being compiled is never left");
+                    if let Some(open) = self.conditions.get(frame.conditions) {
+                        let error = Diagnostic::new("Conditional directive is never closed.")
+                            .with_span(open.span)
+                            .with_label("opened here")
+                            .with_help("close it with `{$ENDIF}` in the same file");
+                        self.conditions.truncate(frame.conditions);
+                        self.dropped_from = None;
+                        return Err(error);
+                    }
+                    if self.frames.len() == 1 {
+                        return Ok(token);
+                    }
+                    self.frames.pop();
+                }
+                TokenType::COMMENT(_) if self.keep_comments && self.active() => return Ok(token),
+                TokenType::COMMENT(_) => {}
+                _ if self.active() => return Ok(token),
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether the code being read is kept.
+    fn active(&self) -> bool {
+        self.conditions.last().is_none_or(|condition| condition.active)
+    }
+
+    /// Carries out the directive written as `text`, delimiters included.
+    fn directive(&mut self, text: &str, span: Span) -> Result<(), Diagnostic> {
+        let body = text
+            .strip_prefix("{$")
+            .and_then(|body| body.strip_suffix('}'))
+            .or_else(|| text.strip_prefix("(*$").and_then(|body| body.strip_suffix("*)")))
+            .unwrap_or_default()
+            .trim();
+        let name_len = body.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(body.len());
+        let (name, argument) = (body[..name_len].to_ascii_uppercase(), body[name_len..].trim());
+        let error = |message: String| Diagnostic::new(message).with_span(span);
+        let symbol = || match argument {
+            "" => Err(error(format!("Expected a symbol after `${}`.", name))
+                .with_help(format!("write it like `{{${} DEBUG}}`", name))),
+            symbol => Ok(ident::key(symbol)),
+        };
+
+        match name.as_str() {
+            "IFDEF" | "IFNDEF" => {
+                let taken = self.defines.contains(&symbol()?) == (name == "IFDEF");
+                let outer = self.active();
+                self.conditions.push(Condition { span, active: outer && taken, outer, seen_else: false });
+                if outer && !taken {
+                    self.start_dropping(span, text);
+                }
+                return Ok(());
+            }
+            "ELSE" | "ENDIF" => {
+                let open = self.frames.last().map_or(0, |frame| frame.conditions);
+                if self.conditions.len() <= open {
+                    return Err(error(format!("`{}` without a matching `{{$IFDEF}}`.", text))
+                        .with_label("nothing to close"));
+                }
+                let condition = self.conditions.last_mut().expect("checked above");
+                let (was_active, outer) = (condition.active, condition.outer);
+                if name == "ELSE" {
+                    if condition.seen_else {
+                        return Err(error("Second `{$ELSE}` for the same `{$IFDEF}`.".to_string())
+                            .with_label("the first one already switched branches"));
+                    }
+                    condition.seen_else = true;
+                    condition.active = outer && !was_active;
+                    if outer && was_active {
+                        self.start_dropping(span, text);
+                    }
+                } else {
+                    self.conditions.pop();
+                }
+                if outer && !was_active {
+                    self.stop_dropping(span);
+                }
+                return Ok(());
+            }
+            _ if !self.active() => return Ok(()),
+            "DEFINE" => {
+                let symbol = symbol()?;
+                self.defines.insert(symbol);
+            }
+            "UNDEF" => {
+                self.defines.remove(&symbol()?);
+            }
+            "I" | "INCLUDE" if !body[name_len..].starts_with(['+', '-']) => {
+                if self.includes {
+                    let path = argument.trim_matches('\'');
+                    if path.is_empty() {
+                        return Err(error("Expected a file name after `$I`.".to_string())
+                            .with_help("write it like `{$I defs.inc}`"));
+                    }
+                    self.include(path, span)?;
+                }
+            }
+            "RANGECHECKS" | "OVERFLOWCHECKS" => {
+                let on = match argument.to_ascii_uppercase().as_str() {
+                    "ON" => true,
+                    "OFF" => false,
+                    _ => return Err(error(format!("Expected `ON` or `OFF` after `${}`.", name))),
+                };
+                if name == "RANGECHECKS" {
+                    self.checks.range = on;
+                } else {
+                    self.checks.overflow = on;
+                }
+            }
+            _ if body[name_len..].starts_with(['+', '-']) => {
+                for switch in body.split(',').map(str::trim) {
+                    let mut chars = switch.chars();
+                    let (letter, sign) = (chars.next(), chars.next());
+                    let on = match sign {
+                        Some('+') => true,
+                        Some('-') => false,
+                        _ => return Err(error(format!("Expected a switch such as `R+` found `{}`.", switch))),
+                    };
+                    match letter.map(|c| c.to_ascii_uppercase()) {
+                        Some('R') if chars.next().is_none() => self.checks.range = on,
+                        Some('Q') if chars.next().is_none() => self.checks.overflow = on,
+                        _ => {
+                            return Err(error(format!("Unknown switch `{}`.", switch))
+                                .with_help("the switches are `R` for range checks and `Q` for overflow checks"))
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(error(format!("Unknown directive `{}`.", text))
+                    .with_label("not a directive")
+                    .with_help("the directives are $R, $Q, $I, $DEFINE, $UNDEF, $IFDEF, $IFNDEF, $ELSE and $ENDIF"))
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, path: &str, span: Span) -> Result<(), Diagnostic> {
+        let including = self.sources.file(self.frames.last().map_or(0, |frame| frame.file));
+        let path = including.resolve(path);
+        let name = path.to_string_lossy().into_owned();
+        let canonical = self.sources.identity(&path);
+        if canonical.is_some() && self.frames.iter().any(|frame| frame.path == canonical) {
+            return Err(
+                Diagnostic::new(format!("`{}` includes itself.", name))
+                    .with_span(span)
+                    .with_label("included again here")
+            );
+        }
+        let bytes = self.sources.read(&path).map_err(|e| {
+            Diagnostic::new(format!("Cannot include `{}`: {}.", name, e))
+                .with_span(span)
+                .with_label("included here")
+        })?;
+        let file = self.sources.add(name, bytes.clone());
+        self.frames.push(Frame {
+            lexer: Lexer::owned(bytes).keep_comments().in_file(file),
+            file,
+            path: canonical,
+            conditions: self.conditions.len(),
+        });
+        Ok(())
+    }
+
+    /// Code stops being kept after the directive `text` at `span`.
+    fn start_dropping(&mut self, span: Span, text: &str) {
+        if !self.keep_comments {
+            return;
+        }
+        let (line_no, column) = match text.rfind('\n') {
+            Some(newline) => (span.line_no + text.matches('\n').count(), text[newline + 1..].chars().count() + 1),
+            None => (span.line_no, span.column + text.chars().count()),
+        };
+        self.dropped_from = Some(Span { start: span.end, end: span.end, line_no, column, ..span });
+    }
+
+    /// Code is kept again from the directive at `span` on. The code dropped
+    /// before it becomes a comment.
+    fn stop_dropping(&mut self, span: Span) {
+        let Some(from) = self.dropped_from.take() else {
+            return;
+        };
+        let frame = self.frames.last().expect("the file being compiled is never left");
+        let text = frame.lexer.text(from.start, span.start);
+        let mut start = from;
+        for char in text.chars().take_while(|c| c.is_whitespace()) {
+            start.start += char.len_utf8();
+            if char == '\n' {
+                start.line_no += 1;
+                start.column = 1;
+            } else {
+                start.column += 1;
+            }
+        }
+        let dropped = text.trim();
+        if !dropped.is_empty() {
+            let span = Span { end: start.start + dropped.len(), ..start };
+            self.pending.push_back(Token::new(TokenType::COMMENT(dropped.to_string()), span));
+        }
+    }
+}
+
+#[cfg(test)]
+fn tokens(source: &str, defines: &[&str]) -> Result<Vec<String>, Diagnostic> {
+    let mut preprocessor = Preprocessor::new(source.as_bytes());
+    for symbol in defines {
+        preprocessor.define(symbol);
+    }
+    let mut tokens = Vec::new();
+    loop {
+        match preprocessor.get_next_token()?.token_type() {
+            TokenType::EOF => return Ok(tokens),
+            TokenType::IDENTIFIER(name) => tokens.push(name.clone()),
+            other => tokens.push(other.describe()),
+        }
+    }
+}
+
+#[test]
+fn keeps_the_branches_whose_condition_holds() {
+    let source = "a {$IFDEF debug} b {$IFNDEF fast} c {$ELSE} d {$ENDIF} {$ELSE} e {$ENDIF} f";
+    assert_eq!(tokens(source, &[]).unwrap(), ["a", "e", "f"]);
+    assert_eq!(tokens(source, &["DEBUG"]).unwrap(), ["a", "b", "c", "f"]);
+    assert_eq!(tokens(source, &["Debug", "fast"]).unwrap(), ["a", "b", "d", "f"]);
+
+    let source = "{$DEFINE x} {$IFDEF X} a {$UNDEF x} {$ENDIF} {$IFDEF x} b {$ENDIF} (*$IFNDEF x*) c (*$ENDIF*)";
+    assert_eq!(tokens(source, &[]).unwrap(), ["a", "c"]);
+    // Directives in dropped code are not carried out, and the code itself
+    // need not lex.
+    assert_eq!(tokens("{$IFDEF x} {$DEFINE y} ? {$R+} {$ENDIF} {$IFDEF y} a {$ENDIF} b", &[]).unwrap(), ["b"]);
+}
+
+#[test]
+fn reports_malformed_directives() {
+    let cases = [
+        ("a {$ELSE}", "`{$ELSE}` without a matching `{$IFDEF}`."),
+        ("{$IFDEF x} {$ENDIF} {$ENDIF}", "`{$ENDIF}` without a matching `{$IFDEF}`."),
+        ("{$IFDEF x} {$ELSE} {$ELSE} {$ENDIF}", "Second `{$ELSE}` for the same `{$IFDEF}`."),
+        ("a\n  {$IFDEF x} b", "Conditional directive is never closed."),
+        ("{$IFDEF}", "Expected a symbol after `$IFDEF`."),
+        ("{$MODE objfpc}", "Unknown directive `{$MODE objfpc}`."),
+        ("{$R+,X-}", "Unknown switch `X-`."),
+        ("{$RANGECHECKS maybe}", "Expected `ON` or `OFF` after `$RANGECHECKS`."),
+    ];
+    for (source, message) in cases {
+        assert_eq!(tokens(source, &[]).unwrap_err().message(), message, "{}", source);
+    }
+    let open = tokens("a\n  {$IFDEF x} b", &[]).unwrap_err();
+    assert_eq!(open.span(), Some(Span::new(4, 14, 2, 3)));
+}
+
+#[test]
+fn switches_checks() {
+    let mut preprocessor = Preprocessor::new(b"{$R+} a {$Q+,R-} b {$OVERFLOWCHECKS OFF} {$RANGECHECKS ON} c");
+    let mut seen = Vec::new();
+    while !matches!(preprocessor.get_next_token().unwrap().token_type(), TokenType::EOF) {
+        seen.push(preprocessor.checks());
+    }
+    let checks = |range, overflow| Checks { range, overflow };
+    assert_eq!(seen, [checks(true, false), checks(false, true), checks(true, false)]);
+}
+
+#[test]
+fn includes_files_and_maps_their_spans() {
+    let dir = std::env::temp_dir().join(format!("rusterp-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("main.pa"), "a {$I inc/one.inc} d").unwrap();
+    std::fs::write(dir.join("inc/one.inc"), "b\n  {$INCLUDE 'two.inc'}").unwrap();
+    std::fs::write(dir.join("inc/two.inc"), "\n\n    c").unwrap();
+    std::fs::write(dir.join("loop.pa"), "{$I loop.pa}").unwrap();
+    std::fs::write(dir.join("bad.pa"), "{$I bad.inc}").unwrap();
+    std::fs::write(dir.join("bad.inc"), "x\n  ?").unwrap();
+
+    let open = |name: &str| {
+        let sources = Arc::new(SourceMap::new());
+        let path = dir.join(name);
+        let file = sources.add(path.to_string_lossy(), std::fs::read(&path).unwrap());
+        Preprocessor::with_sources(sources, file)
+    };
+    let mut preprocessor = open("main.pa");
+    let mut seen = Vec::new();
+    loop {
+        let token = preprocessor.get_next_token().unwrap();
+        if let TokenType::EOF = token.token_type() {
+            break;
+        }
+        seen.push((token.token_type().describe(), token.span().file, token.line_no(), token.column()));
+    }
+    let names = seen.iter().map(|(name, ..)| name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["identifier `a`", "identifier `b`", "identifier `c`", "identifier `d`"]);
+    assert_eq!(seen[2].1..=seen[2].3, 2..=5);
+    assert!(preprocessor.sources().file(2).name.ends_with("two.inc"));
+
+    let error = open("loop.pa").get_next_token().unwrap_err();
+    assert!(error.message().ends_with("loop.pa` includes itself."), "{}", error.message());
+
+    let mut preprocessor = open("bad.pa");
+    preprocessor.get_next_token().unwrap();
+    let error = preprocessor.get_next_token().unwrap_err();
+    let rendered = preprocessor.sources().render(&error.into(), false);
+    assert!(rendered.contains("bad.inc:2:3\n"), "{}", rendered);
+    assert!(rendered.contains("2 |   ?\n"), "{}", rendered);
+
+    let error = Preprocessor::new(b"{$I missing.inc}").get_next_token().unwrap_err();
+    assert!(error.message().starts_with("Cannot include `missing.inc`: "), "{}", error.message());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn keeps_directives_and_dropped_code_as_comments() {
+    let source = "a {$IFDEF x}\n  b; {$I none.inc}\n  c\n{$ELSE} d {$ENDIF}";
+    let mut preprocessor = Preprocessor::new(source.as_bytes()).keep_comments().without_includes();
+    let mut seen = Vec::new();
+    loop {
+        let token = preprocessor.get_next_token().unwrap();
+        match token.token_type() {
+            TokenType::EOF => break,
+            TokenType::COMMENT(text) => seen.push((text.clone(), token.line_no(), token.column())),
+            other => seen.push((other.describe(), token.line_no(), token.column())),
+        }
+    }
+    let comment = |text: &str, line, column| (text.to_string(), line, column);
+    assert_eq!(seen, [
+        comment("identifier `a`", 1, 1),
+        comment("{$IFDEF x}", 1, 3),
+        comment("b; {$I none.inc}\n  c", 2, 3),
+        comment("{$ELSE}", 4, 1),
+        comment("identifier `d`", 4, 9),
+        comment("{$ENDIF}", 4, 11),
+    ]);
+}
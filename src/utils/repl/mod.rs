@@ -0,0 +1,212 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Arc;
+
+use super::bytecode;
+use super::err::diagnostic::Diagnostics;
+use super::host::Host;
+use super::interpreter::Interpreter;
+use super::lexer::{Lexer, Token, TokenType};
+use super::limits::DEFAULT_DEPTH;
+use super::parser::Parser;
+use super::preprocessor::Preprocessor;
+use super::source::SourceMap;
+use super::units;
+
+const NAME: &str = "<repl>";
+
+const HELP: &str = "\
+Enter an expression to print its value, statements to run them, or a VAR
+section to declare variables. Declarations persist for the whole session.
+A BEGIN or PROCEDURE keeps reading lines until its matching END.
+
+Commands:
+    :help         Show this message
+    :vars         List the declared variables and procedures
+    :reset        Forget every declaration
+    :load <file>  Run a program and keep its global scope
+    :quit         Leave the REPL";
+
+/// An interactive session that keeps its global scope between inputs.
+pub struct Repl {
+    interpreter: Interpreter,
+    color: bool,
+}
+
+impl Repl {
+    pub fn new(color: bool) -> Self {
+        Self {
+            interpreter: Interpreter::session(),
+            color,
+        }
+    }
+
+    /// Reads inputs from `input` until it runs out or `:quit` is entered.
+    /// Results go to `output` and errors to `errors`.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write, mut errors: impl Write) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut buffer = String::new();
+        loop {
+            write!(output, "{}", if buffer.is_empty() { "> " } else { "... " })?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            if buffer.is_empty() && line.trim() == ":quit" {
+                break;
+            }
+            buffer.push_str(&line);
+            buffer.push('\n');
+            if !is_complete(&buffer) {
+                continue;
+            }
+            match self.eval(&buffer) {
+                Ok(Some(result)) => writeln!(output, "{}", result)?,
+                Ok(None) => {}
+                Err(e) => write!(errors, "{}", e)?,
+            }
+            buffer.clear();
+        }
+        writeln!(output)
+    }
+
+    /// Evaluates one complete input. Errors come back rendered against it.
+    pub fn eval(&mut self, input: &str) -> Result<Option<String>, String> {
+        let trimmed = input.trim();
+        if let Some(command) = trimmed.strip_prefix(':') {
+            return self.command(command);
+        }
+        if trimmed.is_empty() {
+            return Ok(None);
+        }
+        self.source(input).map_err(|e| e.render(NAME, input, self.color))
+    }
+
+    fn source(&mut self, input: &str) -> Result<Option<String>, Diagnostics> {
+        let bytes = input.as_bytes();
+        let first = Lexer::new(bytes).get_next_token()?;
+        if let TokenType::VAR = first.token_type() {
+            let declarations = Parser::new(bytes)?.parse_declarations()?;
+            self.interpreter.declare(&declarations);
+            return Ok(None);
+        }
+        if let Ok(expr) = Parser::new(bytes)?.parse_expression() {
+            let value = self.interpreter.evaluate(&expr)?;
+            return Ok(Some(value.to_string()));
+        }
+        let statements = Parser::new(bytes)?.parse_statements()?;
+        self.interpreter.execute(&statements)?;
+        Ok(None)
+    }
+
+    /// Runs `command`, a name followed by what it is given: the rest of the
+    /// line, so that a path may hold spaces.
+    fn command(&mut self, command: &str) -> Result<Option<String>, String> {
+        let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        match (name, rest.trim()) {
+            ("help", "") => Ok(Some(HELP.to_string())),
+            ("vars", "") => Ok(Some(self.vars())),
+            ("reset", "") => {
+                self.interpreter = Interpreter::session();
+                Ok(None)
+            }
+            ("load", "") => Err("error: `:load` needs a file name\n".to_string()),
+            ("load", path) => self.load(path),
+            _ => Err(format!("error: unknown command `:{}`, try `:help`\n", command)),
+        }
+    }
+
+    fn vars(&self) -> String {
+        self.interpreter
+            .globals()
+            .iter()
+            .map(|(name, binding)| format!("{} : {}", name, binding))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn load(&mut self, path: &str) -> Result<Option<String>, String> {
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| format!("error: cannot read `{}`: {}\n", path, e))?;
+        let sources = Arc::new(SourceMap::new());
+        let file = sources.add(path, bytes);
+        let render = |e: Diagnostics| sources.render(&e, self.color);
+        let program = Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file))
+            .map_err(Diagnostics::from)
+            .and_then(|mut parser| parser.parse())
+            .map_err(render)?;
+        let units = units::load(&program.uses, file, &sources, &[], DEFAULT_DEPTH).map_err(render)?;
+        bytecode::compile_with_host(&program, &units, &Host::new()).map_err(render)?;
+        let loaded = Interpreter::with_units(program, units);
+        loaded.interprete().map_err(|e| render(e.into()))?;
+        self.interpreter.define(loaded.globals());
+        Ok(None)
+    }
+}
+
+/// Whether `input` can be parsed as it is, or is an unfinished `BEGIN`
+/// block or procedure declaration that needs more lines.
+pub fn is_complete(input: &str) -> bool {
+    let mut lexer = Lexer::new(input.as_bytes());
+    let mut depth = 0usize;
+    let mut procedures = 0usize;
+    loop {
+        match lexer.get_next_token().as_ref().map(Token::token_type) {
+            Ok(TokenType::BEGIN) => {
+                // The outermost BEGIN of a procedure is its body.
+                if depth == 0 && procedures > 0 {
+                    procedures -= 1;
+                }
+                depth += 1;
+            }
+            Ok(TokenType::END) => depth = depth.saturating_sub(1),
+            Ok(TokenType::PROCEDURE) => procedures += 1,
+            Ok(TokenType::EOF) => return depth == 0 && procedures == 0,
+            // Let the parser report it.
+            Err(_) => return true,
+            Ok(_) => {}
+        }
+    }
+}
+
+#[test]
+fn session_keeps_declarations() {
+    let mut repl = Repl::new(false);
+    assert_eq!(repl.eval("VAR a, b : INTEGER"), Ok(None));
+    assert_eq!(repl.eval("a := 4; b := a * 2"), Ok(None));
+    assert_eq!(repl.eval("a + b"), Ok(Some("12".to_string())));
+    assert_eq!(repl.eval(":vars"), Ok(Some("a : INTEGER = 4\nb : INTEGER = 8".to_string())));
+    assert!(repl.eval("c := 1").unwrap_err().contains("Variable `c` not found."));
+    assert_eq!(repl.eval(":reset"), Ok(None));
+    assert!(repl.eval("a").is_err());
+}
+
+#[test]
+fn waits_for_matching_end() {
+    assert!(!is_complete("BEGIN\n a := 1;\n"));
+    assert!(!is_complete("BEGIN BEGIN END;\n"));
+    assert!(is_complete("BEGIN BEGIN END END\n"));
+    assert!(!is_complete("PROCEDURE p;\nVAR x : INTEGER;\n"));
+    assert!(is_complete("PROCEDURE p;\nVAR x : INTEGER;\nBEGIN x := 1 END\n"));
+    assert!(is_complete("a := 1\n"));
+}
+
+#[test]
+fn runs_a_session_writing_errors_apart() {
+    let dir = std::env::temp_dir().join(format!("rusterp-repl-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a program.pa");
+    std::fs::write(&path, "PROGRAM p; VAR n : INTEGER; BEGIN n := 6 END.").unwrap();
+    let input = format!("VAR a : INTEGER\nb := 1\n:load {}\nn * 7\n", path.display());
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let ran = Repl::new(false).run(input.as_bytes(), &mut output, &mut errors);
+    std::fs::remove_dir_all(&dir).unwrap();
+    ran.unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "> > > > 42\n> \n");
+    let errors = String::from_utf8(errors).unwrap();
+    assert!(errors.starts_with("error: Variable `b` not found."), "{}", errors);
+    assert_eq!(errors.matches("error:").count(), 1, "{}", errors);
+}
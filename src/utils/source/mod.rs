@@ -0,0 +1,178 @@
+//! The files that make up a program: the one being compiled and every file
+//! it includes. Spans name their file by its number here, so diagnostics
+//! point into the file the error is in.
+//!
+//! Files are read as the map's `FileAccess` says: from the filesystem, not
+//! at all, or through the embedder.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::err::diagnostic::{Diagnostic, Diagnostics};
+
+/// The number of a file in its `SourceMap`, in the order files were added.
+pub type FileId = usize;
+
+#[derive(Debug)]
+pub struct SourceFile {
+    /// The path the file was read from, or a placeholder such as
+    /// `<stdin>`. Files it includes are looked up next to it.
+    pub name: String,
+    pub bytes: Vec<u8>,
+    /// `bytes` with invalid UTF-8 replaced, for showing in diagnostics.
+    pub text: String,
+}
+
+impl SourceFile {
+    /// Where a file included from this one as `path` is.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        match Path::new(&self.name).parent() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+/// Reads the file at a path for the embedder: its bytes, or a message for
+/// why there are none.
+pub type ReadFile = dyn Fn(&Path) -> Result<Vec<u8>, String> + Send + Sync;
+
+/// How the files a program includes, and the units it uses, are read. Not
+/// at all by default, so that source nobody has checked reads nothing from
+/// the host.
+#[derive(Clone, Default)]
+pub enum FileAccess {
+    /// From the filesystem.
+    Filesystem,
+    /// Not at all: `{$I}` and `USES` fail to compile.
+    #[default]
+    Forbidden,
+    /// By the embedder, given the path as it was resolved.
+    Callback(Arc<ReadFile>),
+}
+
+impl fmt::Debug for FileAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Filesystem => write!(f, "Filesystem"),
+            Self::Forbidden => write!(f, "Forbidden"),
+            Self::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// Files are added while parsing, so the map is shared with the parser and
+/// filled through a shared reference. A compiled program keeps it, and may
+/// be sent to other threads.
+#[derive(Debug)]
+pub struct SourceMap {
+    files: Mutex<Vec<Arc<SourceFile>>>,
+    access: FileAccess,
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SourceMap {
+    /// A map that reads the files added to it from the filesystem, as the
+    /// `rusterp` command does.
+    pub fn new() -> Self {
+        Self::with_access(FileAccess::Filesystem)
+    }
+
+    /// A map that reads the files added to it as `access` says.
+    pub fn with_access(access: FileAccess) -> Self {
+        Self { files: Mutex::default(), access }
+    }
+
+    pub fn access(&self) -> &FileAccess {
+        &self.access
+    }
+
+    /// Reads the file at `path`, to be added.
+    pub fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        match &self.access {
+            FileAccess::Filesystem => std::fs::read(path).map_err(|e| e.to_string()),
+            FileAccess::Forbidden => Err("this program may not read files".to_string()),
+            FileAccess::Callback(read) => read(path),
+        }
+    }
+
+    /// What tells apart the file at `path` from others, if it is found: its
+    /// canonical path on the filesystem, or `path` itself otherwise.
+    pub fn identity(&self, path: &Path) -> Option<PathBuf> {
+        match &self.access {
+            FileAccess::Filesystem => std::fs::canonicalize(path).ok(),
+            _ => Some(path.to_path_buf()),
+        }
+    }
+
+    pub fn add(&self, name: impl Into<String>, bytes: Vec<u8>) -> FileId {
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        let mut files = self.files();
+        files.push(Arc::new(SourceFile { name: name.into(), bytes, text }));
+        files.len() - 1
+    }
+
+    /// # Panics
+    ///
+    /// If `file` was not added to this map.
+    pub fn file(&self, file: FileId) -> Arc<SourceFile> {
+        self.files()[file].clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files().is_empty()
+    }
+
+    /// The files, which no panic can leave half added.
+    fn files(&self) -> MutexGuard<'_, Vec<Arc<SourceFile>>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Renders every diagnostic against the file its span is in. Those
+    /// without a span are shown against file 0.
+    pub fn render(&self, diagnostics: &Diagnostics, color: bool) -> String {
+        let files = self.files();
+        diagnostics.render_with(color, |span| {
+            let file = &files[span.map_or(0, |span| span.file)];
+            (file.name.as_str(), file.text.as_str())
+        })
+    }
+
+    /// Renders the notes of `diagnostic` alone, each against its file, as
+    /// `render` renders them after it.
+    pub fn render_notes(&self, diagnostic: &Diagnostic, color: bool) -> String {
+        let files = self.files();
+        diagnostic.render_notes_with(color, |span| {
+            let file = &files[span.map_or(0, |span| span.file)];
+            (file.name.as_str(), file.text.as_str())
+        })
+    }
+}
+
+#[test]
+fn renders_against_the_file_of_the_span() {
+    use super::lexer::Span;
+
+    let sources = SourceMap::new();
+    sources.add("main.pa", b"PROGRAM p;\n{$I part.inc}\n".to_vec());
+    let part = sources.add("part.inc", b"BEGIN\n  a := ;\nEND".to_vec());
+    let error = Diagnostic::new("Expected expression found `;`").with_span(Span::new(13, 14, 2, 8).in_file(part));
+    let rendered = sources.render(&error.into(), false);
+    assert!(rendered.contains(" --> part.inc:2:8\n"), "{}", rendered);
+    assert!(rendered.contains("2 |   a := ;\n"), "{}", rendered);
+    assert_eq!(sources.file(0).resolve("part.inc"), PathBuf::from("part.inc"));
+    assert_eq!(
+        SourceFile { name: "src/main.pa".to_string(), bytes: Vec::new(), text: String::new() }.resolve("x.inc"),
+        PathBuf::from("src/x.inc")
+    );
+}
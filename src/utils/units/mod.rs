@@ -0,0 +1,231 @@
+//! Finding and loading the units a program uses.
+//!
+//! `USES Name` looks for `Name.pa`, then `name.pa`, first next to the file
+//! that names it and then in each directory of the search path, in order.
+//! Units may not use each other in a cycle. They come back in the order
+//! they are initialized: every unit after the units it uses, each once, in
+//! the order they are first named.
+//!
+//! Unit files are read as the `SourceMap` they are added to says.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::ast::ident::Ident;
+use super::ast::unit::Unit;
+use super::err::diagnostic::{Diagnostic, Diagnostics};
+use super::parser::Parser;
+use super::preprocessor::Preprocessor;
+use super::source::{FileAccess, FileId, SourceMap};
+
+/// The file extension of Pascal sources.
+pub const EXTENSION: &str = "pa";
+
+/// Loads every unit `uses` names, directly or through other units. `file`
+/// is the file naming them; the files read are added to `sources`. Units
+/// may nest `max_depth` levels deep, as `Parser::with_max_depth` takes it.
+pub fn load(
+    uses: &[Ident],
+    file: FileId,
+    sources: &Arc<SourceMap>,
+    search_path: &[PathBuf],
+    max_depth: usize,
+) -> Result<Vec<Unit>, Diagnostics> {
+    let mut loader = Loader {
+        sources,
+        search_path,
+        max_depth,
+        units: Vec::new(),
+        loading: Vec::new(),
+    };
+    loader.uses(uses, file)?;
+    Ok(loader.units)
+}
+
+struct Loader<'a> {
+    sources: &'a Arc<SourceMap>,
+    search_path: &'a [PathBuf],
+    max_depth: usize,
+    /// The units loaded so far, in initialization order.
+    units: Vec<Unit>,
+    /// The units being loaded, each named by the one before.
+    loading: Vec<Ident>,
+}
+
+impl Loader<'_> {
+    fn uses(&mut self, uses: &[Ident], file: FileId) -> Result<(), Diagnostics> {
+        for name in uses {
+            if self.units.iter().any(|unit| unit.name == *name) {
+                continue;
+            }
+            if let Some(first) = self.loading.iter().position(|loading| loading == name) {
+                let cycle = self.loading[first..]
+                    .iter()
+                    .chain([name])
+                    .map(|unit| unit.name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(
+                    Diagnostic::new(format!("Unit `{}` uses itself: {}.", name.name, cycle.join(" -> ")))
+                        .with_span(name.span)
+                        .with_label("used in a cycle")
+                        .with_help("move what the units share into a unit of its own")
+                        .into()
+                );
+            }
+            let unit_file = self.find(name, file)?;
+            let unit = Parser::with_preprocessor(Preprocessor::with_sources(self.sources.clone(), unit_file))
+                .map_err(Diagnostics::from)
+                .and_then(|parser| parser.with_max_depth(self.max_depth).parse_unit())?;
+            if unit.name != *name {
+                return Err(
+                    Diagnostic::new(format!(
+                        "`{}` holds unit `{}`, not `{}`.",
+                        self.sources.file(unit_file).name, unit.name.name, name.name
+                    ))
+                    .with_span(unit.name.span)
+                    .with_label(format!("expected `{}`", name.name))
+                    .into()
+                );
+            }
+            self.loading.push(name.clone());
+            self.uses(&unit.interface.uses, unit_file)?;
+            self.uses(&unit.implementation.uses, unit_file)?;
+            self.loading.pop();
+            self.units.push(unit);
+        }
+        Ok(())
+    }
+
+    /// Reads the file of the unit `name`, named in `file`.
+    fn find(&self, name: &Ident, file: FileId) -> Result<FileId, Diagnostic> {
+        let naming = self.sources.file(file);
+        let dirs = std::iter::once(naming.resolve("")).chain(self.search_path.iter().cloned()).collect::<Vec<_>>();
+        let names = [name.name.clone(), name.name.to_lowercase()];
+        for dir in &dirs {
+            for candidate in &names {
+                let path = dir.join(format!("{}.{}", candidate, EXTENSION));
+                match self.sources.read(&path) {
+                    Ok(bytes) => return Ok(self.sources.add(path.to_string_lossy(), bytes)),
+                    Err(e) if matches!(self.sources.access(), FileAccess::Forbidden) => {
+                        return Err(
+                            Diagnostic::new(format!("Cannot load unit `{}`: {}.", name.name, e))
+                                .with_span(name.span)
+                                .with_label("used here")
+                        )
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        let searched = dirs
+            .iter()
+            .map(|dir| match dir.to_string_lossy() {
+                dir if dir.is_empty() => "`.`".to_string(),
+                dir => format!("`{}`", dir),
+            })
+            .collect::<Vec<_>>();
+        Err(
+            Diagnostic::new(format!("Cannot find unit `{}`.", name.name))
+                .with_span(name.span)
+                .with_label("not found")
+                .with_help(format!(
+                    "looked for `{}.{}` in {}; add directories to search with `--unit-path`",
+                    name.name, EXTENSION, searched.join(", ")
+                ))
+        )
+    }
+}
+
+/// Why each of `units`, as `load` returns them for `uses`, is initialized:
+/// the names in `USES` clauses that led to loading it, the program's first
+/// and the unit's own last.
+pub fn used_at(uses: &[Ident], units: &[Unit]) -> Vec<Vec<Ident>> {
+    fn visit(uses: &[Ident], units: &[Unit], chain: &mut Vec<Ident>, found: &mut [Vec<Ident>]) {
+        for name in uses {
+            let Some(index) = units.iter().position(|unit| unit.name == *name) else {
+                continue;
+            };
+            if !found[index].is_empty() {
+                continue;
+            }
+            chain.push(name.clone());
+            visit(&units[index].interface.uses, units, chain, found);
+            visit(&units[index].implementation.uses, units, chain, found);
+            found[index] = chain.clone();
+            chain.pop();
+        }
+    }
+    let mut found = vec![Vec::new(); units.len()];
+    visit(uses, units, &mut Vec::new(), &mut found);
+    found
+}
+
+/// Adds to `error`, raised in the initialization of a unit, a note for
+/// each name in `used_at` that led to it, innermost first, like a stack
+/// trace.
+pub fn initializing(mut error: Diagnostic, used_at: &[Ident]) -> Diagnostic {
+    for (depth, name) in used_at.iter().enumerate().rev() {
+        let user = match depth {
+            0 => "the program".to_string(),
+            _ => format!("unit `{}`", used_at[depth - 1].name),
+        };
+        let note = Diagnostic::new(format!("in the initialization of unit `{}`, used by {}", name.name, user))
+            .with_span(name.span)
+            .with_label("used here");
+        error = error.with_note(note);
+    }
+    error
+}
+
+#[test]
+fn loads_units_in_initialization_order() {
+    use super::limits::DEFAULT_DEPTH;
+
+    let dir = std::env::temp_dir().join(format!("rusterp-units-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    let unit = |path: &str, name: &str, uses: &str| {
+        let source = format!("UNIT {}; INTERFACE {} IMPLEMENTATION END.", name, uses);
+        std::fs::write(dir.join(path), source).unwrap();
+    };
+    unit("lib/shapes.pa", "Shapes", "USES Base, Maths;");
+    unit("lib/Base.pa", "Base", "");
+    unit("lib/maths.pa", "Maths", "USES Base;");
+    unit("lib/a.pa", "A", "USES B;");
+    unit("lib/b.pa", "B", "USES C;");
+    unit("lib/c.pa", "C", "USES A;");
+    unit("lib/wrong.pa", "Right", "");
+    unit("near.pa", "Near", "USES Base;");
+
+    let load = |uses: &str, search_path: &[PathBuf]| {
+        let sources = Arc::new(SourceMap::new());
+        let source = format!("PROGRAM p; USES {}; BEGIN END.", uses);
+        let file = sources.add(dir.join("main.pa").to_string_lossy(), source.into_bytes());
+        let program = Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file))
+            .unwrap()
+            .parse()
+            .unwrap();
+        load(&program.uses, file, &sources, search_path, DEFAULT_DEPTH)
+            .map(|units| units.iter().map(|unit| unit.name.name.clone()).collect::<Vec<_>>())
+            .map_err(|errors| errors.iter().next().unwrap().message().to_string())
+    };
+    let lib = [dir.join("lib")];
+
+    assert_eq!(load("Shapes, Maths", &lib).unwrap(), ["Base", "Maths", "Shapes"]);
+    let sources = Arc::new(SourceMap::new());
+    let file = sources.add(dir.join("main.pa").to_string_lossy(), b"PROGRAM p; USES Maths, Shapes; BEGIN END.".to_vec());
+    let program = Parser::with_preprocessor(Preprocessor::with_sources(sources.clone(), file)).unwrap().parse().unwrap();
+    let units = super::units::load(&program.uses, file, &sources, &lib, DEFAULT_DEPTH).unwrap();
+    let chains = used_at(&program.uses, &units)
+        .into_iter()
+        .map(|chain| chain.iter().map(|name| name.name.clone()).collect::<Vec<_>>().join(" -> "))
+        .collect::<Vec<_>>();
+    assert_eq!(chains, ["Maths -> Base", "Maths", "Shapes"]);
+    assert_eq!(load("Near", &lib).unwrap(), ["Base", "Near"]);
+    assert_eq!(load("Shapes", &[]).unwrap_err(), "Cannot find unit `Shapes`.");
+    assert_eq!(load("A", &lib).unwrap_err(), "Unit `A` uses itself: A -> B -> C -> A.");
+    assert_eq!(
+        load("Wrong", &lib).unwrap_err(),
+        format!("`{}` holds unit `Right`, not `Wrong`.", dir.join("lib").join("wrong.pa").display())
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,394 @@
+//! A stack machine running the bytecode `bytecode::compile` makes. It gives
+//! the same results and errors as walking the tree.
+//!
+//! Each call to a routine pushes a frame, whose locals sit on a stack of
+//! their own. A frame links to the frame of the procedure its own is
+//! declared in, which is how the routine reaches the locals around it.
+
+use super::bytecode::{Chunk, Op, Symbol};
+use super::err::diagnostic::Diagnostic;
+use super::host::{self, Host};
+use super::interpreter::{self, Value};
+use super::lexer::Span;
+use super::limits::{self, Limits};
+#[cfg(test)]
+use super::interpreter::Interpreter;
+#[cfg(test)]
+use super::parser::Parser;
+
+pub struct Vm<'a> {
+    chunk: &'a Chunk,
+    slots: Vec<Value>,
+    stack: Vec<Value>,
+    /// The calls being run, the outermost first.
+    frames: Vec<Frame>,
+    /// The locals of each frame, one after another.
+    locals: Vec<Value>,
+    /// The host whose functions the chunk calls, if it calls any.
+    host: Option<&'a Host>,
+    limits: Limits,
+}
+
+/// A call to a routine being run.
+struct Frame {
+    /// Where its locals start in `Vm::locals`.
+    base: usize,
+    /// The frame of the procedure its own is declared in, if any.
+    link: Option<usize>,
+    /// The instruction that made the call, which it returns after.
+    call: usize,
+}
+
+impl<'a> Vm<'a> {
+    /// A machine about to run `chunk`, with every slot zero.
+    pub fn new(chunk: &'a Chunk) -> Self {
+        Self {
+            chunk,
+            slots: chunk.slots.iter().map(|(_, ty)| Value::zero(*ty)).collect(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            host: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// A machine about to run `chunk`, compiled for `host`, with the slots
+    /// holding `slots`, one value of the slot's type for each slot.
+    pub fn with_host(chunk: &'a Chunk, slots: Vec<Value>, host: &'a Host) -> Self {
+        Self {
+            chunk,
+            slots,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            host: Some(host),
+            limits: Limits::default(),
+        }
+    }
+
+    /// The machine, stopping every run that goes past `limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Runs the chunk to its end, or up to the first error, which is traced
+    /// through the calls it was raised in.
+    pub fn run(&mut self) -> Result<(), Diagnostic> {
+        self.stack.clear();
+        self.frames.clear();
+        self.locals.clear();
+        let mut pc = 0;
+        self.execute(&mut pc).map_err(|error| {
+            let calls = self.frames.iter().map(|frame| frame.call).collect::<Vec<_>>();
+            self.chunk.trace(pc, &calls, error)
+        })
+    }
+
+    /// Runs the chunk, keeping `at` on the instruction running.
+    fn execute(&mut self, at: &mut usize) -> Result<(), Diagnostic> {
+        let chunk = self.chunk;
+        let end = chunk.top_level().end;
+        let mut clock = limits::Clock::start(&self.limits, self.slots.len());
+        let mut pc = 0;
+        while pc != end || !self.frames.is_empty() {
+            let here = pc;
+            *at = here;
+            pc += 1;
+            let span = || chunk.spans[here];
+            clock.tick(self.stack.len() + self.locals.len(), span)?;
+            let name = |index: u32| chunk.names[index as usize].as_str();
+            let value = match chunk.code[here] {
+                Op::Integer(value) => Value::Integer(value),
+                Op::Real(value) => Value::Real(value),
+                Op::Load(slot) => self.slots[slot as usize],
+                Op::Store(slot) => {
+                    self.slots[slot as usize] = self.pop();
+                    continue;
+                }
+                Op::LoadLocal { up, local } => self.locals[self.local(up, local)],
+                Op::StoreLocal { up, local } => {
+                    let value = self.pop();
+                    let local = self.local(up, local);
+                    self.locals[local] = value;
+                    continue;
+                }
+                Op::Call { routine, up } => {
+                    if self.frames.len() >= self.limits.calls {
+                        return Err(limits::too_many_calls(self.limits.calls, span()));
+                    }
+                    let routine = &chunk.routines[routine as usize];
+                    let link = self.frame(up);
+                    let base = self.locals.len();
+                    let args = self.stack.len() - routine.params as usize;
+                    self.locals.extend(self.stack.drain(args..));
+                    let variables = &routine.locals[routine.params as usize..];
+                    self.locals.extend(variables.iter().map(|(_, ty)| Value::zero(*ty)));
+                    self.frames.push(Frame { base, link, call: here });
+                    pc = routine.code.start;
+                    continue;
+                }
+                Op::Return => {
+                    let frame = self.frames.pop().expect("only routines return");
+                    self.locals.truncate(frame.base);
+                    pc = frame.call + 1;
+                    continue;
+                }
+                Op::Pop => {
+                    self.pop();
+                    continue;
+                }
+                Op::ToReal => Value::Real(self.integer() as f64),
+                Op::Truncate => Value::Integer(self.real() as i64),
+                Op::RangeCheck(target) => match self.real() {
+                    value if interpreter::fits_integer(value) => Value::Integer(value as i64),
+                    value => return Err(interpreter::out_of_range(value, name(target), span())),
+                },
+                Op::NonZero => match self.stack.last() {
+                    Some(divisor) if divisor.as_real() == 0.0 => return Err(interpreter::division_by_zero(span())),
+                    _ => continue,
+                },
+                Op::NegInteger { checked } => {
+                    let a = self.integer();
+                    integer(a.checked_neg(), a.wrapping_neg(), checked, span())?
+                }
+                Op::AddInteger { checked } => {
+                    let (a, b) = self.integers();
+                    integer(a.checked_add(b), a.wrapping_add(b), checked, span())?
+                }
+                Op::SubInteger { checked } => {
+                    let (a, b) = self.integers();
+                    integer(a.checked_sub(b), a.wrapping_sub(b), checked, span())?
+                }
+                Op::MulInteger { checked } => {
+                    let (a, b) = self.integers();
+                    integer(a.checked_mul(b), a.wrapping_mul(b), checked, span())?
+                }
+                Op::DivInteger { checked } => {
+                    let (a, b) = self.integers();
+                    integer(a.checked_div(b), a.wrapping_div(b), checked, span())?
+                }
+                Op::NegReal => Value::Real(-self.real()),
+                Op::AddReal => {
+                    let (a, b) = self.reals();
+                    Value::Real(a + b)
+                }
+                Op::SubReal => {
+                    let (a, b) = self.reals();
+                    Value::Real(a - b)
+                }
+                Op::MulReal => {
+                    let (a, b) = self.reals();
+                    Value::Real(a * b)
+                }
+                Op::DivReal => {
+                    let (a, b) = self.reals();
+                    Value::Real(a / b)
+                }
+                Op::NotFound(target) => return Err(interpreter::not_found(name(target), span())),
+                Op::NotAVariable(target) => return Err(interpreter::not_a_variable(name(target), span())),
+                Op::NotAssignable(target) => return Err(interpreter::not_assignable(name(target), span())),
+                Op::NotFoundToCall(target) => return Err(interpreter::not_found_to_call(name(target), span())),
+                Op::NotAProcedure(target) => return Err(interpreter::not_a_procedure(name(target), span())),
+                Op::NoValue(target) => return Err(host::no_value(name(target), span())),
+                Op::RealDivOperand => return Err(interpreter::real_div_operand(span())),
+                Op::Host { function, args, .. } => {
+                    let host = self.host.expect("chunks calling host functions run with their host");
+                    let args = self.stack.split_off(self.stack.len() - args as usize);
+                    let result = host.get(function).call(&args, span())?;
+                    clock.check_time(span)?;
+                    match result {
+                        Some(result) => result,
+                        None => continue,
+                    }
+                }
+            };
+            self.stack.push(value);
+        }
+        Ok(())
+    }
+
+    /// The program's globals with names as declared, sorted regardless of
+    /// case: the value of each variable, or `None` for a procedure.
+    pub fn globals(&self) -> Vec<(String, Option<Value>)> {
+        self.chunk
+            .globals
+            .iter()
+            .map(|(name, symbol)| match symbol {
+                Symbol::Variable { slot, .. } => (name.clone(), Some(self.slots[*slot as usize])),
+                Symbol::Procedure => (name.clone(), None),
+            })
+            .collect()
+    }
+
+    pub fn print_global_scope(&self) {
+        for (name, value) in self.globals() {
+            match value {
+                Some(value) => println!("{} : {} = {}", name, value.ty().name(), value),
+                None => println!("{} : PROCEDURE", name),
+            }
+        }
+    }
+
+    /// The frame `up` frames out from that of the routine running, or `None`
+    /// past the outermost.
+    fn frame(&self, up: u32) -> Option<usize> {
+        let mut frame = self.frames.len().checked_sub(1);
+        for _ in 0..up {
+            frame = frame.and_then(|frame| self.frames[frame].link);
+        }
+        frame
+    }
+
+    /// Where a local, as `Op::LoadLocal` finds it, is in `locals`.
+    fn local(&self, up: u32, local: u32) -> usize {
+        let frame = self.frame(up).expect("the compiler finds locals in frames");
+        self.frames[frame].base + local as usize
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("the compiler pushes every operand it pops")
+    }
+
+    fn integer(&mut self) -> i64 {
+        match self.pop() {
+            Value::Integer(value) => value,
+            value => unreachable!("{} is not an INTEGER", value),
+        }
+    }
+
+    fn real(&mut self) -> f64 {
+        match self.pop() {
+            Value::Real(value) => value,
+            value => unreachable!("{} is not a REAL", value),
+        }
+    }
+
+    /// The two operands of a binary INTEGER instruction, left first.
+    fn integers(&mut self) -> (i64, i64) {
+        let right = self.integer();
+        (self.integer(), right)
+    }
+
+    fn reals(&mut self) -> (f64, f64) {
+        let right = self.real();
+        (self.real(), right)
+    }
+}
+
+/// The result of INTEGER arithmetic: `checked` when overflow is checked,
+/// `wrapped` when it is not.
+fn integer(checked: Option<i64>, wrapped: i64, check: bool, span: Span) -> Result<Value, Diagnostic> {
+    match checked {
+        None if check => Err(interpreter::overflow(span)),
+        _ => Ok(Value::Integer(checked.unwrap_or(wrapped))),
+    }
+}
+
+
+#[test]
+fn runs_like_the_tree_walker() {
+    super::corpus::check(|program, units, sources| {
+        let chunk = super::bytecode::compile(program, units);
+        let mut vm = Vm::new(&chunk);
+        vm.run()
+            .map(|()| {
+                let globals = vm.globals().into_iter();
+                globals
+                    .map(|(name, value)| match value {
+                        Some(value) => format!("{} : {} = {}\n", name, value.ty().name(), value),
+                        None => format!("{} : PROCEDURE\n", name),
+                    })
+                    .collect()
+            })
+            .map_err(|e| sources.render(&e.into(), false))
+    });
+}
+
+#[test]
+fn traces_errors_to_the_units_using_them() {
+    let unit = |source: &str| Parser::new(source.as_bytes()).unwrap().parse_unit().unwrap();
+    let units = [
+        unit("UNIT Broken; INTERFACE VAR x : INTEGER; IMPLEMENTATION BEGIN x := 1 DIV x END."),
+        unit("UNIT Outer; INTERFACE USES Broken; IMPLEMENTATION BEGIN x := 2 END."),
+    ];
+    let program = Parser::new(b"PROGRAM p; USES Outer; BEGIN END.").unwrap().parse().unwrap();
+    let chunk = super::bytecode::compile(&program, &units);
+    let compiled = Vm::new(&chunk).run().unwrap_err();
+    let walked = Interpreter::with_units(program, units.to_vec()).interprete().unwrap_err();
+    assert_eq!(compiled, walked);
+    let notes = compiled.notes().map(|note| (note.message(), note.span().unwrap().start)).collect::<Vec<_>>();
+    assert_eq!(
+        notes,
+        [
+            ("in the initialization of unit `Broken`, used by unit `Outer`", 27),
+            ("in the initialization of unit `Outer`, used by the program", 16),
+        ]
+    );
+}
+
+#[test]
+fn traces_errors_through_the_calls_running() {
+    let unit = |source: &str| Parser::new(source.as_bytes()).unwrap().parse_unit().unwrap();
+    let units = [unit(
+        "UNIT Broken; INTERFACE VAR x : INTEGER; PROCEDURE Divide(by : INTEGER);
+        IMPLEMENTATION PROCEDURE Divide(by : INTEGER); BEGIN x := x DIV by END; BEGIN x := 4 END.",
+    )];
+    let source = "PROGRAM p; USES Broken;
+        PROCEDURE Outer(n : INTEGER); PROCEDURE Inner; BEGIN Divide(n) END; BEGIN Inner() END;
+        BEGIN Outer(2); Outer(0) END.";
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let chunk = super::bytecode::compile(&program, &units);
+    let compiled = Vm::new(&chunk).run().unwrap_err();
+    let walked = Interpreter::with_units(program, units.to_vec()).interprete().unwrap_err();
+    assert_eq!(compiled, walked);
+    let notes = compiled.notes().map(|note| (note.message(), note.span().unwrap().start)).collect::<Vec<_>>();
+    assert_eq!(
+        notes,
+        [
+            ("in procedure `Divide`, called by procedure `Inner`", 85),
+            ("in procedure `Inner`, called by procedure `Outer`", 106),
+            ("in procedure `Outer`, called by the program", 143),
+        ]
+    );
+}
+
+/// Times both engines on a long arithmetic program, run over and over
+/// the way the body of a loop would be. Run it with
+/// `cargo test --release -- --ignored --nocapture compares_speed`.
+#[test]
+#[ignore]
+fn compares_speed() {
+    let body = "i := i + 1; s := s + i * i DIV 3 - j; j := (s - i) DIV 7 + j; r := r + i / 3.5 - s * 0.25;\n";
+    let source = format!(
+        "PROGRAM bench; VAR i, j, s : INTEGER; r : REAL; BEGIN\n{}END.",
+        body.repeat(1000)
+    );
+    let program = Parser::new(source.as_bytes()).unwrap().parse().unwrap();
+    let runs = 200;
+
+    let interpreter = Interpreter::from_program(program.clone());
+    let start = std::time::Instant::now();
+    for _ in 0..runs {
+        interpreter.interprete().unwrap();
+    }
+    let walked = start.elapsed();
+
+    let chunk = super::bytecode::compile(&program, &[]);
+    let start = std::time::Instant::now();
+    let mut vm = Vm::new(&chunk);
+    for _ in 0..runs {
+        vm.run().unwrap();
+    }
+    let compiled = start.elapsed();
+
+    println!(
+        "{} statements, {} runs: tree {:?}, vm {:?}, {:.1}x faster",
+        4000,
+        runs,
+        walked,
+        compiled,
+        walked.as_secs_f64() / compiled.as_secs_f64()
+    );
+}